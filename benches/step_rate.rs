@@ -0,0 +1,75 @@
+//! Measures the step rates achievable through the GPIO character device on the target board.
+//!
+//! Run with `cargo bench --bench step_rate`. The chip and pin can be changed with the
+//! `GPIO_CHIP` (default /dev/gpiochip0) and `STEP_PIN` (default 13) environment variables. The
+//! pin is toggled, so nothing that reacts to it should be connected.
+
+use gpio_cdev::{Chip, LineRequestFlags};
+use std::time::{Duration, Instant};
+use stepper_rs::gpio::OutputPin;
+use stepper_rs::step_thread::busy_wait;
+
+const PULSES: u32 = 20_000;
+
+fn report(name: &str, pulses: u32, elapsed: Duration) {
+    println!(
+        "{:<40} {:>10.0} steps/s ({:.2} µs per step)",
+        name,
+        pulses as f64 / elapsed.as_secs_f64(),
+        elapsed.as_secs_f64() * 1e6 / pulses as f64
+    );
+}
+
+fn main() {
+    let chip_path = std::env::var("GPIO_CHIP").unwrap_or_else(|_| "/dev/gpiochip0".to_owned());
+    let pin: u32 = std::env::var("STEP_PIN")
+        .ok()
+        .and_then(|pin| pin.parse().ok())
+        .unwrap_or(13);
+
+    let mut chip = match Chip::new(&chip_path) {
+        Ok(chip) => chip,
+        Err(err) => {
+            println!(
+                "Skipping step rate benchmark, could not open {}: {}",
+                chip_path, err
+            );
+            return;
+        }
+    };
+
+    // What Connection::pin_up used to do: request the line again for every pulse
+    let start = Instant::now();
+    for _ in 0..PULSES / 10 {
+        let handle = chip
+            .get_line(pin)
+            .unwrap()
+            .request(LineRequestFlags::OUTPUT, 0, "step_rate_bench")
+            .unwrap();
+        handle.set_value(1).unwrap();
+        handle.set_value(0).unwrap();
+    }
+    report("line request per pulse", PULSES / 10, start.elapsed());
+
+    let step_pin = OutputPin::request(&mut chip, pin, false, "step_rate_bench").unwrap();
+
+    let start = Instant::now();
+    for _ in 0..PULSES {
+        step_pin.set_high().unwrap();
+        step_pin.set_low().unwrap();
+    }
+    report("cached line handle", PULSES, start.elapsed());
+
+    // Same timing as Tmc2209::step: 1 µs high, 1 µs low
+    let width = Duration::from_micros(1);
+    let start = Instant::now();
+    for _ in 0..PULSES {
+        step_pin.pulse(width).unwrap();
+        busy_wait(width);
+    }
+    report(
+        "cached line handle, 1 µs pulse width",
+        PULSES,
+        start.elapsed(),
+    );
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use stepper_rs::driver::tmc2209::Tmc2209;
+use stepper_rs::gcode::Interpreter;
+use stepper_rs::motion_controller::MotionController;
+use stepper_rs::motion_group::MotionGroup;
+
+const USAGE: &str = "Usage: stepper-gcode [--axis STEP,DIR,EN]... [--steps-per-mm X,Y,Z] [FILE|-]
+
+Streams G-code from FILE (or stdin when FILE is - or missing) to TMC2209 drivers.
+Each --axis adds the next axis (X, Y then Z) using the given GPIO pins.";
+
+struct Options {
+    axes: Vec<(u8, u8, u8)>,
+    steps_per_mm: [f64; 3],
+    input: Option<String>,
+}
+
+fn parse_list<T: std::str::FromStr>(value: &str) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(|item| {
+            item.trim()
+                .parse()
+                .map_err(|_| format!("Invalid value '{}'", item))
+        })
+        .collect()
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        axes: Vec::new(),
+        steps_per_mm: [80.0, 80.0, 400.0],
+        input: None,
+    };
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--axis" => {
+                let value = args.next().ok_or("--axis needs STEP,DIR,EN pins")?;
+                match parse_list::<u8>(&value)?.as_slice() {
+                    [step, dir, en] => options.axes.push((*step, *dir, *en)),
+                    _ => return Err("--axis needs exactly three pins".to_owned()),
+                }
+            }
+            "--steps-per-mm" => {
+                let value = args.next().ok_or("--steps-per-mm needs a value")?;
+                for (axis, steps) in parse_list::<f64>(&value)?.into_iter().take(3).enumerate() {
+                    if !steps.is_finite() || steps <= 0.0 {
+                        return Err("--steps-per-mm needs values greater than zero".to_owned());
+                    }
+                    options.steps_per_mm[axis] = steps;
+                }
+            }
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if options.input.is_none() => options.input = Some(arg),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+
+    if options.axes.is_empty() {
+        options.axes.push((13, 19, 26));
+    }
+    if options.axes.len() > 3 {
+        return Err("At most three axes (X, Y, Z) are supported".to_owned());
+    }
+    Ok(options)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+
+    let input: Box<dyn BufRead> = match options.input.as_deref() {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("Could not open {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut controllers = Vec::new();
+    for ((step, dir, en), name) in options.axes.iter().zip(["X", "Y", "Z"]) {
+        let driver = Tmc2209::builder()
+            .step_pin(*step as u32)
+            .dir_pin(*dir as u32)
+            .en_pin(*en as u32)
+            .build();
+        match driver {
+            Ok(driver) => controllers.push(MotionController::new(name.to_owned(), driver)),
+            Err(err) => {
+                eprintln!("Could not set up the {} axis: {}", name, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut interpreter = Interpreter::new(MotionGroup::new(controllers), options.steps_per_mm);
+
+    for (number, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("Could not read line {}: {}", number + 1, err);
+                std::process::exit(1);
+            }
+        };
+
+        if let Err(err) = interpreter.execute_line(&line).await {
+            eprintln!("Line {}: {} ({})", number + 1, err, line.trim());
+            std::process::exit(1);
+        }
+        println!("ok");
+    }
+
+    if let Err(err) = interpreter.flush().await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
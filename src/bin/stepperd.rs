@@ -0,0 +1,352 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use stepper_rs::cancel::EmergencyStop;
+use stepper_rs::connection::{BusStats, Connection};
+use stepper_rs::driver::tmc2209::Tmc2209;
+use stepper_rs::gpio::DEFAULT_CHIP;
+use stepper_rs::handle::AxisHandle;
+use stepper_rs::motion_controller::MotionController;
+use stepper_rs::rpc::{Service, DEFAULT_SOCKET, DEFAULT_STATUS_INTERVAL};
+use stepper_rs::stepper::ConfigurableDriver;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Longest wait for an axis to stop and disable on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Daemon owning the UART and GPIO chip of TMC2209 drivers, applications control the axes with
+/// JSON-RPC on a Unix socket, see `stepper_rs::rpc`
+#[derive(Parser)]
+#[command(name = "stepperd", version)]
+struct Cli {
+    /// TOML file with the bus and one [[axis]] table per driver
+    #[arg(long, short, default_value = "/etc/stepperd.toml")]
+    config: PathBuf,
+    /// Socket to listen on, overrides the config
+    #[arg(long)]
+    socket: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    socket: Option<PathBuf>,
+    /// Permissions of the socket, e.g. 0o660 to let a group of applications connect
+    socket_mode: Option<u32>,
+    port: Option<String>,
+    baud: Option<u32>,
+    gpio_chip: Option<String>,
+    /// Interval of the driver status reads of each axis [default: 200]
+    status_interval_ms: Option<u64>,
+    /// Address of the REST and WebSocket API and the metrics, e.g. "0.0.0.0:8080", needs the http
+    /// feature
+    http: Option<SocketAddr>,
+    /// Interval of the WebSocket telemetry frames [default: 200]
+    telemetry_interval_ms: Option<u64>,
+    /// Broker to publish the telemetry to and take commands from, needs the mqtt feature
+    mqtt: Option<MqttConfig>,
+    axis: Vec<AxisConfig>,
+}
+
+/// Read without the mqtt feature too, so a config written for it fails with a clear error
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+struct MqttConfig {
+    /// Host name or address of the broker
+    broker: String,
+    port: Option<u16>,
+    /// [default: stepperd-<hostname>]
+    client_id: Option<String>,
+    /// Topic below which the machine publishes and takes commands [default: stepperd/<hostname>]
+    prefix: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    /// Interval of the telemetry [default: 1000]
+    telemetry_interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct AxisConfig {
+    name: String,
+    /// Node address 0-3 set with the MS1 and MS2 pins
+    #[serde(default)]
+    address: u8,
+    step_pin: u32,
+    dir_pin: u32,
+    en_pin: u32,
+    max_speed: Option<f64>,
+    acceleration: Option<f64>,
+    /// RMS run current in mA
+    current: Option<u16>,
+    microsteps: Option<u16>,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|err| format!("Invalid config {}: {}", path.display(), err))?;
+        if cfg!(not(feature = "http")) && config.http.is_some() {
+            return Err("stepperd was built without the http feature".to_owned());
+        }
+        if cfg!(not(feature = "mqtt")) && config.mqtt.is_some() {
+            return Err("stepperd was built without the mqtt feature".to_owned());
+        }
+        if config.status_interval_ms == Some(0) {
+            return Err("status-interval-ms must be greater than zero".to_owned());
+        }
+        if config.telemetry_interval_ms == Some(0) {
+            return Err("telemetry-interval-ms must be greater than zero".to_owned());
+        }
+        if let Some(mqtt) = &config.mqtt {
+            if mqtt.telemetry_interval_ms == Some(0) {
+                return Err("mqtt.telemetry-interval-ms must be greater than zero".to_owned());
+            }
+            if mqtt
+                .client_id
+                .as_ref()
+                .is_some_and(|id| id.trim().is_empty())
+            {
+                return Err("mqtt.client-id must not be empty".to_owned());
+            }
+            if let Some(prefix) = &mqtt.prefix {
+                if prefix.is_empty() || prefix.ends_with('/') || prefix.contains(['+', '#']) {
+                    return Err(format!("Invalid mqtt.prefix '{}'", prefix));
+                }
+            }
+        }
+        if config.axis.is_empty() {
+            return Err(format!("{} has no [[axis]]", path.display()));
+        }
+        for (index, axis) in config.axis.iter().enumerate() {
+            if config.axis[..index]
+                .iter()
+                .any(|other| other.name == axis.name)
+            {
+                return Err(format!("Axis {} is configured twice", axis.name));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Axes of the config and the counters of the UART they share
+fn spawn_axes(
+    config: &Config,
+    emergency_stop: &EmergencyStop,
+) -> Result<(Vec<AxisHandle<Tmc2209>>, Arc<BusStats>), String> {
+    let gpio_chip = config.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP);
+    let status_interval = config
+        .status_interval_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_STATUS_INTERVAL);
+    // The drivers share the UART, each one tells its replies apart by its node address
+    let bus = Connection::open_with_baud_rate(
+        config.port.as_deref().unwrap_or(Connection::UART_PORT),
+        gpio_chip,
+        config.baud.unwrap_or(Connection::UART_BAUDRATE),
+    )?;
+
+    let mut axes = Vec::with_capacity(config.axis.len());
+    for axis in &config.axis {
+        let setup = |err: &str| format!("Could not set up axis {}: {}", axis.name, err);
+        let mut driver = Tmc2209::builder()
+            .step_pin(axis.step_pin)
+            .dir_pin(axis.dir_pin)
+            .en_pin(axis.en_pin)
+            .node_address(axis.address)
+            .connection(bus.share(gpio_chip).map_err(setup)?)
+            .build()
+            .map_err(setup)?;
+        if let Some(current) = axis.current {
+            driver.set_motor_current(current).map_err(setup)?;
+        }
+        if let Some(microsteps) = axis.microsteps {
+            driver.set_microsteps(microsteps).map_err(setup)?;
+        }
+
+        let mut controller = MotionController::new(axis.name.clone(), driver);
+        if let Some(max_speed) = axis.max_speed {
+            controller.set_max_speed(max_speed).map_err(setup)?;
+        }
+        if let Some(acceleration) = axis.acceleration {
+            controller.set_acceleration(acceleration).map_err(setup)?;
+        }
+        controller.set_emergency_stop(emergency_stop);
+        controller.set_status_interval(Some(status_interval));
+        axes.push(AxisHandle::spawn(controller).map_err(setup)?);
+    }
+    Ok((axes, bus.stats()))
+}
+
+fn bind(path: &Path, mode: Option<u32>) -> Result<UnixListener, String> {
+    // A socket left behind by a daemon that did not shut down cleanly
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(format!("Could not remove {}: {}", path.display(), err));
+        }
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|err| format!("Could not listen on {}: {}", path.display(), err))?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|err| format!("Could not set the mode of {}: {}", path.display(), err))?;
+    }
+    Ok(listener)
+}
+
+/// Runs the REST and WebSocket API when the config has an address for it, otherwise never ends
+#[cfg(feature = "http")]
+async fn serve_http(
+    config: &Config,
+    service: Arc<Service<Tmc2209>>,
+    bus: Arc<BusStats>,
+) -> Result<(), String> {
+    use stepper_rs::http;
+
+    let Some(address) = config.http else {
+        return std::future::pending().await;
+    };
+    let interval = config
+        .telemetry_interval_ms
+        .map(Duration::from_millis)
+        .unwrap_or(http::DEFAULT_TELEMETRY_INTERVAL);
+    println!("HTTP API on {}", address);
+    http::serve(service, address, interval, Some(bus))
+        .await
+        .map_err(|err| format!("HTTP API on {} failed: {}", address, err))
+}
+
+#[cfg(not(feature = "http"))]
+async fn serve_http(
+    _config: &Config,
+    _service: Arc<Service<Tmc2209>>,
+    _bus: Arc<BusStats>,
+) -> Result<(), String> {
+    std::future::pending().await
+}
+
+#[cfg(feature = "mqtt")]
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_owned())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_owned())
+}
+
+/// Runs the MQTT client when the config has a broker, otherwise never ends. The client keeps
+/// reconnecting to the broker so it never ends either.
+#[cfg(feature = "mqtt")]
+async fn serve_mqtt(config: &Config, service: Arc<Service<Tmc2209>>) {
+    use stepper_rs::mqtt;
+
+    let Some(mqtt) = &config.mqtt else {
+        return std::future::pending().await;
+    };
+    let host = hostname();
+    let client_id = mqtt
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("stepperd-{}", host));
+    let prefix = mqtt
+        .prefix
+        .clone()
+        .unwrap_or_else(|| format!("stepperd/{}", host));
+    let mut options = rumqttc::MqttOptions::new(
+        client_id,
+        &mqtt.broker,
+        mqtt.port.unwrap_or(mqtt::DEFAULT_PORT),
+    );
+    if let Some(username) = &mqtt.username {
+        options.set_credentials(username, mqtt.password.as_deref().unwrap_or(""));
+    }
+    let interval = mqtt
+        .telemetry_interval_ms
+        .map(Duration::from_millis)
+        .unwrap_or(mqtt::DEFAULT_TELEMETRY_INTERVAL);
+    println!("MQTT broker {}", mqtt.broker);
+    let (state, mut states) = tokio::sync::watch::channel(mqtt::ConnectionState::Connecting);
+    let topics = prefix.clone();
+    tokio::spawn(async move {
+        while states.changed().await.is_ok() {
+            match &*states.borrow_and_update() {
+                mqtt::ConnectionState::Connected => {
+                    println!("Connected to the MQTT broker, topics below {}", topics)
+                }
+                mqtt::ConnectionState::Failed(err) => println!(
+                    "MQTT connection failed: {}, reconnecting in {}s",
+                    err,
+                    mqtt::RECONNECT_DELAY.as_secs()
+                ),
+                mqtt::ConnectionState::Connecting => {}
+            }
+        }
+    });
+    mqtt::serve(service, options, &prefix, interval, state).await
+}
+
+#[cfg(not(feature = "mqtt"))]
+async fn serve_mqtt(_config: &Config, _service: Arc<Service<Tmc2209>>) {
+    std::future::pending().await
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let config = Config::load(&cli.config)?;
+    let socket = cli
+        .socket
+        .or(config.socket.clone())
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SOCKET));
+
+    let emergency_stop = EmergencyStop::new();
+    let (axes, bus) = spawn_axes(&config, &emergency_stop)?;
+    let names: Vec<&str> = axes.iter().map(|axis| axis.name()).collect();
+    println!("Axes: {}", names.join(", "));
+
+    let listener = bind(&socket, config.socket_mode)?;
+    println!("Listening on {}", socket.display());
+    let service = Arc::new(Service::new(axes, emergency_stop.clone()));
+
+    let http = serve_http(&config, service.clone(), bus);
+    let mqtt = serve_mqtt(&config, service.clone());
+
+    let mut terminate =
+        signal(SignalKind::terminate()).map_err(|err| format!("No SIGTERM handler: {}", err))?;
+    let result = tokio::select! {
+        result = service.clone().serve(listener) => result.map_err(|err| err.to_string()),
+        result = http => result,
+        _ = mqtt => Ok(()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        _ = terminate.recv() => Ok(()),
+    };
+
+    println!("Shutting down");
+    emergency_stop.trigger();
+    // Disabling queues behind the aborted moves, so the motors are off once it returned
+    for axis in service.axes() {
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, axis.disable()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("Could not disable axis {}: {}", axis.name(), err),
+            Err(_) => eprintln!("Axis {} did not stop in time", axis.name()),
+        }
+    }
+    let _ = std::fs::remove_file(&socket);
+    result
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run(Cli::parse()).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
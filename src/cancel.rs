@@ -0,0 +1,163 @@
+//! Stopping moves while they run. A `CancelToken` is handed to a move and checked before every
+//! step, `EmergencyStop` is a token shared by every controller that aborts all of them at once.
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// How a move is asked to end early
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum StopMode {
+    /// Decelerate to standstill using the acceleration of the move
+    Stop,
+    /// Stop stepping right away and disable the motors
+    Abort,
+}
+
+/// How a move ended
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveOutcome {
+    Completed,
+    Stopped,
+    Aborted,
+    /// A limit or home switch in the direction of travel triggered, the motor stays enabled
+    SwitchTriggered,
+}
+
+impl From<StopMode> for MoveOutcome {
+    fn from(mode: StopMode) -> Self {
+        match mode {
+            StopMode::Stop => MoveOutcome::Stopped,
+            StopMode::Abort => MoveOutcome::Aborted,
+        }
+    }
+}
+
+const RUNNING: u8 = 0;
+const STOP: u8 = 1;
+const ABORT: u8 = 2;
+
+/// Flag asking a move to stop. Clones share the flag, so one clone can be given to the move while
+/// another one stops it from a different task or thread.
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    flags: Vec<Arc<AtomicU8>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self {
+            flags: vec![Arc::new(AtomicU8::new(RUNNING))],
+        }
+    }
+
+    /// Asks the move to decelerate to standstill, does nothing once an abort was requested
+    pub fn stop(&self) {
+        let _ = self.flags[0].compare_exchange(RUNNING, STOP, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub fn abort(&self) {
+        self.flags[0].store(ABORT, Ordering::SeqCst);
+    }
+
+    /// Clears a request made on this token, requests of linked tokens are left alone
+    pub fn reset(&self) {
+        self.flags[0].store(RUNNING, Ordering::SeqCst);
+    }
+
+    /// Strongest request made on this token or any token linked to it
+    pub fn requested(&self) -> Option<StopMode> {
+        match self
+            .flags
+            .iter()
+            .map(|flag| flag.load(Ordering::SeqCst))
+            .max()
+        {
+            Some(STOP) => Some(StopMode::Stop),
+            Some(ABORT) => Some(StopMode::Abort),
+            _ => None,
+        }
+    }
+
+    /// Token that also reports the requests made on `other`. Stopping the returned token only
+    /// stops this one.
+    pub fn linked(&self, other: &CancelToken) -> CancelToken {
+        let mut flags = self.flags.clone();
+        flags.extend(other.flags.iter().cloned());
+        Self { flags }
+    }
+}
+
+/// Aborts every move of the controllers it was given to until it is reset
+#[derive(Clone, Debug, Default)]
+pub struct EmergencyStop {
+    token: CancelToken,
+}
+
+impl EmergencyStop {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.abort();
+    }
+
+    /// Allows moves again, the motors stay disabled until the next move enables them
+    pub fn reset(&self) {
+        self.token.reset();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.requested().is_some()
+    }
+
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abort_overrides_stop() {
+        let token = CancelToken::new();
+        assert_eq!(token.requested(), None);
+
+        token.clone().stop();
+        assert_eq!(token.requested(), Some(StopMode::Stop));
+        token.abort();
+        token.stop();
+        assert_eq!(token.requested(), Some(StopMode::Abort));
+
+        token.reset();
+        assert_eq!(token.requested(), None);
+    }
+
+    #[test]
+    fn linked_emergency_stop() {
+        let estop = EmergencyStop::new();
+        let token = CancelToken::new();
+        let linked = token.linked(estop.token());
+
+        token.stop();
+        assert_eq!(linked.requested(), Some(StopMode::Stop));
+        assert!(!estop.is_triggered());
+
+        estop.trigger();
+        assert_eq!(linked.requested(), Some(StopMode::Abort));
+        linked.reset();
+        assert_eq!(linked.requested(), Some(StopMode::Abort));
+        estop.reset();
+        token.reset();
+        assert_eq!(linked.requested(), None);
+    }
+}
@@ -1,7 +1,11 @@
-use gpio_cdev::{Chip, LineRequestFlags};
-use mockall::automock;
+pub mod spi;
+
+use crate::gpio::{open_chip, OutputPin, DEFAULT_CHIP};
+use gpio_cdev::Chip;
 use serialport::{ClearBuffer, DataBits, Parity, SerialPort, StopBits};
 use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
 //pub enum ConnectionType {
@@ -9,18 +13,75 @@ use std::time::Duration;
     //SPI,
 //}
 
-#[automock]
+/// CRC8 of a datagram of the TMC UART, `datagram` holds the bytes before the CRC byte
+pub fn crc(datagram: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in datagram {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            crc = match (crc >> 7) ^ (byte & 0x01) > 0 {
+                true => (crc << 1) ^ 0x07,
+                false => crc << 1,
+            };
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+/// Counters of the transactions on a UART, shared by the connections of the drivers on it
+#[derive(Debug, Default)]
+pub struct BusStats {
+    transactions: AtomicU64,
+    crc_errors: AtomicU64,
+    retries: AtomicU64,
+    write_check_failures: AtomicU64,
+}
+
+impl BusStats {
+    /// Requests sent on the bus, retries included
+    pub fn transactions(&self) -> u64 {
+        self.transactions.load(Ordering::Relaxed)
+    }
+
+    /// Replies whose CRC did not match
+    pub fn crc_errors(&self) -> u64 {
+        self.crc_errors.load(Ordering::Relaxed)
+    }
+
+    /// Read requests sent again because the previous attempt got no valid reply
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Writes the driver did not count in IFCNT, see `Tmc2209::write_register`
+    pub fn write_check_failures(&self) -> u64 {
+        self.write_check_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn record_write_check_failure(&self) {
+        self.write_check_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 pub struct Connection {
     //connection: ConnectionType,
-    port: Box<dyn SerialPort>,
+    /// Shared by the connections of the drivers on the same bus, see `share`
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
+    stats: Arc<BusStats>,
     chip: Chip
 }
 
 impl Connection {
     //const UART_PORT: &'static str = "/dev/ttyAMA0";
-    const UART_PORT: &'static str = "/dev/ttyS0";
-    const UART_BAUDRATE: u32 = 9600;
+    pub const UART_PORT: &'static str = "/dev/ttyS0";
+    pub const UART_BAUDRATE: u32 = 9600;
     const CALLING_PAUSE: Duration = Duration::from_millis((14) as u64);
+    const READ_ATTEMPTS: u32 = 10;
     // Duration::from_millis((500 / Self::UART_BAUDRATE * 100) as u64);
 
     pub fn new() -> Self {
@@ -30,74 +91,148 @@ impl Connection {
             println!("{}", p.port_name);
         }
 
-        Self {
-            port: Self::get_port(),
-            chip: Chip::new("/dev/gpiochip0").ok().unwrap(),
+        Self::open(Self::UART_PORT, DEFAULT_CHIP).unwrap()
+    }
+
+    /// Opens a UART and GPIO chip other than the defaults of `new`
+    pub fn open(uart_port: &str, gpio_chip: &str) -> Result<Self, &'static str> {
+        Self::open_with_baud_rate(uart_port, gpio_chip, Self::UART_BAUDRATE)
+    }
+
+    /// Same as `open` with a baud rate other than 9600, the TMC2209 detects the baud rate on its own
+    pub fn open_with_baud_rate(
+        uart_port: &str,
+        gpio_chip: &str,
+        baud_rate: u32,
+    ) -> Result<Self, &'static str> {
+        if baud_rate == 0 {
+            return Err("Baud rate must be greater than zero");
         }
+        Ok(Self {
+            port: Arc::new(Mutex::new(Self::get_port(uart_port, baud_rate)?)),
+            stats: Arc::new(BusStats::default()),
+            chip: open_chip(gpio_chip)?,
+        })
+    }
+
+    /// Connection for another driver on the same UART, e.g. with another node address. The
+    /// serial port can only be opened once, so the connections share it and take turns for each
+    /// transaction.
+    pub fn share(&self, gpio_chip: &str) -> Result<Self, &'static str> {
+        Ok(Self {
+            port: self.port.clone(),
+            stats: self.stats.clone(),
+            chip: open_chip(gpio_chip)?,
+        })
     }
 
-    pub fn pin_up(&mut self, pin: u32) {
-        println!("{:?}", pin);
+    /// Counters of the bus, the same for every connection sharing it
+    pub fn stats(&self) -> Arc<BusStats> {
+        self.stats.clone()
+    }
 
-        //chip: Chip::new("/dev/gpiochip0").ok(),
+    fn lock_port(&self) -> MutexGuard<'_, Box<dyn SerialPort>> {
+        // A panic during a transaction leaves nothing half done the next one depends on
+        self.port.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 
-        let handle = self
-        .chip
-        .get_line(pin)
-        .unwrap()
-        .request(LineRequestFlags::OUTPUT, 0, "step_request")
-        .unwrap();
-        
-        handle.set_value(1).unwrap();
+    /// Requests a GPIO line as output once, the returned pin keeps the line until it is dropped
+    pub fn request_output(
+        &mut self,
+        pin: u32,
+        initial_high: bool,
+        consumer: &str,
+    ) -> Result<OutputPin, &'static str> {
+        OutputPin::request(&mut self.chip, pin, initial_high, consumer)
     }
 
-    pub fn pin_down(&mut self, pin: u32) {
-        println!("{:?}", pin);
+    /// Requests `pin` as output on the chip of the connection at the level it has now
+    pub fn request_output_at_current_level(
+        &mut self,
+        pin: u32,
+        consumer: &str,
+    ) -> Result<OutputPin, &'static str> {
+        OutputPin::request_at_current_level(&mut self.chip, pin, consumer)
     }
 
-    fn get_port() -> Box<dyn SerialPort> {
-        serialport::new(Self::UART_PORT, Self::UART_BAUDRATE)
-            .timeout(Duration::from_secs((20000 / Self::UART_BAUDRATE).into()))
+    fn get_port(uart_port: &str, baud_rate: u32) -> Result<Box<dyn SerialPort>, &'static str> {
+        serialport::new(uart_port, baud_rate)
+            .timeout(Duration::from_millis((20_000_000 / baud_rate).max(50).into()))
             .parity(Parity::None)
             .stop_bits(StopBits::One)
             .data_bits(DataBits::Eight)
             .open()
-            .expect("Serial port could not connect")
+            .map_err(|_| "Serial port could not connect")
     }
 
-    /// Reads data via X retry's to ensure maximum success
+    /// Reads data via X retry's to ensure maximum success. Requests that get no reply or a reply
+    /// with a wrong CRC are sent again, `stats` counts how often.
     pub fn read(&mut self, mut read_data: Vec<u8>) -> Result<[u8; 4], &'static str> {
         println!("--- Read Reg: {:?}", read_data);
-        let mut i = 0;
-
-        while i < 10 {
-            self.clear_input_output();
-            let write_result = self.port.write(read_data.as_mut_slice());
-            match write_result {
-                Ok(result) => {
-                    if result != read_data.len() {
-                        println!("Error");
-                        return Err("Missmatch in receive/response counts for reading.");
-                    }
-                    std::thread::sleep(Self::CALLING_PAUSE);
-                    let mut buffer: Vec<u8> = vec![0; 12];
-                    self.port
-                        .read(buffer.as_mut_slice())
-                        .expect("Read not successful on port");
-
-                    println!("Full reply...{:?}", buffer);
-                    let return_read = buffer[7..11].try_into().unwrap();
-                    std::thread::sleep(Self::CALLING_PAUSE);
-                    println!("--- Read Reg reply: {:?}", return_read);
-                    return Ok(return_read);
+        let mut port = self.lock_port();
+
+        for attempt in 0..Self::READ_ATTEMPTS {
+            if attempt > 0 {
+                BusStats::count(&self.stats.retries);
+            }
+            BusStats::count(&self.stats.transactions);
+            Self::clear(port.as_ref())?;
+            match port.write(read_data.as_mut_slice()) {
+                Ok(result) if result != read_data.len() => {
+                    println!("Error");
+                    return Err("Missmatch in receive/response counts for reading.");
                 }
+                Ok(_) => {}
                 Err(_) => {
-                    println!("Failed to read data, retrying...")
+                    println!("Failed to read data, retrying...");
+                    continue;
                 }
             }
-            i = i + 1;
+            std::thread::sleep(Self::CALLING_PAUSE);
+
+            // The single wire UART echoes the request before the 8 byte reply
+            let mut buffer = [0; 12];
+            if port.read_exact(&mut buffer).is_err() {
+                println!("No reply from the driver, retrying...");
+                continue;
+            }
+            println!("Full reply...{:?}", buffer);
+            if crc(&buffer[4..11]) != buffer[11] {
+                BusStats::count(&self.stats.crc_errors);
+                println!("CRC error in the reply, retrying...");
+                continue;
+            }
+
+            let return_read = [buffer[7], buffer[8], buffer[9], buffer[10]];
+            std::thread::sleep(Self::CALLING_PAUSE);
+            println!("--- Read Reg reply: {:?}", return_read);
+            return Ok(return_read);
+        }
+        Err("No valid answer from stepper after 10 tries.")
+    }
+
+    /// Sends a read request like `read` but fails instead of retrying when nothing answers, e.g.
+    /// to find out which node addresses are in use
+    pub fn probe(&mut self, mut read_data: Vec<u8>) -> Result<[u8; 4], &'static str> {
+        let mut port = self.lock_port();
+        BusStats::count(&self.stats.transactions);
+        Self::clear(port.as_ref())?;
+        let written = port
+            .write(read_data.as_mut_slice())
+            .map_err(|_| "Error writing to register")?;
+        if written != read_data.len() {
+            return Err("Missmatch in receive/response counts for reading.");
+        }
+
+        // The single wire UART echoes the request before the 8 byte reply
+        let mut buffer = [0; 12];
+        port.read_exact(&mut buffer)
+            .map_err(|_| "No reply from the driver")?;
+        if crc(&buffer[4..11]) != buffer[11] {
+            BusStats::count(&self.stats.crc_errors);
+            return Err("CRC error in the reply");
         }
-        panic!("No valid answer from stepper after 10 tries.");
+        Ok([buffer[7], buffer[8], buffer[9], buffer[10]])
     }
 
     /// Writes to the register but does not check if write was successfull, that should be done in
@@ -105,8 +240,10 @@ impl Connection {
     pub fn write(&mut self, mut write_data: Vec<u8>) -> Result<(), &'static str> {
         println!("--- Write Reg: {:?}", write_data);
 
-        self.clear_input_output();
-        let write_result = self.port.write(write_data.as_mut_slice());
+        let mut port = self.lock_port();
+        BusStats::count(&self.stats.transactions);
+        Self::clear(port.as_ref())?;
+        let write_result = port.write(write_data.as_mut_slice());
         std::thread::sleep(Self::CALLING_PAUSE);
 
         match write_result {
@@ -120,18 +257,30 @@ impl Connection {
         }
     }
 
-    pub fn clear_input_output(&self) {
-        self.port
-            .clear(ClearBuffer::Output)
-            .expect("Failed to discard output buffer");
-        self.port
-            .clear(ClearBuffer::Input)
-            .expect("Failed to discard input buffer");
+    pub fn clear_input_output(&self) -> Result<(), &'static str> {
+        Self::clear(self.lock_port().as_ref())
+    }
+
+    /// Discards what is left in both buffers, fails when the UART is gone
+    fn clear(port: &dyn SerialPort) -> Result<(), &'static str> {
+        port.clear(ClearBuffer::All)
+            .map_err(|_| "Could not discard the UART buffers")
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn datagram_crc() {
+        assert_eq!(crc(&[0x55, 0x00, 0x00]), 207);
+        assert_eq!(crc(&[85, 15, 0, 0, 13, 0, 0]), 173);
+        let stats = BusStats::default();
+        stats.record_write_check_failure();
+        assert_eq!(stats.write_check_failures(), 1);
+        assert_eq!(stats.transactions(), 0);
+    }
 
     #[test]
     #[ignore]
@@ -0,0 +1,105 @@
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+
+/// SPI transport for Trinamic drivers. Every transfer is a 40 bit datagram of an address byte
+/// and 32 data bits, the reply starts with the SPI_STATUS byte and carries the data requested by
+/// the previous datagram.
+pub struct SpiConnection {
+    spi: Spidev,
+    status: u8,
+}
+
+impl SpiConnection {
+    pub const DEFAULT_DEVICE: &'static str = "/dev/spidev0.0";
+    /// The drivers allow up to 4MHz on their internal clock, 2MHz leaves margin for wiring
+    const SPEED_HZ: u32 = 2_000_000;
+    const WRITE_FLAG: u8 = 0x80;
+
+    // SPI_STATUS
+    pub const STATUS_RESET: u8 = 1 << 0;
+    pub const STATUS_DRIVER_ERROR: u8 = 1 << 1;
+    pub const STATUS_STALLGUARD: u8 = 1 << 2;
+    pub const STATUS_STANDSTILL: u8 = 1 << 3;
+    /// Only set by drivers with a ramp generator
+    pub const STATUS_VELOCITY_REACHED: u8 = 1 << 4;
+    pub const STATUS_POSITION_REACHED: u8 = 1 << 5;
+
+    pub fn open(device: &str) -> Result<Self, &'static str> {
+        let mut spi = Spidev::open(device).map_err(|_| "SPI device could not be opened")?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(Self::SPEED_HZ)
+            .mode(SpiModeFlags::SPI_MODE_3)
+            .build();
+        spi.configure(&options)
+            .map_err(|_| "SPI device could not be configured")?;
+
+        Ok(Self { spi, status: 0 })
+    }
+
+    /// SPI_STATUS byte of the last reply
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    /// Writes a register and returns the SPI_STATUS byte of the reply
+    pub fn write(&mut self, reg: u8, value: u32) -> Result<u8, &'static str> {
+        self.transfer(Self::write_datagram(reg, value))?;
+        Ok(self.status)
+    }
+
+    /// Reads a register, which takes two transfers as the data arrives with the next datagram
+    pub fn read(&mut self, reg: u8) -> Result<u32, &'static str> {
+        self.transfer(Self::read_datagram(reg))?;
+        self.transfer(Self::read_datagram(reg))
+    }
+
+    fn transfer(&mut self, datagram: [u8; 5]) -> Result<u32, &'static str> {
+        let mut reply = [0u8; 5];
+        let mut transfer = SpidevTransfer::read_write(&datagram, &mut reply);
+        self.spi
+            .transfer(&mut transfer)
+            .map_err(|_| "SPI transfer failed")?;
+
+        let (status, data) = Self::parse_reply(reply);
+        self.status = status;
+        Ok(data)
+    }
+
+    fn write_datagram(reg: u8, value: u32) -> [u8; 5] {
+        let data = value.to_be_bytes();
+        [reg | Self::WRITE_FLAG, data[0], data[1], data[2], data[3]]
+    }
+
+    fn read_datagram(reg: u8) -> [u8; 5] {
+        [reg & !Self::WRITE_FLAG, 0, 0, 0, 0]
+    }
+
+    fn parse_reply(reply: [u8; 5]) -> (u8, u32) {
+        (
+            reply[0],
+            u32::from_be_bytes([reply[1], reply[2], reply[3], reply[4]]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams() {
+        assert_eq!(
+            SpiConnection::write_datagram(0x6C, 0x000100C3),
+            [0xEC, 0x00, 0x01, 0x00, 0xC3]
+        );
+        assert_eq!(SpiConnection::read_datagram(0x6F), [0x6F, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reply() {
+        assert_eq!(
+            SpiConnection::parse_reply([0x09, 0x81, 0x02, 0x03, 0x04]),
+            (0x09, 0x81020304)
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! Text commands for bringing up a TMC2209 interactively, see the `stepper repl` console
+use crate::cancel::CancelToken;
+use crate::driver::registers::{self, Register};
+use crate::driver::tmc2209::{GConfOption, Tmc2209};
+use crate::motion_controller::MotionController;
+use crate::stepper::{ConfigurableDriver, DiagnosticDriver, StepGenerator};
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+read <register>             read and decode a register, by name or address like 0x6F
+write <register> <value>    write a raw value, decimal or hex like 0x10000053
+set current <mA>            RMS run current
+set microsteps <n>          microsteps per full step
+set mode stealthchop|spreadcycle
+move <steps>                relative move, Ctrl-C decelerates to a stop
+goto <position>             absolute move
+position [<position>]       show or set the position
+status                      driver status flags
+dump                        decode every readable register
+registers                   list the register map
+enable | disable            motor outputs
+help                        this text
+quit                        leave the console";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConsoleCommand {
+    Read(&'static Register),
+    Write(&'static Register, u32),
+    SetCurrent(u16),
+    SetMicrosteps(u16),
+    SetSpreadCycle(bool),
+    Move(i32),
+    Goto(i32),
+    Position(Option<i32>),
+    Status,
+    Dump,
+    Registers,
+    Enable,
+    Disable,
+    Help,
+    Quit,
+}
+
+impl ConsoleCommand {
+    /// Command of a console line, None for an empty line
+    pub fn parse(line: &str) -> Result<Option<Self>, &'static str> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            [] => return Ok(None),
+            ["read", name] => Self::Read(register(name)?),
+            ["write", name, value] => {
+                let register = register(name)?;
+                if !register.is_writable() {
+                    return Err("Register is read only");
+                }
+                Self::Write(
+                    register,
+                    parse_number(value).ok_or("Invalid register value")?,
+                )
+            }
+            ["set", "current", milliamps] => {
+                Self::SetCurrent(milliamps.parse().map_err(|_| "Invalid current")?)
+            }
+            ["set", "microsteps", microsteps] => {
+                Self::SetMicrosteps(microsteps.parse().map_err(|_| "Invalid microsteps")?)
+            }
+            ["set", "mode", mode] => match mode.to_ascii_lowercase().as_str() {
+                "stealthchop" => Self::SetSpreadCycle(false),
+                "spreadcycle" => Self::SetSpreadCycle(true),
+                _ => return Err("Mode is stealthchop or spreadcycle"),
+            },
+            ["move", steps] => Self::Move(steps.parse().map_err(|_| "Invalid steps")?),
+            ["goto", position] => Self::Goto(position.parse().map_err(|_| "Invalid position")?),
+            ["position"] => Self::Position(None),
+            ["position", position] => {
+                Self::Position(Some(position.parse().map_err(|_| "Invalid position")?))
+            }
+            ["status"] => Self::Status,
+            ["dump"] => Self::Dump,
+            ["registers"] => Self::Registers,
+            ["enable"] => Self::Enable,
+            ["disable"] => Self::Disable,
+            ["help"] | ["?"] => Self::Help,
+            ["quit"] | ["exit"] => Self::Quit,
+            _ => return Err("Unknown command, type help for a list"),
+        };
+        Ok(Some(command))
+    }
+}
+
+fn register(name: &str) -> Result<&'static Register, &'static str> {
+    registers::find(registers::TMC2209, name).ok_or("Unknown register, type registers for a list")
+}
+
+/// Decimal, `0x` hex or `0b` binary number, `_` may separate digits
+fn parse_number(text: &str) -> Option<u32> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Raw register access the console needs on top of the common driver traits
+pub trait ConsoleDriver: StepGenerator + ConfigurableDriver + DiagnosticDriver {
+    fn read_register(&mut self, address: u8) -> Result<u32, &'static str>;
+    fn write_register(&mut self, address: u8, value: u32) -> Result<(), &'static str>;
+    /// Switches between SpreadCycle (true) and StealthChop (false)
+    fn set_spread_cycle(&mut self, spread_cycle: bool) -> Result<(), &'static str>;
+}
+
+impl ConsoleDriver for Tmc2209 {
+    fn read_register(&mut self, address: u8) -> Result<u32, &'static str> {
+        Tmc2209::read_register(self, address)
+    }
+
+    fn write_register(&mut self, address: u8, value: u32) -> Result<(), &'static str> {
+        Tmc2209::write_register(self, address, value)
+    }
+
+    fn set_spread_cycle(&mut self, spread_cycle: bool) -> Result<(), &'static str> {
+        if spread_cycle {
+            self.enable_gconf_option(GConfOption::SpreadCycle)
+        } else {
+            self.disable_gconf_option(GConfOption::SpreadCycle)
+        }
+    }
+}
+
+/// Runs `command` on the controller, returns the text to show. `token` stops moves. Errors
+/// leave the controller usable for the next command.
+pub async fn execute<T: ConsoleDriver>(
+    controller: &mut MotionController<T>,
+    command: ConsoleCommand,
+    token: &CancelToken,
+) -> Result<String, &'static str> {
+    let driver = controller.stepper_mut();
+    let text = match command {
+        ConsoleCommand::Read(register) => {
+            if !register.is_readable() {
+                return Err("Register is write only");
+            }
+            register.format(driver.read_register(register.address)?)
+        }
+        ConsoleCommand::Write(register, value) => {
+            driver.write_register(register.address, value)?;
+            format!(
+                "{} (0x{:02X}) <- 0x{:08X}",
+                register.name, register.address, value
+            )
+        }
+        ConsoleCommand::SetCurrent(milliamps) => {
+            driver.set_motor_current(milliamps)?;
+            format!("Current set to {} mA", milliamps)
+        }
+        ConsoleCommand::SetMicrosteps(microsteps) => {
+            driver.set_microsteps(microsteps)?;
+            format!("{} microsteps", microsteps)
+        }
+        ConsoleCommand::SetSpreadCycle(true) => {
+            driver.set_spread_cycle(true)?;
+            "SpreadCycle".to_owned()
+        }
+        ConsoleCommand::SetSpreadCycle(false) => {
+            driver.set_spread_cycle(false)?;
+            "StealthChop".to_owned()
+        }
+        ConsoleCommand::Move(steps) => {
+            let outcome = controller.move_steps_cancellable(steps, token).await?;
+            format!("{:?} at position {}", outcome, controller.position())
+        }
+        ConsoleCommand::Goto(position) => {
+            let steps = position - controller.position();
+            let outcome = controller.move_steps_cancellable(steps, token).await?;
+            format!("{:?} at position {}", outcome, controller.position())
+        }
+        ConsoleCommand::Position(Some(position)) => {
+            controller.set_position(position)?;
+            format!("Position {}", position)
+        }
+        ConsoleCommand::Position(None) => format!("Position {}", controller.position()),
+        ConsoleCommand::Status => {
+            let status = driver.status()?;
+            let mut text = format!("{:#?}", status);
+            for fault in status.faults() {
+                let _ = write!(text, "\nFault: {}", fault);
+            }
+            text
+        }
+        ConsoleCommand::Dump => {
+            let mut lines = Vec::new();
+            for register in registers::TMC2209.iter().filter(|r| r.is_readable()) {
+                lines.push(register.format(driver.read_register(register.address)?));
+            }
+            lines.join("\n")
+        }
+        ConsoleCommand::Registers => registers::TMC2209
+            .iter()
+            .map(|register| {
+                format!(
+                    "0x{:02X} {:<12} {:?}",
+                    register.address, register.name, register.access
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        ConsoleCommand::Enable => {
+            driver.set_enabled(true)?;
+            "Enabled".to_owned()
+        }
+        ConsoleCommand::Disable => {
+            driver.set_enabled(false)?;
+            "Disabled".to_owned()
+        }
+        ConsoleCommand::Help => HELP.to_owned(),
+        ConsoleCommand::Quit => String::new(),
+    };
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepper::{Direction, DriverStatus};
+    use mockall::mock;
+    use mockall::predicate::eq;
+    use std::time::Duration;
+
+    mock! {
+        Driver {}
+
+        impl StepGenerator for Driver {
+            fn set_steps_to_move(&mut self, steps: i32);
+            fn step(&mut self) -> Result<(), &'static str>;
+            fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str>;
+            fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str>;
+        }
+
+        impl ConfigurableDriver for Driver {
+            fn set_motor_current(&mut self, current: u16) -> Result<(), &'static str>;
+            fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str>;
+            fn set_power_down_delay(&mut self, delay: Duration) -> Result<(), &'static str>;
+        }
+
+        impl DiagnosticDriver for Driver {
+            fn status(&mut self) -> Result<DriverStatus, &'static str>;
+            fn clear_faults(&mut self) -> Result<(), &'static str>;
+        }
+
+        impl ConsoleDriver for Driver {
+            fn read_register(&mut self, address: u8) -> Result<u32, &'static str>;
+            fn write_register(&mut self, address: u8, value: u32) -> Result<(), &'static str>;
+            fn set_spread_cycle(&mut self, spread_cycle: bool) -> Result<(), &'static str>;
+        }
+    }
+
+    fn get_mock_controller(driver: MockDriver) -> MotionController<MockDriver> {
+        let mut controller = MotionController::new("console".to_owned(), driver);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        controller
+    }
+
+    async fn run(
+        controller: &mut MotionController<MockDriver>,
+        line: &str,
+    ) -> Result<String, &'static str> {
+        let command = ConsoleCommand::parse(line).unwrap().unwrap();
+        execute(controller, command, &CancelToken::new()).await
+    }
+
+    #[test]
+    fn parse_commands() {
+        let chopconf = registers::find(registers::TMC2209, "CHOPCONF").unwrap();
+        assert_eq!(
+            ConsoleCommand::parse("write chopconf 0x1000_0053"),
+            Ok(Some(ConsoleCommand::Write(chopconf, 0x10000053)))
+        );
+        assert_eq!(
+            ConsoleCommand::parse("  set current 600 "),
+            Ok(Some(ConsoleCommand::SetCurrent(600)))
+        );
+        assert_eq!(
+            ConsoleCommand::parse("move -3200"),
+            Ok(Some(ConsoleCommand::Move(-3200)))
+        );
+        assert_eq!(ConsoleCommand::parse(""), Ok(None));
+        assert!(ConsoleCommand::parse("write DRV_STATUS 1").is_err());
+        assert!(ConsoleCommand::parse("read FOO").is_err());
+        assert!(ConsoleCommand::parse("move").is_err());
+    }
+
+    #[tokio::test]
+    async fn execute_register_commands() {
+        let mut driver = MockDriver::default();
+        driver
+            .expect_read_register()
+            .with(eq(0x6C))
+            .times(1)
+            .returning(|_| Ok(0x10000053));
+        driver
+            .expect_write_register()
+            .with(eq(0x6C), eq(0x10000054))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        driver
+            .expect_set_spread_cycle()
+            .with(eq(true))
+            .times(1)
+            .returning(|_| Ok(()));
+        let mut controller = get_mock_controller(driver);
+        let chopconf = registers::find(registers::TMC2209, "CHOPCONF").unwrap();
+
+        assert_eq!(
+            run(&mut controller, "read chopconf").await,
+            Ok(chopconf.format(0x10000053))
+        );
+        assert_eq!(
+            run(&mut controller, "write chopconf 0x10000054").await,
+            Ok("CHOPCONF (0x6C) <- 0x10000054".to_owned())
+        );
+        assert_eq!(
+            run(&mut controller, "set mode spreadcycle").await,
+            Ok("SpreadCycle".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_moves() {
+        let mut driver = MockDriver::default();
+        driver.expect_set_steps_to_move().return_const(());
+        driver.expect_step().times(15).returning(|| Ok(()));
+        driver.expect_set_enabled().returning(|_| Ok(()));
+        let mut controller = get_mock_controller(driver);
+
+        assert_eq!(
+            run(&mut controller, "move 10").await,
+            Ok("Completed at position 10".to_owned())
+        );
+        assert_eq!(
+            run(&mut controller, "goto 5").await,
+            Ok("Completed at position 5".to_owned())
+        );
+        assert_eq!(
+            run(&mut controller, "position 100").await,
+            Ok("Position 100".to_owned())
+        );
+        assert_eq!(
+            run(&mut controller, "position").await,
+            Ok("Position 100".to_owned())
+        );
+        assert_eq!(
+            run(&mut controller, "disable").await,
+            Ok("Disabled".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_errors_keep_console_usable() {
+        let mut driver = MockDriver::default();
+        driver
+            .expect_read_register()
+            .times(1)
+            .returning(|_| Err("No answer from the driver"));
+        driver
+            .expect_set_motor_current()
+            .times(1)
+            .returning(|_| Err("Current out of range"));
+        driver.expect_set_steps_to_move().return_const(());
+        let mut steps = 0;
+        driver.expect_step().returning(move || {
+            steps += 1;
+            if steps > 3 {
+                Err("Step pin failed")
+            } else {
+                Ok(())
+            }
+        });
+        driver.expect_set_enabled().returning(|_| Ok(()));
+        let mut controller = get_mock_controller(driver);
+
+        assert_eq!(
+            run(&mut controller, "read gconf").await,
+            Err("No answer from the driver")
+        );
+        assert_eq!(
+            run(&mut controller, "read IHOLD_IRUN").await,
+            Err("Register is write only")
+        );
+        assert_eq!(
+            run(&mut controller, "set current 3000").await,
+            Err("Current out of range")
+        );
+        assert_eq!(
+            run(&mut controller, "move 10").await,
+            Err("Step pin failed")
+        );
+
+        // The steps made before the failure count and the next command still runs
+        assert_eq!(
+            run(&mut controller, "position").await,
+            Ok("Position 3".to_owned())
+        );
+        assert_eq!(run(&mut controller, "help").await, Ok(HELP.to_owned()));
+    }
+}
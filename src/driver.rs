@@ -1 +1,7 @@
+pub mod registers;
+pub mod step_dir;
+pub mod tmc;
+pub mod tmc2130;
 pub mod tmc2209;
+pub mod tmc5160;
+pub mod uln2003;
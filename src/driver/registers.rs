@@ -0,0 +1,324 @@
+//! Register maps of the Trinamic drivers, for reading and writing registers by name and
+//! decoding their fields. Registers and fields the drivers use are built from the constants of
+//! `tmc` and the drivers, only the others are defined here.
+use crate::driver::tmc;
+use crate::driver::tmc2209::Tmc2209;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Bit field of a register
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl Field {
+    const fn new(name: &'static str, shift: u32, width: u32) -> Self {
+        Self { name, shift, width }
+    }
+
+    const fn bit(name: &'static str, shift: u32) -> Self {
+        Self::new(name, shift, 1)
+    }
+
+    /// Field of the set bits of `mask`, which have to be contiguous
+    const fn mask(name: &'static str, mask: u32) -> Self {
+        Self::new(name, mask.trailing_zeros(), mask.count_ones())
+    }
+
+    pub fn get(&self, value: u32) -> u32 {
+        (value >> self.shift) & (u32::MAX >> (32 - self.width))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Register {
+    pub name: &'static str,
+    pub address: u8,
+    pub access: Access,
+    pub fields: &'static [Field],
+}
+
+impl Register {
+    const fn new(
+        name: &'static str,
+        address: u8,
+        access: Access,
+        fields: &'static [Field],
+    ) -> Self {
+        Self {
+            name,
+            address,
+            access,
+            fields,
+        }
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.access != Access::Write
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.access != Access::Read
+    }
+
+    /// Value of every field of the register
+    pub fn decode(&self, value: u32) -> Vec<(&'static str, u32)> {
+        self.fields
+            .iter()
+            .map(|field| (field.name, field.get(value)))
+            .collect()
+    }
+
+    /// Register name and value followed by one line per field
+    pub fn format(&self, value: u32) -> String {
+        let mut text = format!("{} (0x{:02X}) = 0x{:08X}", self.name, self.address, value);
+        for (name, field) in self.decode(value) {
+            let _ = write!(text, "\n  {:<16} {}", name, field);
+        }
+        text
+    }
+}
+
+/// Finds a register by its name, case insensitive, or by its address like `0x6F`
+pub fn find(registers: &'static [Register], name: &str) -> Option<&'static Register> {
+    let address = name
+        .strip_prefix("0x")
+        .or_else(|| name.strip_prefix("0X"))
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    registers.iter().find(|register| {
+        register.name.eq_ignore_ascii_case(name) || Some(register.address) == address
+    })
+}
+
+use Access::{Read, ReadWrite, Write as W};
+
+pub const TMC2209: &[Register] = &[
+    Register::new(
+        "GCONF",
+        tmc::GCONF,
+        ReadWrite,
+        &[
+            Field::mask("i_scale_analog", Tmc2209::I_SCALE_ANALOG as u32),
+            Field::mask("internal_rsense", Tmc2209::INTERNAL_RSENSE as u32),
+            Field::mask("en_spreadcycle", Tmc2209::EN_SPREADCYCLE as u32),
+            Field::mask("shaft", Tmc2209::SHAFT as u32),
+            Field::mask("index_otpw", Tmc2209::INDEX_OTPW as u32),
+            Field::mask("index_step", Tmc2209::INDEX_STEP as u32),
+            Field::bit("pdn_disable", 6),
+            Field::mask("mstep_reg_select", Tmc2209::MSTEP_REG_SELECT as u32),
+            Field::bit("multistep_filt", 8),
+            Field::bit("test_mode", 9),
+        ],
+    ),
+    Register::new(
+        "GSTAT",
+        tmc::GSTAT,
+        ReadWrite,
+        &[
+            Field::mask("reset", tmc::GSTAT_RESET),
+            Field::mask("drv_err", tmc::GSTAT_DRV_ERR),
+            Field::mask("uv_cp", tmc::GSTAT_UV_CP),
+        ],
+    ),
+    Register::new("IFCNT", Tmc2209::IFCNT, Read, &[Field::new("ifcnt", 0, 8)]),
+    Register::new("NODECONF", 0x03, W, &[Field::new("senddelay", 8, 4)]),
+    Register::new(
+        "OTP_PROG",
+        0x04,
+        W,
+        &[
+            Field::new("otpbit", 0, 3),
+            Field::new("otpbyte", 4, 2),
+            Field::new("otpmagic", 8, 8),
+        ],
+    ),
+    Register::new(
+        "OTP_READ",
+        0x05,
+        Read,
+        &[
+            Field::new("otp0", 0, 8),
+            Field::new("otp1", 8, 8),
+            Field::new("otp2", 16, 8),
+        ],
+    ),
+    Register::new(
+        "IOIN",
+        Tmc2209::IOIN,
+        Read,
+        &[
+            Field::mask("enn", Tmc2209::IO_ENN as u32),
+            Field::bit("ms1", 2),
+            Field::bit("ms2", 3),
+            Field::bit("diag", 4),
+            Field::bit("pdn_uart", 6),
+            Field::mask("step", Tmc2209::IO_STEP as u32),
+            Field::mask("spread_en", Tmc2209::IO_SPREAD as u32),
+            Field::mask("dir", Tmc2209::IO_DIR as u32),
+            Field::new("version", 24, 8),
+        ],
+    ),
+    Register::new(
+        "FACTORY_CONF",
+        0x07,
+        ReadWrite,
+        &[Field::new("fclktrim", 0, 5), Field::new("ottrim", 8, 2)],
+    ),
+    Register::new(
+        "IHOLD_IRUN",
+        tmc::IHOLD_IRUN,
+        W,
+        &[
+            Field::new("ihold", 0, 5),
+            Field::new("irun", 8, 5),
+            Field::new("iholddelay", 16, 4),
+        ],
+    ),
+    Register::new(
+        "TPOWERDOWN",
+        tmc::TPOWERDOWN,
+        W,
+        &[Field::new("tpowerdown", 0, 8)],
+    ),
+    Register::new("TSTEP", tmc::TSTEP, Read, &[Field::new("tstep", 0, 20)]),
+    Register::new("TPWMTHRS", 0x13, W, &[Field::new("tpwmthrs", 0, 20)]),
+    Register::new("TCOOLTHRS", 0x14, W, &[Field::new("tcoolthrs", 0, 20)]),
+    Register::new("VACTUAL", 0x22, W, &[Field::new("vactual", 0, 24)]),
+    Register::new("SGTHRS", 0x40, W, &[Field::new("sgthrs", 0, 8)]),
+    Register::new("SG_RESULT", 0x41, Read, &[Field::new("sg_result", 0, 10)]),
+    Register::new(
+        "COOLCONF",
+        0x42,
+        W,
+        &[
+            Field::new("semin", 0, 4),
+            Field::new("seup", 5, 2),
+            Field::new("semax", 8, 4),
+            Field::new("sedn", 13, 2),
+            Field::bit("seimin", 15),
+        ],
+    ),
+    Register::new("MSCNT", 0x6A, Read, &[Field::new("mscnt", 0, 10)]),
+    Register::new(
+        "MSCURACT",
+        0x6B,
+        Read,
+        &[Field::new("cur_a", 0, 9), Field::new("cur_b", 16, 9)],
+    ),
+    Register::new(
+        "CHOPCONF",
+        tmc::CHOPCONF,
+        ReadWrite,
+        &[
+            Field::mask("toff", tmc::CHOPCONF_TOFF),
+            Field::new("hstrt", 4, 3),
+            Field::new("hend", 7, 4),
+            Field::new("tbl", 15, 2),
+            Field::mask("vsense", tmc::CHOPCONF_VSENSE),
+            Field::mask("mres", tmc::CHOPCONF_MRES),
+            Field::mask("intpol", Tmc2209::INTPOL),
+            Field::bit("dedge", 29),
+            Field::bit("diss2g", 30),
+            Field::bit("diss2vs", 31),
+        ],
+    ),
+    Register::new(
+        "DRV_STATUS",
+        tmc::DRV_STATUS,
+        Read,
+        &[
+            Field::mask("otpw", Tmc2209::OTPW as u32),
+            Field::mask("ot", Tmc2209::OT as u32),
+            Field::mask("s2ga", Tmc2209::S2GA as u32),
+            Field::mask("s2gb", Tmc2209::S2GB as u32),
+            Field::mask("s2vsa", Tmc2209::S2VSA as u32),
+            Field::mask("s2vsb", Tmc2209::S2VSB as u32),
+            Field::mask("ola", Tmc2209::OLA as u32),
+            Field::mask("olb", Tmc2209::OLB as u32),
+            Field::mask("t120", Tmc2209::T120),
+            Field::mask("t143", Tmc2209::T143),
+            Field::mask("t150", Tmc2209::T150),
+            Field::mask("t157", Tmc2209::T157),
+            Field::mask("cs_actual", Tmc2209::CS_ACTUAL),
+            Field::mask("stealth", Tmc2209::STEALTH),
+            Field::mask("stst", Tmc2209::STST),
+        ],
+    ),
+    Register::new(
+        "PWMCONF",
+        0x70,
+        ReadWrite,
+        &[
+            Field::new("pwm_ofs", 0, 8),
+            Field::new("pwm_grad", 8, 8),
+            Field::new("pwm_freq", 16, 2),
+            Field::bit("pwm_autoscale", 18),
+            Field::bit("pwm_autograd", 19),
+            Field::new("freewheel", 20, 2),
+            Field::new("pwm_reg", 24, 4),
+            Field::new("pwm_lim", 28, 4),
+        ],
+    ),
+    Register::new(
+        "PWM_SCALE",
+        0x71,
+        Read,
+        &[
+            Field::new("pwm_scale_sum", 0, 8),
+            Field::new("pwm_scale_auto", 16, 9),
+        ],
+    ),
+    Register::new(
+        "PWM_AUTO",
+        0x72,
+        Read,
+        &[
+            Field::new("pwm_ofs_auto", 0, 8),
+            Field::new("pwm_grad_auto", 16, 8),
+        ],
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_by_name_or_address() {
+        assert_eq!(find(TMC2209, "drv_status").unwrap().address, 0x6F);
+        assert_eq!(find(TMC2209, "0x6c").unwrap().name, "CHOPCONF");
+        assert!(find(TMC2209, "NOPE").is_none());
+    }
+
+    #[test]
+    fn decode_chopconf() {
+        let chopconf = find(TMC2209, "CHOPCONF").unwrap();
+        let fields = chopconf.decode(0x10000053);
+        assert!(fields.contains(&("toff", 3)));
+        assert!(fields.contains(&("hstrt", 5)));
+        assert!(fields.contains(&("mres", 0)));
+        assert!(fields.contains(&("intpol", 1)));
+        assert!(chopconf
+            .format(0x10000053)
+            .starts_with("CHOPCONF (0x6C) = 0x10000053"));
+    }
+
+    #[test]
+    fn decode_drv_status() {
+        let drv_status = find(TMC2209, "DRV_STATUS").unwrap();
+        let fields = drv_status.decode(1 << 31 | 20 << 16 | 1 << 6);
+        assert!(fields.contains(&("stst", 1)));
+        assert!(fields.contains(&("cs_actual", 20)));
+        assert!(fields.contains(&("ola", 1)));
+        assert!(fields.contains(&("olb", 0)));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::gpio::{open_chip, OutputPin, DEFAULT_CHIP};
+use crate::step_thread::busy_wait;
+use crate::stepper::{ConfigurableDriver, Direction, StepGenerator};
+use gpio_cdev::Chip;
+use std::time::{Duration, Instant};
+
+/// Timing limits and microstep table of a STEP/DIR driver without a serial interface
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepDirConfig {
+    /// Minimum STEP high time, the same time is kept low after each pulse
+    pub pulse_width: Duration,
+    /// Time DIR has to be stable before a STEP edge
+    pub dir_setup: Duration,
+    /// Time DIR has to stay stable after a STEP edge
+    pub dir_hold: Duration,
+    /// Microsteps per full step and the matching MS1, MS2, MS3 levels
+    pub microstep_table: &'static [(u16, [bool; 3])],
+}
+
+impl StepDirConfig {
+    /// Allegro A4988, 1µs STEP high and low, 200ns DIR setup and hold
+    pub const A4988: StepDirConfig = StepDirConfig {
+        pulse_width: Duration::from_micros(1),
+        dir_setup: Duration::from_nanos(200),
+        dir_hold: Duration::from_nanos(200),
+        microstep_table: &[
+            (1, [false, false, false]),
+            (2, [true, false, false]),
+            (4, [false, true, false]),
+            (8, [true, true, false]),
+            (16, [true, true, true]),
+        ],
+    };
+
+    /// TI DRV8825, 1.9µs STEP high and low, 650ns DIR setup and hold. MS1-3 are MODE0-2.
+    pub const DRV8825: StepDirConfig = StepDirConfig {
+        pulse_width: Duration::from_nanos(1900),
+        dir_setup: Duration::from_nanos(650),
+        dir_hold: Duration::from_nanos(650),
+        microstep_table: &[
+            (1, [false, false, false]),
+            (2, [true, false, false]),
+            (4, [false, true, false]),
+            (8, [true, true, false]),
+            (16, [false, false, true]),
+            (32, [true, false, true]),
+        ],
+    };
+
+    /// Trinamic TMC2208 in standalone mode, 100ns STEP high and low, 20ns DIR setup and hold.
+    /// Only MS1 and MS2 exist, the driver interpolates to 256 microsteps internally.
+    pub const TMC2208: StepDirConfig = StepDirConfig {
+        pulse_width: Duration::from_nanos(100),
+        dir_setup: Duration::from_nanos(20),
+        dir_hold: Duration::from_nanos(20),
+        microstep_table: &[
+            (2, [true, false, false]),
+            (4, [false, true, false]),
+            (8, [false, false, false]),
+            (16, [true, true, false]),
+        ],
+    };
+
+    /// MS pin levels for a resolution, `pin_count` being the amount of MS pins wired up. Pins
+    /// that are not wired are expected to be pulled low on the board.
+    fn microstep_levels(
+        &self,
+        microsteps: u16,
+        pin_count: usize,
+    ) -> Result<[bool; 3], &'static str> {
+        let (_, levels) = self
+            .microstep_table
+            .iter()
+            .find(|(resolution, _)| *resolution == microsteps)
+            .ok_or("Microstep resolution is not supported by the driver")?;
+
+        if levels.iter().skip(pin_count).any(|level| *level) {
+            return Err("Microstep resolution needs more MS pins than are connected");
+        }
+        Ok(*levels)
+    }
+}
+
+/// GPIO lines of a STEP/DIR driver, EN and the MS pins are optional
+#[derive(Clone, Debug, Default)]
+pub struct StepDirPins {
+    pub step: u32,
+    pub dir: u32,
+    pub en: Option<u32>,
+    /// MS1, MS2 and MS3 in that order, pins that are hard wired on the board are left out
+    pub microstep: Vec<u32>,
+}
+
+/// Generic driver for chips that only take STEP, DIR and EN signals such as the A4988, DRV8825
+/// or a TMC2208 in standalone mode
+pub struct StepDir {
+    step_pin: OutputPin,
+    dir_pin: OutputPin,
+    en_pin: Option<OutputPin>,
+    microstep_pins: Vec<OutputPin>,
+    config: StepDirConfig,
+    current_direction: Direction,
+    invert_direction: bool,
+    steps_to_move: i32,
+    microsteps: Option<u16>,
+}
+
+/// Builder for `StepDir`, the STEP and DIR pins are required. Without a config the A4988 timing
+/// is used.
+#[derive(Default)]
+pub struct StepDirBuilder {
+    step_pin: Option<u32>,
+    dir_pin: Option<u32>,
+    en_pin: Option<u32>,
+    microstep_pins: Vec<u32>,
+    config: Option<StepDirConfig>,
+    gpio_chip: Option<String>,
+}
+
+impl StepDirBuilder {
+    pub fn step_pin(mut self, pin: u32) -> Self {
+        self.step_pin = Some(pin);
+        self
+    }
+
+    pub fn dir_pin(mut self, pin: u32) -> Self {
+        self.dir_pin = Some(pin);
+        self
+    }
+
+    pub fn en_pin(mut self, pin: u32) -> Self {
+        self.en_pin = Some(pin);
+        self
+    }
+
+    /// MS1, MS2 and MS3 in that order, pins that are hard wired on the board are left out
+    pub fn microstep_pins(mut self, pins: &[u32]) -> Self {
+        self.microstep_pins = pins.to_vec();
+        self
+    }
+
+    pub fn config(mut self, config: StepDirConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn gpio_chip(mut self, gpio_chip: &str) -> Self {
+        self.gpio_chip = Some(gpio_chip.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<StepDir, &'static str> {
+        let pins = StepDirPins {
+            step: self.step_pin.ok_or("STEP pin is required")?,
+            dir: self.dir_pin.ok_or("DIR pin is required")?,
+            en: self.en_pin,
+            microstep: self.microstep_pins,
+        };
+        let mut chip = open_chip(self.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP))?;
+        StepDir::request(&mut chip, pins, self.config.unwrap_or(StepDirConfig::A4988))
+    }
+}
+
+impl StepGenerator for StepDir {
+    fn set_steps_to_move(&mut self, steps: i32) {
+        self.steps_to_move = steps;
+    }
+
+    /// Makes one of the remaining steps, returns Err() when no more steps remain
+    fn step(&mut self) -> Result<(), &'static str> {
+        match self.steps_to_move {
+            n if n > 0 => {
+                self.write_direction(Direction::CW)?;
+                self.steps_to_move -= 1;
+            }
+            n if n < 0 => {
+                self.write_direction(Direction::CCW)?;
+                self.steps_to_move += 1;
+            }
+            _ => return Err("No more steps to move"),
+        };
+
+        self.step_pin.pulse(self.config.pulse_width)?;
+        busy_wait(self.config.pulse_width);
+        Ok(())
+    }
+
+    fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str> {
+        self.write_direction(direction)
+    }
+
+    /// EN is active low on all supported chips. Without an EN pin the driver is hard wired to
+    /// be enabled, so enabling succeeds and disabling fails.
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str> {
+        match &self.en_pin {
+            Some(en_pin) => en_pin.set(!enabled),
+            None if enabled => Ok(()),
+            None => Err("Driver has no EN pin connected"),
+        }
+    }
+}
+
+impl ConfigurableDriver for StepDir {
+    fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str> {
+        if self.microstep_pins.is_empty() {
+            return Err("Driver has no MS pins connected");
+        }
+
+        let levels = self
+            .config
+            .microstep_levels(microsteps, self.microstep_pins.len())?;
+        for (pin, level) in self.microstep_pins.iter().zip(levels) {
+            pin.set(level)?;
+        }
+        self.microsteps = Some(microsteps);
+        Ok(())
+    }
+}
+
+impl StepDir {
+    pub fn builder() -> StepDirBuilder {
+        StepDirBuilder::default()
+    }
+
+    /// Requests the lines of `pins` from `chip`, no serial port is needed
+    pub fn request(
+        chip: &mut Chip,
+        pins: StepDirPins,
+        config: StepDirConfig,
+    ) -> Result<Self, &'static str> {
+        let step_pin = OutputPin::request(chip, pins.step, false, "step_dir_step")?;
+        let dir_pin = OutputPin::request(chip, pins.dir, false, "step_dir_dir")?;
+        let en_pin = match pins.en {
+            Some(en) => Some(OutputPin::request(chip, en, true, "step_dir_en")?),
+            None => None,
+        };
+        if pins.microstep.len() > 3 {
+            return Err("At most three MS pins can be connected");
+        }
+        let microstep_pins = pins
+            .microstep
+            .iter()
+            .map(|pin| OutputPin::request(chip, *pin, false, "step_dir_ms"))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::from_pins(
+            step_pin,
+            dir_pin,
+            en_pin,
+            microstep_pins,
+            config,
+        ))
+    }
+
+    fn from_pins(
+        step_pin: OutputPin,
+        dir_pin: OutputPin,
+        en_pin: Option<OutputPin>,
+        microstep_pins: Vec<OutputPin>,
+        config: StepDirConfig,
+    ) -> Self {
+        Self {
+            step_pin,
+            dir_pin,
+            en_pin,
+            microstep_pins,
+            config,
+            current_direction: Direction::CW,
+            invert_direction: false,
+            steps_to_move: 0,
+            microsteps: None,
+        }
+    }
+
+    pub fn config(&self) -> &StepDirConfig {
+        &self.config
+    }
+
+    /// Overrides the timing, e.g. for long cables or slow level shifters
+    pub fn set_config(&mut self, config: StepDirConfig) {
+        self.config = config;
+    }
+
+    /// Swaps CW and CCW, e.g. for a motor wired the other way around
+    pub fn set_invert_direction(&mut self, invert: bool) -> Result<(), &'static str> {
+        self.invert_direction = invert;
+        self.write_dir_pin()
+    }
+
+    /// Microstep resolution last set through the MS pins, None when it was never set
+    pub fn microsteps(&self) -> Option<u16> {
+        self.microsteps
+    }
+
+    /// Shared handle to the STEP line, e.g. for a step thread's `StepPins`
+    pub fn step_pin(&self) -> OutputPin {
+        self.step_pin.clone()
+    }
+
+    /// Sets DIR unless it already points in `direction`, the direction is only taken over when
+    /// the pin could be set
+    fn write_direction(&mut self, direction: Direction) -> Result<(), &'static str> {
+        if direction == self.current_direction {
+            return Ok(());
+        }
+
+        let previous = self.current_direction;
+        self.current_direction = direction;
+        if let Err(err) = self.write_dir_pin() {
+            self.current_direction = previous;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Sets DIR for the current direction. The timing holds for steps from `step` as well as
+    /// from a step thread pulsing a clone of the STEP pin, as both are seen by `pulsed_at`.
+    fn write_dir_pin(&mut self) -> Result<(), &'static str> {
+        // DIR has to stay put for the hold time after the last STEP edge
+        if let Some(pulsed_at) = self.step_pin.pulsed_at() {
+            Self::wait_since(pulsed_at, self.config.dir_hold);
+        }
+        let level = (self.current_direction == Direction::CCW) ^ self.invert_direction;
+        self.dir_pin.set(level)?;
+        // and be stable for the setup time before the next one
+        busy_wait(self.config.dir_setup);
+        Ok(())
+    }
+
+    fn wait_since(since: Instant, duration: Duration) {
+        let elapsed = since.elapsed();
+        if elapsed < duration {
+            busy_wait(duration - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a4988_microsteps() {
+        let config = StepDirConfig::A4988;
+        assert_eq!(config.microstep_levels(8, 3), Ok([true, true, false]));
+        assert_eq!(config.microstep_levels(16, 3), Ok([true, true, true]));
+        assert!(config.microstep_levels(32, 3).is_err());
+    }
+
+    #[test]
+    fn drv8825_microsteps() {
+        let config = StepDirConfig::DRV8825;
+        assert_eq!(config.microstep_levels(16, 3), Ok([false, false, true]));
+        assert_eq!(config.microstep_levels(32, 3), Ok([true, false, true]));
+    }
+
+    #[test]
+    fn microsteps_need_pins() {
+        let config = StepDirConfig::TMC2208;
+        assert_eq!(config.microstep_levels(16, 2), Ok([true, true, false]));
+        assert_eq!(config.microstep_levels(8, 0), Ok([false, false, false]));
+        assert!(config.microstep_levels(4, 1).is_err());
+        assert!(StepDirConfig::DRV8825.microstep_levels(16, 2).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Register addresses and field encodings shared by the Trinamic drivers. The TMC2209, TMC2130
+//! and TMC5160 use the same layout for these registers, only the transport differs.
+use crate::driver::step_dir::StepDirConfig;
+use crate::stepper::DriverStatus;
+use std::time::Duration;
+
+// Addresses
+pub const GCONF: u8 = 0x00;
+pub const GSTAT: u8 = 0x01;
+pub const IHOLD_IRUN: u8 = 0x10;
+pub const TPOWERDOWN: u8 = 0x11;
+pub const TSTEP: u8 = 0x12;
+pub const CHOPCONF: u8 = 0x6C;
+pub const DRV_STATUS: u8 = 0x6F;
+
+// GSTAT
+pub const GSTAT_RESET: u32 = 1 << 0;
+pub const GSTAT_DRV_ERR: u32 = 1 << 1;
+pub const GSTAT_UV_CP: u32 = 1 << 2;
+
+// CHOPCONF
+pub const CHOPCONF_TOFF: u32 = 0x0F;
+pub const CHOPCONF_VSENSE: u32 = 1 << 17;
+pub const CHOPCONF_MRES: u32 = 0x0F << 24;
+const CHOPCONF_MRES_SHIFT: u32 = 24;
+
+// DRV_STATUS of the SPI drivers
+const DRV_STATUS_S2VSA: u32 = 1 << 12;
+const DRV_STATUS_S2VSB: u32 = 1 << 13;
+/// Only on the TMC5160, the TMC2130 reads it as 0
+const DRV_STATUS_STEALTH: u32 = 1 << 14;
+const DRV_STATUS_CS_ACTUAL: u32 = 0x1F << 16;
+const DRV_STATUS_CS_ACTUAL_SHIFT: u32 = 16;
+const DRV_STATUS_STALLGUARD: u32 = 1 << 24;
+const DRV_STATUS_OT: u32 = 1 << 25;
+const DRV_STATUS_OTPW: u32 = 1 << 26;
+const DRV_STATUS_S2GA: u32 = 1 << 27;
+const DRV_STATUS_S2GB: u32 = 1 << 28;
+const DRV_STATUS_OLA: u32 = 1 << 29;
+const DRV_STATUS_OLB: u32 = 1 << 30;
+const DRV_STATUS_STST: u32 = 1 << 31;
+
+/// One TPOWERDOWN count is 2^18 clock cycles of the internal 12MHz oscillator
+pub const TPOWERDOWN_UNIT: Duration = Duration::from_nanos(21_845_333);
+
+/// STEP/DIR timing of the SPI drivers, 100ns STEP high and low and 20ns DIR setup and hold.
+/// The microstep resolution is set through CHOPCONF so there are no MS pins.
+pub const STEP_DIR_CONFIG: StepDirConfig = StepDirConfig {
+    pulse_width: Duration::from_nanos(100),
+    dir_setup: Duration::from_nanos(20),
+    dir_hold: Duration::from_nanos(20),
+    microstep_table: &[],
+};
+
+/// Full scale sense voltage in V with VSENSE cleared and set
+pub const VFS_LOW_SENSITIVITY: f32 = 0.325;
+pub const VFS_HIGH_SENSITIVITY: f32 = 0.180;
+
+/// MRES value for a microstep resolution, 256 microsteps is 0 and full steps are 8
+pub fn mres(microsteps: u16) -> Result<u32, &'static str> {
+    if !microsteps.is_power_of_two() || microsteps > 256 {
+        return Err("Microstep resolution must be a power of two between 1 and 256");
+    }
+    Ok(8 - microsteps.trailing_zeros())
+}
+
+/// Microsteps per full step selected by the MRES field of a CHOPCONF value
+pub fn microsteps(chopconf: u32) -> u16 {
+    let mres = (chopconf & CHOPCONF_MRES) >> CHOPCONF_MRES_SHIFT;
+    256 >> mres.min(8)
+}
+
+/// Returns `chopconf` with the MRES field replaced
+pub fn chopconf_with_microsteps(chopconf: u32, microsteps: u16) -> Result<u32, &'static str> {
+    Ok((chopconf & !CHOPCONF_MRES) | mres(microsteps)? << CHOPCONF_MRES_SHIFT)
+}
+
+/// IHOLD_IRUN value, the currents are 0-31 and the delay 0-15
+pub fn ihold_irun(ihold: u32, irun: u32, hold_current_delay: u32) -> u32 {
+    (ihold & 0x1F) | (irun & 0x1F) << 8 | (hold_current_delay & 0x0F) << 16
+}
+
+/// Current scale (CS) 0-31 for an RMS current in mA. `rsense` includes the internal resistance
+/// of the driver where the datasheet adds it (20mΩ on the TMC2209 and TMC2130).
+pub fn current_scale(current: u16, rsense: f32, vfs: f32) -> u32 {
+    let cs = 32.0 * std::f32::consts::SQRT_2 * (current as f32) / 1000.0 * rsense / vfs - 1.0;
+    cs.round().clamp(0.0, 31.0) as u32
+}
+
+/// TPOWERDOWN value for a standstill delay, between ~44ms and ~5.6s. The minimum of 2 counts is
+/// required by the stealthChop automatic tuning.
+pub fn tpowerdown_counts(delay: Duration) -> u32 {
+    let counts = delay.as_secs_f64() / TPOWERDOWN_UNIT.as_secs_f64();
+    counts.round().clamp(2.0, 255.0) as u32
+}
+
+/// Decodes DRV_STATUS of the TMC2130 and TMC5160, the TMC2130 has no short to supply flags
+pub fn spi_driver_status(drv_status: u32) -> DriverStatus {
+    let flag = |mask: u32| drv_status & mask != 0;
+    DriverStatus {
+        standstill: flag(DRV_STATUS_STST),
+        stalled: flag(DRV_STATUS_STALLGUARD),
+        overtemperature_warning: flag(DRV_STATUS_OTPW),
+        overtemperature: flag(DRV_STATUS_OT),
+        short_to_ground: flag(DRV_STATUS_S2GA | DRV_STATUS_S2GB),
+        short_to_supply: flag(DRV_STATUS_S2VSA | DRV_STATUS_S2VSB),
+        open_load: flag(DRV_STATUS_OLA | DRV_STATUS_OLB),
+        stealth: flag(DRV_STATUS_STEALTH),
+        current_scale: ((drv_status & DRV_STATUS_CS_ACTUAL) >> DRV_STATUS_CS_ACTUAL_SHIFT) as u8,
+        temperature_threshold: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mres_values() {
+        assert_eq!(mres(256), Ok(0));
+        assert_eq!(mres(16), Ok(4));
+        assert_eq!(mres(1), Ok(8));
+        assert!(mres(12).is_err());
+        assert!(mres(512).is_err());
+    }
+
+    #[test]
+    fn chopconf_microsteps() {
+        let chopconf = chopconf_with_microsteps(0x100100C3, 16).unwrap();
+        assert_eq!(chopconf, 0x140100C3);
+        assert_eq!(microsteps(chopconf), 16);
+        assert_eq!(microsteps(0x000100C3), 256);
+    }
+
+    #[test]
+    fn ihold_irun_fields() {
+        assert_eq!(ihold_irun(10, 31, 6), 0x00061F0A);
+    }
+
+    #[test]
+    fn current_scale_range() {
+        assert_eq!(current_scale(0, 0.13, VFS_LOW_SENSITIVITY), 0);
+        assert_eq!(current_scale(800, 0.13, VFS_LOW_SENSITIVITY), 13);
+        assert_eq!(current_scale(5000, 0.13, VFS_LOW_SENSITIVITY), 31);
+    }
+
+    #[test]
+    fn spi_driver_status_flags() {
+        let status = spi_driver_status(1 << 31 | 1 << 28 | 1 << 24 | 31 << 16 | 1 << 13);
+        assert!(status.standstill);
+        assert!(status.stalled);
+        assert!(status.short_to_ground);
+        assert!(status.short_to_supply);
+        assert!(!status.overtemperature);
+        assert!(!status.open_load);
+        assert!(!status.stealth);
+        assert_eq!(status.current_scale, 31);
+    }
+
+    #[test]
+    fn tpowerdown_counts_range() {
+        assert_eq!(tpowerdown_counts(Duration::from_millis(437)), 20);
+        assert_eq!(tpowerdown_counts(Duration::ZERO), 2);
+        assert_eq!(tpowerdown_counts(Duration::from_secs(60)), 255);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::connection::spi::SpiConnection;
+use crate::driver::step_dir::{StepDir, StepDirPins};
+use crate::driver::tmc;
+use crate::gpio::{open_chip, OutputPin, DEFAULT_CHIP};
+use crate::stepper::{
+    ConfigurableDriver, DiagnosticDriver, Direction, DriverStatus, StepGenerator,
+};
+use gpio_cdev::Chip;
+use std::time::Duration;
+
+/// Trinamic TMC2130, moved with STEP/DIR pulses and configured over SPI
+pub struct Tmc2130 {
+    step_dir: StepDir,
+    spi: SpiConnection,
+    rsense: f32,
+}
+
+/// Builder for `Tmc2130`, the STEP and DIR pins are required. The default SPI device and GPIO
+/// chip are used unless others are given.
+#[derive(Default)]
+pub struct Tmc2130Builder {
+    step_pin: Option<u32>,
+    dir_pin: Option<u32>,
+    en_pin: Option<u32>,
+    spi_device: Option<String>,
+    gpio_chip: Option<String>,
+    rsense: Option<f32>,
+}
+
+impl Tmc2130Builder {
+    pub fn step_pin(mut self, pin: u32) -> Self {
+        self.step_pin = Some(pin);
+        self
+    }
+
+    pub fn dir_pin(mut self, pin: u32) -> Self {
+        self.dir_pin = Some(pin);
+        self
+    }
+
+    pub fn en_pin(mut self, pin: u32) -> Self {
+        self.en_pin = Some(pin);
+        self
+    }
+
+    pub fn spi_device(mut self, spi_device: &str) -> Self {
+        self.spi_device = Some(spi_device.to_owned());
+        self
+    }
+
+    pub fn gpio_chip(mut self, gpio_chip: &str) -> Self {
+        self.gpio_chip = Some(gpio_chip.to_owned());
+        self
+    }
+
+    /// Sense resistor of the board in Ω, `Tmc2130::DEFAULT_RSENSE` when not set
+    pub fn rsense(mut self, rsense: f32) -> Self {
+        self.rsense = Some(rsense);
+        self
+    }
+
+    pub fn build(self) -> Result<Tmc2130, &'static str> {
+        let pins = StepDirPins {
+            step: self.step_pin.ok_or("STEP pin is required")?,
+            dir: self.dir_pin.ok_or("DIR pin is required")?,
+            en: self.en_pin,
+            microstep: Vec::new(),
+        };
+        let spi = SpiConnection::open(
+            self.spi_device
+                .as_deref()
+                .unwrap_or(SpiConnection::DEFAULT_DEVICE),
+        )?;
+        let mut chip = open_chip(self.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP))?;
+
+        let mut driver = Tmc2130::request(&mut chip, pins, spi)?;
+        if let Some(rsense) = self.rsense {
+            driver.set_rsense(rsense)?;
+        }
+        Ok(driver)
+    }
+}
+
+impl StepGenerator for Tmc2130 {
+    fn set_steps_to_move(&mut self, steps: i32) {
+        self.step_dir.set_steps_to_move(steps);
+    }
+
+    fn step(&mut self) -> Result<(), &'static str> {
+        self.step_dir.step()
+    }
+
+    fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str> {
+        self.step_dir.set_direction(direction)
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str> {
+        self.step_dir.set_enabled(enabled)
+    }
+}
+
+impl ConfigurableDriver for Tmc2130 {
+    fn set_motor_current(&mut self, current: u16) -> Result<(), &'static str> {
+        self.set_current(current)
+    }
+
+    fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str> {
+        let chopconf = self.spi.read(tmc::CHOPCONF)?;
+        self.spi.write(
+            tmc::CHOPCONF,
+            tmc::chopconf_with_microsteps(chopconf, microsteps)?,
+        )?;
+        Ok(())
+    }
+
+    fn set_power_down_delay(&mut self, delay: Duration) -> Result<(), &'static str> {
+        self.spi
+            .write(tmc::TPOWERDOWN, tmc::tpowerdown_counts(delay))?;
+        Ok(())
+    }
+}
+
+impl DiagnosticDriver for Tmc2130 {
+    fn status(&mut self) -> Result<DriverStatus, &'static str> {
+        Ok(tmc::spi_driver_status(self.drv_status()?))
+    }
+
+    fn clear_faults(&mut self) -> Result<(), &'static str> {
+        self.clear_gstat()?;
+        Ok(())
+    }
+}
+
+impl Tmc2130 {
+    /// Sense resistor of most TMC2130 boards in Ω
+    pub const DEFAULT_RSENSE: f32 = 0.11;
+    /// Resistance the datasheet adds to the sense resistor in Ω
+    const INTERNAL_RESISTANCE: f32 = 0.02;
+
+    /// spreadCycle with TOFF=3, HSTRT=4, HEND=1, TBL=2 and 256 microsteps as suggested by the
+    /// datasheet for a first start
+    const DEFAULT_CHOPCONF: u32 = 0x000100C3;
+    const DEFAULT_TPOWERDOWN: u32 = 10;
+    const HOLD_CURRENT_DELAY: u32 = 6;
+
+    pub fn builder() -> Tmc2130Builder {
+        Tmc2130Builder::default()
+    }
+
+    /// Requests the STEP, DIR and EN lines from `chip`, MS pins are ignored as the resolution is
+    /// set over SPI
+    pub fn request(
+        chip: &mut Chip,
+        pins: StepDirPins,
+        spi: SpiConnection,
+    ) -> Result<Self, &'static str> {
+        let pins = StepDirPins {
+            microstep: Vec::new(),
+            ..pins
+        };
+        let step_dir = StepDir::request(chip, pins, tmc::STEP_DIR_CONFIG)?;
+        Ok(Self::from_parts(step_dir, spi))
+    }
+
+    fn from_parts(step_dir: StepDir, spi: SpiConnection) -> Self {
+        Self {
+            step_dir,
+            spi,
+            rsense: Self::DEFAULT_RSENSE,
+        }
+    }
+
+    /// Writes a working chopper configuration, the driver stays disabled (TOFF=0) after a reset
+    /// until CHOPCONF is written
+    pub fn init_default_settings(&mut self) -> Result<(), &'static str> {
+        self.spi.write(tmc::GCONF, 0)?;
+        self.spi.write(tmc::CHOPCONF, Self::DEFAULT_CHOPCONF)?;
+        self.spi.write(tmc::TPOWERDOWN, Self::DEFAULT_TPOWERDOWN)?;
+        self.clear_gstat()?;
+        Ok(())
+    }
+
+    /// Sets the sense resistor of the board in Ω, used to calculate the current scale
+    pub fn set_rsense(&mut self, rsense: f32) -> Result<(), &'static str> {
+        if rsense <= 0.0 {
+            return Err("Sense resistor must be greater than zero");
+        }
+        self.rsense = rsense;
+        Ok(())
+    }
+
+    /// Sets the RMS run current in mA, the hold current is half of it
+    pub fn set_current(&mut self, current: u16) -> Result<(), &'static str> {
+        let (vsense, irun) = Self::current_settings(current, self.rsense);
+
+        let chopconf = self.spi.read(tmc::CHOPCONF)?;
+        let chopconf = match vsense {
+            true => chopconf | tmc::CHOPCONF_VSENSE,
+            false => chopconf & !tmc::CHOPCONF_VSENSE,
+        };
+        self.spi.write(tmc::CHOPCONF, chopconf)?;
+        self.spi.write(
+            tmc::IHOLD_IRUN,
+            tmc::ihold_irun(irun / 2, irun, Self::HOLD_CURRENT_DELAY),
+        )?;
+        Ok(())
+    }
+
+    /// VSENSE and current scale for a current. The high sensitivity range is used for low
+    /// currents so the current scale keeps enough resolution.
+    fn current_settings(current: u16, rsense: f32) -> (bool, u32) {
+        let rsense = rsense + Self::INTERNAL_RESISTANCE;
+        let cs = tmc::current_scale(current, rsense, tmc::VFS_LOW_SENSITIVITY);
+        if cs < 16 {
+            return (
+                true,
+                tmc::current_scale(current, rsense, tmc::VFS_HIGH_SENSITIVITY),
+            );
+        }
+        (false, cs)
+    }
+
+    /// Microstep resolution currently set in CHOPCONF
+    pub fn microsteps(&mut self) -> Result<u16, &'static str> {
+        Ok(tmc::microsteps(self.spi.read(tmc::CHOPCONF)?))
+    }
+
+    /// Reads and clears GSTAT, the flags are `tmc::GSTAT_*`
+    pub fn clear_gstat(&mut self) -> Result<u32, &'static str> {
+        let gstat = self.spi.read(tmc::GSTAT)?;
+        self.spi.write(tmc::GSTAT, gstat)?;
+        Ok(gstat)
+    }
+
+    pub fn drv_status(&mut self) -> Result<u32, &'static str> {
+        self.spi.read(tmc::DRV_STATUS)
+    }
+
+    /// SPI_STATUS byte of the last transfer, see `SpiConnection::STATUS_*`
+    pub fn spi_status(&self) -> u8 {
+        self.spi.status()
+    }
+
+    /// Swaps CW and CCW, e.g. for a motor wired the other way around
+    pub fn set_invert_direction(&mut self, invert: bool) -> Result<(), &'static str> {
+        self.step_dir.set_invert_direction(invert)
+    }
+
+    /// Shared handle to the STEP line, e.g. for a step thread's `StepPins`
+    pub fn step_pin(&self) -> OutputPin {
+        self.step_dir.step_pin()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_settings_switch_vsense() {
+        assert_eq!(Tmc2130::current_settings(1200, 0.11), (false, 21));
+        assert_eq!(Tmc2130::current_settings(400, 0.11), (true, 12));
+        assert_eq!(Tmc2130::current_settings(5000, 0.11), (false, 31));
+    }
+}
@@ -1,7 +1,9 @@
-use crate::connection::Connection;
-use crate::stepper::{Direction, Stepper};
-use std::time::Duration;
-use mockall::automock;
+use crate::connection::{self, Connection};
+use crate::driver::tmc;
+use crate::gpio::{OutputPin, DEFAULT_CHIP};
+use crate::step_thread::busy_wait;
+use crate::stepper::{ConfigurableDriver, DiagnosticDriver, Direction, DriverStatus, StepGenerator};
+use std::time::{Duration, Instant};
 
 pub enum MicrostepRes {
     One = 1,
@@ -11,10 +13,29 @@ pub enum MicrostepRes {
     Sixteen = 16,
     ThirtyTwo = 32,
     SixtyFour = 64,
-    OneTwoFive = 125,
+    OneTwoFive = 128,
     TwoFiveSix = 256,
 }
 
+impl TryFrom<u16> for MicrostepRes {
+    type Error = &'static str;
+
+    fn try_from(microsteps: u16) -> Result<Self, Self::Error> {
+        match microsteps {
+            1 => Ok(MicrostepRes::One),
+            2 => Ok(MicrostepRes::Two),
+            4 => Ok(MicrostepRes::Four),
+            8 => Ok(MicrostepRes::Eight),
+            16 => Ok(MicrostepRes::Sixteen),
+            32 => Ok(MicrostepRes::ThirtyTwo),
+            64 => Ok(MicrostepRes::SixtyFour),
+            128 => Ok(MicrostepRes::OneTwoFive),
+            256 => Ok(MicrostepRes::TwoFiveSix),
+            _ => Err("Microstep resolution must be a power of two between 1 and 256"),
+        }
+    }
+}
+
 pub enum GConfOption {
     Direction = 1 << 3,
     IScaleAnalogue = 1 << 0,
@@ -33,31 +54,153 @@ pub enum Motor {
     Disabled,
 }
 
+/// How the driver is told which way to turn
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DirectionMode {
+    /// DIR pin only, a reversal is a single GPIO write
+    Pin,
+    /// GCONF shaft bit only, a reversal costs a register read and a checked write over UART
+    Uart,
+    /// DIR pin for reversals with the shaft bit holding the invert direction setting, so the
+    /// inversion is stored in the driver and only written when it changes
+    Both,
+}
+
 pub struct Tmc2209 {
-    pins: (u8, u8, u8), // step, dir, en
+    step_pin: OutputPin,
+    dir_pin: OutputPin,
+    en_pin: OutputPin,
     connection: Connection,
     current_position: i16,
     current_direction: Direction,
     steps_to_move: i32,
+    direction_mode: DirectionMode,
+    invert_direction: bool,
+    dir_setup_time: Duration,
+    dir_changed_at: Option<Instant>,
+    node_address: u8,
+}
+
+/// Builder for `Tmc2209`, the STEP, DIR and EN pins are required. Without a connection the
+/// default UART and GPIO chip are opened.
+#[derive(Default)]
+pub struct Tmc2209Builder {
+    step_pin: Option<u32>,
+    dir_pin: Option<u32>,
+    en_pin: Option<u32>,
+    uart_port: Option<String>,
+    gpio_chip: Option<String>,
+    connection: Option<Connection>,
+    direction_mode: Option<DirectionMode>,
+    baud_rate: Option<u32>,
+    node_address: Option<u8>,
+    keep_en_level: bool,
 }
 
-impl Stepper for Tmc2209 {
-    fn new(pins: (u8, u8, u8), connection: Connection) -> Self {
-        Self {
-            pins,
+impl Tmc2209Builder {
+    pub fn step_pin(mut self, pin: u32) -> Self {
+        self.step_pin = Some(pin);
+        self
+    }
+
+    pub fn dir_pin(mut self, pin: u32) -> Self {
+        self.dir_pin = Some(pin);
+        self
+    }
+
+    pub fn en_pin(mut self, pin: u32) -> Self {
+        self.en_pin = Some(pin);
+        self
+    }
+
+    pub fn uart_port(mut self, uart_port: &str) -> Self {
+        self.uart_port = Some(uart_port.to_owned());
+        self
+    }
+
+    pub fn gpio_chip(mut self, gpio_chip: &str) -> Self {
+        self.gpio_chip = Some(gpio_chip.to_owned());
+        self
+    }
+
+    /// Uses an already opened connection instead of `uart_port` and `gpio_chip`
+    pub fn connection(mut self, connection: Connection) -> Self {
+        self.connection = Some(connection);
+        self
+    }
+
+    pub fn direction_mode(mut self, mode: DirectionMode) -> Self {
+        self.direction_mode = Some(mode);
+        self
+    }
+
+    /// Baud rate of the UART opened by the builder, 9600 by default
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = Some(baud_rate);
+        self
+    }
+
+    /// Address 0-3 set with the MS1 and MS2 pins, so up to four drivers can share one UART
+    pub fn node_address(mut self, node_address: u8) -> Self {
+        self.node_address = Some(node_address);
+        self
+    }
+
+    /// Requests EN at the level it has instead of high, so a motor enabled by an earlier process
+    /// stays enabled, e.g. for command line tools that run once per command
+    pub fn keep_en_level(mut self, keep: bool) -> Self {
+        self.keep_en_level = keep;
+        self
+    }
+
+    pub fn build(self) -> Result<Tmc2209, &'static str> {
+        let step = self.step_pin.ok_or("STEP pin is required")?;
+        let dir = self.dir_pin.ok_or("DIR pin is required")?;
+        let en = self.en_pin.ok_or("EN pin is required")?;
+        let node_address = self.node_address.unwrap_or(0);
+        if node_address > Tmc2209::MAX_NODE_ADDRESS {
+            return Err("TMC2209 node address must be between 0 and 3");
+        }
+        let mut connection = match self.connection {
+            Some(connection) => connection,
+            None => Connection::open_with_baud_rate(
+                self.uart_port.as_deref().unwrap_or(Connection::UART_PORT),
+                self.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP),
+                self.baud_rate.unwrap_or(Connection::UART_BAUDRATE),
+            )?,
+        };
+
+        // The lines are requested once here and held by the driver, EN starts high so the
+        // outputs stay off until the motor is enabled unless its level is kept
+        let step_pin = connection.request_output(step, false, "tmc2209_step")?;
+        let dir_pin = connection.request_output(dir, false, "tmc2209_dir")?;
+        let en_pin = match self.keep_en_level {
+            true => connection.request_output_at_current_level(en, "tmc2209_en")?,
+            false => connection.request_output(en, true, "tmc2209_en")?,
+        };
+
+        let mut driver = Tmc2209 {
+            step_pin,
+            dir_pin,
+            en_pin,
             connection,
             current_position: 0,
             current_direction: Direction::CW,
             steps_to_move: 0,
-            //msres: 0,
-        }
-        //Self::Builder {
-        //pins,
-        //chip: Chip::new("/dev/gpiochip0").ok(),
-        //connection: Connection::new(ConnectionType::UART),
-        //}
+            direction_mode: self.direction_mode.unwrap_or(DirectionMode::Pin),
+            invert_direction: false,
+            dir_setup_time: Tmc2209::DIR_SETUP_TIME,
+            dir_changed_at: None,
+            node_address,
+        };
+        // GCONF keeps the shaft bit until a power cycle, e.g. from an earlier session in Uart
+        // mode, so it is synced with the direction mode right away
+        driver.apply_direction()?;
+        Ok(driver)
     }
+}
 
+impl StepGenerator for Tmc2209 {
     ///// Calculates the signed int amount of steps that need to be moved and in what direction and passes this to the step function
     //fn move_to_position(&mut self, position: i32) {
     ////let target_position = self.current_position as i32 + position;
@@ -81,58 +224,74 @@ impl Stepper for Tmc2209 {
 
     /// Amount of steps we need to move in total in sigend-int format for direction. This works along with step() to
     /// ensure all steps are made and so we can calcular remaining steps and the timings inbweteen.
-    //fn set_steps_to_move(&mut self, steps: i32) {
-    //self.steps_to_move = steps;
-    //}
+    fn set_steps_to_move(&mut self, steps: i32) {
+        self.steps_to_move = steps;
+    }
 
     /// Runs throuhg the amount of steps required and reduces the count as it goes so we can run
     /// this in sync for multiple motors. Returns Err() when no more steps remain.
     fn step(&mut self) -> Result<(), &'static str> {
         match self.steps_to_move {
             n if n > 0 => {
+                self.write_direction(Direction::CW)?;
                 self.steps_to_move -= 1;
                 self.current_position += 1;
-                self.set_direction(Direction::CW);
             }
             n if n < 0 => {
+                self.write_direction(Direction::CCW)?;
                 self.steps_to_move += 1;
                 self.current_position -= 1;
-                self.set_direction(Direction::CCW);
             }
             _ => return Err("No more steps to move"),
         };
 
-        self.connection.pin_up(self.pins.0 as u32);
-        //handle.set_value(1).unwrap();
-        std::thread::sleep(Duration::from_micros(1));
-        self.connection.pin_down(self.pins.0 as u32);
-        //handle.set_value(0).unwrap();
-        std::thread::sleep(Duration::from_micros(1));
-        println!("Step Made!");
+        // The new DIR level has to be stable for the setup time before the STEP edge
+        if let Some(changed_at) = self.dir_changed_at.take() {
+            let elapsed = changed_at.elapsed();
+            if elapsed < self.dir_setup_time {
+                busy_wait(self.dir_setup_time - elapsed);
+            }
+        }
+
+        self.step_pin.pulse(Self::STEP_PULSE_WIDTH)?;
+        busy_wait(Self::STEP_PULSE_WIDTH);
         Ok(())
     }
 
-    fn set_direction(&mut self, direction: Direction) {
-        if direction != self.current_direction {
-            let reg = 1 << 3;
-            let mut gconf = self.read_int(self.get_read_bytes(Self::GCONF));
+    fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str> {
+        self.write_direction(direction)
+    }
 
-            match direction {
-                Direction::CW => {
-                    gconf = Self::clear_bit(gconf, reg);
-                }
-                Direction::CCW => {
-                    gconf = Self::set_bit(gconf, reg);
-                }
-            };
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str> {
+        match enabled {
+            true => self.set_motor_enabled(Motor::Enabled),
+            false => self.set_motor_enabled(Motor::Disabled),
+        }
+    }
+}
 
-            self.write_check(self.get_write_bytes(Self::GCONF, gconf))
-                .unwrap();
+impl ConfigurableDriver for Tmc2209 {
+    fn set_motor_current(&mut self, current: u16) -> Result<(), &'static str> {
+        self.set_current(current)
+    }
 
-            self.current_direction = direction;
-        } else {
-            println!("Direction was already set to the requested option");
-        }
+    fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str> {
+        self.set_microstepping_resolution(MicrostepRes::try_from(microsteps)?)
+    }
+
+    fn set_power_down_delay(&mut self, delay: Duration) -> Result<(), &'static str> {
+        self.set_tpowerdown(delay)
+    }
+}
+
+impl DiagnosticDriver for Tmc2209 {
+    fn status(&mut self) -> Result<DriverStatus, &'static str> {
+        let drvstatus = self.read_int(self.get_read_bytes(Self::DRVSTATUS))?;
+        Ok(Self::driver_status(drvstatus))
+    }
+
+    fn clear_faults(&mut self) -> Result<(), &'static str> {
+        self.clear_gstat()
     }
 }
 
@@ -143,29 +302,45 @@ impl Tmc2209 {
     //const WRITE_FLAG: u8 = 0x00;
     //const READ_FLAG: u8 = 0x01;
 
-    //// Addresses
-    const GCONF: u8 = 0x00;
-    const GSTAT: u8 = 0x01;
-    const IFCNT: u8 = 0x02;
-    const IOIN: u8 = 0x06;
-    const IHOLD_IRUN: u8 = 0x10;
+    /// Sense resistors of the common TMC2209 boards in Ω
+    const RSENSE: f32 = 0.11;
+    /// Resistance the datasheet adds to RSENSE for the current calculation
+    const INTERNAL_RESISTANCE: f32 = 0.02;
+    /// Voltage on VREF of the common boards, it scales the full scale sense voltage by VREF / 2.5V
+    const VREF: f32 = 1.2;
+
+    /// DIR has to be stable 20ns before the STEP edge, 1µs leaves room for slow GPIO edges
+    const DIR_SETUP_TIME: Duration = Duration::from_micros(1);
+
+    /// Minimum STEP high and low time is 100ns, sleeping for it would take 60µs or more on Linux
+    const STEP_PULSE_WIDTH: Duration = Duration::from_micros(1);
+
+    pub const MAX_NODE_ADDRESS: u8 = 3;
+
+    //// Addresses, also used by the register map in `driver::registers`
+    const GCONF: u8 = tmc::GCONF;
+    const GSTAT: u8 = tmc::GSTAT;
+    pub(crate) const IFCNT: u8 = 0x02;
+    pub(crate) const IOIN: u8 = 0x06;
+    const IHOLD_IRUN: u8 = tmc::IHOLD_IRUN;
+    const TPOWERDOWN: u8 = tmc::TPOWERDOWN;
     //const TSTEP: u8 = 0x12;
     //const VACTUAL: u8 = 0x22;
     //const TCOOLTHRS: u8 = 0x14;
     //const SGTHRS: u8 = 0x40;
     //const SG_RESULT: u8 = 0x41;
     //const MSCNT: u8 = 0x6A;
-    const CHOPCONF: u8 = 0x6C;
-    const DRVSTATUS: u8 = 0x6F;
+    const CHOPCONF: u8 = tmc::CHOPCONF;
+    const DRVSTATUS: u8 = tmc::DRV_STATUS;
 
     //// GCONF
-    const I_SCALE_ANALOG: u8 = 1 << 0;
-    const INTERNAL_RSENSE: u8 = 1 << 1;
-    const EN_SPREADCYCLE: u8 = 1 << 2;
-    const SHAFT: u8 = 1 << 3;
-    const INDEX_OTPW: u8 = 1 << 4;
-    const INDEX_STEP: u8 = 1 << 5;
-    const MSTEP_REG_SELECT: u8 = 1 << 7;
+    pub(crate) const I_SCALE_ANALOG: u8 = 1 << 0;
+    pub(crate) const INTERNAL_RSENSE: u8 = 1 << 1;
+    pub(crate) const EN_SPREADCYCLE: u8 = 1 << 2;
+    pub(crate) const SHAFT: u8 = 1 << 3;
+    pub(crate) const INDEX_OTPW: u8 = 1 << 4;
+    pub(crate) const INDEX_STEP: u8 = 1 << 5;
+    pub(crate) const MSTEP_REG_SELECT: u8 = 1 << 7;
 
     //// GSTAT
     const RESET: u8 = 1 << 0;
@@ -173,35 +348,31 @@ impl Tmc2209 {
     //const UV_CP: u8 = 1 << 2;
 
     //// CHOPCONF
-    const VSENSE: u32 = 1 << 17;
-    const MSRES0: u32 = 1 << 24;
-    const MSRES1: u32 = 1 << 25;
-    const MSRES2: u32 = 1 << 26;
-    const MSRES3: u32 = 1 << 27;
-    const INTPOL: u32 = 1 << 28;
+    const VSENSE: u32 = tmc::CHOPCONF_VSENSE;
+    pub(crate) const INTPOL: u32 = 1 << 28;
 
     //// IOIN
-    const IO_ENN: u8 = 1 << 0;
-    const IO_STEP: u8 = 1 << 7;
-    const IO_SPREAD: u16 = 1 << 8;
-    const IO_DIR: u16 = 1 << 9;
+    pub(crate) const IO_ENN: u8 = 1 << 0;
+    pub(crate) const IO_STEP: u8 = 1 << 7;
+    pub(crate) const IO_SPREAD: u16 = 1 << 8;
+    pub(crate) const IO_DIR: u16 = 1 << 9;
 
     // DRVSTATUS
-    const STST: u32 = 1 << 31;
-    const STEALTH: u32 = 1 << 30;
-    //const CS_ACTUAL: u32 = 31 << 16;
-    //const T157: u16 = 1 << 11;
-    //const T150: u16 = 1 << 10;
-    //const T143: u16 = 1 << 9;
-    //const T120: u16 = 1 << 8;
-    const OLB: u8 = 1 << 7;
-    const OLA: u8 = 1 << 6;
-    const S2VSB: u8 = 1 << 5;
-    const S2VSA: u8 = 1 << 4;
-    const S2GB: u8 = 1 << 3;
-    const S2GA: u8 = 1 << 2;
-    const OT: u8 = 1 << 1;
-    const OTPW: u8 = 1 << 0;
+    pub(crate) const STST: u32 = 1 << 31;
+    pub(crate) const STEALTH: u32 = 1 << 30;
+    pub(crate) const CS_ACTUAL: u32 = 31 << 16;
+    pub(crate) const T157: u32 = 1 << 11;
+    pub(crate) const T150: u32 = 1 << 10;
+    pub(crate) const T143: u32 = 1 << 9;
+    pub(crate) const T120: u32 = 1 << 8;
+    pub(crate) const OLB: u8 = 1 << 7;
+    pub(crate) const OLA: u8 = 1 << 6;
+    pub(crate) const S2VSB: u8 = 1 << 5;
+    pub(crate) const S2VSA: u8 = 1 << 4;
+    pub(crate) const S2GB: u8 = 1 << 3;
+    pub(crate) const S2GA: u8 = 1 << 2;
+    pub(crate) const OT: u8 = 1 << 1;
+    pub(crate) const OTPW: u8 = 1 << 0;
 
     //// IHOLD_IRUN
     //const IHOLD: u8 = 31 << 0;
@@ -211,6 +382,52 @@ impl Tmc2209 {
     //// SGTHRS
     //const SGTHRS_MOD: u8 = 255 << 0;
 
+    pub fn builder() -> Tmc2209Builder {
+        Tmc2209Builder::default()
+    }
+
+    /// Node addresses answering on `connection` with the chip version read from IOIN
+    pub fn scan(connection: &mut Connection) -> Vec<(u8, u8)> {
+        (0..=Self::MAX_NODE_ADDRESS)
+            .filter_map(|node_address| {
+                let reply = connection
+                    .probe(Self::read_frame(node_address, Self::IOIN))
+                    .ok()?;
+                Some((node_address, reply[0]))
+            })
+            .collect()
+    }
+
+    pub fn node_address(&self) -> u8 {
+        self.node_address
+    }
+
+    /// Raw values of the readable registers, by name
+    pub fn registers(&mut self) -> Result<Vec<(&'static str, u32)>, &'static str> {
+        [
+            ("GCONF", Self::GCONF),
+            ("GSTAT", Self::GSTAT),
+            ("IFCNT", Self::IFCNT),
+            ("IOIN", Self::IOIN),
+            ("CHOPCONF", Self::CHOPCONF),
+            ("DRV_STATUS", Self::DRVSTATUS),
+        ]
+        .into_iter()
+        .map(|(name, reg)| Ok((name, self.read_int(self.get_read_bytes(reg))?)))
+        .collect()
+    }
+
+    /// Raw value of the register at `reg`, see `driver::registers::TMC2209` for the addresses
+    pub fn read_register(&mut self, reg: u8) -> Result<u32, &'static str> {
+        let frame = self.get_read_bytes(reg);
+        self.connection.read(frame).map(u32::from_be_bytes)
+    }
+
+    /// Writes a raw value to the register at `reg` and checks that the driver took it
+    pub fn write_register(&mut self, reg: u8, value: u32) -> Result<(), &'static str> {
+        self.write_check(self.get_write_bytes(reg, value)).map(|_| ())
+    }
+
     pub fn get_connection(&self) -> &Connection {
         &self.connection
     }
@@ -223,56 +440,133 @@ impl Tmc2209 {
     //self.connection.clear_input_output();
     //}
 
-    pub fn init_default_settings(&mut self) {}
+    /// Switches between the DIR pin and the GCONF shaft bit for setting the direction
+    pub fn set_direction_mode(&mut self, mode: DirectionMode) -> Result<(), &'static str> {
+        self.direction_mode = mode;
+        self.apply_direction()
+    }
 
-    pub fn reset_gpios(&mut self) {
-        self.connection.pin_down(self.pins.0 as u32);
-        self.connection.pin_down(self.pins.1 as u32);
-        self.connection.pin_down(self.pins.2 as u32);
+    pub fn direction_mode(&self) -> DirectionMode {
+        self.direction_mode
+    }
 
-        //self.chip
-        //.get_line(self.pins.0 as u32)
-        //.unwrap()
-        //.request(LineRequestFlags::OUTPUT, 0, "output_pin_step")
-        //.expect("En pin could not be set as output");
+    /// Swaps CW and CCW, e.g. for a motor wired the other way around
+    pub fn set_invert_direction(&mut self, invert: bool) -> Result<(), &'static str> {
+        self.invert_direction = invert;
+        self.apply_direction()
+    }
 
-        //self.chip
-        //.get_line(self.pins.1 as u32)
-        //.unwrap()
-        //.request(LineRequestFlags::OUTPUT, 0, "output_pin_dir")
-        //.expect("En pin could not be set as output");
+    /// Time the DIR pin must be stable before the next STEP pulse
+    pub fn set_dir_setup_time(&mut self, dir_setup_time: Duration) {
+        self.dir_setup_time = dir_setup_time;
+    }
+
+    /// Brings the DIR pin and the shaft bit in line with the current direction settings
+    fn apply_direction(&mut self) -> Result<(), &'static str> {
+        let direction = self.current_direction;
+        let level = Self::dir_pin_level(self.direction_mode, direction, self.invert_direction);
+        self.dir_pin.set(level)?;
+        self.dir_changed_at = Some(Instant::now());
+        self.write_shaft(Self::shaft_bit(
+            self.direction_mode,
+            direction,
+            self.invert_direction,
+        ))
+    }
 
-        //self.chip
-        //.get_line(self.pins.2 as u32)
-        //.unwrap()
-        //.request(LineRequestFlags::OUTPUT, 0, "output_pin_en")
-        //.expect("En pin could not be set as output");
+    /// Sets the DIR pin or the shaft bit unless the driver already turns in `direction`, the
+    /// direction is only taken over when it was written
+    fn write_direction(&mut self, direction: Direction) -> Result<(), &'static str> {
+        if direction == self.current_direction {
+            return Ok(());
+        }
+        match self.direction_mode {
+            DirectionMode::Pin | DirectionMode::Both => {
+                let level =
+                    Self::dir_pin_level(self.direction_mode, direction, self.invert_direction);
+                self.dir_pin.set(level)?;
+                self.dir_changed_at = Some(Instant::now());
+            }
+            DirectionMode::Uart => {
+                self.write_shaft(Self::shaft_bit(
+                    self.direction_mode,
+                    direction,
+                    self.invert_direction,
+                ))?;
+            }
+        }
+        self.current_direction = direction;
+        Ok(())
     }
 
-    fn read_int(&mut self, reg: Vec<u8>) -> u32 {
+    /// Level of the DIR pin for a direction, the pin is held low when only UART is used
+    fn dir_pin_level(mode: DirectionMode, direction: Direction, invert: bool) -> bool {
+        let ccw = direction == Direction::CCW;
+        match mode {
+            DirectionMode::Pin => ccw ^ invert,
+            DirectionMode::Uart => false,
+            DirectionMode::Both => ccw,
+        }
+    }
+
+    /// Value of the GCONF shaft bit for a direction
+    fn shaft_bit(mode: DirectionMode, direction: Direction, invert: bool) -> bool {
+        let ccw = direction == Direction::CCW;
+        match mode {
+            DirectionMode::Pin => false,
+            DirectionMode::Uart => ccw ^ invert,
+            DirectionMode::Both => invert,
+        }
+    }
+
+    fn write_shaft(&mut self, inverse: bool) -> Result<(), &'static str> {
+        let mut gconf = self.read_int(self.get_read_bytes(Self::GCONF))?;
+
+        match inverse {
+            true => gconf = Self::set_bit(gconf, Self::SHAFT as u32),
+            false => gconf = Self::clear_bit(gconf, Self::SHAFT as u32),
+        };
+
+        self.write_check(self.get_write_bytes(Self::GCONF, gconf))?;
+        Ok(())
+    }
+
+    /// Shared handle to the STEP line, e.g. for a step thread's `StepPins`
+    pub fn step_pin(&self) -> OutputPin {
+        self.step_pin.clone()
+    }
+
+    pub fn reset_gpios(&mut self) -> Result<(), &'static str> {
+        self.step_pin.set_low()?;
+        self.dir_pin.set_low()?;
+        self.en_pin.set_low()
+    }
+
+    fn read_int(&mut self, reg: Vec<u8>) -> Result<u32, &'static str> {
         println!("--- Read int: {:?}", reg);
-        let reply = u32::from_be_bytes(self.connection.read(reg).unwrap());
+        let reply = u32::from_be_bytes(self.connection.read(reg)?);
         println!("--- Read int reply: {:?}", reply);
-        reply
+        Ok(reply)
     }
 
-    pub fn clear_gstat(&mut self) {
+    pub fn clear_gstat(&mut self) -> Result<(), &'static str> {
         println!("Clear GSTAT");
-        let mut gstat: u32 = self.read_int(self.get_read_bytes(Self::GSTAT));
+        let mut gstat: u32 = self.read_int(self.get_read_bytes(Self::GSTAT))?;
         //check here for 4 bytes being returned otherwise something went wrong and we should retry?
         gstat = Self::set_bit(gstat, Self::RESET as u32);
         gstat = Self::set_bit(gstat, Self::DRV_ERR as u32);
-        self.write_check(self.get_write_bytes(Self::GSTAT, gstat))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::GSTAT, gstat))?;
+        Ok(())
     }
 
     /// This does the write but also checks the IFCNT to ensure the write was successful or not.
     fn write_check(&mut self, write_reg: Vec<u8>) -> Result<u8, &'static str> {
-        let ifcnt1 = self.read_int(self.get_read_bytes(Self::IFCNT));
-        self.connection.write(write_reg).unwrap();
-        let ifcnt2 = self.read_int(self.get_read_bytes(Self::IFCNT));
+        let ifcnt1 = self.read_int(self.get_read_bytes(Self::IFCNT))?;
+        self.connection.write(write_reg)?;
+        let ifcnt2 = self.read_int(self.get_read_bytes(Self::IFCNT))?;
 
         if ifcnt1 >= ifcnt2 {
+            self.connection.stats().record_write_check_failure();
             println!(
                 "Write not successfull. IFCNT was {:?} now {:?}.",
                 ifcnt1, ifcnt2
@@ -284,42 +578,23 @@ impl Tmc2209 {
         }
     }
 
-    fn read_steps_per_revolution(&mut self) -> u16 {
-        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF)); // Read int here.
-        self.get_steps_per_rev(chopconf)
+    fn read_steps_per_revolution(&mut self) -> Result<u16, &'static str> {
+        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF))?; // Read int here.
+        Ok(self.get_steps_per_rev(chopconf))
     }
 
     fn get_steps_per_rev(&mut self, chopconf: u32) -> u16 {
-        let mut msresdezimal =
-            chopconf & (Self::MSRES0 | Self::MSRES1 | Self::MSRES2 | Self::MSRES3);
-        msresdezimal = msresdezimal >> 24;
-        msresdezimal = 8 - msresdezimal;
-        2_u32.pow(msresdezimal) as u16
+        tmc::microsteps(chopconf)
     }
 
     /// Calculates CRC parity bit
     fn calculate_crc(&self, datagram: &mut Vec<u8>) -> u8 {
-        let mut counter = datagram.len() - 1;
-        let mut crc: u8 = 0;
-
-        for byte in datagram.iter() {
-            let mut new_byte = *byte;
-            counter -= 1;
-
-            for _ in 0..8 {
-                if (crc >> 7) ^ (new_byte & 0x01) > 0 {
-                    crc = ((crc << 1) ^ 0x07) & 0xFF;
-                } else {
-                    crc = (crc << 1) & 0xFF;
-                }
-                new_byte = new_byte >> 1; // This would change the inner byte, right? But I want to keep this for use in the calling function
-            }
+        Self::crc(datagram)
+    }
 
-            if counter <= 0 {
-                break;
-            }
-        }
-        crc
+    /// CRC of a frame whose last byte is left for the CRC
+    fn crc(datagram: &[u8]) -> u8 {
+        connection::crc(&datagram[..datagram.len() - 1])
     }
 
     /// Sets a speicific bit to 1
@@ -341,7 +616,7 @@ impl Tmc2209 {
         let val_split = val.to_be_bytes();
         let mut write_frame = vec![0xFF; 8];
         write_frame[0] = 0x55;
-        write_frame[1] = 0x00;
+        write_frame[1] = self.node_address;
         write_frame[2] = reg | 0x80;
         write_frame[3] = val_split[0];
         write_frame[4] = val_split[1];
@@ -354,119 +629,112 @@ impl Tmc2209 {
     /// Get the full Vec for a read in correct format: [sync, address, register, crc]
     /// [8,8,8,8]
     fn get_read_bytes(&self, reg: u8) -> Vec<u8> {
+        Self::read_frame(self.node_address, reg)
+    }
+
+    fn read_frame(node_address: u8, reg: u8) -> Vec<u8> {
         let mut read_frame = vec![0xFF; 4]; // could this be using with_capacity?
         read_frame[0] = 0x55;
-        read_frame[1] = 0x00;
+        read_frame[1] = node_address;
         read_frame[2] = reg;
-        read_frame[3] = self.calculate_crc(&mut read_frame);
+        read_frame[3] = Self::crc(&read_frame);
         read_frame
     }
 
-    pub fn enable_gconf_option(&mut self, option: GConfOption) {
-        let mut gconf = self.read_int(self.get_read_bytes(Self::GCONF));
+    pub fn enable_gconf_option(&mut self, option: GConfOption) -> Result<(), &'static str> {
+        let mut gconf = self.read_int(self.get_read_bytes(Self::GCONF))?;
         gconf = Self::set_bit(gconf, option as u32);
-        self.write_check(self.get_write_bytes(Self::GCONF, gconf))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::GCONF, gconf))?;
+        Ok(())
     }
 
-    pub fn disable_gconf_option(&mut self, option: GConfOption) {
-        let mut gconf = self.read_int(self.get_read_bytes(Self::GCONF));
+    pub fn disable_gconf_option(&mut self, option: GConfOption) -> Result<(), &'static str> {
+        let mut gconf = self.read_int(self.get_read_bytes(Self::GCONF))?;
         gconf = Self::clear_bit(gconf, option as u32);
-        self.write_check(self.get_write_bytes(Self::GCONF, gconf))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::GCONF, gconf))?;
+        Ok(())
     }
 
-    pub fn enable_chopconf_option(&mut self, option: ChopConfOption) {
-        let mut chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF));
+    pub fn enable_chopconf_option(&mut self, option: ChopConfOption) -> Result<(), &'static str> {
+        let mut chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF))?;
         chopconf = Self::set_bit(chopconf, option as u32);
-        self.write_check(self.get_write_bytes(Self::CHOPCONF, chopconf))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::CHOPCONF, chopconf))?;
+        Ok(())
     }
 
-    pub fn disable_chopconf_option(&mut self, option: ChopConfOption) {
-        let mut chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF));
+    pub fn disable_chopconf_option(&mut self, option: ChopConfOption) -> Result<(), &'static str> {
+        let mut chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF))?;
         chopconf = Self::clear_bit(chopconf, option as u32);
-        self.write_check(self.get_write_bytes(Self::CHOPCONF, chopconf))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::CHOPCONF, chopconf))?;
+        Ok(())
     }
 
-    pub fn get_vsense(&mut self) -> u32 {
-        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF));
-        chopconf & Self::VSENSE
+    pub fn get_vsense(&mut self) -> Result<u32, &'static str> {
+        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF))?;
+        Ok(chopconf & Self::VSENSE)
     }
 
-    pub fn set_current(&mut self, current: u16) {
-        let hold_current_multiplier = 0.5;
+    /// Sets IRUN for an RMS current in mA and IHOLD to half of it
+    pub fn set_current(&mut self, current: u16) -> Result<(), &'static str> {
         let hold_current_delay = 10;
-        let vref = 1.2;
-        let rsense = 0.11;
-        let vfs;
-
-        if self.get_vsense() > 0 {
-            vfs = 0.180 * vref / 2.5;
-        } else {
-            vfs = 0.325 * vref / 2.5;
-        }
+        let vfs = match self.get_vsense()? > 0 {
+            true => tmc::VFS_HIGH_SENSITIVITY,
+            false => tmc::VFS_LOW_SENSITIVITY,
+        };
 
-        let mut cs_irun = 32.0 * 1.41421 * (current as f32) / 1000.0 * (rsense + 0.02) / vfs - 1.0;
-        cs_irun = cs_irun.min(31.0).max(0.0);
-        let cs_ihold = hold_current_multiplier * cs_irun;
-        let cs_irun_u32 = cs_irun.round() as u32;
-        let cs_ihold_u32 = cs_ihold.round() as u32;
-        self.set_irun_ihold(cs_ihold_u32, cs_irun_u32, hold_current_delay);
+        let irun = tmc::current_scale(
+            current,
+            Self::RSENSE + Self::INTERNAL_RESISTANCE,
+            vfs * Self::VREF / 2.5,
+        );
+        self.set_irun_ihold(irun.div_ceil(2), irun, hold_current_delay)
     }
 
-    fn set_irun_ihold(&mut self, ihold: u32, irun: u32, hold_current_delay: u32) {
-        let mut ihold_irun = 0;
-        ihold_irun = ihold_irun | ihold << 0;
-        ihold_irun = ihold_irun | irun << 8;
-        ihold_irun = ihold_irun | hold_current_delay << 16;
+    fn set_irun_ihold(
+        &mut self,
+        ihold: u32,
+        irun: u32,
+        hold_current_delay: u32,
+    ) -> Result<(), &'static str> {
+        let ihold_irun = tmc::ihold_irun(ihold, irun, hold_current_delay);
 
-        self.write_check(self.get_write_bytes(Self::IHOLD_IRUN, ihold_irun))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::IHOLD_IRUN, ihold_irun))?;
+        Ok(())
     }
 
-    pub fn set_microstepping_resolution(&mut self, resolution: MicrostepRes) {
-        let mut chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF));
-        let mut msresdezimal = ((resolution as u8) as f32).log2() as u32;
+    /// Sets the standstill time after which the current ramps down to IHOLD, see
+    /// `tmc::tpowerdown_counts` for the range
+    pub fn set_tpowerdown(&mut self, delay: Duration) -> Result<(), &'static str> {
+        let tpowerdown = tmc::tpowerdown_counts(delay);
+        self.write_check(self.get_write_bytes(Self::TPOWERDOWN, tpowerdown))?;
+        Ok(())
+    }
 
-        chopconf = chopconf & (!Self::MSRES0 | !Self::MSRES1 | !Self::MSRES2 | !Self::MSRES3);
-        msresdezimal = 8 - msresdezimal;
-        chopconf = chopconf & 0xF0FFFFFF;
-        chopconf = chopconf | msresdezimal << 24;
+    pub fn set_microstepping_resolution(
+        &mut self,
+        resolution: MicrostepRes,
+    ) -> Result<(), &'static str> {
+        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF))?;
+        let chopconf = tmc::chopconf_with_microsteps(chopconf, resolution as u16)?;
 
-        self.write_check(self.get_write_bytes(Self::CHOPCONF, chopconf))
-            .unwrap();
+        self.write_check(self.get_write_bytes(Self::CHOPCONF, chopconf))?;
 
-        self.enable_gconf_option(GConfOption::MStepResolution);
+        self.enable_gconf_option(GConfOption::MStepResolution)
     }
 
-    pub fn set_motor_enabled(&mut self, enabled: Motor) {
-        //self.connection.pin_up(self.pins.2)
-        //let handle = self
-        //.chip
-        //.get_line(self.pins.2 as u32)
-        //.unwrap()
-        //.request(LineRequestFlags::OUTPUT, 1, "motor_enabled")
-        //.unwrap();
-
+    /// EN is active low on the TMC2209
+    pub fn set_motor_enabled(&mut self, enabled: Motor) -> Result<(), &'static str> {
         match enabled {
-            Motor::Enabled => {
-                self.connection.pin_down(self.pins.2 as u32)
-                //handle.set_value(0).unwrap();
-            }
-            Motor::Disabled => {
-                self.connection.pin_up(self.pins.2 as u32)
-                //handle.set_value(1).unwrap();
-            }
+            Motor::Enabled => self.en_pin.set_low(),
+            Motor::Disabled => self.en_pin.set_high(),
         }
     }
 
     #[allow(non_snake_case)]
-    pub fn read_IOIN(&mut self) {
+    pub fn read_IOIN(&mut self) -> Result<(), &'static str> {
         println!("Reading IOIN: ---");
 
-        let ioin = self.read_int(self.get_read_bytes(Self::IOIN));
+        let ioin = self.read_int(self.get_read_bytes(Self::IOIN))?;
 
         if ioin as u16 & Self::IO_SPREAD > 0 {
             println!("Spread is high");
@@ -492,18 +760,19 @@ impl Tmc2209 {
             println!("En is low");
         }
 
-        println!("------")
+        println!("------");
+        Ok(())
     }
 
     #[allow(non_snake_case)]
-    pub fn read_CHOPCONF(&mut self) {
+    pub fn read_CHOPCONF(&mut self) -> Result<(), &'static str> {
         println!("Reading ChopConfig: ---");
 
-        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF));
+        let chopconf = self.read_int(self.get_read_bytes(Self::CHOPCONF))?;
 
         println!(
             "Native {:?} microstep setting",
-            self.read_steps_per_revolution()
+            self.read_steps_per_revolution()?
         );
 
         if chopconf & Self::INTPOL > 0 {
@@ -517,12 +786,13 @@ impl Tmc2209 {
         }
 
         println!("------");
+        Ok(())
     }
 
     #[allow(non_snake_case)]
-    pub fn read_DRVSTATUS(&mut self) {
+    pub fn read_DRVSTATUS(&mut self) -> Result<(), &'static str> {
         println!("Reading DRIVER STATUS: ---");
-        let drvstatus = self.read_int(self.get_read_bytes(Self::DRVSTATUS));
+        let drvstatus = self.read_int(self.get_read_bytes(Self::DRVSTATUS))?;
 
         if drvstatus & Self::STST > 0 {
             println!("TMC2209: Info: motor is standing still");
@@ -575,12 +845,37 @@ impl Tmc2209 {
         }
 
         println!("---");
+        Ok(())
+    }
+
+    fn driver_status(drvstatus: u32) -> DriverStatus {
+        let flags = drvstatus as u8;
+        DriverStatus {
+            standstill: drvstatus & Self::STST > 0,
+            stalled: false,
+            overtemperature_warning: flags & Self::OTPW > 0,
+            overtemperature: flags & Self::OT > 0,
+            short_to_ground: flags & (Self::S2GA | Self::S2GB) > 0,
+            short_to_supply: flags & (Self::S2VSA | Self::S2VSB) > 0,
+            open_load: flags & (Self::OLA | Self::OLB) > 0,
+            stealth: drvstatus & Self::STEALTH > 0,
+            current_scale: ((drvstatus & Self::CS_ACTUAL) >> 16) as u8,
+            temperature_threshold: [
+                (Self::T157, 157),
+                (Self::T150, 150),
+                (Self::T143, 143),
+                (Self::T120, 120),
+            ]
+            .into_iter()
+            .find(|(flag, _)| drvstatus & flag > 0)
+            .map_or(0, |(_, celsius)| celsius),
+        }
     }
 
     #[allow(non_snake_case)]
-    pub fn read_GCONF(&mut self) {
+    pub fn read_GCONF(&mut self) -> Result<(), &'static str> {
         println!("Reading GCONF: ---");
-        let gconf = self.read_int(self.get_read_bytes(Self::GCONF)) as u8;
+        let gconf = self.read_int(self.get_read_bytes(Self::GCONF))? as u8;
 
         if gconf & Self::I_SCALE_ANALOG > 0 {
             println!("TMC2209: Driver is using voltage supplied to VREF as current reference");
@@ -624,6 +919,7 @@ impl Tmc2209 {
         }
 
         println!("------");
+        Ok(())
     }
 }
 
@@ -632,14 +928,31 @@ mod tests {
     use super::*;
 
     fn get_mock_tmc() -> Tmc2209 {
-        let connection = Connection::new();
-        Tmc2209 {
-            pins: (1, 1, 1), // step, dir, en
-            connection,
-            current_position: 0,
-            current_direction: Direction::CW,
-            steps_to_move: 0,
-        }
+        Tmc2209::builder()
+            .step_pin(1)
+            .dir_pin(2)
+            .en_pin(3)
+            .connection(Connection::new())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn driver_status_flags() {
+        let status = Tmc2209::driver_status(1 << 31 | 1 << 30 | 12 << 16 | 1 << 6 | 1 << 2 | 1 << 0);
+        assert!(status.standstill);
+        assert!(status.stealth);
+        assert_eq!(status.current_scale, 12);
+        assert_eq!(status.temperature_threshold, 0);
+        assert!(status.open_load);
+        assert!(status.short_to_ground);
+        assert!(status.overtemperature_warning);
+        assert!(!status.overtemperature);
+        assert!(!status.short_to_supply);
+        assert!(status.has_fault());
+
+        let status = Tmc2209::driver_status(1 << 9 | 1 << 8);
+        assert_eq!(status.temperature_threshold, 143);
     }
 
     #[test]
@@ -717,6 +1030,36 @@ mod tests {
         )
     }
 
+    #[test]
+    fn read_frame_node_address() {
+        assert_eq!(Tmc2209::read_frame(0, Tmc2209::GCONF), vec![0x55, 0x00, 0x00, 207]);
+        let frame = Tmc2209::read_frame(2, Tmc2209::IOIN);
+        assert_eq!(frame[1], 2);
+        assert_eq!(frame[3], Tmc2209::crc(&frame));
+    }
+
+    #[test]
+    fn direction_pin_mode() {
+        assert!(!Tmc2209::dir_pin_level(DirectionMode::Pin, Direction::CW, false));
+        assert!(Tmc2209::dir_pin_level(DirectionMode::Pin, Direction::CCW, false));
+        assert!(Tmc2209::dir_pin_level(DirectionMode::Pin, Direction::CW, true));
+        assert!(!Tmc2209::shaft_bit(DirectionMode::Pin, Direction::CCW, true));
+    }
+
+    #[test]
+    fn direction_uart_mode() {
+        assert!(!Tmc2209::dir_pin_level(DirectionMode::Uart, Direction::CCW, true));
+        assert!(Tmc2209::shaft_bit(DirectionMode::Uart, Direction::CCW, false));
+        assert!(!Tmc2209::shaft_bit(DirectionMode::Uart, Direction::CCW, true));
+    }
+
+    #[test]
+    fn direction_both_mode() {
+        assert!(Tmc2209::dir_pin_level(DirectionMode::Both, Direction::CCW, true));
+        assert!(Tmc2209::shaft_bit(DirectionMode::Both, Direction::CW, true));
+        assert!(!Tmc2209::shaft_bit(DirectionMode::Both, Direction::CCW, false));
+    }
+
     //#[test]
     //fn get_drv_status_vec() {
         ////let the_tmc = Tmc2209::new(1, 2, 3);
@@ -0,0 +1,408 @@
+use crate::connection::spi::SpiConnection;
+use crate::driver::step_dir::{StepDir, StepDirPins};
+use crate::driver::tmc;
+use crate::gpio::{open_chip, OutputPin, DEFAULT_CHIP};
+use crate::stepper::{
+    ConfigurableDriver, DiagnosticDriver, Direction, DriverStatus, StepGenerator,
+};
+use gpio_cdev::Chip;
+use std::time::Duration;
+
+/// Trinamic TMC5160, configured over SPI and moved either with STEP/DIR pulses (SD_MODE high) or
+/// by its internal ramp generator (SD_MODE low) which needs no pulses from the host at all
+pub struct Tmc5160 {
+    step_dir: Option<StepDir>,
+    en_pin: Option<OutputPin>,
+    spi: SpiConnection,
+    rsense: f32,
+    vmax: u32,
+}
+
+/// Builder for `Tmc5160`. With STEP and DIR pins the driver is moved by pulses, without them
+/// the internal ramp generator is used. The default SPI device and GPIO chip are used unless
+/// others are given.
+#[derive(Default)]
+pub struct Tmc5160Builder {
+    step_pin: Option<u32>,
+    dir_pin: Option<u32>,
+    en_pin: Option<u32>,
+    spi_device: Option<String>,
+    gpio_chip: Option<String>,
+    rsense: Option<f32>,
+}
+
+impl Tmc5160Builder {
+    pub fn step_pin(mut self, pin: u32) -> Self {
+        self.step_pin = Some(pin);
+        self
+    }
+
+    pub fn dir_pin(mut self, pin: u32) -> Self {
+        self.dir_pin = Some(pin);
+        self
+    }
+
+    pub fn en_pin(mut self, pin: u32) -> Self {
+        self.en_pin = Some(pin);
+        self
+    }
+
+    pub fn spi_device(mut self, spi_device: &str) -> Self {
+        self.spi_device = Some(spi_device.to_owned());
+        self
+    }
+
+    pub fn gpio_chip(mut self, gpio_chip: &str) -> Self {
+        self.gpio_chip = Some(gpio_chip.to_owned());
+        self
+    }
+
+    /// Sense resistor of the board in Ω, `Tmc5160::DEFAULT_RSENSE` when not set
+    pub fn rsense(mut self, rsense: f32) -> Self {
+        self.rsense = Some(rsense);
+        self
+    }
+
+    pub fn build(self) -> Result<Tmc5160, &'static str> {
+        let spi = SpiConnection::open(
+            self.spi_device
+                .as_deref()
+                .unwrap_or(SpiConnection::DEFAULT_DEVICE),
+        )?;
+        let mut chip = open_chip(self.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP))?;
+
+        let mut driver = match (self.step_pin, self.dir_pin) {
+            (Some(step), Some(dir)) => {
+                let pins = StepDirPins {
+                    step,
+                    dir,
+                    en: self.en_pin,
+                    microstep: Vec::new(),
+                };
+                Tmc5160::with_step_dir(&mut chip, pins, spi)?
+            }
+            (None, None) => Tmc5160::with_ramp_generator(&mut chip, self.en_pin, spi)?,
+            _ => return Err("STEP and DIR pins have to be given together"),
+        };
+        if let Some(rsense) = self.rsense {
+            driver.set_rsense(rsense)?;
+        }
+        Ok(driver)
+    }
+}
+
+impl StepGenerator for Tmc5160 {
+    fn set_steps_to_move(&mut self, steps: i32) {
+        if let Some(step_dir) = &mut self.step_dir {
+            step_dir.set_steps_to_move(steps);
+        }
+    }
+
+    fn step(&mut self) -> Result<(), &'static str> {
+        match &mut self.step_dir {
+            Some(step_dir) => step_dir.step(),
+            None => Err("TMC5160 runs on its ramp generator, move it with move_to"),
+        }
+    }
+
+    fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str> {
+        match &mut self.step_dir {
+            Some(step_dir) => step_dir.set_direction(direction),
+            None => Ok(()),
+        }
+    }
+
+    /// Uses the EN pin when there is one, otherwise the chopper is switched off through TOFF
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str> {
+        if let Some(step_dir) = &mut self.step_dir {
+            return step_dir.set_enabled(enabled);
+        }
+        if let Some(en_pin) = &self.en_pin {
+            return en_pin.set(!enabled);
+        }
+
+        let chopconf = self.spi.read(tmc::CHOPCONF)? & !tmc::CHOPCONF_TOFF;
+        let toff = match enabled {
+            true => Self::DEFAULT_CHOPCONF & tmc::CHOPCONF_TOFF,
+            false => 0,
+        };
+        self.spi.write(tmc::CHOPCONF, chopconf | toff)?;
+        Ok(())
+    }
+}
+
+impl ConfigurableDriver for Tmc5160 {
+    fn set_motor_current(&mut self, current: u16) -> Result<(), &'static str> {
+        self.set_current(current)
+    }
+
+    fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str> {
+        let chopconf = self.spi.read(tmc::CHOPCONF)?;
+        self.spi.write(
+            tmc::CHOPCONF,
+            tmc::chopconf_with_microsteps(chopconf, microsteps)?,
+        )?;
+        Ok(())
+    }
+
+    fn set_power_down_delay(&mut self, delay: Duration) -> Result<(), &'static str> {
+        self.spi
+            .write(tmc::TPOWERDOWN, tmc::tpowerdown_counts(delay))?;
+        Ok(())
+    }
+}
+
+impl DiagnosticDriver for Tmc5160 {
+    fn status(&mut self) -> Result<DriverStatus, &'static str> {
+        Ok(tmc::spi_driver_status(self.drv_status()?))
+    }
+
+    fn clear_faults(&mut self) -> Result<(), &'static str> {
+        self.clear_gstat()?;
+        Ok(())
+    }
+}
+
+impl Tmc5160 {
+    /// Sense resistor of most TMC5160 boards in Ω
+    pub const DEFAULT_RSENSE: f32 = 0.075;
+    /// Frequency of the internal clock the ramp generator runs on
+    const CLOCK_FREQUENCY: f64 = 12_000_000.0;
+
+    /// spreadCycle with TOFF=3, HSTRT=4, HEND=1, TBL=2 and 256 microsteps as suggested by the
+    /// datasheet for a first start
+    const DEFAULT_CHOPCONF: u32 = 0x000100C3;
+    const DEFAULT_TPOWERDOWN: u32 = 10;
+    const HOLD_CURRENT_DELAY: u32 = 6;
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    // Addresses
+    const GLOBAL_SCALER: u8 = 0x0B;
+    const RAMPMODE: u8 = 0x20;
+    const XACTUAL: u8 = 0x21;
+    const VSTART: u8 = 0x23;
+    const A1: u8 = 0x24;
+    const V1: u8 = 0x25;
+    const AMAX: u8 = 0x26;
+    const VMAX: u8 = 0x27;
+    const DMAX: u8 = 0x28;
+    const D1: u8 = 0x2A;
+    const VSTOP: u8 = 0x2B;
+    const XTARGET: u8 = 0x2D;
+    const RAMP_STAT: u8 = 0x35;
+
+    // RAMPMODE
+    const RAMPMODE_POSITION: u32 = 0;
+    const RAMPMODE_VELOCITY: u32 = 1;
+
+    // RAMP_STAT
+    const POSITION_REACHED: u32 = 1 << 9;
+
+    /// Largest VMAX and AMAX the registers take
+    const VMAX_LIMIT: u32 = (1 << 23) - 512;
+    const AMAX_LIMIT: u32 = (1 << 16) - 1;
+    /// Stop speed of the ramp, the datasheet recommends at least 10
+    const VSTOP_DEFAULT: u32 = 10;
+
+    pub fn builder() -> Tmc5160Builder {
+        Tmc5160Builder::default()
+    }
+
+    /// Requests the STEP, DIR and EN lines from `chip` for SD_MODE high, MS pins are ignored as
+    /// the resolution is set over SPI
+    pub fn with_step_dir(
+        chip: &mut Chip,
+        pins: StepDirPins,
+        spi: SpiConnection,
+    ) -> Result<Self, &'static str> {
+        let pins = StepDirPins {
+            microstep: Vec::new(),
+            ..pins
+        };
+        let step_dir = StepDir::request(chip, pins, tmc::STEP_DIR_CONFIG)?;
+        Ok(Self::from_parts(Some(step_dir), None, spi))
+    }
+
+    /// Uses the internal ramp generator for SD_MODE low, the EN line is optional
+    pub fn with_ramp_generator(
+        chip: &mut Chip,
+        en: Option<u32>,
+        spi: SpiConnection,
+    ) -> Result<Self, &'static str> {
+        let en_pin = match en {
+            Some(en) => Some(OutputPin::request(chip, en, true, "tmc5160_en")?),
+            None => None,
+        };
+        Ok(Self::from_parts(None, en_pin, spi))
+    }
+
+    fn from_parts(
+        step_dir: Option<StepDir>,
+        en_pin: Option<OutputPin>,
+        spi: SpiConnection,
+    ) -> Self {
+        Self {
+            step_dir,
+            en_pin,
+            spi,
+            rsense: Self::DEFAULT_RSENSE,
+            vmax: 0,
+        }
+    }
+
+    /// Writes a working chopper configuration and a ramp of 1000 steps/s at 2000 steps/s^2. The
+    /// driver stays disabled (TOFF=0) after a reset until CHOPCONF is written.
+    pub fn init_default_settings(&mut self) -> Result<(), &'static str> {
+        self.spi.write(tmc::GCONF, 0)?;
+        self.spi.write(tmc::CHOPCONF, Self::DEFAULT_CHOPCONF)?;
+        self.spi.write(tmc::TPOWERDOWN, Self::DEFAULT_TPOWERDOWN)?;
+        self.spi.write(Self::GLOBAL_SCALER, 0)?;
+        if self.step_dir.is_none() {
+            self.set_ramp(1000.0, 2000.0)?;
+        }
+        self.clear_gstat()?;
+        Ok(())
+    }
+
+    /// Sets the sense resistor of the board in Ω, used to calculate the current scale
+    pub fn set_rsense(&mut self, rsense: f32) -> Result<(), &'static str> {
+        if rsense <= 0.0 {
+            return Err("Sense resistor must be greater than zero");
+        }
+        self.rsense = rsense;
+        Ok(())
+    }
+
+    /// Sets the RMS run current in mA with the global scaler at full scale, the hold current is
+    /// half of it
+    pub fn set_current(&mut self, current: u16) -> Result<(), &'static str> {
+        let irun = tmc::current_scale(current, self.rsense, tmc::VFS_LOW_SENSITIVITY);
+        self.spi.write(
+            tmc::IHOLD_IRUN,
+            tmc::ihold_irun(irun / 2, irun, Self::HOLD_CURRENT_DELAY),
+        )?;
+        Ok(())
+    }
+
+    /// Microstep resolution currently set in CHOPCONF
+    pub fn microsteps(&mut self) -> Result<u16, &'static str> {
+        Ok(tmc::microsteps(self.spi.read(tmc::CHOPCONF)?))
+    }
+
+    /// Sets the top speed in steps/s and the acceleration and deceleration in steps/s^2 of the
+    /// ramp generator. The ramp is a plain trapezoid, the A1/V1/D1 phase is not used.
+    pub fn set_ramp(&mut self, max_speed: f64, acceleration: f64) -> Result<(), &'static str> {
+        if max_speed <= 0.0 || acceleration <= 0.0 {
+            return Err("Speed and acceleration must be greater than zero");
+        }
+        let vmax = Self::vmax_register(max_speed);
+        let amax = Self::amax_register(acceleration);
+
+        self.spi.write(Self::VSTART, 0)?;
+        self.spi.write(Self::V1, 0)?;
+        self.spi.write(Self::A1, amax)?;
+        self.spi.write(Self::AMAX, amax)?;
+        self.spi.write(Self::DMAX, amax)?;
+        self.spi.write(Self::D1, amax)?;
+        self.spi.write(Self::VSTOP, Self::VSTOP_DEFAULT)?;
+        self.spi.write(Self::VMAX, vmax)?;
+        self.vmax = vmax;
+        Ok(())
+    }
+
+    /// Starts a move of the ramp generator to an absolute position in microsteps and returns
+    /// right away, see `position_reached`
+    pub fn move_to(&mut self, position: i32) -> Result<(), &'static str> {
+        if self.vmax == 0 {
+            return Err("Ramp is not set, call set_ramp first");
+        }
+        self.spi.write(Self::RAMPMODE, Self::RAMPMODE_POSITION)?;
+        self.spi.write(Self::VMAX, self.vmax)?;
+        self.spi.write(Self::XTARGET, position as u32)?;
+        Ok(())
+    }
+
+    /// Same as `move_to` but waits until the target is reached
+    pub async fn move_to_and_wait(&mut self, position: i32) -> Result<(), &'static str> {
+        self.move_to(position)?;
+        while !self.position_reached()? {
+            tokio::time::sleep(Self::POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Decelerates the ramp generator to standstill, the target of the last move is dropped
+    pub fn stop(&mut self) -> Result<(), &'static str> {
+        self.spi.write(Self::RAMPMODE, Self::RAMPMODE_VELOCITY)?;
+        self.spi.write(Self::VMAX, 0)?;
+        Ok(())
+    }
+
+    pub fn position_reached(&mut self) -> Result<bool, &'static str> {
+        Ok(self.spi.read(Self::RAMP_STAT)? & Self::POSITION_REACHED != 0)
+    }
+
+    /// Position of the ramp generator in microsteps
+    pub fn position(&mut self) -> Result<i32, &'static str> {
+        Ok(self.spi.read(Self::XACTUAL)? as i32)
+    }
+
+    /// Overrides the position of the ramp generator, only do this at standstill
+    pub fn set_position(&mut self, position: i32) -> Result<(), &'static str> {
+        self.spi.write(Self::XACTUAL, position as u32)?;
+        self.spi.write(Self::XTARGET, position as u32)?;
+        Ok(())
+    }
+
+    /// Reads and clears GSTAT, the flags are `tmc::GSTAT_*`
+    pub fn clear_gstat(&mut self) -> Result<u32, &'static str> {
+        let gstat = self.spi.read(tmc::GSTAT)?;
+        self.spi.write(tmc::GSTAT, gstat)?;
+        Ok(gstat)
+    }
+
+    pub fn drv_status(&mut self) -> Result<u32, &'static str> {
+        self.spi.read(tmc::DRV_STATUS)
+    }
+
+    /// SPI_STATUS byte of the last transfer, see `SpiConnection::STATUS_*`
+    pub fn spi_status(&self) -> u8 {
+        self.spi.status()
+    }
+
+    /// Shared handle to the STEP line when STEP/DIR is used, e.g. for a step thread's `StepPins`
+    pub fn step_pin(&self) -> Option<OutputPin> {
+        self.step_dir.as_ref().map(|step_dir| step_dir.step_pin())
+    }
+
+    /// VMAX for a speed in steps/s, one unit is fCLK / 2^24 steps/s
+    fn vmax_register(speed: f64) -> u32 {
+        let vmax = speed * (1u64 << 24) as f64 / Self::CLOCK_FREQUENCY;
+        vmax.round().clamp(0.0, Self::VMAX_LIMIT as f64) as u32
+    }
+
+    /// AMAX for an acceleration in steps/s^2, one unit is fCLK^2 / 2^41 steps/s^2
+    fn amax_register(acceleration: f64) -> u32 {
+        let amax = acceleration * (1u64 << 41) as f64 / Self::CLOCK_FREQUENCY.powi(2);
+        amax.round().clamp(1.0, Self::AMAX_LIMIT as f64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmax_units() {
+        assert_eq!(Tmc5160::vmax_register(1000.0), 1398);
+        assert_eq!(Tmc5160::vmax_register(0.0), 0);
+        assert_eq!(Tmc5160::vmax_register(1e9), Tmc5160::VMAX_LIMIT);
+    }
+
+    #[test]
+    fn amax_units() {
+        assert_eq!(Tmc5160::amax_register(1000.0), 15);
+        assert_eq!(Tmc5160::amax_register(0.001), 1);
+        assert_eq!(Tmc5160::amax_register(1e9), Tmc5160::AMAX_LIMIT);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::gpio::{open_chip, OutputPin, DEFAULT_CHIP};
+use crate::stepper::{ConfigurableDriver, Direction, StepGenerator};
+use gpio_cdev::Chip;
+
+/// Coil sequence used to turn a unipolar motor
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StepMode {
+    /// One coil at a time, lowest current and torque
+    Wave,
+    /// Two coils at a time, full torque
+    Full,
+    /// Alternates between one and two coils, twice the resolution of `Full`
+    Half,
+}
+
+/// Drives a unipolar motor such as the 28BYJ-48 through the four inputs of a ULN2003 darlington
+/// array. There is no driver chip keeping the current, the coils are switched directly from the
+/// phase table so a step is a write to each coil line.
+pub struct Uln2003 {
+    coils: [OutputPin; 4],
+    mode: StepMode,
+    phase: usize,
+    steps_to_move: i32,
+    enabled: bool,
+}
+
+/// Builder for `Uln2003`, the four coil pins are required
+#[derive(Default)]
+pub struct Uln2003Builder {
+    coil_pins: Option<[u32; 4]>,
+    step_mode: Option<StepMode>,
+    gpio_chip: Option<String>,
+}
+
+impl Uln2003Builder {
+    /// IN1 to IN4 of the ULN2003 board
+    pub fn coil_pins(mut self, pins: [u32; 4]) -> Self {
+        self.coil_pins = Some(pins);
+        self
+    }
+
+    /// Coil sequence to start with, half steps when not set
+    pub fn step_mode(mut self, mode: StepMode) -> Self {
+        self.step_mode = Some(mode);
+        self
+    }
+
+    pub fn gpio_chip(mut self, gpio_chip: &str) -> Self {
+        self.gpio_chip = Some(gpio_chip.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<Uln2003, &'static str> {
+        let pins = self.coil_pins.ok_or("Coil pins are required")?;
+        let mut chip = open_chip(self.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP))?;
+        Uln2003::request(&mut chip, pins, self.step_mode.unwrap_or(StepMode::Half))
+    }
+}
+
+impl StepGenerator for Uln2003 {
+    fn set_steps_to_move(&mut self, steps: i32) {
+        self.steps_to_move = steps;
+    }
+
+    /// Moves the coils to the next phase, returns Err() when no more steps remain or the coils
+    /// are not energised
+    fn step(&mut self) -> Result<(), &'static str> {
+        if !self.enabled {
+            return Err("Coils are not energised, enable the motor first");
+        }
+
+        let direction = match self.steps_to_move {
+            n if n > 0 => Direction::CW,
+            n if n < 0 => Direction::CCW,
+            _ => return Err("No more steps to move"),
+        };
+
+        self.phase = Self::next_phase(self.phase, self.mode, direction);
+        self.write_coils(Self::coil_levels(self.phase))?;
+        self.steps_to_move -= self.steps_to_move.signum();
+        Ok(())
+    }
+
+    /// The direction follows the sign of the steps to move, there is no DIR line
+    fn set_direction(&mut self, _direction: Direction) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Energises the coils of the current phase or releases all of them, a released motor keeps
+    /// no holding torque but does not heat up either
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str> {
+        match enabled {
+            true => self.write_coils(Self::coil_levels(self.phase))?,
+            false => self.write_coils([false; 4])?,
+        }
+        self.enabled = enabled;
+        Ok(())
+    }
+}
+
+impl ConfigurableDriver for Uln2003 {
+    /// 1 selects full steps and 2 half steps
+    fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str> {
+        match microsteps {
+            1 => self.set_step_mode(StepMode::Full),
+            2 => self.set_step_mode(StepMode::Half),
+            _ => Err("ULN2003 only supports full (1) and half (2) steps"),
+        }
+    }
+}
+
+impl Uln2003 {
+    /// Half step sequence of coils A, B, C, D. Wave steps use the even and full steps the odd
+    /// phases of it.
+    const HALF_STEP_SEQUENCE: [[bool; 4]; 8] = [
+        [true, false, false, false],
+        [true, true, false, false],
+        [false, true, false, false],
+        [false, true, true, false],
+        [false, false, true, false],
+        [false, false, true, true],
+        [false, false, false, true],
+        [true, false, false, true],
+    ];
+
+    pub fn builder() -> Uln2003Builder {
+        Uln2003Builder::default()
+    }
+
+    /// Requests IN1 to IN4 from `chip`, all coils start released
+    pub fn request(chip: &mut Chip, pins: [u32; 4], mode: StepMode) -> Result<Self, &'static str> {
+        let coils = [
+            OutputPin::request(chip, pins[0], false, "uln2003_in1")?,
+            OutputPin::request(chip, pins[1], false, "uln2003_in2")?,
+            OutputPin::request(chip, pins[2], false, "uln2003_in3")?,
+            OutputPin::request(chip, pins[3], false, "uln2003_in4")?,
+        ];
+
+        Ok(Self {
+            coils,
+            mode,
+            phase: Self::snap_phase(0, mode),
+            steps_to_move: 0,
+            enabled: false,
+        })
+    }
+
+    /// Switches the coil sequence, the rotor moves to the nearest phase of the new sequence when
+    /// the coils are energised
+    pub fn set_step_mode(&mut self, mode: StepMode) -> Result<(), &'static str> {
+        self.mode = mode;
+        self.phase = Self::snap_phase(self.phase, mode);
+        if self.enabled {
+            self.write_coils(Self::coil_levels(self.phase))?;
+        }
+        Ok(())
+    }
+
+    pub fn step_mode(&self) -> StepMode {
+        self.mode
+    }
+
+    fn write_coils(&self, levels: [bool; 4]) -> Result<(), &'static str> {
+        for (coil, level) in self.coils.iter().zip(levels) {
+            coil.set(level)?;
+        }
+        Ok(())
+    }
+
+    fn coil_levels(phase: usize) -> [bool; 4] {
+        Self::HALF_STEP_SEQUENCE[phase % 8]
+    }
+
+    /// Moves a phase onto the sequence of `mode`, wave steps live on even and full steps on odd
+    /// phases
+    fn snap_phase(phase: usize, mode: StepMode) -> usize {
+        match mode {
+            StepMode::Wave if !phase.is_multiple_of(2) => (phase + 7) % 8,
+            StepMode::Full if phase.is_multiple_of(2) => (phase + 1) % 8,
+            _ => phase,
+        }
+    }
+
+    fn next_phase(phase: usize, mode: StepMode, direction: Direction) -> usize {
+        let stride = match mode {
+            StepMode::Half => 1,
+            StepMode::Wave | StepMode::Full => 2,
+        };
+        match direction {
+            Direction::CW => (phase + stride) % 8,
+            Direction::CCW => (phase + 8 - stride) % 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(mode: StepMode, direction: Direction, steps: usize) -> Vec<[bool; 4]> {
+        let mut phase = Uln2003::snap_phase(0, mode);
+        (0..steps)
+            .map(|_| {
+                phase = Uln2003::next_phase(phase, mode, direction);
+                Uln2003::coil_levels(phase)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wave_sequence() {
+        assert_eq!(
+            sequence(StepMode::Wave, Direction::CW, 4),
+            vec![
+                [false, true, false, false],
+                [false, false, true, false],
+                [false, false, false, true],
+                [true, false, false, false],
+            ]
+        );
+    }
+
+    #[test]
+    fn full_sequence_backwards() {
+        assert_eq!(
+            sequence(StepMode::Full, Direction::CCW, 4),
+            vec![
+                [true, false, false, true],
+                [false, false, true, true],
+                [false, true, true, false],
+                [true, true, false, false],
+            ]
+        );
+    }
+
+    #[test]
+    fn half_sequence_wraps() {
+        let steps = sequence(StepMode::Half, Direction::CW, 9);
+        assert_eq!(steps[7], [true, false, false, false]);
+        assert_eq!(steps[8], [true, true, false, false]);
+        assert!(steps
+            .iter()
+            .all(|coils| (1..=2).contains(&coils.iter().filter(|on| **on).count())));
+    }
+
+    #[test]
+    fn snap_phase_to_mode() {
+        assert_eq!(Uln2003::snap_phase(3, StepMode::Wave), 2);
+        assert_eq!(Uln2003::snap_phase(0, StepMode::Wave), 0);
+        assert_eq!(Uln2003::snap_phase(6, StepMode::Full), 7);
+        assert_eq!(Uln2003::snap_phase(5, StepMode::Full), 5);
+        assert_eq!(Uln2003::snap_phase(5, StepMode::Half), 5);
+    }
+}
@@ -0,0 +1,335 @@
+//! Position feedback from encoders attached to an axis, see `MotionController::set_encoder`
+use crate::gpio::open_chip;
+use gpio_cdev::{Chip, EventRequestFlags, LineEventHandle, LineRequestFlags};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the encoder threads check whether the encoder was dropped
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub trait Encoder: Send {
+    /// Counts since the encoder was opened, the direction matches the direction of the counts
+    /// of the device
+    fn count(&self) -> Result<i64, &'static str>;
+}
+
+/// What happens when the encoder and the commanded position are further apart than allowed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FollowingErrorAction {
+    /// The axis refuses to move until the fault is cleared
+    Fault,
+    /// The position of the axis is set to the encoder position
+    Correct,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EncoderConfig {
+    /// Motor steps per encoder count, negative when the encoder counts against the motor
+    pub steps_per_count: f64,
+    /// Largest allowed distance in steps between the encoder and the commanded position
+    pub max_following_error: u32,
+    pub action: FollowingErrorAction,
+}
+
+impl EncoderConfig {
+    pub fn new(
+        steps_per_count: f64,
+        max_following_error: u32,
+        action: FollowingErrorAction,
+    ) -> Result<Self, &'static str> {
+        if steps_per_count == 0.0 || !steps_per_count.is_finite() {
+            return Err("Steps per encoder count must be a finite number other than zero");
+        }
+        Ok(Self {
+            steps_per_count,
+            max_following_error,
+            action,
+        })
+    }
+}
+
+/// Closed-loop correction of an axis with an encoder, see `MotionController::set_closed_loop`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ClosedLoopConfig {
+    /// Largest distance in steps between the encoder and the target a move may end with
+    pub tolerance: u32,
+    /// Corrective moves made at the end of a move before it ends with the remaining error
+    pub max_attempts: u32,
+    /// Steps between encoder checks during moves stepped from the calling thread. Lost steps are
+    /// made up by planning the rest of the move again from the encoder position. None only
+    /// corrects at the end of moves.
+    pub check_interval: Option<u32>,
+}
+
+/// Thread counting into a shared counter until it is dropped or `poll` fails
+struct CountThread {
+    count: Arc<AtomicI64>,
+    running: Arc<AtomicBool>,
+    failure: Arc<OnceLock<&'static str>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CountThread {
+    fn spawn<F>(name: String, mut poll: F) -> Result<Self, &'static str>
+    where
+        F: FnMut(&AtomicI64) -> Result<(), &'static str> + Send + 'static,
+    {
+        let count = Arc::new(AtomicI64::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+        let failure = Arc::new(OnceLock::new());
+        let thread_count = count.clone();
+        let thread_running = running.clone();
+        let thread_failure = failure.clone();
+        let handle = std::thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                while thread_running.load(Ordering::SeqCst) {
+                    if let Err(err) = poll(&thread_count) {
+                        let _ = thread_failure.set(err);
+                        break;
+                    }
+                }
+            })
+            .map_err(|_| "Could not start the encoder thread")?;
+
+        Ok(Self {
+            count,
+            running,
+            failure,
+            handle: Some(handle),
+        })
+    }
+
+    /// The count is no longer valid once the thread stopped on an error
+    fn count(&self) -> Result<i64, &'static str> {
+        match self.failure.get() {
+            Some(err) => Err(err),
+            None => Ok(self.count.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+impl Drop for CountThread {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Waits until one of `fds` can be read or `timeout` passed, returns which ones are readable. A
+/// hung up or failed fd counts as readable so the next read returns the error instead of the
+/// poll returning right away forever.
+fn wait_readable<const N: usize>(fds: [RawFd; N], timeout: Duration) -> [bool; N] {
+    let mut poll_fds = fds.map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let ready = unsafe { libc::poll(poll_fds.as_mut_ptr(), N as libc::nfds_t, timeout) };
+    let events = libc::POLLIN | libc::POLLHUP | libc::POLLERR;
+    poll_fds.map(|fd| ready > 0 && fd.revents & events != 0)
+}
+
+/// Quadrature encoder on two GPIO lines decoded in software from their edge events. Edges
+/// coming faster than the thread can read them are lost, so it suits slow shafts or encoders
+/// with few counts per revolution.
+pub struct QuadratureEncoder {
+    thread: CountThread,
+}
+
+impl QuadratureEncoder {
+    pub fn open(gpio_chip: &str, pin_a: u32, pin_b: u32) -> Result<Self, &'static str> {
+        Self::request(&mut open_chip(gpio_chip)?, pin_a, pin_b)
+    }
+
+    pub fn request(chip: &mut Chip, pin_a: u32, pin_b: u32) -> Result<Self, &'static str> {
+        let mut request = |pin| {
+            chip.get_line(pin)
+                .map_err(|_| "GPIO line does not exist on the chip")?
+                .events(
+                    LineRequestFlags::INPUT,
+                    EventRequestFlags::BOTH_EDGES,
+                    "stepper-encoder",
+                )
+                .map_err(|_| "GPIO line could not be requested as input, is it already in use?")
+        };
+        let mut lines = [request(pin_a)?, request(pin_b)?];
+        let mut decoder = QuadratureDecoder::new(read_state(&lines)?);
+
+        let thread = CountThread::spawn(format!("encoder-{}", pin_a), move |count| {
+            let readable =
+                wait_readable(lines.each_ref().map(|line| line.as_raw_fd()), POLL_INTERVAL);
+            if !readable.contains(&true) {
+                return Ok(());
+            }
+            for (line, readable) in lines.iter_mut().zip(readable) {
+                if readable {
+                    line.get_event()
+                        .map_err(|_| "Could not read GPIO line event")?;
+                }
+            }
+            if let Ok(state) = read_state(&lines) {
+                count.fetch_add(decoder.update(state), Ordering::SeqCst);
+            }
+            Ok(())
+        })?;
+        Ok(Self { thread })
+    }
+}
+
+impl Encoder for QuadratureEncoder {
+    fn count(&self) -> Result<i64, &'static str> {
+        self.thread.count()
+    }
+}
+
+fn read_state(lines: &[LineEventHandle; 2]) -> Result<u8, &'static str> {
+    let mut state = 0;
+    for line in lines {
+        let value = line
+            .get_value()
+            .map_err(|_| "Could not read GPIO line value")?;
+        state = state << 1 | value;
+    }
+    Ok(state)
+}
+
+/// Turns the A/B states of a quadrature encoder into counts. A change of both lines at once
+/// means an edge was missed and is not counted.
+#[derive(Debug)]
+struct QuadratureDecoder {
+    state: u8,
+}
+
+impl QuadratureDecoder {
+    /// Counts of a transition, indexed by the previous state times four plus the new state
+    const TRANSITIONS: [i64; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+    fn new(state: u8) -> Self {
+        Self { state }
+    }
+
+    fn update(&mut self, state: u8) -> i64 {
+        let count = Self::TRANSITIONS[(self.state * 4 + state) as usize];
+        self.state = state;
+        count
+    }
+}
+
+/// Encoder exposed as a Linux input device, e.g. by the `rotary-encoder` driver. Relative events
+/// are added up, absolute events set the count.
+pub struct InputEncoder {
+    thread: CountThread,
+}
+
+impl InputEncoder {
+    const EV_REL: u16 = 0x02;
+    const EV_ABS: u16 = 0x03;
+
+    /// `code` is the axis the device reports the rotation on, e.g. 0 for REL_X or ABS_X
+    pub fn open(path: &str, code: u16) -> Result<Self, &'static str> {
+        let mut device = File::open(path).map_err(|_| "Input device could not be opened")?;
+        let name = format!("encoder-{}", path.rsplit('/').next().unwrap_or(path));
+
+        let thread = CountThread::spawn(name, move |count| {
+            if wait_readable([device.as_raw_fd()], POLL_INTERVAL) != [true] {
+                return Ok(());
+            }
+            let mut buffer = [0u8; std::mem::size_of::<libc::input_event>()];
+            device
+                .read_exact(&mut buffer)
+                .map_err(|_| "Input device of the encoder was disconnected")?;
+            let event: libc::input_event =
+                unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const _) };
+            match event.type_ {
+                Self::EV_REL if event.code == code => {
+                    count.fetch_add(event.value as i64, Ordering::SeqCst);
+                }
+                Self::EV_ABS if event.code == code => {
+                    count.store(event.value as i64, Ordering::SeqCst)
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(Self { thread })
+    }
+}
+
+impl Encoder for InputEncoder {
+    fn count(&self) -> Result<i64, &'static str> {
+        self.thread.count()
+    }
+}
+
+/// Hardware counter of the Linux counter subsystem, read from its sysfs count file, e.g.
+/// `/sys/bus/counter/devices/counter0/count0/count`
+pub struct CounterEncoder {
+    path: String,
+}
+
+impl CounterEncoder {
+    pub fn open(path: &str) -> Result<Self, &'static str> {
+        let encoder = Self {
+            path: path.to_owned(),
+        };
+        encoder.count()?;
+        Ok(encoder)
+    }
+}
+
+impl Encoder for CounterEncoder {
+    fn count(&self) -> Result<i64, &'static str> {
+        std::fs::read_to_string(&self.path)
+            .map_err(|_| "Could not read the counter")?
+            .trim()
+            .parse()
+            .map_err(|_| "Counter value is not a number")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quadrature_decoding() {
+        let mut decoder = QuadratureDecoder::new(0b00);
+        let forward = [0b01, 0b11, 0b10, 0b00];
+        assert_eq!(forward.iter().map(|s| decoder.update(*s)).sum::<i64>(), 4);
+        let backward = [0b10, 0b11, 0b01, 0b00];
+        assert_eq!(backward.iter().map(|s| decoder.update(*s)).sum::<i64>(), -4);
+
+        // Missed edge
+        assert_eq!(decoder.update(0b11), 0);
+        assert_eq!(decoder.update(0b11), 0);
+    }
+
+    #[test]
+    fn count_thread_stops_on_error() {
+        let thread = CountThread::spawn("encoder-test".to_owned(), |count| {
+            match count.fetch_add(1, Ordering::SeqCst) {
+                2 => Err("Input device of the encoder was disconnected"),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+
+        let failed = std::time::Instant::now() + Duration::from_secs(1);
+        while thread.count().is_ok() && std::time::Instant::now() < failed {
+            std::thread::yield_now();
+        }
+        assert_eq!(
+            thread.count(),
+            Err("Input device of the encoder was disconnected")
+        );
+    }
+}
@@ -0,0 +1,831 @@
+use crate::interpolation::arc_points;
+use crate::motion_controller::MotionController;
+use crate::motion_group::MotionGroup;
+use crate::planner::Planner;
+use crate::stepper::{ConfigurableDriver, Direction, StepGenerator};
+use std::time::Duration;
+
+/// Axis words given on a G-code line, axes that are not on the line are left as None. Depending
+/// on the command the values are positions, currents or microstep resolutions.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AxisWords {
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+}
+
+impl AxisWords {
+    pub fn is_empty(&self) -> bool {
+        self.x.is_none() && self.y.is_none() && self.z.is_none()
+    }
+
+    fn get(&self, axis: usize) -> Option<f64> {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            2 => self.z,
+            _ => None,
+        }
+    }
+
+    /// Checks that every given word is a whole number a driver setting can hold
+    fn driver_setting(self, error: &'static str) -> Result<Self, &'static str> {
+        let valid = |value: f64| value.fract() == 0.0 && (0.0..=u16::MAX as f64).contains(&value);
+        match [self.x, self.y, self.z].into_iter().flatten().all(valid) {
+            true => Ok(self),
+            false => Err(error),
+        }
+    }
+}
+
+/// Commands from the supported subset of G-code
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// G0
+    Rapid {
+        target: AxisWords,
+        feed_rate: Option<f64>,
+    },
+    /// G1
+    Linear {
+        target: AxisWords,
+        feed_rate: Option<f64>,
+    },
+    /// G2 (CW) and G3 (CCW), the center is given relative to the start point by I and J
+    Arc {
+        target: AxisWords,
+        center_offset: (f64, f64),
+        direction: Direction,
+        feed_rate: Option<f64>,
+    },
+    /// G4
+    Dwell(Duration),
+    /// G20
+    Inches,
+    /// G21
+    Millimeters,
+    /// G28, moves the given axes (all when none are given) back to the machine origin
+    Home(AxisWords),
+    /// G90
+    Absolute,
+    /// G91
+    Relative,
+    /// G92
+    SetPosition(AxisWords),
+    /// M17, enables the given axes (all when none are given)
+    EnableMotors(AxisWords),
+    /// M18, disables the given axes (all when none are given)
+    DisableMotors(AxisWords),
+    /// M204
+    SetAcceleration(f64),
+    /// M906, run current per axis in mA
+    SetCurrent(AxisWords),
+    /// M350, microsteps per full step per axis
+    SetMicrosteps(AxisWords),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MotionMode {
+    Rapid,
+    Linear,
+    Arc(Direction),
+}
+
+/// Words found on a single line, indexed by letter
+struct Words {
+    g_codes: Vec<f64>,
+    m_codes: Vec<f64>,
+    params: [Option<f64>; 26],
+}
+
+impl Words {
+    fn param(&self, letter: char) -> Option<f64> {
+        self.params[(letter as u8 - b'A') as usize]
+    }
+
+    fn axes(&self) -> AxisWords {
+        AxisWords {
+            x: self.param('X'),
+            y: self.param('Y'),
+            z: self.param('Z'),
+        }
+    }
+}
+
+/// Turns lines of G-code into commands. Motion commands are modal so the parser remembers the
+/// last motion mode for lines that only hold coordinates.
+#[derive(Default)]
+pub struct Parser {
+    motion_mode: Option<MotionMode>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a single line, returning the commands in the order they need to be executed
+    pub fn parse_line(&mut self, line: &str) -> Result<Vec<Command>, &'static str> {
+        let words = Self::read_words(line)?;
+        let mut commands = Vec::new();
+        let mut motion_mode = None;
+        let mut axes_used = false;
+
+        for code in words.g_codes.iter() {
+            match Self::code_number(*code) {
+                Some(0) => motion_mode = Some(MotionMode::Rapid),
+                Some(1) => motion_mode = Some(MotionMode::Linear),
+                Some(2) => motion_mode = Some(MotionMode::Arc(Direction::CW)),
+                Some(3) => motion_mode = Some(MotionMode::Arc(Direction::CCW)),
+                Some(4) => {
+                    let millis = match (words.param('P'), words.param('S')) {
+                        (Some(millis), _) => millis,
+                        (None, Some(seconds)) => seconds * 1000.0,
+                        (None, None) => 0.0,
+                    };
+                    if millis < 0.0 {
+                        return Err("Dwell time can not be negative");
+                    }
+                    commands.push(Command::Dwell(Duration::from_micros(
+                        (millis * 1000.0) as u64,
+                    )));
+                }
+                // Only the XY plane is supported so selecting it does nothing
+                Some(17) => {}
+                Some(20) => commands.push(Command::Inches),
+                Some(21) => commands.push(Command::Millimeters),
+                Some(28) => {
+                    commands.push(Command::Home(words.axes()));
+                    axes_used = true;
+                }
+                Some(90) => commands.push(Command::Absolute),
+                Some(91) => commands.push(Command::Relative),
+                Some(92) => {
+                    commands.push(Command::SetPosition(words.axes()));
+                    axes_used = true;
+                }
+                _ => return Err("Unsupported G-code command"),
+            }
+        }
+
+        for code in words.m_codes.iter() {
+            match Self::code_number(*code) {
+                Some(17) => commands.push(Command::EnableMotors(words.axes())),
+                Some(18) => commands.push(Command::DisableMotors(words.axes())),
+                Some(204) => match words.param('S').or(words.param('P')) {
+                    Some(acceleration) if acceleration > 0.0 => {
+                        commands.push(Command::SetAcceleration(acceleration))
+                    }
+                    _ => return Err("M204 needs a positive S or P acceleration"),
+                },
+                Some(906) => commands.push(Command::SetCurrent(
+                    words
+                        .axes()
+                        .driver_setting("M906 needs whole currents from 0 to 65535 mA")?,
+                )),
+                Some(350) => commands.push(Command::SetMicrosteps(
+                    words
+                        .axes()
+                        .driver_setting("M350 needs whole microsteps from 0 to 65535")?,
+                )),
+                _ => return Err("Unsupported M-code command"),
+            }
+            axes_used = true;
+        }
+
+        if motion_mode.is_some() {
+            self.motion_mode = motion_mode;
+        }
+
+        let target = words.axes();
+        let feed_rate = words.param('F');
+        let has_motion = motion_mode.is_some() || (!axes_used && !target.is_empty());
+
+        if has_motion {
+            let command = match self.motion_mode {
+                Some(MotionMode::Rapid) => Command::Rapid { target, feed_rate },
+                Some(MotionMode::Linear) => Command::Linear { target, feed_rate },
+                Some(MotionMode::Arc(direction)) => {
+                    if words.param('R').is_some() {
+                        return Err("Radius format arcs are not supported, use I and J");
+                    }
+                    Command::Arc {
+                        target,
+                        center_offset: (
+                            words.param('I').unwrap_or(0.0),
+                            words.param('J').unwrap_or(0.0),
+                        ),
+                        direction,
+                        feed_rate,
+                    }
+                }
+                None => return Err("Coordinates given without a motion command"),
+            };
+            commands.push(command);
+        }
+
+        Ok(commands)
+    }
+
+    /// Number of a G or M code, sub codes such as G92.1 are not supported
+    fn code_number(code: f64) -> Option<u32> {
+        match code.fract() == 0.0 && code >= 0.0 {
+            true => Some(code as u32),
+            false => None,
+        }
+    }
+
+    /// Splits a line into its words, dropping comments, line numbers and checksums
+    fn read_words(line: &str) -> Result<Words, &'static str> {
+        let mut words = Words {
+            g_codes: Vec::new(),
+            m_codes: Vec::new(),
+            params: [None; 26],
+        };
+        // Files made for tape readers start and end with a line holding only a percent sign
+        if line.trim() == "%" {
+            return Ok(words);
+        }
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                ';' | '*' => break,
+                '(' => {
+                    for c in chars.by_ref() {
+                        if c == ')' {
+                            break;
+                        }
+                    }
+                }
+                c if c.is_whitespace() => {}
+                c if c.is_ascii_alphabetic() => {
+                    let mut number = String::new();
+                    while let Some(n) = chars.peek() {
+                        if n.is_ascii_digit() || *n == '.' || *n == '-' || *n == '+' {
+                            number.push(*n);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    let value: f64 = number
+                        .parse()
+                        .map_err(|_| "Word is missing a valid number")?;
+
+                    match c.to_ascii_uppercase() {
+                        'G' => words.g_codes.push(value),
+                        'M' => words.m_codes.push(value),
+                        'N' => {}
+                        letter => words.params[(letter as u8 - b'A') as usize] = Some(value),
+                    }
+                }
+                _ => return Err("Unexpected character in line"),
+            }
+        }
+        Ok(words)
+    }
+}
+
+/// Runs G-code on a motion group. Axis 0, 1 and 2 of the group are X, Y and Z, a group may have
+/// fewer axes as long as the G-code does not use them. Moves are queued in a planner so
+/// consecutive segments flow into each other, call `flush` to run whatever is still queued.
+pub struct Interpreter<T> {
+    group: MotionGroup<T>,
+    parser: Parser,
+    planner: Planner,
+    /// Commanded machine position in mm, kept separately to avoid rounding drift on relative moves
+    position: [f64; 3],
+    /// Difference between machine and work coordinates as set by G92
+    offset: [f64; 3],
+    inches: bool,
+    absolute: bool,
+    feed_rate: f64,
+    rapid_rate: f64,
+    acceleration: f64,
+    chord_tolerance: f64,
+}
+
+impl<T> Interpreter<T>
+where
+    T: StepGenerator + ConfigurableDriver,
+{
+    const MM_PER_INCH: f64 = 25.4;
+    const DEFAULT_FEED_RATE: f64 = 600.0;
+    const DEFAULT_RAPID_RATE: f64 = 3000.0;
+    const DEFAULT_ACCELERATION: f64 = 500.0;
+    const DEFAULT_CHORD_TOLERANCE: f64 = 0.01;
+    /// Difference in mm allowed between the start and end radius of an arc, covers programs
+    /// written with three decimals
+    const ARC_RADIUS_TOLERANCE: f64 = 0.002;
+    /// Amount of segments kept in the planner for lookahead before the oldest one is run
+    const LOOKAHEAD: usize = 16;
+
+    pub fn new(group: MotionGroup<T>, steps_per_mm: [f64; 3]) -> Self {
+        let step_positions: Vec<i32> = group.positions().into_iter().take(3).collect();
+        let mut position = [0.0; 3];
+        for (axis, step_position) in step_positions.iter().enumerate() {
+            position[axis] = *step_position as f64 / steps_per_mm[axis];
+        }
+
+        let mut planner = Planner::new(steps_per_mm[..step_positions.len()].to_vec());
+        let _ = planner.set_position(&step_positions);
+
+        Self {
+            group,
+            parser: Parser::new(),
+            planner,
+            position,
+            offset: [0.0; 3],
+            inches: false,
+            absolute: true,
+            feed_rate: Self::DEFAULT_FEED_RATE,
+            rapid_rate: Self::DEFAULT_RAPID_RATE,
+            acceleration: Self::DEFAULT_ACCELERATION,
+            chord_tolerance: Self::DEFAULT_CHORD_TOLERANCE,
+        }
+    }
+
+    pub fn group(&self) -> &MotionGroup<T> {
+        &self.group
+    }
+
+    pub fn group_mut(&mut self) -> &mut MotionGroup<T> {
+        &mut self.group
+    }
+
+    /// Position in work coordinates (mm)
+    pub fn position(&self) -> [f64; 3] {
+        [
+            self.position[0] - self.offset[0],
+            self.position[1] - self.offset[1],
+            self.position[2] - self.offset[2],
+        ]
+    }
+
+    /// Feed rate in mm/min
+    pub fn feed_rate(&self) -> f64 {
+        self.feed_rate
+    }
+
+    /// Acceleration in mm/s^2
+    pub fn acceleration(&self) -> f64 {
+        self.acceleration
+    }
+
+    /// Sets the speed of G0 moves in mm/min
+    pub fn set_rapid_rate(&mut self, rapid_rate: f64) -> Result<(), &'static str> {
+        if rapid_rate <= 0.0 {
+            return Err("Rapid rate must be greater than zero");
+        }
+        self.rapid_rate = rapid_rate;
+        Ok(())
+    }
+
+    /// Sets the maximum distance in mm between an arc and the chords it is cut into
+    pub fn set_chord_tolerance(&mut self, chord_tolerance: f64) -> Result<(), &'static str> {
+        if chord_tolerance <= 0.0 {
+            return Err("Chord tolerance must be greater than zero");
+        }
+        self.chord_tolerance = chord_tolerance;
+        Ok(())
+    }
+
+    /// Sets the junction deviation in mm used by the planner between consecutive segments
+    pub fn set_junction_deviation(&mut self, junction_deviation: f64) -> Result<(), &'static str> {
+        self.planner.set_junction_deviation(junction_deviation)
+    }
+
+    /// Runs every move still queued in the planner, ending at standstill
+    pub async fn flush(&mut self) -> Result<(), &'static str> {
+        self.run_planner(0).await
+    }
+
+    pub async fn execute_line(&mut self, line: &str) -> Result<(), &'static str> {
+        for command in self.parser.parse_line(line)? {
+            self.execute(command).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn execute(&mut self, command: Command) -> Result<(), &'static str> {
+        match command {
+            Command::Rapid { target, feed_rate } => {
+                self.set_feed_rate(feed_rate);
+                let target = self.resolve_target(&target)?;
+                self.move_to(target, self.rapid_rate).await
+            }
+            Command::Linear { target, feed_rate } => {
+                self.set_feed_rate(feed_rate);
+                let target = self.resolve_target(&target)?;
+                self.move_to(target, self.feed_rate).await
+            }
+            Command::Arc {
+                target,
+                center_offset,
+                direction,
+                feed_rate,
+            } => {
+                self.set_feed_rate(feed_rate);
+                let target = self.resolve_target(&target)?;
+                self.arc_to(target, center_offset, direction).await
+            }
+            Command::Dwell(duration) => {
+                self.flush().await?;
+                tokio::time::sleep(duration).await;
+                Ok(())
+            }
+            Command::Inches => {
+                self.inches = true;
+                Ok(())
+            }
+            Command::Millimeters => {
+                self.inches = false;
+                Ok(())
+            }
+            Command::Home(axes) => {
+                let mut target = self.position;
+                for (axis, position) in target.iter_mut().enumerate() {
+                    if axes.is_empty() || axes.get(axis).is_some() {
+                        *position = 0.0;
+                    }
+                }
+                self.move_to(target, self.rapid_rate).await
+            }
+            Command::Absolute => {
+                self.absolute = true;
+                Ok(())
+            }
+            Command::Relative => {
+                self.absolute = false;
+                Ok(())
+            }
+            Command::SetPosition(axes) => {
+                for axis in 0..3 {
+                    if let Some(value) = axes.get(axis) {
+                        self.offset[axis] = self.position[axis] - self.to_mm(value);
+                    }
+                }
+                Ok(())
+            }
+            Command::EnableMotors(axes) => {
+                self.flush().await?;
+                self.for_each_axis(&axes, |controller, _| controller.enable())
+            }
+            Command::DisableMotors(axes) => {
+                self.flush().await?;
+                self.for_each_axis(&axes, |controller, _| controller.disable())
+            }
+            Command::SetAcceleration(acceleration) => {
+                self.acceleration = self.to_mm(acceleration);
+                Ok(())
+            }
+            Command::SetCurrent(axes) => {
+                self.flush().await?;
+                self.for_each_axis(&axes, |controller, current| match current {
+                    Some(current) => controller.stepper_mut().set_motor_current(current as u16),
+                    None => Ok(()),
+                })
+            }
+            Command::SetMicrosteps(axes) => {
+                self.flush().await?;
+                self.for_each_axis(&axes, |controller, microsteps| match microsteps {
+                    Some(microsteps) => controller.stepper_mut().set_microsteps(microsteps as u16),
+                    None => Ok(()),
+                })
+            }
+        }
+    }
+
+    fn set_feed_rate(&mut self, feed_rate: Option<f64>) {
+        if let Some(feed_rate) = feed_rate {
+            self.feed_rate = self.to_mm(feed_rate);
+        }
+    }
+
+    fn to_mm(&self, value: f64) -> f64 {
+        match self.inches {
+            true => value * Self::MM_PER_INCH,
+            false => value,
+        }
+    }
+
+    /// Converts the axis words of a move into an absolute machine position in mm
+    fn resolve_target(&self, target: &AxisWords) -> Result<[f64; 3], &'static str> {
+        let mut resolved = self.position;
+
+        for (axis, position) in resolved.iter_mut().enumerate() {
+            if let Some(value) = target.get(axis) {
+                if self.group.axis(axis).is_none() {
+                    return Err("Move uses an axis that is not part of the motion group");
+                }
+                *position = match self.absolute {
+                    true => self.to_mm(value) + self.offset[axis],
+                    false => *position + self.to_mm(value),
+                };
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn axes(&self) -> usize {
+        self.planner.position().len()
+    }
+
+    /// Queues a straight move to `target` (machine mm) at `rate` mm/min
+    async fn move_to(&mut self, target: [f64; 3], rate: f64) -> Result<(), &'static str> {
+        let axes = self.axes();
+        self.planner
+            .push(&target[..axes], rate / 60.0, self.acceleration)?;
+        self.position = target;
+        self.run_planner(Self::LOOKAHEAD).await
+    }
+
+    /// Queues the chords of an arc (or helix when Z changes) ending at `target`
+    async fn arc_to(
+        &mut self,
+        target: [f64; 3],
+        center_offset: (f64, f64),
+        direction: Direction,
+    ) -> Result<(), &'static str> {
+        if self.axes() < 2 {
+            return Err("Arcs need at least two axes in the motion group");
+        }
+
+        let start = self.position;
+        let center = (
+            start[0] + self.to_mm(center_offset.0),
+            start[1] + self.to_mm(center_offset.1),
+        );
+        let points = arc_points(
+            (start[0], start[1]),
+            center,
+            (target[0], target[1]),
+            direction,
+            self.chord_tolerance,
+            Self::ARC_RADIUS_TOLERANCE,
+        )?;
+        let segments = points.len() as f64;
+
+        for (i, (x, y)) in points.into_iter().enumerate() {
+            let z = start[2] + (target[2] - start[2]) * (i + 1) as f64 / segments;
+            self.move_to([x, y, z], self.feed_rate).await?;
+        }
+        self.position = target;
+        Ok(())
+    }
+
+    /// Runs queued moves until at most `keep` remain in the planner
+    async fn run_planner(&mut self, keep: usize) -> Result<(), &'static str> {
+        while self.planner.len() > keep {
+            if let Some(block) = self.planner.pop() {
+                self.group.run_block(&block).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `action` on the stepper of every selected axis (all axes when none are selected) with
+    /// the value given for that axis
+    fn for_each_axis<F>(&mut self, axes: &AxisWords, mut action: F) -> Result<(), &'static str>
+    where
+        F: FnMut(&mut MotionController<T>, Option<f64>) -> Result<(), &'static str>,
+    {
+        for axis in 0..3 {
+            if !axes.is_empty() && axes.get(axis).is_none() {
+                continue;
+            }
+            match self.group.axis_mut(axis) {
+                Some(controller) => action(controller, axes.get(axis))?,
+                None if axes.get(axis).is_some() => {
+                    return Err("Command uses an axis that is not part of the motion group")
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motion_controller::MotionController;
+    use crate::stepper::MockStepper;
+
+    fn parse(line: &str) -> Vec<Command> {
+        Parser::new().parse_line(line).unwrap()
+    }
+
+    fn get_mock_interpreter(axes: usize) -> Interpreter<MockStepper> {
+        let controllers = (0..axes)
+            .map(|i| {
+                let mut stepper = MockStepper::default();
+                stepper.expect_set_steps_to_move().return_const(());
+                stepper.expect_step().returning(|| Ok(()));
+                stepper.expect_set_enabled().returning(|_| Ok(()));
+                MotionController::new(format!("axis{}", i), stepper)
+            })
+            .collect();
+        let mut interpreter = Interpreter::new(MotionGroup::new(controllers), [10.0, 10.0, 100.0]);
+        interpreter.feed_rate = 600000.0;
+        interpreter.rapid_rate = 600000.0;
+        interpreter.acceleration = 1000000.0;
+        interpreter
+    }
+
+    #[test]
+    fn parse_linear_move() {
+        assert_eq!(
+            parse("N10 G1 X10.5 Y-2 F1200 ; cut"),
+            vec![Command::Linear {
+                target: AxisWords {
+                    x: Some(10.5),
+                    y: Some(-2.0),
+                    z: None
+                },
+                feed_rate: Some(1200.0)
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_modal_motion() {
+        let mut parser = Parser::new();
+        parser.parse_line("G0 Z5").unwrap();
+
+        assert_eq!(
+            parser.parse_line("X1 (comment) Y2").unwrap(),
+            vec![Command::Rapid {
+                target: AxisWords {
+                    x: Some(1.0),
+                    y: Some(2.0),
+                    z: None
+                },
+                feed_rate: None
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_coordinates_without_motion() {
+        assert!(Parser::new().parse_line("X10").is_err());
+    }
+
+    #[test]
+    fn parse_percent_lines() {
+        assert_eq!(parse("%"), vec![]);
+        assert_eq!(parse(" % "), vec![]);
+        assert!(Parser::new().parse_line("G1 % X1").is_err());
+    }
+
+    #[test]
+    fn parse_arc() {
+        assert_eq!(
+            parse("G2 X10 Y0 I5 J0"),
+            vec![Command::Arc {
+                target: AxisWords {
+                    x: Some(10.0),
+                    y: Some(0.0),
+                    z: None
+                },
+                center_offset: (5.0, 0.0),
+                direction: Direction::CW,
+                feed_rate: None
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_modal_setup_line() {
+        assert_eq!(
+            parse("G90 G21 G17"),
+            vec![Command::Absolute, Command::Millimeters]
+        );
+    }
+
+    #[test]
+    fn parse_dwell() {
+        assert_eq!(
+            parse("G4 P250"),
+            vec![Command::Dwell(Duration::from_millis(250))]
+        );
+        assert_eq!(parse("G4 S2"), vec![Command::Dwell(Duration::from_secs(2))]);
+    }
+
+    #[test]
+    fn parse_set_position_does_not_move() {
+        assert_eq!(
+            parse("G92 X0 Y0"),
+            vec![Command::SetPosition(AxisWords {
+                x: Some(0.0),
+                y: Some(0.0),
+                z: None
+            })]
+        );
+    }
+
+    #[test]
+    fn parse_driver_settings() {
+        assert_eq!(
+            parse("M906 X600 Y800"),
+            vec![Command::SetCurrent(AxisWords {
+                x: Some(600.0),
+                y: Some(800.0),
+                z: None
+            })]
+        );
+        assert_eq!(
+            parse("M350 Z16"),
+            vec![Command::SetMicrosteps(AxisWords {
+                x: None,
+                y: None,
+                z: Some(16.0)
+            })]
+        );
+        assert_eq!(parse("M204 S1500"), vec![Command::SetAcceleration(1500.0)]);
+    }
+
+    #[test]
+    fn parse_unsupported() {
+        assert!(Parser::new().parse_line("G5 X1").is_err());
+        assert!(Parser::new().parse_line("M3 S1000").is_err());
+        assert!(Parser::new().parse_line("G2 X1 R5").is_err());
+        assert!(Parser::new().parse_line("M906 X-600").is_err());
+        assert!(Parser::new().parse_line("M906 X70000").is_err());
+        assert!(Parser::new().parse_line("M350 Y0.5").is_err());
+    }
+
+    #[tokio::test]
+    async fn interpreter_absolute_and_relative_moves() {
+        let mut interpreter = get_mock_interpreter(2);
+
+        interpreter.execute_line("G1 X2 Y1").await.unwrap();
+        interpreter.flush().await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![20, 10]);
+
+        interpreter.execute_line("G91").await.unwrap();
+        interpreter.execute_line("G1 X-0.5").await.unwrap();
+        interpreter.flush().await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![15, 10]);
+    }
+
+    #[tokio::test]
+    async fn interpreter_inches_and_offsets() {
+        let mut interpreter = get_mock_interpreter(2);
+
+        interpreter.execute_line("G92 X10").await.unwrap();
+        interpreter.execute_line("G0 X11").await.unwrap();
+        interpreter.flush().await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![10, 0]);
+        assert!((interpreter.position()[0] - 11.0).abs() < 1e-9);
+
+        interpreter.execute_line("G20 G0 X1").await.unwrap();
+        interpreter.flush().await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![154, 0]);
+    }
+
+    #[tokio::test]
+    async fn interpreter_queues_moves_until_flushed() {
+        let mut interpreter = get_mock_interpreter(2);
+
+        interpreter.execute_line("G1 X1").await.unwrap();
+        interpreter.execute_line("G1 X1 Y1").await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![0, 0]);
+
+        interpreter.flush().await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![10, 10]);
+    }
+
+    #[tokio::test]
+    async fn interpreter_enable_and_disable() {
+        let mut interpreter = get_mock_interpreter(2);
+
+        interpreter.execute_line("G1 X1").await.unwrap();
+        interpreter.flush().await.unwrap();
+        assert!(interpreter.group().axis(0).unwrap().is_enabled());
+        assert!(!interpreter.group().axis(1).unwrap().is_enabled());
+
+        interpreter.execute_line("M18 X0").await.unwrap();
+        assert!(!interpreter.group().axis(0).unwrap().is_enabled());
+
+        interpreter.execute_line("M17").await.unwrap();
+        assert!(interpreter.group().axis(1).unwrap().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn interpreter_missing_axis() {
+        let mut interpreter = get_mock_interpreter(2);
+        assert!(interpreter.execute_line("G1 Z1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn interpreter_full_circle_returns_to_start() {
+        let mut interpreter = get_mock_interpreter(2);
+
+        interpreter.execute_line("G0 X5 Y0").await.unwrap();
+        interpreter.execute_line("G2 X5 Y0 I-5 J0").await.unwrap();
+        interpreter.flush().await.unwrap();
+        assert_eq!(interpreter.group().positions(), vec![50, 0]);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::step_thread::{busy_wait, PulseOutput};
+use crate::stepper::Direction;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// GPIO chip used when a driver builder is not given one
+pub const DEFAULT_CHIP: &str = "/dev/gpiochip0";
+
+pub fn open_chip(path: &str) -> Result<Chip, &'static str> {
+    Chip::new(path).map_err(|_| "GPIO chip could not be opened")
+}
+
+/// GPIO output line requested once and held for as long as the pin is in use, so toggling it is
+/// a single ioctl instead of a line request per pulse. Clones share the same line handle, which
+/// lets a step thread pulse a line owned by a driver.
+#[derive(Clone)]
+pub struct OutputPin {
+    handle: Arc<LineHandle>,
+    pin: u32,
+    pulsed_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl OutputPin {
+    pub fn request(
+        chip: &mut Chip,
+        pin: u32,
+        initial_high: bool,
+        consumer: &str,
+    ) -> Result<Self, &'static str> {
+        let handle = chip
+            .get_line(pin)
+            .map_err(|_| "GPIO line does not exist on the chip")?
+            .request(LineRequestFlags::OUTPUT, initial_high as u8, consumer)
+            .map_err(|_| "GPIO line could not be requested as output, is it already in use?")?;
+
+        Ok(Self {
+            handle: Arc::new(handle),
+            pin,
+            pulsed_at: Arc::default(),
+        })
+    }
+
+    /// Requests `pin` as output at the level it has now, so the level left by an earlier process
+    /// is kept. Reading it does not change the direction of the line.
+    pub fn request_at_current_level(
+        chip: &mut Chip,
+        pin: u32,
+        consumer: &str,
+    ) -> Result<Self, &'static str> {
+        let level = chip
+            .get_line(pin)
+            .map_err(|_| "GPIO line does not exist on the chip")?
+            .request(LineRequestFlags::empty(), 0, consumer)
+            .and_then(|handle| handle.get_value())
+            .map_err(|_| "GPIO line could not be read, is it already in use?")?;
+        Self::request(chip, pin, level != 0, consumer)
+    }
+
+    pub fn pin(&self) -> u32 {
+        self.pin
+    }
+
+    pub fn set(&self, high: bool) -> Result<(), &'static str> {
+        self.handle
+            .set_value(high as u8)
+            .map_err(|_| "Could not set GPIO line value")
+    }
+
+    pub fn set_high(&self) -> Result<(), &'static str> {
+        self.set(true)
+    }
+
+    pub fn set_low(&self) -> Result<(), &'static str> {
+        self.set(false)
+    }
+
+    /// Drives the line high for `width` and back low
+    pub fn pulse(&self, width: Duration) -> Result<(), &'static str> {
+        self.set_high()?;
+        busy_wait(width);
+        self.set_low()?;
+        *self.pulsed_at.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+
+    /// End of the last `pulse` through this pin or any of its clones
+    pub fn pulsed_at(&self) -> Option<Instant> {
+        *self.pulsed_at.lock().unwrap()
+    }
+}
+
+/// STEP lines of several axes as the pulse output of a step thread, axis N pulses line N. The
+/// direction is expected to be set on the drivers before the steps are submitted.
+pub struct StepPins {
+    pins: Vec<OutputPin>,
+    pulse_width: Duration,
+}
+
+impl StepPins {
+    pub fn new(pins: Vec<OutputPin>, pulse_width: Duration) -> Self {
+        Self { pins, pulse_width }
+    }
+}
+
+impl PulseOutput for StepPins {
+    fn pulse(&mut self, axis: usize, _direction: Direction) -> Result<(), &'static str> {
+        self.pins
+            .get(axis)
+            .ok_or("No STEP pin for axis")?
+            .pulse(self.pulse_width)
+    }
+}
@@ -0,0 +1,564 @@
+//! Async handles to motion controllers and groups for tokio services. Each handle owns its
+//! controller on a dedicated thread, so step timing and blocking driver access (e.g. the UART
+//! replies of the TMC2209) never stall the runtime and several axes can move concurrently.
+use crate::cancel::{CancelToken, EmergencyStop, MoveOutcome};
+use crate::motion_controller::{AxisMonitor, HomingConfig, MotionController};
+use crate::motion_group::MotionGroup;
+use crate::stepper::{Direction, StepGenerator};
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+
+/// How often an idle worker calls `poll_idle` on the controller it owns
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type Job<S> = Box<dyn FnOnce(&mut S, &Handle) + Send>;
+
+/// Thread owning `S` that runs jobs one after another in the order they were sent
+struct Worker<S> {
+    jobs: mpsc::Sender<Job<S>>,
+}
+
+impl<S> Clone for Worker<S> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<S> Worker<S>
+where
+    S: Send + 'static,
+{
+    /// Starts the worker thread, it stops once every handle is dropped. Must be called from
+    /// within a tokio runtime, which the jobs use to run async methods. A job or idle check that
+    /// panics does not end the thread, the state stays as the panic left it.
+    fn spawn<I>(name: &str, mut state: S, mut idle: I) -> Result<Self, &'static str>
+    where
+        I: FnMut(&mut S) + Send + 'static,
+    {
+        let runtime =
+            Handle::try_current().map_err(|_| "Handles must be created in a tokio runtime")?;
+        let (jobs, queue) = mpsc::channel::<Job<S>>();
+
+        std::thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || loop {
+                match queue.recv_timeout(IDLE_POLL_INTERVAL) {
+                    Ok(job) => job(&mut state, &runtime),
+                    Err(RecvTimeoutError::Timeout) => {
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| idle(&mut state)));
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            })
+            .map_err(|_| "Worker thread could not be started")?;
+
+        Ok(Self { jobs })
+    }
+
+    /// Queues `job`, the returned receiver resolves with its result once it ran or with an
+    /// error if it panicked
+    fn submit<R, F>(
+        &self,
+        job: F,
+    ) -> Result<oneshot::Receiver<Result<R, &'static str>>, &'static str>
+    where
+        F: FnOnce(&mut S, &Handle) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |state, runtime| {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| job(state, runtime)))
+                    .map_err(|_| "Worker thread panicked while running the request");
+                let _ = reply.send(result);
+            }))
+            .map_err(|_| "Worker thread has stopped")?;
+        Ok(result)
+    }
+
+    /// Runs `job` on the worker thread and returns its result once it finished
+    async fn call<R, F>(&self, job: F) -> Result<R, &'static str>
+    where
+        F: FnOnce(&mut S, &Handle) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.submit(job)?
+            .await
+            .map_err(|_| "Worker thread stopped while running the request")?
+    }
+
+    /// Queues a move run with its own token and returns a handle to stop it
+    fn start_move<P, F>(&self, job: F) -> Result<MoveHandle<P>, &'static str>
+    where
+        F: FnOnce(&mut S, &Handle, &CancelToken) -> Result<MoveReport<P>, &'static str>
+            + Send
+            + 'static,
+        P: Send + 'static,
+    {
+        let token = CancelToken::new();
+        let move_token = token.clone();
+        let finished = self.submit(move |state, runtime| job(state, runtime, &move_token))?;
+        Ok(MoveHandle { token, finished })
+    }
+}
+
+/// How a move ended and where the axes are afterwards
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MoveReport<P> {
+    pub outcome: MoveOutcome,
+    pub position: P,
+}
+
+/// A queued or running move. Stopping a move that has not started yet makes it end right away
+/// once its turn comes.
+pub struct MoveHandle<P> {
+    token: CancelToken,
+    finished: oneshot::Receiver<Result<Result<MoveReport<P>, &'static str>, &'static str>>,
+}
+
+impl<P> MoveHandle<P> {
+    /// Decelerates the move to standstill
+    pub fn stop(&self) {
+        self.token.stop();
+    }
+
+    /// Ends the move right away and disables the motors
+    pub fn abort(&self) {
+        self.token.abort();
+    }
+
+    /// Token of the move, e.g. to stop it from another task while waiting here
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+
+    /// Waits for the move to end
+    pub async fn finished(self) -> Result<MoveReport<P>, &'static str> {
+        self.finished
+            .await
+            .map_err(|_| "Worker thread stopped while running the move")??
+    }
+}
+
+/// Async handle to a single axis. Cloned handles share the axis, their requests run in the order
+/// they were made and moves complete once the motor stopped.
+pub struct AxisHandle<T> {
+    name: String,
+    monitor: Arc<AxisMonitor>,
+    worker: Worker<MotionController<T>>,
+}
+
+impl<T> Clone for AxisHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            monitor: self.monitor.clone(),
+            worker: self.worker.clone(),
+        }
+    }
+}
+
+impl<T> AxisHandle<T>
+where
+    T: StepGenerator + Send + 'static,
+{
+    /// Moves `controller` to its own thread, which also applies its idle policy between requests
+    pub fn spawn(controller: MotionController<T>) -> Result<Self, &'static str> {
+        let name = controller.name().to_owned();
+        let monitor = controller.monitor();
+        // Idle failures are kept in the monitor, see `AxisMonitor::idle_error`
+        let worker = Worker::spawn(&format!("axis-{}", name), controller, |controller| {
+            let _ = controller.poll_idle();
+        })?;
+        Ok(Self {
+            name,
+            monitor,
+            worker,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Position of the axis without waiting for the running move, see `AxisMonitor`
+    pub fn monitor(&self) -> &AxisMonitor {
+        &self.monitor
+    }
+
+    /// Moves the given amount of steps and reports the position once the move ended
+    pub async fn move_steps(&self, steps: i32) -> Result<MoveReport<i32>, &'static str> {
+        self.start_move(steps)?.finished().await
+    }
+
+    /// Moves to an absolute position and reports the position once the move ended
+    pub async fn move_to(&self, position: i32) -> Result<MoveReport<i32>, &'static str> {
+        self.start_move_to(position)?.finished().await
+    }
+
+    /// Queues a move of the given amount of steps, the returned handle can stop it
+    pub fn start_move(&self, steps: i32) -> Result<MoveHandle<i32>, &'static str> {
+        self.worker.start_move(move |controller, runtime, token| {
+            let outcome = runtime.block_on(controller.move_steps_cancellable(steps, token))?;
+            Ok(MoveReport {
+                outcome,
+                position: controller.position(),
+            })
+        })
+    }
+
+    /// Queues a move to an absolute position, the returned handle can stop it
+    pub fn start_move_to(&self, position: i32) -> Result<MoveHandle<i32>, &'static str> {
+        self.worker.start_move(move |controller, runtime, token| {
+            let steps = position - controller.position();
+            let outcome = runtime.block_on(controller.move_steps_cancellable(steps, token))?;
+            Ok(MoveReport {
+                outcome,
+                position: controller.position(),
+            })
+        })
+    }
+
+    /// Queues a velocity move in steps/s that runs until it is stopped or reaches a soft limit
+    pub fn start_velocity(&self, speed: f64) -> Result<MoveHandle<i32>, &'static str> {
+        self.worker.start_move(move |controller, runtime, token| {
+            let outcome = runtime.block_on(controller.move_velocity_cancellable(speed, token))?;
+            Ok(MoveReport {
+                outcome,
+                position: controller.position(),
+            })
+        })
+    }
+
+    /// Runs the homing sequence and reports the home position, see `MotionController::home`
+    pub async fn home(&self, config: HomingConfig) -> Result<i32, &'static str> {
+        Ok(self.start_home(config)?.finished().await?.position)
+    }
+
+    /// Queues the homing sequence, the returned handle can stop it which fails the homing
+    pub fn start_home(&self, config: HomingConfig) -> Result<MoveHandle<i32>, &'static str> {
+        self.worker.start_move(move |controller, runtime, token| {
+            runtime.block_on(controller.home(&config, token))?;
+            Ok(MoveReport {
+                outcome: MoveOutcome::Completed,
+                position: controller.position(),
+            })
+        })
+    }
+
+    pub async fn position(&self) -> Result<i32, &'static str> {
+        self.worker
+            .call(|controller, _| controller.position())
+            .await
+    }
+
+    pub async fn enable(&self) -> Result<(), &'static str> {
+        self.worker
+            .call(|controller, _| controller.enable())
+            .await?
+    }
+
+    pub async fn disable(&self) -> Result<(), &'static str> {
+        self.worker
+            .call(|controller, _| controller.disable())
+            .await?
+    }
+
+    /// Runs `job` with the controller on the axis thread, for everything without its own method
+    pub async fn with<R, F>(&self, job: F) -> Result<R, &'static str>
+    where
+        F: FnOnce(&mut MotionController<T>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.worker.call(move |controller, _| job(controller)).await
+    }
+}
+
+/// Async handle to a group of axes moving in coordinated lines, arcs and helices
+pub struct GroupHandle<T> {
+    worker: Worker<MotionGroup<T>>,
+}
+
+impl<T> Clone for GroupHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            worker: self.worker.clone(),
+        }
+    }
+}
+
+impl<T> GroupHandle<T>
+where
+    T: StepGenerator + Send + 'static,
+{
+    /// Moves `group` to its own thread, which also applies the idle policies between requests
+    pub fn spawn(group: MotionGroup<T>) -> Result<Self, &'static str> {
+        // Idle failures are kept in the monitor of the failing axis
+        let worker = Worker::spawn("motion-group", group, |group| {
+            let _ = group.poll_idle();
+        })?;
+        Ok(Self { worker })
+    }
+
+    /// Moves in a straight line and reports the positions once the move ended
+    pub async fn line_to(&self, target: Vec<i32>) -> Result<MoveReport<Vec<i32>>, &'static str> {
+        self.start_line(target)?.finished().await
+    }
+
+    pub async fn arc_to(
+        &self,
+        center: (f64, f64),
+        end: (i32, i32),
+        direction: Direction,
+    ) -> Result<MoveReport<Vec<i32>>, &'static str> {
+        self.start_arc(center, end, direction)?.finished().await
+    }
+
+    pub async fn helix_to(
+        &self,
+        center: (f64, f64),
+        end: (i32, i32),
+        end_z: i32,
+        direction: Direction,
+    ) -> Result<MoveReport<Vec<i32>>, &'static str> {
+        self.start_helix(center, end, end_z, direction)?
+            .finished()
+            .await
+    }
+
+    /// Queues a straight line, the returned handle can stop it
+    pub fn start_line(&self, target: Vec<i32>) -> Result<MoveHandle<Vec<i32>>, &'static str> {
+        self.worker.start_move(move |group, runtime, token| {
+            let outcome = runtime.block_on(group.line_to_cancellable(&target, token))?;
+            Ok(MoveReport {
+                outcome,
+                position: group.positions(),
+            })
+        })
+    }
+
+    pub fn start_arc(
+        &self,
+        center: (f64, f64),
+        end: (i32, i32),
+        direction: Direction,
+    ) -> Result<MoveHandle<Vec<i32>>, &'static str> {
+        self.worker.start_move(move |group, runtime, token| {
+            let outcome =
+                runtime.block_on(group.arc_to_cancellable(center, end, direction, token))?;
+            Ok(MoveReport {
+                outcome,
+                position: group.positions(),
+            })
+        })
+    }
+
+    pub fn start_helix(
+        &self,
+        center: (f64, f64),
+        end: (i32, i32),
+        end_z: i32,
+        direction: Direction,
+    ) -> Result<MoveHandle<Vec<i32>>, &'static str> {
+        self.worker.start_move(move |group, runtime, token| {
+            let outcome = runtime
+                .block_on(group.helix_to_cancellable(center, end, end_z, direction, token))?;
+            Ok(MoveReport {
+                outcome,
+                position: group.positions(),
+            })
+        })
+    }
+
+    pub async fn positions(&self) -> Result<Vec<i32>, &'static str> {
+        self.worker.call(|group, _| group.positions()).await
+    }
+
+    /// Runs `job` with the group on its thread, for everything without its own method
+    pub async fn with<R, F>(&self, job: F) -> Result<R, &'static str>
+    where
+        F: FnOnce(&mut MotionGroup<T>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.worker.call(move |group, _| job(group)).await
+    }
+}
+
+/// Triggers `emergency_stop` and reports where each axis stopped once its move was aborted. The
+/// axes must have been given the same emergency stop before they were spawned.
+pub async fn emergency_stop<T>(
+    emergency_stop: &EmergencyStop,
+    axes: &[AxisHandle<T>],
+) -> Vec<(String, Result<i32, &'static str>)>
+where
+    T: StepGenerator + Send + 'static,
+{
+    emergency_stop.trigger();
+
+    let mut positions = Vec::with_capacity(axes.len());
+    for axis in axes {
+        positions.push((axis.name().to_owned(), axis.position().await));
+    }
+    positions
+}
+
+/// Mock driver that accepts any amount of steps, direction changes and enabling
+#[cfg(test)]
+pub(crate) fn test_stepper() -> crate::stepper::MockStepper {
+    let mut stepper = crate::stepper::MockStepper::default();
+    stepper.expect_set_steps_to_move().return_const(());
+    stepper.expect_step().returning(|| Ok(()));
+    stepper.expect_set_enabled().returning(|_| Ok(()));
+    stepper.expect_set_direction().returning(|_| Ok(()));
+    stepper
+}
+
+/// Controller over `stepper` fast enough that moves in tests end right away
+#[cfg(test)]
+pub(crate) fn test_controller<T: StepGenerator>(name: &str, stepper: T) -> MotionController<T> {
+    let mut controller = MotionController::new(name.to_owned(), stepper);
+    controller.set_max_speed(100000.0).unwrap();
+    controller.set_acceleration(10000000.0).unwrap();
+    controller
+}
+
+/// Axis over a `test_stepper`, for testing the services built on handles
+#[cfg(test)]
+pub(crate) fn test_axis(name: &str) -> AxisHandle<crate::stepper::MockStepper> {
+    AxisHandle::spawn(test_controller(name, test_stepper())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepper::MockStepper;
+    use crate::switch::Switch;
+
+    fn get_mock_controller(name: &str) -> MotionController<MockStepper> {
+        test_controller(name, test_stepper())
+    }
+
+    #[tokio::test]
+    async fn axis_moves() {
+        let axis = AxisHandle::spawn(get_mock_controller("x")).unwrap();
+        assert_eq!(axis.name(), "x");
+
+        let report = axis.move_steps(5).await.unwrap();
+        assert_eq!(report.outcome, MoveOutcome::Completed);
+        assert_eq!(report.position, 5);
+        assert_eq!(axis.move_to(-2).await.unwrap().position, -2);
+        assert_eq!(axis.position().await, Ok(-2));
+        assert_eq!(
+            axis.with(|controller| controller.is_enabled()).await,
+            Ok(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn axes_move_concurrently() {
+        let x = AxisHandle::spawn(get_mock_controller("x")).unwrap();
+        let y = AxisHandle::spawn(get_mock_controller("y")).unwrap();
+
+        let (x_report, y_report) = tokio::join!(x.move_steps(10), y.move_steps(-20));
+        assert_eq!(x_report.unwrap().position, 10);
+        assert_eq!(y_report.unwrap().position, -20);
+    }
+
+    #[tokio::test]
+    async fn group_line() {
+        let group = MotionGroup::new(vec![get_mock_controller("x"), get_mock_controller("y")]);
+        let group = GroupHandle::spawn(group).unwrap();
+
+        assert_eq!(
+            group.line_to(vec![4, -3]).await.unwrap().position,
+            vec![4, -3]
+        );
+        assert_eq!(group.positions().await, Ok(vec![4, -3]));
+    }
+
+    #[tokio::test]
+    async fn stopped_before_start() {
+        let axis = AxisHandle::spawn(get_mock_controller("x")).unwrap();
+        let first = axis.start_move(100).unwrap();
+        let second = axis.start_move(100).unwrap();
+        second.stop();
+
+        assert_eq!(first.finished().await.unwrap().position, 100);
+        let report = second.finished().await.unwrap();
+        assert_eq!(report.outcome, MoveOutcome::Stopped);
+        assert_eq!(report.position, 100);
+    }
+
+    #[tokio::test]
+    async fn emergency_stop_reports_positions() {
+        let estop = EmergencyStop::new();
+        let mut x = get_mock_controller("x");
+        x.set_emergency_stop(&estop);
+        let x = AxisHandle::spawn(x).unwrap();
+        x.move_steps(3).await.unwrap();
+
+        let positions = emergency_stop(&estop, std::slice::from_ref(&x)).await;
+        assert_eq!(positions, vec![("x".to_owned(), Ok(3))]);
+        let report = x.move_steps(5).await.unwrap();
+        assert_eq!(report.outcome, MoveOutcome::Aborted);
+        assert_eq!(report.position, 3);
+    }
+
+    #[test]
+    fn spawn_needs_runtime() {
+        assert!(AxisHandle::spawn(get_mock_controller("x")).is_err());
+    }
+
+    #[tokio::test]
+    async fn panic_keeps_worker() {
+        let axis = AxisHandle::spawn(get_mock_controller("x")).unwrap();
+        axis.move_steps(4).await.unwrap();
+
+        assert_eq!(
+            axis.with(|_| panic!("job failed")).await,
+            Err::<(), _>("Worker thread panicked while running the request")
+        );
+        assert_eq!(axis.position().await, Ok(4));
+        assert_eq!(axis.move_steps(1).await.unwrap().position, 5);
+    }
+
+    #[tokio::test]
+    async fn velocity_move_stops() {
+        let axis = AxisHandle::spawn(get_mock_controller("x")).unwrap();
+        let jog = axis.start_velocity(20000.0).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        jog.stop();
+
+        let report = jog.finished().await.unwrap();
+        assert_eq!(report.outcome, MoveOutcome::Stopped);
+        assert!(report.position > 0);
+    }
+
+    #[tokio::test]
+    async fn homing_stops() {
+        let switch = Switch::manual();
+        let mut controller = get_mock_controller("x");
+        controller.set_home_switch(&switch);
+        let axis = AxisHandle::spawn(controller).unwrap();
+        let config = HomingConfig {
+            direction: Direction::CW,
+            fast_speed: 20000.0,
+            slow_speed: 1000.0,
+            back_off: 5,
+            max_travel: 1_000_000,
+            home_position: 0,
+        };
+
+        let home = axis.start_home(config).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        home.stop();
+        assert_eq!(home.finished().await, Err("Homing was stopped"));
+    }
+}
@@ -0,0 +1,287 @@
+//! REST and WebSocket API of `stepperd` for browser dashboards, built with the `http` feature. The
+//! routes call the methods of `rpc::Service`, parameters are sent as a JSON object in the body:
+//!
+//! - `GET /axes` -> names of the axes, `GET /axes/{axis}` -> `AxisStatus`
+//! - `POST /axes/{axis}/{action}` with `action` one of `move` (`steps` or `position`), `jog`,
+//!   `home`, `stop`, `configure`, `enable`, `disable`, `set_position` and `clear_faults`
+//! - `POST /emergency-stop` triggers the emergency stop, `DELETE /emergency-stop` resets it
+//! - `GET /ws` upgrades to a WebSocket sending a `Telemetry` frame as JSON text every interval
+//! - `GET /metrics` -> the metrics of the `metrics` module for Prometheus
+use crate::connection::BusStats;
+use crate::metrics;
+use crate::rpc::{
+    axis_method, RpcError, Service, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND,
+};
+use crate::stepper::{ConfigurableDriver, DiagnosticDriver, StepGenerator};
+use crate::telemetry::{spawn_telemetry, Telemetry};
+use axum::body::Bytes;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+pub const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+struct AppState<T> {
+    service: Arc<Service<T>>,
+    telemetry: watch::Receiver<Telemetry>,
+    bus: Option<Arc<BusStats>>,
+}
+
+impl<T> Clone for AppState<T> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            telemetry: self.telemetry.clone(),
+            bus: self.bus.clone(),
+        }
+    }
+}
+
+/// Routes of the API, the metrics include the counters of `bus` when given
+pub fn router<T>(
+    service: Arc<Service<T>>,
+    telemetry: watch::Receiver<Telemetry>,
+    bus: Option<Arc<BusStats>>,
+) -> Router
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    Router::new()
+        .route("/axes", get(axes::<T>))
+        .route("/axes/{axis}", get(status::<T>))
+        .route("/axes/{axis}/{action}", post(action::<T>))
+        .route(
+            "/emergency-stop",
+            post(emergency_stop::<T>).delete(reset_emergency_stop::<T>),
+        )
+        .route("/ws", get(websocket::<T>))
+        .route("/metrics", get(metrics::<T>))
+        .with_state(AppState {
+            service,
+            telemetry,
+            bus,
+        })
+}
+
+/// Serves the API on `address` until it fails
+pub async fn serve<T>(
+    service: Arc<Service<T>>,
+    address: SocketAddr,
+    telemetry_interval: Duration,
+    bus: Option<Arc<BusStats>>,
+) -> std::io::Result<()>
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let telemetry = spawn_telemetry(service.clone(), telemetry_interval);
+    let listener = TcpListener::bind(address).await?;
+    axum::serve(listener, router(service, telemetry, bus)).await
+}
+
+fn respond(result: Result<Value, RpcError>) -> Response {
+    match result {
+        Ok(result) => Json(result).into_response(),
+        Err(error) => {
+            let status = match error.code {
+                INVALID_PARAMS | INVALID_REQUEST => StatusCode::BAD_REQUEST,
+                METHOD_NOT_FOUND => StatusCode::NOT_FOUND,
+                // The axis refused the request in its current state, e.g. a fault or a limit
+                _ => StatusCode::CONFLICT,
+            };
+            (status, Json(error)).into_response()
+        }
+    }
+}
+
+async fn axes<T>(State(state): State<AppState<T>>) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    respond(state.service.call("axes", Value::Null).await)
+}
+
+async fn status<T>(State(state): State<AppState<T>>, Path(axis): Path<String>) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let params = Value::Object(Map::from_iter([("axis".to_owned(), Value::from(axis))]));
+    respond(state.service.call("status", params).await)
+}
+
+async fn action<T>(
+    State(state): State<AppState<T>>,
+    Path((axis, action)): Path<(String, String)>,
+    body: Bytes,
+) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let mut params = match body.is_empty() {
+        true => Map::new(),
+        false => match serde_json::from_slice(&body) {
+            Ok(Value::Object(params)) => params,
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "Body must be a JSON object");
+                return respond(Err(error));
+            }
+        },
+    };
+    let Some(method) = axis_method(&action, &params) else {
+        let error = RpcError::new(METHOD_NOT_FOUND, format!("Unknown action '{}'", action));
+        return respond(Err(error));
+    };
+    params.insert("axis".to_owned(), Value::from(axis));
+    respond(state.service.call(method, Value::Object(params)).await)
+}
+
+async fn emergency_stop<T>(State(state): State<AppState<T>>) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    respond(state.service.call("emergency_stop", Value::Null).await)
+}
+
+async fn reset_emergency_stop<T>(State(state): State<AppState<T>>) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    respond(
+        state
+            .service
+            .call("reset_emergency_stop", Value::Null)
+            .await,
+    )
+}
+
+async fn metrics<T>(State(state): State<AppState<T>>) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let text = metrics::render(
+        state.bus.as_deref(),
+        &state.service,
+        &state.telemetry.borrow(),
+    );
+    ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], text).into_response()
+}
+
+async fn websocket<T>(State(state): State<AppState<T>>, upgrade: WebSocketUpgrade) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let telemetry = state.telemetry.clone();
+    upgrade.on_upgrade(move |socket| stream_telemetry(socket, telemetry))
+}
+
+async fn stream_telemetry(mut socket: WebSocket, mut telemetry: watch::Receiver<Telemetry>) {
+    loop {
+        let frame = serde_json::to_string(&*telemetry.borrow_and_update());
+        let Ok(frame) = frame else {
+            break;
+        };
+        if socket.send(Message::Text(frame.into())).await.is_err() {
+            break;
+        }
+
+        // Messages from the browser are not used, they are read to notice when it closes
+        tokio::select! {
+            changed = telemetry.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                if !matches!(message, Some(Ok(message)) if !matches!(message, Message::Close(_))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::EmergencyStop;
+    use crate::handle::{test_controller, test_stepper, AxisHandle};
+    use crate::stepper::{DriverStatus, MockStepper};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn get_mock_service() -> Arc<Service<MockStepper>> {
+        let mut stepper = test_stepper();
+        stepper.expect_status().returning(|| {
+            Ok(DriverStatus {
+                overtemperature_warning: true,
+                ..Default::default()
+            })
+        });
+        let axis = AxisHandle::spawn(test_controller("x", stepper)).unwrap();
+        Arc::new(Service::new(vec![axis], EmergencyStop::new()))
+    }
+
+    async fn request(router: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rest_routes() {
+        let service = get_mock_service();
+        let (_, telemetry) = watch::channel(Telemetry::default());
+        let router = router(service, telemetry, Some(Arc::new(BusStats::default())));
+
+        let (status, axes) = request(&router, "GET", "/axes", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(axes, serde_json::json!(["x"]));
+
+        let (status, report) = request(&router, "POST", "/axes/x/move", r#"{"steps": 30}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["position"], 30);
+        let (_, report) = request(&router, "POST", "/axes/x/move", r#"{"position": 10}"#).await;
+        assert_eq!(report["position"], 10);
+        let (_, axis) = request(&router, "GET", "/axes/x", "").await;
+        assert_eq!(axis["position"], 10);
+
+        let (status, _) = request(&router, "GET", "/axes/y", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(&router, "POST", "/axes/x/explode", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = request(&router, "POST", "/axes/x/move", "[1]").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = request(&router, "POST", "/emergency-stop", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request(&router, "DELETE", "/emergency-stop", "").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("stepper_uart_transactions_total 0\n"));
+        assert!(text.contains("stepper_axis_steps_total{axis=\"x\"} 50\n"));
+    }
+}
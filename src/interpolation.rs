@@ -0,0 +1,241 @@
+use crate::stepper::Direction;
+use std::f64::consts::PI;
+
+/// Iterator over the steps of a straight line through any number of axes (multi-axis Bresenham).
+/// Every item holds the step for each axis as -1, 0 or 1 and the axis with the most steps to make
+/// moves on every item, so all axes arrive at the same time.
+pub struct LineSteps {
+    deltas: Vec<i32>,
+    errors: Vec<i32>,
+    major: i32,
+    taken: i32,
+}
+
+impl LineSteps {
+    pub fn new(deltas: &[i32]) -> Self {
+        let major = deltas.iter().map(|delta| delta.abs()).max().unwrap_or(0);
+
+        Self {
+            deltas: deltas.to_vec(),
+            errors: vec![major / 2; deltas.len()],
+            major,
+            taken: 0,
+        }
+    }
+}
+
+impl Iterator for LineSteps {
+    type Item = Vec<i8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.taken >= self.major {
+            return None;
+        }
+        self.taken += 1;
+
+        let mut steps = vec![0; self.deltas.len()];
+        for (i, delta) in self.deltas.iter().enumerate() {
+            self.errors[i] -= delta.abs();
+            if self.errors[i] < 0 {
+                self.errors[i] += self.major;
+                steps[i] = delta.signum() as i8;
+            }
+        }
+        Some(steps)
+    }
+}
+
+/// Splits an arc in the XY plane into chords whose distance from the true arc never exceeds
+/// `chord_tolerance`. Returns every chord end point with the last one being `end`. When `start`
+/// and `end` are the same point a full circle is made. `end` may be up to `radius_tolerance`
+/// closer to or further from the center than `start`, e.g. for ends rounded to whole steps.
+pub fn arc_points(
+    start: (f64, f64),
+    center: (f64, f64),
+    end: (f64, f64),
+    direction: Direction,
+    chord_tolerance: f64,
+    radius_tolerance: f64,
+) -> Result<Vec<(f64, f64)>, &'static str> {
+    if chord_tolerance <= 0.0 {
+        return Err("Chord tolerance must be greater than zero");
+    }
+    if radius_tolerance.is_nan() || radius_tolerance < 0.0 {
+        return Err("Radius tolerance can not be negative");
+    }
+
+    let radius = (start.0 - center.0).hypot(start.1 - center.1);
+    let end_radius = (end.0 - center.0).hypot(end.1 - center.1);
+
+    if radius == 0.0 {
+        return Err("Arc start point can not be the arc center");
+    }
+    if (radius - end_radius).abs() > radius_tolerance {
+        return Err("Arc end point is not on the circle of the start point");
+    }
+
+    let start_angle = (start.1 - center.1).atan2(start.0 - center.0);
+    let end_angle = (end.1 - center.1).atan2(end.0 - center.0);
+
+    // Angle swept when travelling from start to end in the requested direction (CCW is positive)
+    let mut sweep = match direction {
+        Direction::CCW => (end_angle - start_angle).rem_euclid(2.0 * PI),
+        Direction::CW => (start_angle - end_angle).rem_euclid(2.0 * PI),
+    };
+    if sweep < f64::EPSILON {
+        sweep = 2.0 * PI;
+    }
+
+    // Largest angle a chord can cover before it strays further than the tolerance from the arc
+    let max_segment_angle = if chord_tolerance >= radius {
+        PI / 2.0
+    } else {
+        (2.0 * (1.0 - chord_tolerance / radius).acos()).min(PI / 2.0)
+    };
+    let segments = (sweep / max_segment_angle).ceil().max(1.0) as usize;
+    let step_angle = match direction {
+        Direction::CCW => sweep / segments as f64,
+        Direction::CW => -sweep / segments as f64,
+    };
+
+    let mut points: Vec<(f64, f64)> = (1..segments)
+        .map(|i| {
+            let angle = start_angle + step_angle * i as f64;
+            (
+                center.0 + radius * angle.cos(),
+                center.1 + radius * angle.sin(),
+            )
+        })
+        .collect();
+    points.push(end);
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_steps_single_axis() {
+        let steps: Vec<Vec<i8>> = LineSteps::new(&[-3]).collect();
+        assert_eq!(steps, vec![vec![-1], vec![-1], vec![-1]]);
+    }
+
+    #[test]
+    fn line_steps_reach_target() {
+        let deltas = [120, -37, 5];
+        let mut totals = [0i32; 3];
+
+        for steps in LineSteps::new(&deltas) {
+            for (total, step) in totals.iter_mut().zip(steps) {
+                *total += step as i32;
+            }
+        }
+        assert_eq!(totals, deltas);
+    }
+
+    #[test]
+    fn line_steps_no_movement() {
+        assert_eq!(LineSteps::new(&[0, 0]).count(), 0);
+    }
+
+    #[test]
+    fn arc_points_quarter_circle() {
+        let points = arc_points(
+            (100.0, 0.0),
+            (0.0, 0.0),
+            (0.0, 100.0),
+            Direction::CCW,
+            0.5,
+            0.0,
+        )
+        .unwrap();
+
+        assert_eq!(*points.last().unwrap(), (0.0, 100.0));
+        for (x, y) in points.iter() {
+            assert!((x.hypot(*y) - 100.0).abs() < 1e-9);
+            assert!(*x >= -1e-9 && *y >= -1e-9);
+        }
+    }
+
+    #[test]
+    fn arc_points_clockwise_takes_long_way() {
+        let ccw = arc_points(
+            (100.0, 0.0),
+            (0.0, 0.0),
+            (0.0, 100.0),
+            Direction::CCW,
+            0.5,
+            0.0,
+        )
+        .unwrap();
+        let cw = arc_points(
+            (100.0, 0.0),
+            (0.0, 0.0),
+            (0.0, 100.0),
+            Direction::CW,
+            0.5,
+            0.0,
+        )
+        .unwrap();
+
+        assert!(cw.len() > ccw.len() * 2);
+        assert!(cw[0].1 < 0.0);
+    }
+
+    #[test]
+    fn arc_points_full_circle() {
+        let points = arc_points(
+            (10.0, 0.0),
+            (0.0, 0.0),
+            (10.0, 0.0),
+            Direction::CW,
+            0.1,
+            0.0,
+        )
+        .unwrap();
+
+        assert!(points.len() > 4);
+        assert!(points.iter().any(|(x, _)| *x < -9.0));
+    }
+
+    #[test]
+    fn arc_points_respects_chord_tolerance() {
+        let radius = 1000.0;
+        let tolerance = 0.25;
+        let points = arc_points(
+            (radius, 0.0),
+            (0.0, 0.0),
+            (-radius, 0.0),
+            Direction::CCW,
+            tolerance,
+            0.0,
+        )
+        .unwrap();
+
+        let mut previous = (radius, 0.0);
+        for point in points {
+            let mid: (f64, f64) = ((previous.0 + point.0) / 2.0, (previous.1 + point.1) / 2.0);
+            assert!(radius - mid.0.hypot(mid.1) <= tolerance + 1e-9);
+            previous = point;
+        }
+    }
+
+    #[test]
+    fn arc_points_end_off_circle() {
+        let arc = |end, radius_tolerance| {
+            arc_points(
+                (10.0, 0.0),
+                (0.0, 0.0),
+                end,
+                Direction::CCW,
+                0.5,
+                radius_tolerance,
+            )
+        };
+        assert!(arc((0.0, 20.0), 1.0).is_err());
+        assert!(arc((0.0, 10.4), 0.5).is_ok());
+        assert!(arc((0.0, 10.4), 0.1).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+//! Mapping between Cartesian positions and motor positions for machines whose motors do not move
+//! a single Cartesian axis each. Positions are in machine units (e.g. mm), motor positions in the
+//! units of the motor (mm of belt or carriage travel, radians for rotary joints).
+use crate::cancel::{CancelToken, MoveOutcome};
+use crate::motion_group::MotionGroup;
+use crate::stepper::StepGenerator;
+use std::f64::consts::{PI, TAU};
+
+pub trait Kinematics: Send {
+    /// Amount of Cartesian axes, which is also the amount of motors
+    fn axes(&self) -> usize;
+
+    /// Motor positions reaching `position`. `current` holds the current motor positions, used to
+    /// pick the closest solution when there are several.
+    fn inverse(&self, position: &[f64], current: &[f64]) -> Result<Vec<f64>, &'static str>;
+
+    /// Cartesian position of the given motor positions
+    fn forward(&self, motors: &[f64]) -> Result<Vec<f64>, &'static str>;
+
+    /// Whether straight lines stay straight in motor space, other kinematics get their lines
+    /// split into short segments
+    fn is_linear(&self) -> bool {
+        true
+    }
+}
+
+fn check_axes(position: &[f64], axes: usize) -> Result<(), &'static str> {
+    match position.len() == axes {
+        true => Ok(()),
+        false => Err("Position does not match the amount of kinematic axes"),
+    }
+}
+
+/// CoreXY with motor A moving X + Y and motor B moving X - Y. Axes after X and Y, e.g. Z, are
+/// passed through to their own motors.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CoreXY {
+    axes: usize,
+}
+
+/// H-bot machines use the same motor mapping as CoreXY, they only differ mechanically
+pub type HBot = CoreXY;
+
+impl CoreXY {
+    pub fn new(axes: usize) -> Result<Self, &'static str> {
+        if axes < 2 {
+            return Err("CoreXY needs at least the X and Y axis");
+        }
+        Ok(Self { axes })
+    }
+}
+
+impl Kinematics for CoreXY {
+    fn axes(&self) -> usize {
+        self.axes
+    }
+
+    fn inverse(&self, position: &[f64], _current: &[f64]) -> Result<Vec<f64>, &'static str> {
+        check_axes(position, self.axes)?;
+        let mut motors = position.to_vec();
+        motors[0] = position[0] + position[1];
+        motors[1] = position[0] - position[1];
+        Ok(motors)
+    }
+
+    fn forward(&self, motors: &[f64]) -> Result<Vec<f64>, &'static str> {
+        check_axes(motors, self.axes)?;
+        let mut position = motors.to_vec();
+        position[0] = (motors[0] + motors[1]) / 2.0;
+        position[1] = (motors[0] - motors[1]) / 2.0;
+        Ok(position)
+    }
+}
+
+/// Polar machine with a rotary motor turning the bed (radians) and a linear motor moving the
+/// tool along the radius. Axes after X and Y are passed through to their own motors.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Polar {
+    axes: usize,
+}
+
+impl Polar {
+    pub fn new(axes: usize) -> Result<Self, &'static str> {
+        if axes < 2 {
+            return Err("Polar kinematics need at least the X and Y axis");
+        }
+        Ok(Self { axes })
+    }
+}
+
+impl Kinematics for Polar {
+    fn axes(&self) -> usize {
+        self.axes
+    }
+
+    /// The angle is unwrapped to the turn closest to the current angle so the bed never spins a
+    /// full turn around. The center keeps the current angle.
+    fn inverse(&self, position: &[f64], current: &[f64]) -> Result<Vec<f64>, &'static str> {
+        check_axes(position, self.axes)?;
+        let current_angle = current.first().copied().unwrap_or(0.0);
+        let radius = position[0].hypot(position[1]);
+        let angle = match radius > f64::EPSILON {
+            true => {
+                let angle = position[1].atan2(position[0]);
+                angle + ((current_angle - angle + PI) / TAU).floor() * TAU
+            }
+            false => current_angle,
+        };
+
+        let mut motors = position.to_vec();
+        motors[0] = angle;
+        motors[1] = radius;
+        Ok(motors)
+    }
+
+    fn forward(&self, motors: &[f64]) -> Result<Vec<f64>, &'static str> {
+        check_axes(motors, self.axes)?;
+        let mut position = motors.to_vec();
+        position[0] = motors[1] * motors[0].cos();
+        position[1] = motors[1] * motors[0].sin();
+        Ok(position)
+    }
+
+    fn is_linear(&self) -> bool {
+        false
+    }
+}
+
+/// Linear delta with three vertical towers at 210°, 330° and 90° around the center. The motors
+/// move the carriage heights, which are 0 while the effector sits on the bed at the center.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LinearDelta {
+    /// Horizontal distance from the center to the carriage joints minus the effector offset
+    radius: f64,
+    /// Length of the diagonal rods
+    rod_length: f64,
+}
+
+impl LinearDelta {
+    pub fn new(radius: f64, rod_length: f64) -> Result<Self, &'static str> {
+        if radius <= 0.0 || rod_length <= radius {
+            return Err("Delta rods must be longer than the delta radius");
+        }
+        Ok(Self { radius, rod_length })
+    }
+
+    fn towers(&self) -> [(f64, f64); 3] {
+        [210.0_f64, 330.0, 90.0].map(|angle| {
+            let angle = angle.to_radians();
+            (self.radius * angle.cos(), self.radius * angle.sin())
+        })
+    }
+
+    /// Carriage height above the effector while it sits at the center
+    fn center_height(&self) -> f64 {
+        (self.rod_length.powi(2) - self.radius.powi(2)).sqrt()
+    }
+}
+
+impl Kinematics for LinearDelta {
+    fn axes(&self) -> usize {
+        3
+    }
+
+    fn inverse(&self, position: &[f64], _current: &[f64]) -> Result<Vec<f64>, &'static str> {
+        check_axes(position, 3)?;
+        self.towers()
+            .iter()
+            .map(|(x, y)| {
+                let height =
+                    self.rod_length.powi(2) - (position[0] - x).powi(2) - (position[1] - y).powi(2);
+                match height >= 0.0 {
+                    true => Ok(position[2] + height.sqrt() - self.center_height()),
+                    false => Err("Position is out of reach of the delta arms"),
+                }
+            })
+            .collect()
+    }
+
+    /// Intersection of the three spheres of rod length around the carriage joints, below them
+    fn forward(&self, motors: &[f64]) -> Result<Vec<f64>, &'static str> {
+        check_axes(motors, 3)?;
+        let [p1, p2, p3] = {
+            let towers = self.towers();
+            [0, 1, 2].map(|i| [towers[i].0, towers[i].1, motors[i] + self.center_height()])
+        };
+
+        let sub = |a: [f64; 3], b: [f64; 3]| [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+        let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let scale = |a: [f64; 3], f: f64| [a[0] * f, a[1] * f, a[2] * f];
+        let normalize = |a: [f64; 3]| scale(a, 1.0 / dot(a, a).sqrt());
+
+        let d = dot(sub(p2, p1), sub(p2, p1)).sqrt();
+        let ex = normalize(sub(p2, p1));
+        let i = dot(ex, sub(p3, p1));
+        let ey = normalize(sub(sub(p3, p1), scale(ex, i)));
+        let ez = [
+            ex[1] * ey[2] - ex[2] * ey[1],
+            ex[2] * ey[0] - ex[0] * ey[2],
+            ex[0] * ey[1] - ex[1] * ey[0],
+        ];
+        let j = dot(ey, sub(p3, p1));
+
+        // All spheres have the same radius, which simplifies the usual trilateration
+        let x = d / 2.0;
+        let y = (i * i + j * j) / (2.0 * j) - i * x / j;
+        let z = self.rod_length.powi(2) - x * x - y * y;
+        if z < 0.0 {
+            return Err("Carriage heights can not be reached by the delta arms");
+        }
+        let z = match ez[2] > 0.0 {
+            true => -z.sqrt(),
+            false => z.sqrt(),
+        };
+
+        Ok((0..3)
+            .map(|axis| p1[axis] + x * ex[axis] + y * ey[axis] + z * ez[axis])
+            .collect())
+    }
+
+    fn is_linear(&self) -> bool {
+        false
+    }
+}
+
+/// Motion group moving in Cartesian coordinates through a `Kinematics`. Axis N of the group is
+/// motor N of the kinematics. Lines of non-linear kinematics are split into segments so the
+/// path between the segment ends stays close to the straight line.
+pub struct KinematicGroup<T> {
+    group: MotionGroup<T>,
+    kinematics: Box<dyn Kinematics>,
+    steps_per_unit: Vec<f64>,
+    position: Vec<f64>,
+    feed_rate: f64,
+    segment_length: f64,
+}
+
+impl<T> KinematicGroup<T>
+where
+    T: StepGenerator,
+{
+    /// Default Cartesian speed in units/s
+    pub const DEFAULT_FEED_RATE: f64 = 10.0;
+    /// Default length in units of the segments lines of non-linear kinematics are split into
+    pub const DEFAULT_SEGMENT_LENGTH: f64 = 1.0;
+
+    /// `steps_per_unit` holds the steps per motor unit of every motor. The Cartesian position is
+    /// taken from the current motor positions.
+    pub fn new(
+        group: MotionGroup<T>,
+        kinematics: Box<dyn Kinematics>,
+        steps_per_unit: Vec<f64>,
+    ) -> Result<Self, &'static str> {
+        if steps_per_unit.len() != kinematics.axes() || group.positions().len() != kinematics.axes()
+        {
+            return Err("Motion group and steps per unit must have one entry per kinematic axis");
+        }
+        if steps_per_unit.contains(&0.0) {
+            return Err("Steps per unit must not be zero");
+        }
+
+        let mut kinematic_group = Self {
+            group,
+            kinematics,
+            steps_per_unit,
+            position: Vec::new(),
+            feed_rate: Self::DEFAULT_FEED_RATE,
+            segment_length: Self::DEFAULT_SEGMENT_LENGTH,
+        };
+        kinematic_group.position = kinematic_group.forward_position()?;
+        Ok(kinematic_group)
+    }
+
+    pub fn group(&self) -> &MotionGroup<T> {
+        &self.group
+    }
+
+    /// Moving the group directly leaves the Cartesian position unknown until `sync_position`
+    pub fn group_mut(&mut self) -> &mut MotionGroup<T> {
+        &mut self.group
+    }
+
+    pub fn set_feed_rate(&mut self, feed_rate: f64) -> Result<(), &'static str> {
+        if feed_rate <= 0.0 {
+            return Err("Feed rate must be greater than zero");
+        }
+        self.feed_rate = feed_rate;
+        Ok(())
+    }
+
+    pub fn feed_rate(&self) -> f64 {
+        self.feed_rate
+    }
+
+    pub fn set_segment_length(&mut self, segment_length: f64) -> Result<(), &'static str> {
+        if segment_length <= 0.0 {
+            return Err("Segment length must be greater than zero");
+        }
+        self.segment_length = segment_length;
+        Ok(())
+    }
+
+    pub fn segment_length(&self) -> f64 {
+        self.segment_length
+    }
+
+    /// Cartesian position of the last move
+    pub fn position(&self) -> &[f64] {
+        &self.position
+    }
+
+    /// Takes the Cartesian position from the motor positions again, e.g. after homing the motors
+    pub fn sync_position(&mut self) -> Result<(), &'static str> {
+        self.position = self.forward_position()?;
+        Ok(())
+    }
+
+    /// Moves in a straight Cartesian line to `target` at the feed rate
+    pub async fn move_to(&mut self, target: &[f64]) -> Result<(), &'static str> {
+        match self
+            .move_to_cancellable(target, &CancelToken::new())
+            .await?
+        {
+            MoveOutcome::Completed => Ok(()),
+            _ => Err("Move ended before reaching its target"),
+        }
+    }
+
+    /// Same as `move_to` while `token` or the emergency stop can end the move early
+    pub async fn move_to_cancellable(
+        &mut self,
+        target: &[f64],
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let path = self.plan_line(target)?;
+        let outcome = self.group.path_to_cancellable(&path, token).await?;
+        match outcome {
+            MoveOutcome::Completed => self.position = target.to_vec(),
+            _ => self.sync_position()?,
+        }
+        Ok(outcome)
+    }
+
+    /// Motor step targets of the segments of a line to `target`, each with its speed in steps/s
+    /// along the motor path matching the feed rate
+    fn plan_line(&self, target: &[f64]) -> Result<Vec<(Vec<i32>, f64)>, &'static str> {
+        check_axes(target, self.kinematics.axes())?;
+        let length = target
+            .iter()
+            .zip(self.position.iter())
+            .map(|(target, position)| (target - position).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        let segments = match self.kinematics.is_linear() {
+            true => 1,
+            false => ((length / self.segment_length).ceil() as usize).max(1),
+        };
+
+        let mut motors: Vec<f64> = self.motor_position();
+        let mut steps = self.group.positions();
+        let mut path = Vec::with_capacity(segments);
+        for segment in 1..=segments {
+            let fraction = segment as f64 / segments as f64;
+            let point: Vec<f64> = self
+                .position
+                .iter()
+                .zip(target.iter())
+                .map(|(start, end)| start + (end - start) * fraction)
+                .collect();
+            motors = self.kinematics.inverse(&point, &motors)?;
+            let next: Vec<i32> = motors
+                .iter()
+                .zip(self.steps_per_unit.iter())
+                .map(|(motor, steps_per_unit)| (motor * steps_per_unit).round() as i32)
+                .collect();
+
+            let step_distance = next
+                .iter()
+                .zip(steps.iter())
+                .map(|(next, steps)| ((next - steps) as f64).powi(2))
+                .sum::<f64>()
+                .sqrt();
+            if step_distance > 0.0 {
+                let speed = self.feed_rate * step_distance * segments as f64 / length;
+                path.push((next.clone(), speed));
+            }
+            steps = next;
+        }
+        Ok(path)
+    }
+
+    fn motor_position(&self) -> Vec<f64> {
+        self.group
+            .positions()
+            .iter()
+            .zip(self.steps_per_unit.iter())
+            .map(|(steps, steps_per_unit)| *steps as f64 / steps_per_unit)
+            .collect()
+    }
+
+    fn forward_position(&self) -> Result<Vec<f64>, &'static str> {
+        self.kinematics.forward(&self.motor_position())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motion_controller::MotionController;
+    use crate::stepper::MockStepper;
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn core_xy() {
+        let kinematics = CoreXY::new(3).unwrap();
+        let motors = kinematics.inverse(&[3.0, 1.0, 2.0], &[]).unwrap();
+        assert_eq!(motors, vec![4.0, 2.0, 2.0]);
+        assert_eq!(kinematics.forward(&motors).unwrap(), vec![3.0, 1.0, 2.0]);
+        assert!(kinematics.inverse(&[1.0, 2.0], &[]).is_err());
+    }
+
+    #[test]
+    fn polar_unwraps_angle() {
+        let kinematics = Polar::new(2).unwrap();
+        let motors = kinematics.inverse(&[0.0, 2.0], &[0.0, 0.0]).unwrap();
+        assert_close(&motors, &[PI / 2.0, 2.0]);
+
+        // Crossing the negative X axis continues the angle instead of jumping back a turn
+        let motors = kinematics.inverse(&[-1.0, -0.001], &[3.0, 1.0]).unwrap();
+        assert!(motors[0] > PI);
+        assert_close(&kinematics.forward(&motors).unwrap(), &[-1.0, -0.001]);
+
+        let motors = kinematics.inverse(&[0.0, 0.0], &[1.0, 5.0]).unwrap();
+        assert_eq!(motors, vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let kinematics = LinearDelta::new(100.0, 250.0).unwrap();
+        assert_close(
+            &kinematics.inverse(&[0.0, 0.0, 0.0], &[]).unwrap(),
+            &[0.0; 3],
+        );
+
+        let position = [20.0, -35.0, 12.5];
+        let motors = kinematics.inverse(&position, &[]).unwrap();
+        assert!(motors[0] != motors[1]);
+        assert_close(&kinematics.forward(&motors).unwrap(), &position);
+
+        assert!(kinematics.inverse(&[400.0, 0.0, 0.0], &[]).is_err());
+        assert!(LinearDelta::new(100.0, 50.0).is_err());
+    }
+
+    fn get_mock_controller() -> MotionController<MockStepper> {
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(|| Ok(()));
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        stepper.expect_set_direction().returning(|_| Ok(()));
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        controller
+    }
+
+    #[tokio::test]
+    async fn polar_line() {
+        let mut group = MotionGroup::new(vec![get_mock_controller(), get_mock_controller()]);
+        group.set_speed(100000.0).unwrap();
+        group.set_acceleration(10000000.0).unwrap();
+        let mut group =
+            KinematicGroup::new(group, Box::new(Polar::new(2).unwrap()), vec![1000.0, 10.0])
+                .unwrap();
+        group.set_feed_rate(1000.0).unwrap();
+
+        group.move_to(&[10.0, 0.0]).await.unwrap();
+        assert_eq!(group.group().positions(), vec![0, 100]);
+        group.move_to(&[0.0, 10.0]).await.unwrap();
+        assert_eq!(group.group().positions(), vec![1571, 100]);
+        assert_eq!(group.position(), &[0.0, 10.0]);
+    }
+}
@@ -1,4 +1,23 @@
+pub mod cancel;
 pub mod connection;
+pub mod console;
 pub mod driver;
+pub mod encoder;
+pub mod gcode;
+pub mod gpio;
+pub mod handle;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod interpolation;
+pub mod kinematics;
+pub mod metrics;
 pub mod stepper;
 pub mod motion_controller;
+pub mod motion_group;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod planner;
+pub mod rpc;
+pub mod step_thread;
+pub mod switch;
+pub mod telemetry;
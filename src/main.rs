@@ -1,62 +1,405 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use stepper_rs::cancel::CancelToken;
 use stepper_rs::connection::Connection;
-use stepper_rs::driver::tmc2209::Tmc2209;
-use stepper_rs::driver::tmc2209::{ChopConfOption, GConfOption, MicrostepRes, Motor};
-use stepper_rs::motion_controller::MotionController;
-use stepper_rs::stepper::Direction;
-use stepper_rs::stepper::Stepper;
+use stepper_rs::console::{self, ConsoleCommand};
+use stepper_rs::driver::tmc2209::{GConfOption, Tmc2209};
+use stepper_rs::gpio::DEFAULT_CHIP;
+use stepper_rs::motion_controller::{HomingConfig, MotionController};
+use stepper_rs::stepper::{ConfigurableDriver, DiagnosticDriver, Direction, StepGenerator};
+use stepper_rs::switch::{ActiveLevel, Switch, SwitchConfig};
+
+/// Command line tool for TMC2209 drivers on a UART and GPIO pins. Options left out are taken
+/// from the config file, a TOML file with the same keys as the long options.
+#[derive(Parser)]
+#[command(name = "stepper", version)]
+struct Cli {
+    /// TOML file with defaults for the options, e.g. `step-pin = 13`
+    #[arg(long, short, global = true)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    options: Options,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args, Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Options {
+    /// Serial port of the drivers [default: /dev/ttyS0]
+    #[arg(long, global = true)]
+    port: Option<String>,
+    /// UART baud rate [default: 9600]
+    #[arg(long, global = true)]
+    baud: Option<u32>,
+    /// Node address 0-3 set with the MS1 and MS2 pins [default: 0]
+    #[arg(long, global = true)]
+    address: Option<u8>,
+    /// [default: /dev/gpiochip0]
+    #[arg(long, global = true)]
+    gpio_chip: Option<String>,
+    #[arg(long, global = true)]
+    step_pin: Option<u32>,
+    #[arg(long, global = true)]
+    dir_pin: Option<u32>,
+    #[arg(long, global = true)]
+    en_pin: Option<u32>,
+    /// GPIO line of the home switch
+    #[arg(long, global = true)]
+    home_pin: Option<u32>,
+    /// Level of the home switch line while it is triggered [default: high]
+    #[arg(long, global = true, value_enum)]
+    home_level: Option<Level>,
+    /// Max speed of moves in steps/s
+    #[arg(long, global = true)]
+    max_speed: Option<f64>,
+    /// Acceleration of moves in steps/s^2
+    #[arg(long, global = true)]
+    acceleration: Option<f64>,
+}
+
+impl Options {
+    /// Options given on the command line win over the ones from the config file
+    fn or(self, config: Options) -> Options {
+        Options {
+            port: self.port.or(config.port),
+            baud: self.baud.or(config.baud),
+            address: self.address.or(config.address),
+            gpio_chip: self.gpio_chip.or(config.gpio_chip),
+            step_pin: self.step_pin.or(config.step_pin),
+            dir_pin: self.dir_pin.or(config.dir_pin),
+            en_pin: self.en_pin.or(config.en_pin),
+            home_pin: self.home_pin.or(config.home_pin),
+            home_level: self.home_level.or(config.home_level),
+            max_speed: self.max_speed.or(config.max_speed),
+            acceleration: self.acceleration.or(config.acceleration),
+        }
+    }
+
+    fn load(path: &Path) -> Result<Options, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        toml::from_str(&text).map_err(|err| format!("Invalid config {}: {}", path.display(), err))
+    }
+
+    fn port(&self) -> &str {
+        self.port.as_deref().unwrap_or(Connection::UART_PORT)
+    }
+
+    fn gpio_chip(&self) -> &str {
+        self.gpio_chip.as_deref().unwrap_or(DEFAULT_CHIP)
+    }
+
+    fn connection(&self) -> Result<Connection, &'static str> {
+        Connection::open_with_baud_rate(
+            self.port(),
+            self.gpio_chip(),
+            self.baud.unwrap_or(Connection::UART_BAUDRATE),
+        )
+    }
+
+    fn driver(&self) -> Result<Tmc2209, String> {
+        let missing =
+            |name: &str| format!("--{} is needed, on the command line or in the config", name);
+        Tmc2209::builder()
+            .step_pin(self.step_pin.ok_or_else(|| missing("step-pin"))?)
+            .dir_pin(self.dir_pin.ok_or_else(|| missing("dir-pin"))?)
+            .en_pin(self.en_pin.ok_or_else(|| missing("en-pin"))?)
+            .node_address(self.address.unwrap_or(0))
+            .keep_en_level(true)
+            .connection(self.connection()?)
+            .build()
+            .map_err(String::from)
+    }
+
+    fn controller(&self) -> Result<MotionController<Tmc2209>, String> {
+        let mut controller = MotionController::new("stepper".to_owned(), self.driver()?);
+        if let Some(max_speed) = self.max_speed {
+            controller.set_max_speed(max_speed)?;
+        }
+        if let Some(acceleration) = self.acceleration {
+            controller.set_acceleration(acceleration)?;
+        }
+        Ok(controller)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the node addresses answering on the UART
+    Scan,
+    /// Shows the status flags of the driver
+    Status,
+    /// Prints the raw values of the readable registers
+    Dump,
+    /// Changes a driver setting
+    Set {
+        #[command(subcommand)]
+        setting: Setting,
+    },
+    /// Moves the motor, Ctrl-C decelerates it to a stop
+    Move(MoveArgs),
+    /// Finds the home switch, see --home-pin
+    Home(HomeArgs),
+    /// Turns the motor outputs on. The EN line keeps its level after the tool exits on most GPIO
+    /// chips and the other commands request it at that level, so the motor stays enabled.
+    Enable,
+    /// Turns the motor outputs off
+    Disable,
+    /// Interactive console with line editing and history, type help for the commands
+    Repl,
+}
+
+#[derive(Subcommand)]
+enum Setting {
+    /// RMS run current in mA
+    Current { milliamps: u16 },
+    /// Microsteps per full step, a power of two up to 256
+    Microsteps { microsteps: u16 },
+    /// Chopper mode
+    Mode {
+        #[arg(value_enum)]
+        mode: ChopperMode,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChopperMode {
+    /// Quiet voltage PWM mode
+    StealthChop,
+    /// Classic current chopper, more torque at higher speeds
+    SpreadCycle,
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Level {
+    High,
+    Low,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Rotation {
+    Cw,
+    Ccw,
+}
+
+impl From<Rotation> for Direction {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Cw => Direction::CW,
+            Rotation::Ccw => Direction::CCW,
+        }
+    }
+}
+
+#[derive(Args)]
+struct MoveArgs {
+    /// Steps to move, negative values turn the other way
+    #[arg(long, allow_negative_numbers = true, required_unless_present = "to")]
+    steps: Option<i32>,
+    /// Absolute target position in steps
+    #[arg(long, allow_negative_numbers = true, conflicts_with = "steps")]
+    to: Option<i32>,
+    /// Position the motor is at for --to, positions are not kept between runs
+    #[arg(long, allow_negative_numbers = true, default_value_t = 0)]
+    from: i32,
+    /// Max speed in steps/s, overrides --max-speed
+    #[arg(long)]
+    speed: Option<f64>,
+    /// Acceleration in steps/s^2, overrides --acceleration
+    #[arg(long)]
+    accel: Option<f64>,
+}
+
+#[derive(Args)]
+struct HomeArgs {
+    /// Direction of travel towards the switch
+    #[arg(long, value_enum, default_value = "ccw")]
+    direction: Rotation,
+    /// Speed in steps/s while searching the switch
+    #[arg(long, default_value_t = 400.0)]
+    fast_speed: f64,
+    /// Speed in steps/s of the second, precise approach
+    #[arg(long, default_value_t = 50.0)]
+    slow_speed: f64,
+    /// Steps to back off from the switch before the slow approach
+    #[arg(long, default_value_t = 50)]
+    back_off: i32,
+    /// Longest distance in steps searched for the switch
+    #[arg(long, default_value_t = 100_000)]
+    max_travel: i32,
+}
+
+/// Token stopped by Ctrl-C, so a running move decelerates instead of being cut off
+fn stop_on_ctrl_c() -> CancelToken {
+    let token = CancelToken::new();
+    let stop = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Stopping");
+            stop.stop();
+        }
+    });
+    token
+}
+
+/// Console history, kept in the home directory between sessions
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".stepper_history"))
+}
+
+async fn repl(mut controller: MotionController<Tmc2209>) -> Result<(), String> {
+    let mut editor =
+        DefaultEditor::new().map_err(|err| format!("Could not start the console: {}", err))?;
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+    println!("Type help for the commands");
+
+    loop {
+        let line = match editor.readline("stepper> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(format!("Could not read the console: {}", err)),
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        let command = match ConsoleCommand::parse(&line) {
+            Ok(Some(ConsoleCommand::Quit)) => break,
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("{}", err);
+                continue;
+            }
+        };
+
+        // Only listens for Ctrl-C while the command runs, at the prompt it clears the line
+        let token = CancelToken::new();
+        let stop = token.clone();
+        let ctrl_c = tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("Stopping");
+                stop.stop();
+            }
+        });
+        match console::execute(&mut controller, command, &token).await {
+            Ok(text) => println!("{}", text),
+            Err(err) => println!("Error: {}", err),
+        }
+        ctrl_c.abort();
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+async fn run(command: Command, options: Options) -> Result<(), String> {
+    match command {
+        Command::Scan => {
+            let found = Tmc2209::scan(&mut options.connection()?);
+            if found.is_empty() {
+                println!("No driver answered on {}", options.port());
+            }
+            for (address, version) in found {
+                println!("Node address {}: version 0x{:02X}", address, version);
+            }
+        }
+        Command::Status => {
+            let status = options.driver()?.status()?;
+            println!("{:#?}", status);
+            for fault in status.faults() {
+                println!("Fault: {}", fault);
+            }
+        }
+        Command::Dump => {
+            for (name, value) in options.driver()?.registers()? {
+                println!("{:<10} 0x{:08X}", name, value);
+            }
+        }
+        Command::Set { setting } => {
+            let mut driver = options.driver()?;
+            match setting {
+                Setting::Current { milliamps } => driver.set_motor_current(milliamps)?,
+                Setting::Microsteps { microsteps } => driver.set_microsteps(microsteps)?,
+                Setting::Mode {
+                    mode: ChopperMode::StealthChop,
+                } => driver.disable_gconf_option(GConfOption::SpreadCycle)?,
+                Setting::Mode {
+                    mode: ChopperMode::SpreadCycle,
+                } => driver.enable_gconf_option(GConfOption::SpreadCycle)?,
+            }
+        }
+        Command::Move(args) => {
+            let mut controller = options.controller()?;
+            if let Some(speed) = args.speed {
+                controller.set_max_speed(speed)?;
+            }
+            if let Some(accel) = args.accel {
+                controller.set_acceleration(accel)?;
+            }
+            controller.set_position(args.from)?;
+            let steps = match (args.steps, args.to) {
+                (Some(steps), _) => steps,
+                (None, Some(to)) => to - args.from,
+                (None, None) => unreachable!("clap requires --steps or --to"),
+            };
+
+            let outcome = controller
+                .move_steps_cancellable(steps, &stop_on_ctrl_c())
+                .await?;
+            println!("{:?} at position {}", outcome, controller.position());
+        }
+        Command::Home(args) => {
+            let pin = options
+                .home_pin
+                .ok_or("Homing needs --home-pin, on the command line or in the config")?;
+            let level = match options.home_level.unwrap_or(Level::High) {
+                Level::High => ActiveLevel::High,
+                Level::Low => ActiveLevel::Low,
+            };
+            let switch = Switch::open(options.gpio_chip(), SwitchConfig::new(pin, level))?;
+            let mut controller = options.controller()?;
+            controller.set_home_switch(&switch);
+
+            let config = HomingConfig {
+                direction: args.direction.into(),
+                fast_speed: args.fast_speed,
+                slow_speed: args.slow_speed,
+                back_off: args.back_off,
+                max_travel: args.max_travel,
+                home_position: 0,
+            };
+            controller.home(&config, &stop_on_ctrl_c()).await?;
+            println!("Homed at position {}", controller.position());
+        }
+        Command::Enable => options.driver()?.set_enabled(true)?,
+        Command::Disable => options.driver()?.set_enabled(false)?,
+        Command::Repl => repl(options.controller()?).await?,
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    println!("Running main...");
-    let connection = Connection::new();
-    //let connection2 = Connection::new();
-    let mut tmc = Tmc2209::new((13, 19, 26), connection); // .build(); // step, dir, en
-    let tmc2 = Tmc2209::new((13, 19, 26), connection2); // .build(); // step, dir, en
-                                                        //
-                                                        //println!("Set dir");
-                                                        //tmc.set_direction(Direction::CCW);
-                                                        //println!("Enable Vsense");
-                                                        //tmc.enable_chopconf_option(ChopConfOption::Vsense);
-                                                        //println!("Set current");
-                                                        //tmc.set_current(300);
-                                                        //println!("Enable Iscale");
-                                                        //tmc.enable_gconf_option(GConfOption::IScaleAnalogue);
-                                                        //println!("Enable Intpol");
-                                                        //tmc.enable_chopconf_option(ChopConfOption::Intpol);
-                                                        //println!("Disable Spreadcycle");
-                                                        //tmc.disable_gconf_option(GConfOption::SpreadCycle);
-                                                        //println!("Microstep resolution");
-                                                        //tmc.set_microstepping_resolution(MicrostepRes::Two);
-                                                        //println!("Disable InternalRSense");
-                                                        //tmc.disable_gconf_option(GConfOption::InternalRSense);
-
-    ////// Read details
-    //println!("Read IOIN");
-    //tmc.read_IOIN();
-    //tmc.read_CHOPCONF();
-    //tmc.read_DRVSTATUS();
-    //tmc.read_GCONF();
-
-    ////tmc.set_acceleration(2000);
-    ////tmc.set_max_speed(500);
-    //tmc.set_motor_enabled(Motor::Enabled);
-
-    //tmc.move_to_position(200);
-    //tmc.move_steps(50);
-    //tmc.move_steps(-50);
-
-    // New way
-
-    // Motion controller so we can controll the activation of which steper we're using inbetween
-    // steps and swithc where needed
-    let mut motion_controller = MotionController::new("stepper1".to_owned(), tmc);
-    //let mut motion_controller2 = MotionController::new(tmc2);
-
-    motion_controller.move_steps(50).await;
-    motion_controller.move_steps(-50).await;
-
-    //motion_controller2.move_steps(50);
-    //motion_controller2.move_steps(-50);
-
-    println!("Complete!");
+    let cli = Cli::parse();
+    let options = match &cli.config {
+        Some(path) => Options::load(path).map(|config| cli.options.or(config)),
+        None => Ok(cli.options),
+    };
+
+    if let Err(err) = match options {
+        Ok(options) => run(cli.command, options).await,
+        Err(err) => Err(err),
+    } {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,259 @@
+//! Metrics of the bus, the axes and their drivers in the Prometheus text format, served on
+//! `GET /metrics` by the `http` feature
+use crate::connection::BusStats;
+use crate::rpc::Service;
+use crate::step_thread::Histogram;
+use crate::stepper::{ConfigurableDriver, DiagnosticDriver, DriverStatus, StepGenerator};
+use crate::telemetry::Telemetry;
+use std::fmt::{Display, Write};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Name, help and value of the gauges of a driver status
+fn driver_gauges(status: &DriverStatus) -> [(&'static str, &'static str, u16); 10] {
+    [
+        ("standstill", "Motor stands still", status.standstill.into()),
+        (
+            "stealth",
+            "Chopper runs in stealthChop",
+            status.stealth.into(),
+        ),
+        (
+            "current_scale",
+            "Actual current scale (CS_ACTUAL) 0-31",
+            status.current_scale.into(),
+        ),
+        (
+            "stalled",
+            "StallGuard detected a stall",
+            status.stalled.into(),
+        ),
+        (
+            "overtemperature_warning",
+            "Temperature above the prewarning threshold",
+            status.overtemperature_warning.into(),
+        ),
+        (
+            "overtemperature",
+            "Overtemperature shutdown",
+            status.overtemperature.into(),
+        ),
+        (
+            "short_to_ground",
+            "Short to ground on a coil",
+            status.short_to_ground.into(),
+        ),
+        (
+            "short_to_supply",
+            "Short to supply on a coil",
+            status.short_to_supply.into(),
+        ),
+        ("open_load", "Open load on a coil", status.open_load.into()),
+        (
+            "temperature_threshold_celsius",
+            "Highest temperature threshold exceeded, 0 below all of them",
+            status.temperature_threshold,
+        ),
+    ]
+}
+
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    fn histogram(&mut self, name: &str, axis: &str, histogram: &Histogram) {
+        let mut count = 0;
+        for (bound, bucket) in Histogram::BOUNDS.iter().zip(histogram.counts()) {
+            count += bucket;
+            // The buckets exclude their bound, close enough to the `le` of Prometheus
+            let le = (*bound as f64 / 1e6).to_string();
+            self.sample(
+                &format!("{}_bucket", name),
+                &[("axis", axis), ("le", &le)],
+                count,
+            );
+        }
+        let samples = histogram.samples();
+        let buckets = &[("axis", axis), ("le", "+Inf")];
+        self.sample(&format!("{}_bucket", name), buckets, samples);
+        let sum = histogram.total().as_secs_f64();
+        self.sample(&format!("{}_sum", name), &[("axis", axis)], sum);
+        self.sample(&format!("{}_count", name), &[("axis", axis)], samples);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the counters of `bus`, the step counts and jitter of the axes of `service` and the
+/// driver status of the last `telemetry` frame. Axes whose driver status was not read yet have
+/// no driver metrics.
+pub fn render<T>(bus: Option<&BusStats>, service: &Service<T>, telemetry: &Telemetry) -> String
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let mut out = Exposition {
+        text: String::new(),
+    };
+
+    if let Some(bus) = bus {
+        let counters = [
+            (
+                "transactions",
+                "Requests sent on the UART, retries included",
+                bus.transactions(),
+            ),
+            ("crc_errors", "Replies with a wrong CRC", bus.crc_errors()),
+            (
+                "retries",
+                "Read requests sent again after no valid reply",
+                bus.retries(),
+            ),
+            (
+                "write_check_failures",
+                "Writes the driver did not count in IFCNT",
+                bus.write_check_failures(),
+            ),
+        ];
+        for (name, help, value) in counters {
+            let name = format!("stepper_uart_{}_total", name);
+            out.family(&name, "counter", help);
+            out.sample(&name, &[], value);
+        }
+    }
+
+    out.family(
+        "stepper_emergency_stop",
+        "gauge",
+        "Emergency stop is triggered",
+    );
+    out.sample(
+        "stepper_emergency_stop",
+        &[],
+        telemetry.emergency_stop as u8,
+    );
+
+    let axes = service.axes();
+    out.family(
+        "stepper_axis_position_steps",
+        "gauge",
+        "Position of the axis",
+    );
+    for axis in axes {
+        let position = axis.monitor().position();
+        out.sample(
+            "stepper_axis_position_steps",
+            &[("axis", axis.name())],
+            position,
+        );
+    }
+    out.family("stepper_axis_moving", "gauge", "Axis is moving");
+    for axis in axes {
+        let moving = axis.monitor().is_moving() as u8;
+        out.sample("stepper_axis_moving", &[("axis", axis.name())], moving);
+    }
+    out.family(
+        "stepper_axis_steps_total",
+        "counter",
+        "Steps emitted in either direction",
+    );
+    for axis in axes {
+        let steps = axis.monitor().steps();
+        out.sample("stepper_axis_steps_total", &[("axis", axis.name())], steps);
+    }
+    out.family(
+        "stepper_axis_step_jitter_seconds",
+        "histogram",
+        "Difference between the scheduled and the actual time between consecutive steps",
+    );
+    for axis in axes {
+        let jitter = axis.monitor().jitter();
+        out.histogram("stepper_axis_step_jitter_seconds", axis.name(), &jitter);
+    }
+
+    let drivers: Vec<(&str, _)> = telemetry
+        .axes
+        .iter()
+        .filter_map(|axis| Some((axis.name.as_str(), driver_gauges(axis.driver.as_ref()?))))
+        .collect();
+    for (index, (name, help, _)) in driver_gauges(&DriverStatus::default()).iter().enumerate() {
+        let name = format!("stepper_driver_{}", name);
+        out.family(&name, "gauge", help);
+        for (axis, gauges) in &drivers {
+            out.sample(&name, &[("axis", axis)], gauges[index].2);
+        }
+    }
+    out.text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::EmergencyStop;
+    use crate::handle::test_axis;
+    use crate::telemetry::AxisTelemetry;
+
+    #[tokio::test]
+    async fn exposition() {
+        let service = Service::new(vec![test_axis("x")], EmergencyStop::new());
+        service
+            .call("move", serde_json::json!({"axis": "x", "steps": -20}))
+            .await
+            .unwrap();
+
+        let telemetry = Telemetry {
+            emergency_stop: false,
+            axes: vec![AxisTelemetry {
+                name: "x".to_owned(),
+                position: -20,
+                velocity: 0.0,
+                moving: false,
+                driver: Some(DriverStatus {
+                    current_scale: 16,
+                    temperature_threshold: 120,
+                    ..Default::default()
+                }),
+                driver_stale: false,
+                faults: Vec::new(),
+                warnings: Vec::new(),
+            }],
+        };
+        let bus = BusStats::default();
+        bus.record_write_check_failure();
+        let text = render(Some(&bus), &service, &telemetry);
+
+        assert!(text.contains("# TYPE stepper_uart_write_check_failures_total counter\n"));
+        assert!(text.contains("stepper_uart_write_check_failures_total 1\n"));
+        assert!(text.contains("stepper_axis_position_steps{axis=\"x\"} -20\n"));
+        assert!(text.contains("stepper_axis_steps_total{axis=\"x\"} 20\n"));
+        assert!(text.contains("stepper_axis_step_jitter_seconds_count{axis=\"x\"} 19\n"));
+        assert!(
+            text.contains("stepper_axis_step_jitter_seconds_bucket{axis=\"x\",le=\"+Inf\"} 19\n")
+        );
+        assert!(text.contains("stepper_driver_current_scale{axis=\"x\"} 16\n"));
+        assert!(text.contains("stepper_driver_temperature_threshold_celsius{axis=\"x\"} 120\n"));
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
@@ -1,49 +1,1692 @@
-use crate::driver::tmc2209::Tmc2209;
-use crate::stepper::Stepper;
+use crate::cancel::{CancelToken, EmergencyStop, MoveOutcome, StopMode};
+use crate::encoder::{ClosedLoopConfig, Encoder, EncoderConfig, FollowingErrorAction};
+use crate::planner::{Block, Planner};
+use crate::step_thread::{
+    wait_until_async, Cancellation, Histogram, RealtimeConfig, StepEvent, StepThread,
+};
+use crate::stepper::{
+    ConfigurableDriver, DiagnosticDriver, Direction, DriverStatus, StepGenerator,
+};
+use crate::switch::Switch;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What happens to the motor once it stops moving
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IdlePolicy {
+    /// Motor stays enabled and keeps its position
+    Keep,
+    /// Driver lowers the current to the hold current after standing still for the delay
+    HoldCurrent(Duration),
+    /// Motor is disabled by `poll_idle` after standing still for the delay, the position may be
+    /// lost while it is disabled
+    Disable(Duration),
+}
+
+/// What happens to a move whose target lies outside the soft limits
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LimitMode {
+    /// The move fails before the motor starts
+    Reject,
+    /// The move ends at the limit instead
+    Clip,
+}
+
+/// Range in steps an axis may travel in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SoftLimits {
+    pub min: i32,
+    pub max: i32,
+    pub mode: LimitMode,
+}
+
+impl SoftLimits {
+    pub fn new(min: i32, max: i32, mode: LimitMode) -> Result<Self, &'static str> {
+        if min > max {
+            return Err("Lower soft limit must not be above the upper one");
+        }
+        Ok(Self { min, max, mode })
+    }
+
+    pub fn contains(&self, position: i32) -> bool {
+        (self.min..=self.max).contains(&position)
+    }
+}
+
+/// Homing sequence: seek the switch fast, back off and seek it again slowly for a repeatable
+/// position. Speeds are in steps/s, distances in steps.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HomingConfig {
+    /// Direction of travel towards the switch
+    pub direction: Direction,
+    pub fast_speed: f64,
+    pub slow_speed: f64,
+    /// Steps moved off the switch between both seeks
+    pub back_off: i32,
+    /// Longest distance searched for the switch during the fast seek
+    pub max_travel: i32,
+    /// Position set once the switch is found
+    pub home_position: i32,
+}
+
+/// Encoder of an axis with the position in steps of its count 0
+struct AxisEncoder {
+    encoder: Box<dyn Encoder>,
+    config: EncoderConfig,
+    offset: f64,
+}
+
+/// Position, settings and driver status of an axis that other threads can read while the axis
+/// moves, see `MotionController::monitor`
+#[derive(Debug, Default)]
+pub struct AxisMonitor {
+    position: AtomicI32,
+    moving: AtomicBool,
+    enabled: AtomicBool,
+    fault: AtomicBool,
+    max_speed: AtomicU64,
+    acceleration: AtomicU64,
+    steps: AtomicU64,
+    jitter: Mutex<Histogram>,
+    idle_error: Mutex<Option<&'static str>>,
+    driver: Mutex<Option<(DriverStatus, Instant)>>,
+}
+
+impl AxisMonitor {
+    pub fn position(&self) -> i32 {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving.load(Ordering::Relaxed)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Following error fault, see `MotionController::has_fault`
+    pub fn has_fault(&self) -> bool {
+        self.fault.load(Ordering::Relaxed)
+    }
+
+    pub fn max_speed(&self) -> f64 {
+        f64::from_bits(self.max_speed.load(Ordering::Relaxed))
+    }
+
+    pub fn acceleration(&self) -> f64 {
+        f64::from_bits(self.acceleration.load(Ordering::Relaxed))
+    }
+
+    /// Last driver status and when it was read, see `MotionController::set_status_interval`
+    pub fn driver_status(&self) -> Option<(DriverStatus, Instant)> {
+        *self.driver.lock().unwrap()
+    }
+
+    /// Steps emitted in either direction since the controller was created
+    pub fn steps(&self) -> u64 {
+        self.steps.load(Ordering::Relaxed)
+    }
+
+    /// Jitter of the steps timed by the controller itself, moves on a step thread are in
+    /// `StepThread::stats` instead
+    pub fn jitter(&self) -> Histogram {
+        self.jitter.lock().unwrap().clone()
+    }
+
+    /// Failure of the last `MotionController::poll_idle`, none once a check passes again
+    pub fn idle_error(&self) -> Option<&'static str> {
+        *self.idle_error.lock().unwrap()
+    }
+
+    fn count_steps(&self, steps: i32) {
+        self.steps
+            .fetch_add(steps.unsigned_abs() as u64, Ordering::Relaxed);
+    }
+}
 
 pub struct MotionController<T> {
     stepper_motor: T, // @TODO - make this generic
     name: String,
+    position: i32,
+    max_speed: f64,
+    acceleration: f64,
+    step_thread: Option<(Arc<StepThread>, usize)>,
+    enabled: bool,
+    idle_policy: IdlePolicy,
+    idle_since: Option<Instant>,
+    emergency_stop: Option<EmergencyStop>,
+    soft_limits: Option<SoftLimits>,
+    limit_switches: Vec<(Direction, CancelToken)>,
+    home_switch: Option<CancelToken>,
+    backlash: u32,
+    backlash_speed: Option<f64>,
+    last_direction: Option<Direction>,
+    encoder: Option<AxisEncoder>,
+    fault: bool,
+    closed_loop: Option<ClosedLoopConfig>,
+    residual_error: Option<i32>,
+    status_reader: Option<StatusReader<T>>,
+    monitor: Arc<AxisMonitor>,
 }
 
+/// Interval of the driver status reads and the read itself, which needs a `DiagnosticDriver`
+type StatusReader<T> = (Duration, fn(&mut T) -> Result<DriverStatus, &'static str>);
+
 impl<T> MotionController<T>
 where
-    T: Stepper,
+    T: StepGenerator,
 {
+    /// Default speed limit in steps/s
+    pub const DEFAULT_MAX_SPEED: f64 = 1000.0;
+    /// Default acceleration in steps/s^2
+    pub const DEFAULT_ACCELERATION: f64 = 2000.0;
+    /// Shortest block queued by velocity moves
+    const VELOCITY_CHUNK_STEPS: i32 = 1000;
+    /// Longest wait for the home switch to release after backing off
+    const HOME_RELEASE_TIMEOUT: Duration = Duration::from_millis(100);
+
     pub fn new(name: String, stepper: T) -> Self {
+        let monitor = AxisMonitor::default();
+        monitor
+            .max_speed
+            .store(Self::DEFAULT_MAX_SPEED.to_bits(), Ordering::Relaxed);
+        monitor
+            .acceleration
+            .store(Self::DEFAULT_ACCELERATION.to_bits(), Ordering::Relaxed);
         Self {
             stepper_motor: stepper,
             name,
+            position: 0,
+            max_speed: Self::DEFAULT_MAX_SPEED,
+            acceleration: Self::DEFAULT_ACCELERATION,
+            step_thread: None,
+            enabled: false,
+            idle_policy: IdlePolicy::Keep,
+            idle_since: None,
+            emergency_stop: None,
+            soft_limits: None,
+            limit_switches: Vec::new(),
+            home_switch: None,
+            backlash: 0,
+            backlash_speed: None,
+            last_direction: None,
+            encoder: None,
+            fault: false,
+            closed_loop: None,
+            residual_error: None,
+            status_reader: None,
+            monitor: Arc::new(monitor),
+        }
+    }
+
+    /// Hands the step pulses of `move_steps` to a step thread, `axis` being the axis of the
+    /// thread's pulse output this motor is connected to
+    pub fn set_step_thread(&mut self, step_thread: Arc<StepThread>, axis: usize) {
+        self.step_thread = Some((step_thread, axis));
+    }
+
+    /// Lets `emergency_stop` abort every move of this controller
+    pub fn set_emergency_stop(&mut self, emergency_stop: &EmergencyStop) {
+        self.emergency_stop = Some(emergency_stop.clone());
+    }
+
+    /// `token` linked to the emergency stop when one is set
+    pub(crate) fn cancel_token(&self, token: &CancelToken) -> CancelToken {
+        match &self.emergency_stop {
+            Some(emergency_stop) => token.linked(emergency_stop.token()),
+            None => token.clone(),
+        }
+    }
+
+    /// Adds a limit switch ending moves in `direction` while it is triggered, moves away from it
+    /// stay possible
+    pub fn set_limit_switch(&mut self, direction: Direction, switch: &Switch) {
+        self.limit_switches
+            .push((direction, switch.token().clone()));
+    }
+
+    /// Switch used by `home`, the limit switch in the homing direction is used when none is set
+    pub fn set_home_switch(&mut self, switch: &Switch) {
+        self.home_switch = Some(switch.token().clone());
+    }
+
+    /// `token` linked to the limit switches of `direction`
+    pub(crate) fn switch_guard(&self, token: &CancelToken, direction: Direction) -> CancelToken {
+        self.limit_switches
+            .iter()
+            .filter(|(switch_direction, _)| *switch_direction == direction)
+            .fold(token.clone(), |guard, (_, switch)| guard.linked(switch))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stepper(&self) -> &T {
+        &self.stepper_motor
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut T {
+        &mut self.stepper_motor
+    }
+
+    /// Position in steps relative to where the controller was created
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    /// Position, motion state and settings shared with other threads. The position follows every
+    /// step made from the calling thread, moves run on a step thread update it once per block.
+    pub fn monitor(&self) -> Arc<AxisMonitor> {
+        self.monitor.clone()
+    }
+
+    fn publish_position(&self) {
+        self.monitor
+            .position
+            .store(self.position, Ordering::Relaxed);
+    }
+
+    /// Overrides the tracked position without moving, e.g. after steps were made elsewhere. The
+    /// position is taken over even when the encoder could not be read to follow it.
+    pub fn set_position(&mut self, position: i32) -> Result<(), &'static str> {
+        self.position = position;
+        self.publish_position();
+        self.sync_encoder()
+    }
+
+    /// Sets the top speed of `move_steps` in steps/s
+    pub fn set_max_speed(&mut self, max_speed: f64) -> Result<(), &'static str> {
+        if max_speed <= 0.0 {
+            return Err("Max speed must be greater than zero");
         }
+        self.max_speed = max_speed;
+        self.monitor
+            .max_speed
+            .store(max_speed.to_bits(), Ordering::Relaxed);
+        Ok(())
     }
 
-    pub async fn move_steps(&mut self, steps: i32) {
-        println!("moving stepper {}", self.name);
+    pub fn max_speed(&self) -> f64 {
+        self.max_speed
+    }
 
-        for i in 0..steps {
-            println!("Moving step {}", i);
-            //let _ = self.stepper_motor.step(); //.await;
+    /// Sets the acceleration and deceleration of `move_steps` in steps/s^2
+    pub fn set_acceleration(&mut self, acceleration: f64) -> Result<(), &'static str> {
+        if acceleration <= 0.0 {
+            return Err("Acceleration must be greater than zero");
         }
+        self.acceleration = acceleration;
+        self.monitor
+            .acceleration
+            .store(acceleration.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn acceleration(&self) -> f64 {
+        self.acceleration
+    }
+
+    /// Limits the travel of every following move, None removes the limits. Single steps beyond a
+    /// limit fail while steps back towards the range are allowed.
+    pub fn set_soft_limits(&mut self, soft_limits: Option<SoftLimits>) {
+        self.soft_limits = soft_limits;
+    }
+
+    pub fn soft_limits(&self) -> Option<SoftLimits> {
+        self.soft_limits
+    }
+
+    /// Takes up `steps` of backlash before every move that reverses the direction of travel, at
+    /// `take_up_speed` steps/s or the max speed. The take-up steps do not change the position.
+    /// Single steps made with `step` are not compensated.
+    pub fn set_backlash(
+        &mut self,
+        steps: u32,
+        take_up_speed: Option<f64>,
+    ) -> Result<(), &'static str> {
+        if take_up_speed.is_some_and(|speed| !(speed.is_finite() && speed > 0.0)) {
+            return Err("Backlash take-up speed must be greater than zero");
+        }
+        self.backlash = steps;
+        self.backlash_speed = take_up_speed;
+        Ok(())
+    }
+
+    pub fn backlash(&self) -> u32 {
+        self.backlash
+    }
+
+    /// Adds steps made for this axis by someone else, e.g. the step thread of a motion group
+    pub(crate) fn record_steps(&mut self, steps: i32) {
+        self.position += steps;
+        self.publish_position();
+        self.monitor.count_steps(steps);
+        match steps.signum() {
+            1 => self.last_direction = Some(Direction::CW),
+            -1 => self.last_direction = Some(Direction::CCW),
+            _ => {}
+        }
+    }
+
+    /// Attaches an encoder whose current count is taken as the current position. The following
+    /// error is checked after every move and by `poll_idle`.
+    pub fn set_encoder(
+        &mut self,
+        encoder: Box<dyn Encoder>,
+        config: EncoderConfig,
+    ) -> Result<(), &'static str> {
+        self.encoder = Some(AxisEncoder {
+            encoder,
+            config,
+            offset: 0.0,
+        });
+        self.sync_encoder()
+    }
+
+    /// Also turns the closed loop off
+    pub fn remove_encoder(&mut self) {
+        self.encoder = None;
+        self.closed_loop = None;
+    }
+
+    /// Corrects moves with the encoder until they end within the tolerance of their target, None
+    /// runs open loop again
+    pub fn set_closed_loop(
+        &mut self,
+        closed_loop: Option<ClosedLoopConfig>,
+    ) -> Result<(), &'static str> {
+        if closed_loop.is_some() && self.encoder.is_none() {
+            return Err("Closed loop needs an encoder");
+        }
+        if closed_loop.is_some_and(|config| config.check_interval == Some(0)) {
+            return Err("Closed loop check interval must be greater than zero");
+        }
+        self.closed_loop = closed_loop;
+        Ok(())
+    }
+
+    pub fn closed_loop(&self) -> Option<ClosedLoopConfig> {
+        self.closed_loop
+    }
+
+    /// Distance in steps from the encoder position to the target of the last closed-loop move
+    pub fn residual_error(&self) -> Option<i32> {
+        self.residual_error
+    }
+
+    /// Position in steps measured by the encoder, None without an encoder
+    pub fn encoder_position(&self) -> Option<Result<i32, &'static str>> {
+        let encoder = self.encoder.as_ref()?;
+        Some(encoder.encoder.count().map(|count| {
+            (encoder.offset + count as f64 * encoder.config.steps_per_count).round() as i32
+        }))
+    }
+
+    fn sync_encoder(&mut self) -> Result<(), &'static str> {
+        if let Some(encoder) = self.encoder.as_mut() {
+            let count = encoder.encoder.count()?;
+            encoder.offset = self.position as f64 - count as f64 * encoder.config.steps_per_count;
+        }
+        Ok(())
+    }
+
+    /// Compares the position with the encoder position and returns the difference in steps. A
+    /// difference above the limit corrects the position or faults the axis, see
+    /// `FollowingErrorAction`.
+    pub fn check_following_error(&mut self) -> Result<i32, &'static str> {
+        let (Some(encoder), Some(measured)) = (&self.encoder, self.encoder_position().transpose()?)
+        else {
+            return Ok(0);
+        };
+        let error = self.position - measured;
+        if error.unsigned_abs() <= encoder.config.max_following_error {
+            return Ok(error);
+        }
+
+        match encoder.config.action {
+            FollowingErrorAction::Fault => {
+                self.fault = true;
+                self.monitor.fault.store(true, Ordering::Relaxed);
+                Err("Following error exceeded the limit")
+            }
+            FollowingErrorAction::Correct => {
+                self.position = measured;
+                self.publish_position();
+                Ok(error)
+            }
+        }
+    }
+
+    /// Whether a following error stopped the axis, it does not move until `clear_fault`
+    pub fn has_fault(&self) -> bool {
+        self.fault
+    }
+
+    /// Lets a faulted axis move again, taking the encoder position as the position
+    pub fn clear_fault(&mut self) -> Result<(), &'static str> {
+        if let Some(measured) = self.encoder_position().transpose()? {
+            self.position = measured;
+            self.publish_position();
+        }
+        self.fault = false;
+        self.monitor.fault.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Target a move to `target` ends at within the soft limits, clipped or rejected depending on
+    /// the limit mode
+    pub fn limit_target(&self, target: i32) -> Result<i32, &'static str> {
+        let Some(limits) = self.soft_limits else {
+            return Ok(target);
+        };
+        if limits.contains(target) {
+            return Ok(target);
+        }
+
+        match limits.mode {
+            LimitMode::Reject => Err("Move would exceed the soft limits"),
+            LimitMode::Clip => Ok(target.clamp(limits.min, limits.max)),
+        }
+    }
+
+    /// Turns the motor outputs on, motion enables the motor on its own when needed
+    pub fn enable(&mut self) -> Result<(), &'static str> {
+        self.stepper_motor.set_enabled(true)?;
+        self.enabled = true;
+        self.monitor.enabled.store(true, Ordering::Relaxed);
+        self.idle_since = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Turns the motor outputs off so the motor can turn freely and stops drawing current
+    pub fn disable(&mut self) -> Result<(), &'static str> {
+        self.stepper_motor.set_enabled(false)?;
+        self.enabled = false;
+        self.monitor.enabled.store(false, Ordering::Relaxed);
+        self.idle_since = None;
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn idle_policy(&self) -> IdlePolicy {
+        self.idle_policy
+    }
+
+    /// Disables the motor when the idle policy says so and it stood still long enough. Returns
+    /// true when the motor was disabled by this call. Also checks the following error when an
+    /// encoder is attached. A failure is also kept in the monitor, see `AxisMonitor::idle_error`.
+    pub fn poll_idle(&mut self) -> Result<bool, &'static str> {
+        self.poll_status();
+        let result = self.check_idle();
+        *self.monitor.idle_error.lock().unwrap() = result.err();
+        result
+    }
+
+    fn check_idle(&mut self) -> Result<bool, &'static str> {
+        if !self.fault {
+            self.check_following_error()?;
+        }
+        let IdlePolicy::Disable(delay) = self.idle_policy else {
+            return Ok(false);
+        };
+
+        match self.idle_since {
+            Some(since) if self.enabled && since.elapsed() >= delay => {
+                self.disable()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Reads the driver status into the monitor once the status interval passed since the last
+    /// read, a failed read leaves the last status there
+    pub(crate) fn poll_status(&mut self) {
+        let Some((interval, read)) = self.status_reader else {
+            return;
+        };
+        let now = Instant::now();
+        if self
+            .monitor
+            .driver_status()
+            .is_some_and(|(_, read_at)| now.duration_since(read_at) < interval)
+        {
+            return;
+        }
+        if let Ok(status) = read(&mut self.stepper_motor) {
+            *self.monitor.driver.lock().unwrap() = Some((status, now));
+        }
+    }
+
+    /// Enables the motor if needed and stops the idle timer, called before the motor moves
+    pub(crate) fn begin_motion(&mut self) -> Result<(), &'static str> {
+        if self.fault {
+            return Err("Axis has a following error fault");
+        }
+        if !self.enabled {
+            self.enable()?;
+        }
+        self.idle_since = None;
+        self.monitor.moving.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Starts the idle timer, called once the motor stopped moving
+    pub(crate) fn end_motion(&mut self) {
+        self.idle_since = Some(Instant::now());
+        self.monitor.moving.store(false, Ordering::Relaxed);
+    }
+
+    /// Makes a single step in the given direction and keeps track of the position, this lets
+    /// other controllers interleave steps between several motors.
+    pub fn step(&mut self, direction: Direction) -> Result<(), &'static str> {
+        let steps = match direction {
+            Direction::CW => 1,
+            Direction::CCW => -1,
+        };
+
+        if let Some(limits) = self.soft_limits {
+            let next = self.position + steps;
+            if (steps > 0 && next > limits.max) || (steps < 0 && next < limits.min) {
+                return Err("Step would exceed the soft limits");
+            }
+        }
+
+        if self.fault {
+            return Err("Axis has a following error fault");
+        }
+        if !self.enabled {
+            self.enable()?;
+        }
+        self.stepper_motor.set_steps_to_move(steps);
+        self.stepper_motor.step()?;
+        self.position += steps;
+        self.publish_position();
+        self.monitor.count_steps(steps);
+        self.last_direction = Some(direction);
+        self.idle_since = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Moves the given amount of steps, accelerating from standstill up to the max speed and
+    /// decelerating back to standstill at the end of the move. Only the emergency stop or a switch
+    /// can end the move early.
+    pub async fn move_steps(&mut self, steps: i32) -> Result<MoveOutcome, &'static str> {
+        self.move_steps_cancellable(steps, &CancelToken::new())
+            .await
+    }
+
+    /// Same as `move_steps` while `token` or the emergency stop can end the move early. Stopping
+    /// decelerates with the acceleration of the move, aborting stops right away and disables the
+    /// motor. The position is kept up to date either way. Without a step thread the steps are
+    /// timed on the awaiting task, which only yields between steps further apart than about a
+    /// millisecond, so fast moves are better run through an `AxisHandle` on their own thread.
+    pub async fn move_steps_cancellable(
+        &mut self,
+        steps: i32,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let target = self.limit_target(self.position.saturating_add(steps))?;
+        self.move_guarded(target, self.max_speed, token, None).await
+    }
+
+    /// Moves to `target` while `token`, the emergency stop, the limit switches in the direction of
+    /// travel and `switch` can end the move
+    async fn move_guarded(
+        &mut self,
+        target: i32,
+        max_speed: f64,
+        token: &CancelToken,
+        switch: Option<&CancelToken>,
+    ) -> Result<MoveOutcome, &'static str> {
+        let token = self.cancel_token(token);
+        let direction = match target < self.position {
+            true => Direction::CCW,
+            false => Direction::CW,
+        };
+        let mut guard = self.switch_guard(&token, direction);
+        if let Some(switch) = switch {
+            guard = guard.linked(switch);
+        }
+
+        let mut planner = Planner::new(vec![1.0]);
+        planner.set_position(&[self.position])?;
+        planner.push(&[target as f64], max_speed, self.acceleration)?;
+
+        let Some(block) = planner.pop() else {
+            return Ok(MoveOutcome::Completed);
+        };
+        if let Some(mode) = guard.requested() {
+            return Ok(switch_outcome(mode.into(), &token));
+        }
+        self.begin_motion()?;
+
+        let outcome = match self.take_up_backlash(direction, &guard).await {
+            Ok(MoveOutcome::Completed) => self.run_block(&block, &guard).await,
+            outcome => outcome,
+        };
+        let outcome = match outcome {
+            Ok(MoveOutcome::Completed) => self.correct_position(target, max_speed, &guard).await,
+            outcome => outcome,
+        };
+        self.finish_motion(outcome.map(|outcome| switch_outcome(outcome, &token)))
+    }
+
+    /// Runs at `speed` steps/s until `token` or the emergency stop ends the move, the sign of the
+    /// speed gives the direction. The speed is capped at the max speed. With soft limits the
+    /// motor decelerates in time to stop at the limit it is heading to.
+    pub async fn move_velocity_cancellable(
+        &mut self,
+        speed: f64,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        if !speed.is_finite() {
+            return Err("Speed must be a finite number");
+        }
+        if speed == 0.0 {
+            return Ok(MoveOutcome::Completed);
+        }
+
+        let token = self.cancel_token(token);
+        let sign = if speed < 0.0 { -1 } else { 1 };
+        let guard = match sign {
+            1 => self.switch_guard(&token, Direction::CW),
+            _ => self.switch_guard(&token, Direction::CCW),
+        };
+        let speed = speed.abs().min(self.max_speed);
+        let end = match (self.soft_limits, sign) {
+            (Some(limits), 1) => limits.max,
+            (Some(limits), _) => limits.min,
+            (None, 1) => i32::MAX,
+            (None, _) => i32::MIN,
+        };
+        if (end as i64 - self.position as i64) * sign as i64 <= 0 {
+            return Err("Axis is at its soft limit");
+        }
+
+        // Blocks are queued two ahead so the running one keeps its speed into the next, they are
+        // long enough for braking to finish within the one after the running block
+        let acceleration = self.acceleration;
+        let braking = (speed.powi(2) / (2.0 * acceleration)).ceil() as i32;
+        let chunk = (2 * braking).max(Self::VELOCITY_CHUNK_STEPS);
+        let mut planner = Planner::new(vec![1.0]);
+        planner.set_position(&[self.position])?;
+        let mut queued = self.position;
+        let mut queue_chunk = |planner: &mut Planner| {
+            let next = queued.saturating_add(chunk * sign);
+            let next = if sign > 0 {
+                next.min(end)
+            } else {
+                next.max(end)
+            };
+            if next != queued {
+                queued = next;
+                planner.push(&[next as f64], speed, acceleration)?;
+            }
+            Ok::<(), &'static str>(())
+        };
+        queue_chunk(&mut planner)?;
+        queue_chunk(&mut planner)?;
+
+        if let Some(mode) = guard.requested() {
+            return Ok(switch_outcome(mode.into(), &token));
+        }
+        self.begin_motion()?;
+
+        let direction = match sign {
+            1 => Direction::CW,
+            _ => Direction::CCW,
+        };
+        let mut outcome = self.take_up_backlash(direction, &guard).await;
+        while outcome == Ok(MoveOutcome::Completed) {
+            let Some(block) = planner.pop() else {
+                break;
+            };
+            outcome = self.run_block(&block, &guard).await;
+            if let Err(err) = queue_chunk(&mut planner) {
+                outcome = Err(err);
+            }
+        }
+        self.finish_motion(outcome.map(|outcome| switch_outcome(outcome, &token)))
+    }
+
+    /// Finds the home switch and sets the position there, see `HomingConfig`. Soft limits are
+    /// ignored while homing as the position is not known yet.
+    pub async fn home(
+        &mut self,
+        config: &HomingConfig,
+        token: &CancelToken,
+    ) -> Result<(), &'static str> {
+        if ![config.fast_speed, config.slow_speed]
+            .iter()
+            .all(|speed| speed.is_finite() && *speed > 0.0)
+        {
+            return Err("Homing speeds must be greater than zero");
+        }
+        if config.back_off <= 0 || config.max_travel <= 0 {
+            return Err("Homing back off and travel must be greater than zero");
+        }
+        let switch = match &self.home_switch {
+            Some(switch) => switch.clone(),
+            None => self
+                .limit_switches
+                .iter()
+                .find(|(direction, _)| *direction == config.direction)
+                .map(|(_, switch)| switch.clone())
+                .ok_or("Homing needs a home switch or a limit switch in the homing direction")?,
+        };
+
+        let soft_limits = self.soft_limits.take();
+        let result = self.seek_home(config, token, &switch).await;
+        self.soft_limits = soft_limits;
+        result
+    }
+
+    async fn seek_home(
+        &mut self,
+        config: &HomingConfig,
+        token: &CancelToken,
+        switch: &CancelToken,
+    ) -> Result<(), &'static str> {
+        let sign = match config.direction {
+            Direction::CW => 1,
+            Direction::CCW => -1,
+        };
+
+        if switch.requested().is_none() {
+            let target = self.position.saturating_add(config.max_travel * sign);
+            match self
+                .move_guarded(target, config.fast_speed, token, Some(switch))
+                .await?
+            {
+                MoveOutcome::SwitchTriggered => {}
+                MoveOutcome::Completed => {
+                    return Err("Home switch not found within the maximum travel")
+                }
+                _ => return Err("Homing was stopped"),
+            }
+        }
+
+        let target = self.position - config.back_off * sign;
+        if self
+            .move_guarded(target, config.slow_speed, token, None)
+            .await?
+            != MoveOutcome::Completed
+        {
+            return Err("Homing was stopped");
+        }
+        // Give a debounced switch time to release
+        let released_by = Instant::now() + Self::HOME_RELEASE_TIMEOUT;
+        while switch.requested().is_some() {
+            if Instant::now() >= released_by {
+                return Err("Home switch still triggered after backing off");
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let target = self.position + 2 * config.back_off * sign;
+        match self
+            .move_guarded(target, config.slow_speed, token, Some(switch))
+            .await?
+        {
+            MoveOutcome::SwitchTriggered => {}
+            MoveOutcome::Completed => return Err("Home switch not found again after backing off"),
+            _ => return Err("Homing was stopped"),
+        }
+
+        self.set_position(config.home_position)?;
+        Ok(())
+    }
+
+    /// Runs a single axis block here or on the step thread, the motor must be enabled
+    async fn run_block(
+        &mut self,
+        block: &Block,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let direction = match block.steps[0] < 0 {
+            true => Direction::CCW,
+            false => Direction::CW,
+        };
+
+        let outcome = match self.step_thread.clone() {
+            Some((step_thread, axis)) => {
+                self.stepper_motor.set_direction(direction)?;
+                let events = StepEvent::from_block(block)
+                    .into_iter()
+                    .map(|event| StepEvent { axis, ..event })
+                    .collect();
+                let cancellation =
+                    Cancellation::for_axis(token.clone(), block.clone(), axis, direction);
+                let result = step_thread
+                    .run_cancellable(events, Some(cancellation))
+                    .await?;
+                self.position += result.steps(axis);
+                self.publish_position();
+                self.monitor.count_steps(result.steps(axis));
+                if result.steps(axis) != 0 {
+                    self.last_direction = Some(direction);
+                }
+                match result.error {
+                    Some(err) => Err(err),
+                    None => Ok(result.outcome),
+                }
+            }
+            None => self.run_ticks(block, direction, token).await,
+        };
+        self.poll_status();
+        outcome
+    }
+
+    /// Makes the backlash take-up steps when `direction` reverses the last direction of travel,
+    /// the position is the same afterwards
+    pub(crate) async fn take_up_backlash(
+        &mut self,
+        direction: Direction,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        if self.backlash == 0 || self.last_direction.is_none_or(|last| last == direction) {
+            return Ok(MoveOutcome::Completed);
+        }
+        let steps = match direction {
+            Direction::CW => self.backlash as i32,
+            Direction::CCW => -(self.backlash as i32),
+        };
+        let speed = self.backlash_speed.unwrap_or(self.max_speed);
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[steps as f64], speed, self.acceleration)?;
+        let Some(block) = planner.pop() else {
+            return Ok(MoveOutcome::Completed);
+        };
+
+        // Start behind the position so the take-up steps end on it, this also keeps them clear
+        // of the soft limit checks of single steps
+        let position = self.position;
+        self.position -= steps;
+        let outcome = self.run_block(&block, token).await;
+        self.position = position;
+        self.publish_position();
+        outcome
+    }
+
+    /// Makes corrective moves until the encoder is within the tolerance of `target` or the
+    /// attempts run out, the remaining error is kept as the residual error
+    async fn correct_position(
+        &mut self,
+        target: i32,
+        max_speed: f64,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let Some(config) = self.closed_loop else {
+            return Ok(MoveOutcome::Completed);
+        };
+
+        let mut attempts = 0;
+        loop {
+            let measured = self
+                .encoder_position()
+                .ok_or("Closed loop needs an encoder")??;
+            let error = target - measured;
+            self.residual_error = Some(error);
+            if error.unsigned_abs() <= config.tolerance || attempts == config.max_attempts {
+                return Ok(MoveOutcome::Completed);
+            }
+            attempts += 1;
+
+            self.position = measured;
+            let direction = match error < 0 {
+                true => Direction::CCW,
+                false => Direction::CW,
+            };
+            let mut planner = Planner::new(vec![1.0]);
+            planner.push(&[error as f64], max_speed, self.acceleration)?;
+            let Some(block) = planner.pop() else {
+                return Ok(MoveOutcome::Completed);
+            };
+            let outcome = match self.take_up_backlash(direction, token).await {
+                Ok(MoveOutcome::Completed) => self.run_block(&block, token).await,
+                outcome => outcome,
+            };
+            if outcome != Ok(MoveOutcome::Completed) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Plans the rest of `block` again from the encoder position when the encoder lags behind
+    /// while moving in `direction`, so the lost steps are made up at the speed profile of the
+    /// block. The new block starts at `speed`, the speed the motor runs at, as far as it still
+    /// leaves room to stop at `target`. `replanned_from` is the encoder position of the last
+    /// time the block was planned again.
+    fn catch_up(
+        &mut self,
+        block: &Block,
+        direction: Direction,
+        target: i32,
+        speed: f64,
+        replanned_from: Option<i32>,
+    ) -> Result<Option<Block>, &'static str> {
+        let (Some(config), Some(measured)) =
+            (self.closed_loop, self.encoder_position().transpose()?)
+        else {
+            return Ok(None);
+        };
+        let lag = match direction {
+            Direction::CW => self.position - measured,
+            Direction::CCW => measured - self.position,
+        };
+        if lag <= config.tolerance as i32 {
+            return Ok(None);
+        }
+        // A stalled motor would be planned again forever, it is left to the following error
+        // check at the end of the move
+        let advanced = match (direction, replanned_from) {
+            (_, None) => true,
+            (Direction::CW, Some(from)) => measured > from,
+            (Direction::CCW, Some(from)) => measured < from,
+        };
+        if !advanced {
+            return Ok(None);
+        }
+
+        self.position = measured;
+        let mut planner = Planner::new(vec![1.0]);
+        planner.set_position(&[measured])?;
+        planner.push(&[target as f64], block.nominal_speed, block.acceleration)?;
+        let Some(mut rest) = planner.pop() else {
+            return Ok(None);
+        };
+        let stoppable = (2.0 * rest.acceleration * rest.distance).sqrt();
+        rest.entry_speed = speed.min(stoppable).min(rest.nominal_speed);
+        Ok(Some(rest))
+    }
+
+    /// Starts the idle timer after a move, disables the motor when it was aborted and checks the
+    /// following error otherwise. A motor that could not be disabled is an error.
+    fn finish_motion(
+        &mut self,
+        outcome: Result<MoveOutcome, &'static str>,
+    ) -> Result<MoveOutcome, &'static str> {
+        self.end_motion();
+
+        match outcome {
+            Ok(MoveOutcome::Aborted) => {
+                self.disable()?;
+            }
+            Ok(_) => {
+                self.check_following_error()?;
+            }
+            Err(_) => {}
+        }
+        outcome
+    }
+
+    /// Steps through the ticks of `block` from this task, checking `token` before every step and
+    /// yielding to the runtime while the next step is far enough away, see `wait_until_async`.
+    /// With a check interval the rest of the block is planned again whenever the encoder lags.
+    async fn run_ticks(
+        &mut self,
+        block: &Block,
+        direction: Direction,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let spin_threshold = RealtimeConfig::default().spin_threshold;
+        let target = self.position + block.steps[0];
+        let mut block = block.clone();
+        let mut start = Instant::now();
+        let mut times = block.tick_times().collect::<Vec<_>>().into_iter();
+        let mut outcome = MoveOutcome::Completed;
+        let mut ticks = 0;
+        let mut speed = block.entry_speed;
+        let mut replanned_from = None;
+        let mut previous: Option<(Duration, Instant)> = None;
+
+        while let Some(at) = times.next() {
+            match token.requested() {
+                Some(StopMode::Abort) => return Ok(MoveOutcome::Aborted),
+                Some(StopMode::Stop) if outcome == MoveOutcome::Completed => {
+                    outcome = MoveOutcome::Stopped;
+                    times = block.stop_tick_times(ticks).into_iter();
+                    continue;
+                }
+                _ => {}
+            }
+
+            wait_until_async(start + at, spin_threshold).await;
+            let now = Instant::now();
+            if let Some((previous_at, previous_time)) = previous {
+                let scheduled = at.saturating_sub(previous_at);
+                let actual = now - previous_time;
+                self.monitor
+                    .jitter
+                    .lock()
+                    .unwrap()
+                    .record(match actual > scheduled {
+                        true => actual - scheduled,
+                        false => scheduled - actual,
+                    });
+                if !scheduled.is_zero() {
+                    speed = 1.0 / scheduled.as_secs_f64();
+                }
+            }
+            previous = Some((at, now));
+            self.step(direction)?;
+            ticks += 1;
+
+            let Some(interval) = self.closed_loop.and_then(|config| config.check_interval) else {
+                continue;
+            };
+            if outcome == MoveOutcome::Completed && ticks % interval == 0 {
+                if let Some(rest) =
+                    self.catch_up(&block, direction, target, speed, replanned_from)?
+                {
+                    replanned_from = Some(self.position);
+                    block = rest;
+                    start = Instant::now();
+                    times = block.tick_times().collect::<Vec<_>>().into_iter();
+                    ticks = 0;
+                    previous = None;
+                }
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+/// A move aborted by a switch instead of its token or the emergency stop ended at the switch
+pub(crate) fn switch_outcome(outcome: MoveOutcome, token: &CancelToken) -> MoveOutcome {
+    match outcome {
+        MoveOutcome::Aborted if token.requested() != Some(StopMode::Abort) => {
+            MoveOutcome::SwitchTriggered
+        }
+        outcome => outcome,
+    }
+}
+
+impl<T> MotionController<T>
+where
+    T: StepGenerator + ConfigurableDriver,
+{
+    /// Sets what happens to the motor after a move, `HoldCurrent` is configured on the driver
+    /// right away while `Disable` needs `poll_idle` to be called regularly
+    pub fn set_idle_policy(&mut self, idle_policy: IdlePolicy) -> Result<(), &'static str> {
+        if let IdlePolicy::HoldCurrent(delay) = idle_policy {
+            self.stepper_motor.set_power_down_delay(delay)?;
+        }
+        self.idle_policy = idle_policy;
+        Ok(())
+    }
+}
+
+impl<T> MotionController<T>
+where
+    T: StepGenerator + DiagnosticDriver,
+{
+    /// Reads the driver status into the monitor at most every `interval`, from `poll_idle` and
+    /// between the blocks of a move, None stops the reads. A read between blocks holds the motor
+    /// for as long as the read takes, so the interval should be well above that.
+    pub fn set_status_interval(&mut self, interval: Option<Duration>) {
+        self.status_reader = interval.map(|interval| (interval, T::status as _));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{connection::Connection, motion_controller, stepper::MockStepper};
+    use crate::stepper::MockStepper;
+    use std::sync::atomic::{AtomicI32, Ordering};
 
     #[test]
     fn new() {
-        panic!("failll");
+        let controller = MotionController::new("test_stepper".to_owned(), MockStepper::default());
+
+        assert_eq!(controller.name(), "test_stepper");
+        assert_eq!(controller.position(), 0);
+        assert!(!controller.is_enabled());
+        assert_eq!(controller.idle_policy(), IdlePolicy::Keep);
+    }
+
+    #[tokio::test]
+    async fn move_steps() {
+        let mut stepper = MockStepper::default();
+        stepper
+            .expect_set_steps_to_move()
+            .withf(|steps| *steps == -1)
+            .times(5)
+            .return_const(());
+        stepper.expect_step().times(5).returning(|| Ok(()));
+        stepper
+            .expect_set_enabled()
+            .withf(|enabled| *enabled)
+            .times(1)
+            .returning(|_| Ok(()));
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+
+        controller.move_steps(-5).await.unwrap();
+        assert_eq!(controller.position(), -5);
+        assert!(controller.is_enabled());
+        assert_eq!(controller.monitor().steps(), 5);
+        assert_eq!(controller.monitor().jitter().samples(), 4);
+    }
+
+    fn get_mock_controller() -> MotionController<MockStepper> {
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(|| Ok(()));
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        MotionController::new("test_stepper".to_owned(), stepper)
+    }
+
+    #[test]
+    fn step_enables_motor() {
+        let mut controller = get_mock_controller();
+        assert!(!controller.is_enabled());
+
+        controller.step(Direction::CCW).unwrap();
+        assert!(controller.is_enabled());
+        assert_eq!(controller.position(), -1);
+    }
+
+    #[test]
+    fn idle_disable() {
+        let mut controller = get_mock_controller();
+        controller
+            .set_idle_policy(IdlePolicy::Disable(Duration::ZERO))
+            .unwrap();
+        assert!(!controller.poll_idle().unwrap());
+
+        controller.step(Direction::CW).unwrap();
+        assert!(controller.poll_idle().unwrap());
+        assert!(!controller.is_enabled());
+        assert!(!controller.poll_idle().unwrap());
+    }
+
+    #[test]
+    fn idle_failure_in_monitor() {
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(|| Ok(()));
+        stepper
+            .expect_set_enabled()
+            .returning(|enabled| match enabled {
+                true => Ok(()),
+                false => Err("Enable pin failed"),
+            });
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller
+            .set_idle_policy(IdlePolicy::Disable(Duration::ZERO))
+            .unwrap();
+        let monitor = controller.monitor();
+
+        controller.step(Direction::CW).unwrap();
+        assert!(controller.poll_idle().is_err());
+        assert_eq!(monitor.idle_error(), Some("Enable pin failed"));
     }
 
     #[test]
-    fn move_steps() {
-        let mock_connection = MockConnection;
-        let mock_stepper = MockStepper::new((1, 2, 3), Connection::Chip);
+    fn idle_keep() {
+        let mut controller = get_mock_controller();
+        controller.step(Direction::CW).unwrap();
+        assert!(!controller.poll_idle().unwrap());
+        assert!(controller.is_enabled());
+    }
+
+    #[test]
+    fn idle_hold_current() {
+        let mut controller = get_mock_controller();
+        controller
+            .stepper_mut()
+            .expect_set_power_down_delay()
+            .withf(|delay| *delay == Duration::from_millis(500))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        controller
+            .set_idle_policy(IdlePolicy::HoldCurrent(Duration::from_millis(500)))
+            .unwrap();
+        assert_eq!(
+            controller.idle_policy(),
+            IdlePolicy::HoldCurrent(Duration::from_millis(500))
+        );
+    }
+
+    fn get_stopping_controller(
+        mode: StopMode,
+        token: &CancelToken,
+    ) -> MotionController<MockStepper> {
+        let token = token.clone();
+        let mut steps = 0;
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        stepper.expect_step().returning(move || {
+            steps += 1;
+            if steps == 10 {
+                match mode {
+                    StopMode::Stop => token.stop(),
+                    StopMode::Abort => token.abort(),
+                }
+            }
+            Ok(())
+        });
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(1000.0).unwrap();
+        controller.set_acceleration(100000.0).unwrap();
+        controller
+    }
+
+    #[tokio::test]
+    async fn stop_decelerates() {
+        let token = CancelToken::new();
+        let mut controller = get_stopping_controller(StopMode::Stop, &token);
+
+        // Cruising at 1000 steps/s takes 5 steps to stop
+        let outcome = controller.move_steps_cancellable(1000, &token).await;
+        assert_eq!(outcome, Ok(MoveOutcome::Stopped));
+        assert_eq!(controller.position(), 15);
+        assert!(controller.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn abort_disables() {
+        let token = CancelToken::new();
+        let mut controller = get_stopping_controller(StopMode::Abort, &token);
+
+        let outcome = controller.move_steps_cancellable(-1000, &token).await;
+        assert_eq!(outcome, Ok(MoveOutcome::Aborted));
+        assert_eq!(controller.position(), -10);
+        assert!(!controller.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn abort_reports_disable_failure() {
+        let token = CancelToken::new();
+        let abort = token.clone();
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(move || {
+            abort.abort();
+            Ok(())
+        });
+        stepper
+            .expect_set_enabled()
+            .returning(|enabled| match enabled {
+                true => Ok(()),
+                false => Err("EN line failed"),
+            });
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+
+        let outcome = controller.move_steps_cancellable(10, &token).await;
+        assert_eq!(outcome, Err("EN line failed"));
+        assert!(controller.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn slow_move_yields() {
+        let mut controller = get_mock_controller();
+        controller.set_max_speed(200.0).unwrap();
+        controller.set_acceleration(1000000.0).unwrap();
+        let monitor = controller.monitor();
+
+        // A single threaded runtime only gets to the check if the move yields while it waits
+        let token = CancelToken::new();
+        let (outcome, moving) =
+            tokio::join!(controller.move_steps_cancellable(10, &token), async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                (monitor.is_moving(), monitor.position())
+            });
+        assert_eq!(outcome, Ok(MoveOutcome::Completed));
+        assert!(moving.0);
+        assert!(moving.1 > 0 && moving.1 < 10);
+    }
+
+    #[tokio::test]
+    async fn emergency_stop_aborts() {
+        let emergency_stop = EmergencyStop::new();
+        let mut controller = get_mock_controller();
+        controller.set_emergency_stop(&emergency_stop);
+
+        emergency_stop.trigger();
+        let outcome = controller
+            .move_steps_cancellable(10, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::Aborted));
+        assert_eq!(controller.position(), 0);
+
+        emergency_stop.reset();
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        let outcome = controller
+            .move_steps_cancellable(10, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::Completed));
+        assert_eq!(controller.position(), 10);
+    }
+
+    #[tokio::test]
+    async fn soft_limits_reject() {
+        let mut controller = get_mock_controller();
+        controller.set_soft_limits(Some(SoftLimits::new(-10, 10, LimitMode::Reject).unwrap()));
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+
+        let outcome = controller
+            .move_steps_cancellable(11, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Err("Move would exceed the soft limits"));
+        assert_eq!(controller.position(), 0);
+        assert!(!controller.is_enabled());
+
+        controller.move_steps(-10).await.unwrap();
+        assert_eq!(controller.position(), -10);
+        assert!(controller.step(Direction::CCW).is_err());
+        assert!(controller.step(Direction::CW).is_ok());
+    }
+
+    #[tokio::test]
+    async fn soft_limits_clip() {
+        let mut controller = get_mock_controller();
+        controller.set_soft_limits(Some(SoftLimits::new(0, 10, LimitMode::Clip).unwrap()));
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+
+        controller.move_steps(25).await.unwrap();
+        assert_eq!(controller.position(), 10);
+        assert!(SoftLimits::new(5, 0, LimitMode::Clip).is_err());
+    }
+
+    struct SharedEncoder(Arc<AtomicI32>);
+
+    impl Encoder for SharedEncoder {
+        fn count(&self) -> Result<i64, &'static str> {
+            Ok(self.0.load(Ordering::SeqCst) as i64)
+        }
+    }
+
+    #[tokio::test]
+    async fn following_error() {
+        let count = Arc::new(AtomicI32::new(100));
+        let mut controller = get_mock_controller();
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        let config = EncoderConfig::new(0.5, 4, FollowingErrorAction::Correct).unwrap();
+        controller
+            .set_encoder(Box::new(SharedEncoder(count.clone())), config)
+            .unwrap();
+        assert_eq!(controller.encoder_position(), Some(Ok(0)));
+
+        // Lost 6 steps
+        count.store(108, Ordering::SeqCst);
+        controller.move_steps(10).await.unwrap();
+        assert_eq!(controller.position(), 4);
+
+        let config = EncoderConfig::new(0.5, 4, FollowingErrorAction::Fault).unwrap();
+        controller
+            .set_encoder(Box::new(SharedEncoder(count.clone())), config)
+            .unwrap();
+        count.store(96, Ordering::SeqCst);
+        assert_eq!(
+            controller.check_following_error(),
+            Err("Following error exceeded the limit")
+        );
+        assert!(controller.has_fault());
+        assert!(controller.step(Direction::CW).is_err());
+
+        controller.clear_fault().unwrap();
+        assert_eq!(controller.position(), -2);
+        controller.step(Direction::CW).unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_loop_correction() {
+        let count = Arc::new(AtomicI32::new(0));
+        let calls = Arc::new(AtomicI32::new(0));
+        let step_count = count.clone();
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(move || {
+            // Every fifth step is lost
+            if calls.fetch_add(1, Ordering::SeqCst) % 5 != 4 {
+                step_count.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        });
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+
+        let config = ClosedLoopConfig {
+            tolerance: 0,
+            max_attempts: 3,
+            check_interval: None,
+        };
+        assert!(controller.set_closed_loop(Some(config)).is_err());
+        let encoder = EncoderConfig::new(1.0, 100, FollowingErrorAction::Fault).unwrap();
+        controller
+            .set_encoder(Box::new(SharedEncoder(count.clone())), encoder)
+            .unwrap();
+        controller.set_closed_loop(Some(config)).unwrap();
+
+        controller.move_steps(20).await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 20);
+        assert_eq!(controller.position(), 20);
+        assert_eq!(controller.residual_error(), Some(0));
+
+        controller
+            .set_closed_loop(Some(ClosedLoopConfig {
+                check_interval: Some(4),
+                max_attempts: 0,
+                ..config
+            }))
+            .unwrap();
+        controller.move_steps(20).await.unwrap();
+        assert_eq!(controller.residual_error(), Some(0));
+        assert_eq!(controller.position(), 40);
+    }
+
+    #[tokio::test]
+    async fn closed_loop_stalled_motor() {
+        let calls = Arc::new(AtomicI32::new(0));
+        let step_calls = calls.clone();
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(move || {
+            step_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        let encoder = EncoderConfig::new(1.0, 100, FollowingErrorAction::Fault).unwrap();
+        controller
+            .set_encoder(
+                Box::new(SharedEncoder(Arc::new(AtomicI32::new(0)))),
+                encoder,
+            )
+            .unwrap();
+        controller
+            .set_closed_loop(Some(ClosedLoopConfig {
+                tolerance: 0,
+                max_attempts: 0,
+                check_interval: Some(4),
+            }))
+            .unwrap();
+
+        // The encoder never moves, the move is planned again once and then runs out
+        controller.move_steps(20).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 24);
+        assert_eq!(controller.residual_error(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn backlash_take_up() {
+        let steps = Arc::new(AtomicI32::new(0));
+        let counted = steps.clone();
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_step().returning(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        controller.set_backlash(5, Some(50000.0)).unwrap();
+        assert!(controller.set_backlash(5, Some(0.0)).is_err());
+
+        controller.move_steps(10).await.unwrap();
+        controller.move_steps(10).await.unwrap();
+        assert_eq!(steps.load(Ordering::SeqCst), 20);
+
+        controller.move_steps(-10).await.unwrap();
+        assert_eq!(steps.load(Ordering::SeqCst), 35);
+        assert_eq!(controller.position(), 10);
+    }
+
+    #[tokio::test]
+    async fn velocity_stops_at_soft_limit() {
+        let mut controller = get_mock_controller();
+        controller.set_soft_limits(Some(
+            SoftLimits::new(-2500, 2500, LimitMode::Reject).unwrap(),
+        ));
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+
+        let outcome = controller
+            .move_velocity_cancellable(-50000.0, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::Completed));
+        assert_eq!(controller.position(), -2500);
+
+        let outcome = controller
+            .move_velocity_cancellable(-50000.0, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Err("Axis is at its soft limit"));
+    }
+
+    #[tokio::test]
+    async fn velocity_stop_decelerates() {
+        let token = CancelToken::new();
+        let mut controller = get_stopping_controller(StopMode::Stop, &token);
+
+        // Cruising at 1000 steps/s takes 5 steps to stop
+        let outcome = controller.move_velocity_cancellable(2000.0, &token).await;
+        assert_eq!(outcome, Ok(MoveOutcome::Stopped));
+        assert_eq!(controller.position(), 15);
+    }
+
+    /// Controller whose switch triggers while the motor is at or below `switch_position`
+    fn get_switch_controller(switch_position: i32) -> (MotionController<MockStepper>, Switch) {
+        let switch = Switch::manual();
+        let token = switch.token().clone();
+        let position = Arc::new(AtomicI32::new(0));
+        let direction = Arc::new(AtomicI32::new(0));
+        let step_direction = direction.clone();
+
+        let mut stepper = MockStepper::default();
+        stepper
+            .expect_set_steps_to_move()
+            .returning(move |steps| direction.store(steps, Ordering::SeqCst));
+        stepper.expect_step().returning(move || {
+            let steps = step_direction.load(Ordering::SeqCst);
+            let position = position.fetch_add(steps, Ordering::SeqCst) + steps;
+            match position <= switch_position {
+                true => token.abort(),
+                false => token.reset(),
+            }
+            Ok(())
+        });
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+
+        let mut controller = MotionController::new("test_stepper".to_owned(), stepper);
+        controller.set_max_speed(100000.0).unwrap();
+        controller.set_acceleration(10000000.0).unwrap();
+        controller.set_limit_switch(Direction::CCW, &switch);
+        (controller, switch)
+    }
+
+    #[tokio::test]
+    async fn limit_switch_ends_move() {
+        let (mut controller, switch) = get_switch_controller(-20);
+
+        let outcome = controller
+            .move_steps_cancellable(-100, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::SwitchTriggered));
+        assert_eq!(controller.position(), -20);
+        assert!(controller.is_enabled());
+        assert!(switch.is_triggered());
+
+        let outcome = controller
+            .move_steps_cancellable(-1, &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::SwitchTriggered));
+        controller.move_steps(10).await.unwrap();
+        assert_eq!(controller.position(), -10);
+        assert!(!switch.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn homing() {
+        let (mut controller, _switch) = get_switch_controller(-20);
+        controller.set_soft_limits(Some(SoftLimits::new(0, 100, LimitMode::Reject).unwrap()));
+        let config = HomingConfig {
+            direction: Direction::CCW,
+            fast_speed: 100000.0,
+            slow_speed: 50000.0,
+            back_off: 5,
+            max_travel: 1000,
+            home_position: -2,
+        };
+
+        controller.home(&config, &CancelToken::new()).await.unwrap();
+        assert_eq!(controller.position(), -2);
+        assert!(controller.soft_limits().is_some());
+
+        let config = HomingConfig {
+            direction: Direction::CW,
+            ..config
+        };
+        assert!(controller.home(&config, &CancelToken::new()).await.is_err());
+    }
 
-        //let motion_controller = MotionController::new("test_stepper".to_owned(), mock_stepper);
+    #[tokio::test]
+    async fn homing_config_is_checked() {
+        let (mut controller, _switch) = get_switch_controller(-20);
+        let config = HomingConfig {
+            direction: Direction::CCW,
+            fast_speed: 0.0,
+            slow_speed: 50000.0,
+            back_off: 5,
+            max_travel: 1000,
+            home_position: -2,
+        };
 
-        panic!("failll");
+        assert!(controller.home(&config, &CancelToken::new()).await.is_err());
+        let config = HomingConfig {
+            fast_speed: 100000.0,
+            slow_speed: f64::NAN,
+            ..config
+        };
+        assert!(controller.home(&config, &CancelToken::new()).await.is_err());
+        let config = HomingConfig {
+            slow_speed: 50000.0,
+            back_off: 0,
+            ..config
+        };
+        assert!(controller.home(&config, &CancelToken::new()).await.is_err());
+        assert_eq!(controller.position(), 0);
+        assert!(!controller.is_enabled());
     }
 }
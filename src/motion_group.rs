@@ -0,0 +1,667 @@
+use crate::cancel::{CancelToken, EmergencyStop, MoveOutcome, StopMode};
+use crate::interpolation::{arc_points, LineSteps};
+use crate::motion_controller::{switch_outcome, MotionController};
+use crate::planner::{Block, Planner};
+use crate::step_thread::{wait_until_async, Cancellation, RealtimeConfig, StepEvent, StepThread};
+use crate::stepper::{Direction, StepGenerator};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Coordinates several motion controllers so they can move together, e.g. the X and Y axis of a
+/// plotter. Axis 0 and 1 form the plane used for arcs and axis 2 (when present) is the linear axis
+/// used for helices. All positions are in steps, speeds in steps/s along the path.
+pub struct MotionGroup<T> {
+    axes: Vec<MotionController<T>>,
+    chord_tolerance: f64,
+    junction_deviation: f64,
+    speed: f64,
+    acceleration: f64,
+    step_thread: Option<Arc<StepThread>>,
+    emergency_stop: Option<EmergencyStop>,
+}
+
+impl<T> MotionGroup<T>
+where
+    T: StepGenerator,
+{
+    /// Default maximum distance in steps between an arc and the chords used to approximate it
+    pub const DEFAULT_CHORD_TOLERANCE: f64 = 0.5;
+    /// Both ends of an arc are whole steps, each up to half a diagonal step off the true circle
+    const ARC_RADIUS_TOLERANCE: f64 = std::f64::consts::SQRT_2;
+    /// Default junction deviation in steps used when planning consecutive segments
+    pub const DEFAULT_JUNCTION_DEVIATION: f64 = 1.0;
+
+    pub fn new(axes: Vec<MotionController<T>>) -> Self {
+        Self {
+            axes,
+            chord_tolerance: Self::DEFAULT_CHORD_TOLERANCE,
+            junction_deviation: Self::DEFAULT_JUNCTION_DEVIATION,
+            speed: MotionController::<T>::DEFAULT_MAX_SPEED,
+            acceleration: MotionController::<T>::DEFAULT_ACCELERATION,
+            step_thread: None,
+            emergency_stop: None,
+        }
+    }
+
+    /// Hands the step pulses of every move to a step thread instead of stepping from the calling
+    /// task. Axis N of the group is axis N of the thread's pulse output.
+    pub fn set_step_thread(&mut self, step_thread: Arc<StepThread>) {
+        self.step_thread = Some(step_thread);
+    }
+
+    /// Lets `emergency_stop` abort every move of the group and of its axes
+    pub fn set_emergency_stop(&mut self, emergency_stop: &EmergencyStop) {
+        for axis in self.axes.iter_mut() {
+            axis.set_emergency_stop(emergency_stop);
+        }
+        self.emergency_stop = Some(emergency_stop.clone());
+    }
+
+    /// Sets the path speed of `line_to`, `arc_to` and `helix_to` in steps/s
+    pub fn set_speed(&mut self, speed: f64) -> Result<(), &'static str> {
+        if speed <= 0.0 {
+            return Err("Speed must be greater than zero");
+        }
+        self.speed = speed;
+        Ok(())
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Sets the path acceleration of `line_to`, `arc_to` and `helix_to` in steps/s^2
+    pub fn set_acceleration(&mut self, acceleration: f64) -> Result<(), &'static str> {
+        if acceleration <= 0.0 {
+            return Err("Acceleration must be greater than zero");
+        }
+        self.acceleration = acceleration;
+        Ok(())
+    }
+
+    pub fn acceleration(&self) -> f64 {
+        self.acceleration
+    }
+
+    /// Sets the maximum distance in steps allowed between an arc and the chords it is cut into.
+    /// Smaller values give smoother arcs at the cost of more segments.
+    pub fn set_chord_tolerance(&mut self, chord_tolerance: f64) -> Result<(), &'static str> {
+        if chord_tolerance <= 0.0 {
+            return Err("Chord tolerance must be greater than zero");
+        }
+        self.chord_tolerance = chord_tolerance;
+        Ok(())
+    }
+
+    pub fn chord_tolerance(&self) -> f64 {
+        self.chord_tolerance
+    }
+
+    /// Sets the junction deviation in steps used between the segments of arcs and helices
+    pub fn set_junction_deviation(&mut self, junction_deviation: f64) -> Result<(), &'static str> {
+        if junction_deviation < 0.0 {
+            return Err("Junction deviation can not be negative");
+        }
+        self.junction_deviation = junction_deviation;
+        Ok(())
+    }
+
+    pub fn axis(&self, index: usize) -> Option<&MotionController<T>> {
+        self.axes.get(index)
+    }
+
+    pub fn axis_mut(&mut self, index: usize) -> Option<&mut MotionController<T>> {
+        self.axes.get_mut(index)
+    }
+
+    pub fn positions(&self) -> Vec<i32> {
+        self.axes.iter().map(|axis| axis.position()).collect()
+    }
+
+    /// Moves every axis in a straight line so they all start and finish together. `target` holds
+    /// the absolute position of each axis in order, axes without a target entry stay where they are.
+    pub async fn line_to(&mut self, target: &[i32]) -> Result<(), &'static str> {
+        completed(self.line_to_cancellable(target, &CancelToken::new()).await)
+    }
+
+    /// Same as `line_to` while `token` or the emergency stop can end the move early
+    pub async fn line_to_cancellable(
+        &mut self,
+        target: &[i32],
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let mut planner = self.planner();
+        self.queue(&mut planner, target)?;
+        self.run_planner(&mut planner, token).await
+    }
+
+    /// Moves through the absolute positions of `path` in order without stopping at every point,
+    /// each point is approached at its own speed in steps/s along the path. Used for paths made
+    /// of many short segments, e.g. by `KinematicGroup`.
+    pub async fn path_to_cancellable(
+        &mut self,
+        path: &[(Vec<i32>, f64)],
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let mut planner = self.planner();
+        for (target, speed) in path {
+            self.queue_at(&mut planner, target, speed.min(self.speed))?;
+        }
+        self.run_planner(&mut planner, token).await
+    }
+
+    /// Runs a block from a planner whose axes match the axes of this group. The steps are
+    /// interleaved between the axes and timed following the speed profile of the block, either
+    /// here or on the step thread when one is set.
+    pub async fn run_block(&mut self, block: &Block) -> Result<(), &'static str> {
+        completed(self.run_block_cancellable(block, &CancelToken::new()).await)
+    }
+
+    /// Same as `run_block` while `token` or the emergency stop can end the block early. Stopping
+    /// decelerates along the path within the block, aborting stops right away and disables the
+    /// axes of the block.
+    pub async fn run_block_cancellable(
+        &mut self,
+        block: &Block,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        if block.steps.len() > self.axes.len() {
+            return Err("Block has more axes than the motion group");
+        }
+        for (axis, steps) in block.steps.iter().enumerate() {
+            if *steps != 0 {
+                self.check_soft_limits(axis, self.axes[axis].position() + steps)?;
+            }
+        }
+        let token = match &self.emergency_stop {
+            Some(emergency_stop) => token.linked(emergency_stop.token()),
+            None => token.clone(),
+        };
+        let mut guard = token.clone();
+        for (axis, steps) in self.axes.iter().zip(block.steps.iter()) {
+            match steps.signum() {
+                1 => guard = axis.switch_guard(&guard, Direction::CW),
+                -1 => guard = axis.switch_guard(&guard, Direction::CCW),
+                _ => {}
+            }
+        }
+        if let Some(mode) = guard.requested() {
+            return Ok(switch_outcome(mode.into(), &token));
+        }
+        for (axis, steps) in self.axes.iter_mut().zip(block.steps.iter()) {
+            if *steps != 0 {
+                axis.begin_motion()?;
+            }
+        }
+
+        // Backlash is taken up one axis after the other before the block starts
+        let mut outcome = MoveOutcome::Completed;
+        for (axis, steps) in self.axes.iter_mut().zip(block.steps.iter()) {
+            let direction = match steps.signum() {
+                1 => Direction::CW,
+                -1 => Direction::CCW,
+                _ => continue,
+            };
+            outcome = axis.take_up_backlash(direction, &guard).await?;
+            if outcome != MoveOutcome::Completed {
+                break;
+            }
+        }
+
+        let outcome = match self.step_thread.clone() {
+            _ if outcome != MoveOutcome::Completed => outcome,
+            Some(step_thread) => {
+                for (axis, steps) in self.axes.iter_mut().zip(block.steps.iter()) {
+                    match steps.signum() {
+                        1 => axis.stepper_mut().set_direction(Direction::CW)?,
+                        -1 => axis.stepper_mut().set_direction(Direction::CCW)?,
+                        _ => {}
+                    }
+                }
+
+                let axes = (0..block.steps.len()).collect();
+                let cancellation = Cancellation::for_block(guard, block.clone(), axes);
+                let result = step_thread
+                    .run_cancellable(StepEvent::from_block(block), Some(cancellation))
+                    .await?;
+                for (index, axis) in self.axes.iter_mut().enumerate() {
+                    axis.record_steps(result.steps(index));
+                }
+                if let Some(err) = result.error {
+                    return Err(err);
+                }
+                result.outcome
+            }
+            None => self.run_ticks(block, &guard).await?,
+        };
+        let outcome = switch_outcome(outcome, &token);
+        let mut result = Ok(outcome);
+
+        for (axis, steps) in self.axes.iter_mut().zip(block.steps.iter()) {
+            if *steps == 0 {
+                continue;
+            }
+            axis.end_motion();
+            axis.poll_status();
+            // Every axis is disabled or checked before a failure is returned
+            let checked = match outcome {
+                MoveOutcome::Aborted => axis.disable(),
+                _ => axis.check_following_error().map(|_| ()),
+            };
+            if let Err(err) = checked {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Steps through the ticks of `block` from this task, checking `token` before every tick and
+    /// yielding to the runtime while the next tick is far enough away
+    async fn run_ticks(
+        &mut self,
+        block: &Block,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        let spin_threshold = RealtimeConfig::default().spin_threshold;
+        let start = Instant::now();
+        let mut steps = LineSteps::new(&block.steps);
+        let mut times = block.tick_times().collect::<Vec<_>>().into_iter();
+        let mut outcome = MoveOutcome::Completed;
+        let mut ticks = 0;
+
+        while let Some(at) = times.next() {
+            match token.requested() {
+                Some(StopMode::Abort) => return Ok(MoveOutcome::Aborted),
+                Some(StopMode::Stop) if outcome == MoveOutcome::Completed => {
+                    outcome = MoveOutcome::Stopped;
+                    times = block.stop_tick_times(ticks).into_iter();
+                    continue;
+                }
+                _ => {}
+            }
+            let Some(tick_steps) = steps.next() else {
+                break;
+            };
+
+            wait_until_async(start + at, spin_threshold).await;
+            for (axis, step) in self.axes.iter_mut().zip(tick_steps) {
+                match step {
+                    1 => axis.step(Direction::CW)?,
+                    -1 => axis.step(Direction::CCW)?,
+                    _ => {}
+                }
+            }
+            ticks += 1;
+        }
+        Ok(outcome)
+    }
+
+    /// Applies the idle policy of every axis, see `MotionController::poll_idle`. All axes are
+    /// checked, the first failure is returned.
+    pub fn poll_idle(&mut self) -> Result<(), &'static str> {
+        let mut result = Ok(());
+        for axis in self.axes.iter_mut() {
+            if let Err(err) = axis.poll_idle() {
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Planner working in steps starting from the current position of the group
+    fn planner(&self) -> Planner {
+        let mut planner = Planner::new(vec![1.0; self.axes.len()]);
+        let _ = planner.set_position(&self.positions());
+        let _ = planner.set_junction_deviation(self.junction_deviation);
+        planner
+    }
+
+    fn queue(&self, planner: &mut Planner, target: &[i32]) -> Result<(), &'static str> {
+        self.queue_at(planner, target, self.speed)
+    }
+
+    fn queue_at(
+        &self,
+        planner: &mut Planner,
+        target: &[i32],
+        speed: f64,
+    ) -> Result<(), &'static str> {
+        if target.len() > self.axes.len() {
+            return Err("More target positions given than axes in the group");
+        }
+
+        let mut full_target: Vec<f64> = planner.position().iter().map(|p| *p as f64).collect();
+        for (axis, position) in target.iter().enumerate() {
+            self.check_soft_limits(axis, *position)?;
+            full_target[axis] = *position as f64;
+        }
+        planner.push(&full_target, speed, self.acceleration)
+    }
+
+    /// Clipping would bend the path, so every point of a path has to lie within the soft limits
+    fn check_soft_limits(&self, axis: usize, position: i32) -> Result<(), &'static str> {
+        match self.axes[axis].soft_limits() {
+            Some(limits) if !limits.contains(position) => {
+                Err("Move would exceed the soft limits of an axis")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the queued blocks until one of them ends early, the rest of the queue is dropped then
+    async fn run_planner(
+        &mut self,
+        planner: &mut Planner,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        while let Some(block) = planner.pop() {
+            let outcome = self.run_block_cancellable(&block, token).await?;
+            if outcome != MoveOutcome::Completed {
+                return Ok(outcome);
+            }
+        }
+        Ok(MoveOutcome::Completed)
+    }
+
+    /// Moves axis 0 and 1 along a circular arc around `center` until `end` is reached. Moving to
+    /// the current position makes a full circle.
+    pub async fn arc_to(
+        &mut self,
+        center: (f64, f64),
+        end: (i32, i32),
+        direction: Direction,
+    ) -> Result<(), &'static str> {
+        completed(
+            self.arc_to_cancellable(center, end, direction, &CancelToken::new())
+                .await,
+        )
+    }
+
+    /// Same as `arc_to` while `token` or the emergency stop can end the move early
+    pub async fn arc_to_cancellable(
+        &mut self,
+        center: (f64, f64),
+        end: (i32, i32),
+        direction: Direction,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        if self.axes.len() < 2 {
+            return Err("Arcs need at least two axes in the group");
+        }
+
+        let mut planner = self.planner();
+        for (x, y) in self.arc_plan(center, end, direction)? {
+            self.queue(&mut planner, &[x, y])?;
+        }
+        self.run_planner(&mut planner, token).await
+    }
+
+    /// Same as `arc_to` while axis 2 moves linearly to `end_z` over the length of the arc
+    pub async fn helix_to(
+        &mut self,
+        center: (f64, f64),
+        end: (i32, i32),
+        end_z: i32,
+        direction: Direction,
+    ) -> Result<(), &'static str> {
+        completed(
+            self.helix_to_cancellable(center, end, end_z, direction, &CancelToken::new())
+                .await,
+        )
+    }
+
+    /// Same as `helix_to` while `token` or the emergency stop can end the move early
+    pub async fn helix_to_cancellable(
+        &mut self,
+        center: (f64, f64),
+        end: (i32, i32),
+        end_z: i32,
+        direction: Direction,
+        token: &CancelToken,
+    ) -> Result<MoveOutcome, &'static str> {
+        if self.axes.len() < 3 {
+            return Err("Helices need at least three axes in the group");
+        }
+
+        let start_z = self.axes[2].position();
+        let points = self.arc_plan(center, end, direction)?;
+        let segments = points.len() as f64;
+
+        let mut planner = self.planner();
+        for (i, (x, y)) in points.into_iter().enumerate() {
+            let z = start_z + ((end_z - start_z) as f64 * (i + 1) as f64 / segments).round() as i32;
+            self.queue(&mut planner, &[x, y, z])?;
+        }
+        self.run_planner(&mut planner, token).await
+    }
+
+    /// Chord end points of an arc starting at the current XY position, rounded to whole steps
+    fn arc_plan(
+        &self,
+        center: (f64, f64),
+        end: (i32, i32),
+        direction: Direction,
+    ) -> Result<Vec<(i32, i32)>, &'static str> {
+        let start = (
+            self.axes[0].position() as f64,
+            self.axes[1].position() as f64,
+        );
+        let points = arc_points(
+            start,
+            center,
+            (end.0 as f64, end.1 as f64),
+            direction,
+            self.chord_tolerance,
+            Self::ARC_RADIUS_TOLERANCE,
+        )?;
+
+        Ok(points
+            .into_iter()
+            .map(|(x, y)| (x.round() as i32, y.round() as i32))
+            .collect())
+    }
+}
+
+/// Turns a move that ended early into an error for the methods without a token
+fn completed(outcome: Result<MoveOutcome, &'static str>) -> Result<(), &'static str> {
+    match outcome? {
+        MoveOutcome::Completed => Ok(()),
+        MoveOutcome::Stopped => Err("Move was stopped"),
+        MoveOutcome::Aborted => Err("Move was aborted"),
+        MoveOutcome::SwitchTriggered => Err("Move was ended by a limit switch"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepper::MockStepper;
+
+    /// Axis whose driver calls `on_step` with the number of steps made so far
+    fn get_mock_axis(
+        name: &str,
+        mut on_step: impl FnMut(u32) + Send + 'static,
+    ) -> MotionController<MockStepper> {
+        let mut steps = 0;
+        let mut stepper = MockStepper::default();
+        stepper.expect_set_steps_to_move().return_const(());
+        stepper.expect_set_enabled().returning(|_| Ok(()));
+        stepper.expect_set_direction().returning(|_| Ok(()));
+        stepper.expect_step().returning(move || {
+            steps += 1;
+            on_step(steps);
+            Ok(())
+        });
+        MotionController::new(name.to_owned(), stepper)
+    }
+
+    fn get_mock_group(axes: usize) -> MotionGroup<MockStepper> {
+        let axes = ["x", "y", "z"][..axes]
+            .iter()
+            .map(|name| get_mock_axis(name, |_| {}))
+            .collect();
+        let mut group = MotionGroup::new(axes);
+        group.set_speed(100000.0).unwrap();
+        group.set_acceleration(10000000.0).unwrap();
+        group
+    }
+
+    /// Group of X and Y where the 10th step of X requests `mode` on `token`
+    fn get_stopping_group(mode: StopMode, token: &CancelToken) -> MotionGroup<MockStepper> {
+        let token = token.clone();
+        let x = get_mock_axis("x", move |steps| {
+            if steps == 10 {
+                match mode {
+                    StopMode::Stop => token.stop(),
+                    StopMode::Abort => token.abort(),
+                }
+            }
+        });
+        let mut group = MotionGroup::new(vec![x, get_mock_axis("y", |_| {})]);
+        group.set_speed(1000.0).unwrap();
+        group.set_acceleration(100000.0).unwrap();
+        group
+    }
+
+    fn distance_to_circle(position: (i32, i32), center: (f64, f64), radius: f64) -> f64 {
+        let (x, y) = (position.0 as f64 - center.0, position.1 as f64 - center.1);
+        (x.hypot(y) - radius).abs()
+    }
+
+    #[tokio::test]
+    async fn line_to_reaches_target() {
+        let mut group = get_mock_group(3);
+
+        group.line_to(&[120, -37, 5]).await.unwrap();
+        assert_eq!(group.positions(), vec![120, -37, 5]);
+        group.line_to(&[100]).await.unwrap();
+        assert_eq!(group.positions(), vec![100, -37, 5]);
+        assert!(group.line_to(&[0, 0, 0, 0]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn arc_to_reaches_end() {
+        let mut group = get_mock_group(2);
+        group.line_to(&[100, 0]).await.unwrap();
+
+        group
+            .arc_to((0.0, 0.0), (0, 100), Direction::CCW)
+            .await
+            .unwrap();
+        assert_eq!(group.positions(), vec![0, 100]);
+        assert!(get_mock_group(1)
+            .arc_to((0.0, 0.0), (0, 100), Direction::CCW)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn arc_chords_within_tolerance() {
+        let mut group = get_mock_group(2);
+        group.set_chord_tolerance(0.25).unwrap();
+        group.line_to(&[1000, 0]).await.unwrap();
+
+        let radius = 1000.0;
+        let points = group
+            .arc_plan((0.0, 0.0), (-1000, 0), Direction::CCW)
+            .unwrap();
+        assert_eq!(*points.last().unwrap(), (-1000, 0));
+        // Rounding the chord ends to whole steps moves them up to half a step per axis
+        let rounding = 0.5f64.hypot(0.5);
+        let mut previous = (1000, 0);
+        for point in points {
+            assert!(distance_to_circle(point, (0.0, 0.0), radius) <= rounding);
+            let mid = (
+                (previous.0 + point.0) as f64 / 2.0,
+                (previous.1 + point.1) as f64 / 2.0,
+            );
+            assert!(radius - mid.0.hypot(mid.1) <= 0.25 + rounding);
+            previous = point;
+        }
+    }
+
+    #[tokio::test]
+    async fn helix_to_reaches_end() {
+        let mut group = get_mock_group(3);
+        group.line_to(&[100, 0, 0]).await.unwrap();
+
+        group
+            .helix_to((0.0, 0.0), (-100, 0), 40, Direction::CW)
+            .await
+            .unwrap();
+        assert_eq!(group.positions(), vec![-100, 0, 40]);
+        assert!(get_mock_group(2)
+            .helix_to((0.0, 0.0), (-100, 0), 40, Direction::CW)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn line_stop_stays_on_line() {
+        let token = CancelToken::new();
+        let mut group = get_stopping_group(StopMode::Stop, &token);
+
+        let outcome = group.line_to_cancellable(&[1000, 500], &token).await;
+        assert_eq!(outcome, Ok(MoveOutcome::Stopped));
+        let positions = group.positions();
+        assert!(positions[0] > 10 && positions[0] < 1000);
+        assert!((positions[1] - positions[0] / 2).abs() <= 1);
+        assert!(group.axis(0).unwrap().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn arc_stop_stays_on_arc() {
+        let token = CancelToken::new();
+        let mut group = get_stopping_group(StopMode::Stop, &token);
+
+        let outcome = group
+            .arc_to_cancellable((-100.0, 0.0), (-200, 0), Direction::CCW, &token)
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::Stopped));
+        let positions = group.positions();
+        assert!(positions[0] < -10 && positions[0] > -200 && positions[1] > 0);
+        // Chord error, rounding to whole steps and the step of the other axis still to come
+        assert!(distance_to_circle((positions[0], positions[1]), (-100.0, 0.0), 100.0) < 2.0);
+    }
+
+    #[tokio::test]
+    async fn helix_abort_stays_on_helix() {
+        let token = CancelToken::new();
+        let x = get_mock_axis("x", {
+            let token = token.clone();
+            move |steps| {
+                if steps == 30 {
+                    token.abort();
+                }
+            }
+        });
+        let axes = vec![x, get_mock_axis("y", |_| {}), get_mock_axis("z", |_| {})];
+        let mut group = MotionGroup::new(axes);
+        group.set_speed(100000.0).unwrap();
+        group.set_acceleration(10000000.0).unwrap();
+
+        let outcome = group
+            .helix_to_cancellable((-100.0, 0.0), (-200, 0), 100, Direction::CCW, &token)
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::Aborted));
+        let positions = group.positions();
+        assert!(positions[0] == -30 && positions[1] > 0);
+        assert!(positions[2] > 0 && positions[2] < 100);
+        assert!(distance_to_circle((positions[0], positions[1]), (-100.0, 0.0), 100.0) < 2.0);
+        assert!(!group.axis(0).unwrap().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn emergency_stop_aborts_group() {
+        let emergency_stop = EmergencyStop::new();
+        let mut group = get_mock_group(2);
+        group.set_emergency_stop(&emergency_stop);
+
+        emergency_stop.trigger();
+        let outcome = group
+            .line_to_cancellable(&[10, 10], &CancelToken::new())
+            .await;
+        assert_eq!(outcome, Ok(MoveOutcome::Aborted));
+        assert_eq!(group.positions(), vec![0, 0]);
+    }
+}
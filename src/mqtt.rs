@@ -0,0 +1,305 @@
+//! MQTT client of `stepperd` for fleets of machines, built with the `mqtt` feature. The topics are
+//! below a prefix that tells the machines apart, e.g. `stepperd/<hostname>`:
+//!
+//! - `<prefix>/online` is `true` while the daemon is connected, the broker publishes `false` when
+//!   the connection is lost. Retained like the telemetry.
+//! - `<prefix>/emergency_stop` is `true` or `false`
+//! - `<prefix>/axes/<axis>` is the `AxisTelemetry` of the axis as JSON, published when it changed.
+//!   Its `driver` holds the standstill, stealthChop, CS_ACTUAL, temperature and open load flags.
+//! - `<prefix>/command/<axis>/<action>` runs an action on one axis, `action` is one of the
+//!   `POST /axes/{axis}/{action}` actions of the `http` module. `<prefix>/command/<action>` with
+//!   `stop`, `emergency_stop` or `reset_emergency_stop` acts on every axis. The payload is a JSON
+//!   object with the parameters, it may be empty.
+//! - `<prefix>/reply/...` gets the JSON-RPC `Response` of each command, on the topic of the
+//!   command with `command` replaced by `reply`. An `id` in the command payload is returned in it.
+//!
+//! With a local mosquitto broker:
+//!
+//! ```text
+//! mosquitto_sub -v -t 'stepperd/#'
+//! mosquitto_pub -t stepperd/bench/command/x/move -m '{"steps": 800, "id": 1}'
+//! mosquitto_pub -t stepperd/bench/command/x/configure -m '{"current": 600}'
+//! ```
+use crate::rpc::{axis_method, Response, RpcError, Service, INVALID_REQUEST, METHOD_NOT_FOUND};
+use crate::stepper::{ConfigurableDriver, DiagnosticDriver, StepGenerator};
+use crate::telemetry::{spawn_telemetry, AxisTelemetry, Telemetry};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+pub const DEFAULT_PORT: u16 = 1883;
+/// Brokers are often shared by many machines so the telemetry is sampled less often than for the
+/// WebSocket feed
+pub const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before connecting again after the connection to the broker failed
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Connection to the broker as reported by `serve`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// The connection failed with this error, `serve` retries after `RECONNECT_DELAY`
+    Failed(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Command {
+    /// None for the commands for every axis
+    axis: Option<String>,
+    action: String,
+    reply: String,
+}
+
+fn parse_command(prefix: &str, topic: &str) -> Option<Command> {
+    let command = topic.strip_prefix(prefix)?.strip_prefix("/command/")?;
+    let (axis, action) = match command.split_once('/') {
+        Some((axis, action)) => (Some(axis.to_owned()), action),
+        None => (None, command),
+    };
+    if action.is_empty() || action.contains('/') {
+        return None;
+    }
+    Some(Command {
+        axis,
+        action: action.to_owned(),
+        reply: format!("{}/reply/{}", prefix, command),
+    })
+}
+
+async fn run_command<T>(service: &Service<T>, command: &Command, payload: &[u8]) -> Response
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let mut params = match payload.is_empty() {
+        true => Map::new(),
+        false => match serde_json::from_slice(payload) {
+            Ok(Value::Object(params)) => params,
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "Payload must be a JSON object");
+                return Response::new(Value::Null, Err(error));
+            }
+        },
+    };
+    let id = params.remove("id").unwrap_or(Value::Null);
+
+    let action = command.action.as_str();
+    let method = match &command.axis {
+        Some(axis) => {
+            let method = axis_method(action, &params);
+            params.insert("axis".to_owned(), Value::from(axis.as_str()));
+            method
+        }
+        None => match action {
+            "stop" | "emergency_stop" | "reset_emergency_stop" => Some(action),
+            _ => None,
+        },
+    };
+    let result = match method {
+        Some(method) => service.call(method, Value::Object(params)).await,
+        None => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown action '{}'", action),
+        )),
+    };
+    Response::new(id, result)
+}
+
+/// Publishes the frames that changed, or all of them after `republish` is notified
+async fn publish_telemetry(
+    client: AsyncClient,
+    prefix: String,
+    mut telemetry: watch::Receiver<Telemetry>,
+    republish: Arc<Notify>,
+) {
+    let mut emergency_stop = None;
+    let mut published: HashMap<String, AxisTelemetry> = HashMap::new();
+    loop {
+        let frame = telemetry.borrow_and_update().clone();
+        if emergency_stop != Some(frame.emergency_stop) {
+            let payload = frame.emergency_stop.to_string();
+            let topic = format!("{}/emergency_stop", prefix);
+            if client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            emergency_stop = Some(frame.emergency_stop);
+        }
+        for axis in frame.axes {
+            if published.get(&axis.name) == Some(&axis) {
+                continue;
+            }
+            let Ok(payload) = serde_json::to_vec(&axis) else {
+                continue;
+            };
+            let topic = format!("{}/axes/{}", prefix, axis.name);
+            if client
+                .publish(topic, QoS::AtMostOnce, true, payload)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            published.insert(axis.name.clone(), axis);
+        }
+
+        tokio::select! {
+            changed = telemetry.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            // A broker without persistence lost the retained messages when it restarted
+            _ = republish.notified() => {
+                emergency_stop = None;
+                published.clear();
+            }
+        }
+    }
+}
+
+/// Publishes the telemetry of `service` every `telemetry_interval` and runs the commands sent to
+/// it. Reconnects to the broker of `options` whenever the connection is lost, so it only ends
+/// when the task is dropped. `state` follows the connection, failed commands are only reported
+/// on their reply topic.
+pub async fn serve<T>(
+    service: Arc<Service<T>>,
+    mut options: MqttOptions,
+    prefix: &str,
+    telemetry_interval: Duration,
+    state: watch::Sender<ConnectionState>,
+) where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let online = format!("{}/online", prefix);
+    options.set_last_will(LastWill::new(&online, "false", QoS::AtLeastOnce, true));
+    let (client, mut events) = AsyncClient::new(options, 64);
+
+    let republish = Arc::new(Notify::new());
+    let telemetry = spawn_telemetry(service.clone(), telemetry_interval);
+    tokio::spawn(publish_telemetry(
+        client.clone(),
+        prefix.to_owned(),
+        telemetry,
+        republish.clone(),
+    ));
+
+    loop {
+        match events.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                state.send_replace(ConnectionState::Connected);
+                // The requests only go out while the events are polled, so they must not wait
+                // here for room in the queue. A clean session forgets the subscription.
+                let _ = client.try_subscribe(format!("{}/command/#", prefix), QoS::AtLeastOnce);
+                let _ = client.try_publish(&online, QoS::AtLeastOnce, true, "true");
+                republish.notify_one();
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let Some(command) = parse_command(prefix, &publish.topic) else {
+                    continue;
+                };
+                let service = service.clone();
+                let client = client.clone();
+                // Moves run until they end, commands for other axes must not wait for them
+                tokio::spawn(async move {
+                    let response = run_command(&service, &command, &publish.payload).await;
+                    if let Ok(payload) = serde_json::to_vec(&response) {
+                        let _ = client
+                            .publish(command.reply, QoS::AtLeastOnce, false, payload)
+                            .await;
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(err) => {
+                state.send_replace(ConnectionState::Failed(err.to_string()));
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                state.send_replace(ConnectionState::Connecting);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::EmergencyStop;
+    use crate::handle::test_axis;
+    use crate::stepper::MockStepper;
+
+    fn get_mock_service() -> Service<MockStepper> {
+        Service::new(vec![test_axis("x")], EmergencyStop::new())
+    }
+
+    #[test]
+    fn command_topics() {
+        let command = parse_command("stepperd/bench", "stepperd/bench/command/x/move").unwrap();
+        assert_eq!(command.axis.as_deref(), Some("x"));
+        assert_eq!(command.action, "move");
+        assert_eq!(command.reply, "stepperd/bench/reply/x/move");
+
+        let command = parse_command("stepperd/bench", "stepperd/bench/command/stop").unwrap();
+        assert_eq!(command.axis, None);
+        assert_eq!(command.reply, "stepperd/bench/reply/stop");
+
+        assert_eq!(
+            parse_command("stepperd/bench", "stepperd/bench/axes/x"),
+            None
+        );
+        assert_eq!(
+            parse_command("stepperd/bench", "stepperd/bench/command/x/"),
+            None
+        );
+        assert_eq!(
+            parse_command("stepperd/bench", "stepperd/bench/command/x/a/b"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let service = get_mock_service();
+        let command = |topic: &str| parse_command("fleet", topic).unwrap();
+
+        let response = run_command(
+            &service,
+            &command("fleet/command/x/move"),
+            br#"{"steps": 30, "id": 7}"#,
+        )
+        .await;
+        assert_eq!(response.id, Value::from(7));
+        assert_eq!(response.result.unwrap()["position"], 30);
+        let response = run_command(
+            &service,
+            &command("fleet/command/x/move"),
+            br#"{"position": 10}"#,
+        )
+        .await;
+        assert_eq!(response.result.unwrap()["position"], 10);
+
+        let response = run_command(&service, &command("fleet/command/x/explode"), b"").await;
+        assert_eq!(response.error.unwrap().code, METHOD_NOT_FOUND);
+        let response = run_command(&service, &command("fleet/command/move"), b"").await;
+        assert_eq!(response.error.unwrap().code, METHOD_NOT_FOUND);
+        let response = run_command(&service, &command("fleet/command/x/move"), b"30").await;
+        assert_eq!(response.error.unwrap().code, INVALID_REQUEST);
+
+        let response = run_command(&service, &command("fleet/command/emergency_stop"), b"").await;
+        assert!(response.error.is_none());
+        assert!(service.is_emergency_stopped());
+        let response = run_command(
+            &service,
+            &command("fleet/command/reset_emergency_stop"),
+            b"",
+        )
+        .await;
+        assert!(response.error.is_none());
+        assert!(!service.is_emergency_stopped());
+    }
+}
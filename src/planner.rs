@@ -0,0 +1,439 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// A straight segment queued in the planner. Speeds are in units/s and the acceleration in
+/// units/s^2 where a unit is whatever `steps_per_unit` of the planner was given in (e.g. mm).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Steps each axis has to make for this segment
+    pub steps: Vec<i32>,
+    pub distance: f64,
+    pub nominal_speed: f64,
+    pub acceleration: f64,
+    pub entry_speed: f64,
+    pub exit_speed: f64,
+    max_entry_speed: f64,
+}
+
+impl Block {
+    /// Amount of step ticks the block takes, every axis steps at most once per tick
+    pub fn ticks(&self) -> u32 {
+        self.steps
+            .iter()
+            .map(|steps| steps.unsigned_abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Highest speed reached in the block, lower than the nominal speed when the block is too
+    /// short to accelerate to it
+    pub fn peak_speed(&self) -> f64 {
+        let peak = ((2.0 * self.acceleration * self.distance
+            + self.entry_speed.powi(2)
+            + self.exit_speed.powi(2))
+            / 2.0)
+            .sqrt();
+        peak.min(self.nominal_speed)
+            .max(self.entry_speed)
+            .max(self.exit_speed)
+    }
+
+    /// Time from the start of the block at which each tick is due, following a trapezoidal
+    /// profile from the entry speed up to the peak speed and back down to the exit speed.
+    pub fn tick_times(&self) -> impl Iterator<Item = Duration> + '_ {
+        let ticks = self.ticks();
+        (1..=ticks).map(move |tick| {
+            Duration::from_secs_f64(self.time_at(self.distance * tick as f64 / ticks as f64))
+        })
+    }
+
+    /// Times at which the ticks following `tick` are due when the block is asked to stop right
+    /// after that tick, decelerating at the block's acceleration from the speed reached there.
+    /// Ticks that would need the motor to turn back are left out. When the block ends above
+    /// standstill the ticks run on past its end, callers following a multi axis path cut them off
+    /// at the end of the block. Times are from the start of the block.
+    pub fn stop_tick_times(&self, tick: u32) -> Vec<Duration> {
+        let ticks = self.ticks();
+        if ticks == 0 {
+            return Vec::new();
+        }
+
+        let tick_distance = self.distance / ticks as f64;
+        let stop_position = tick_distance * tick.min(ticks) as f64;
+        let stop_time = self.time_at(stop_position);
+        let speed = self.speed_at(stop_position);
+
+        (tick + 1..)
+            .map_while(|next| {
+                let braking = tick_distance * (next - tick) as f64;
+                let remaining = speed.powi(2) - 2.0 * self.acceleration * braking;
+                if remaining < 0.0 {
+                    return None;
+                }
+                let braked = stop_time + (speed - remaining.sqrt()) / self.acceleration;
+                // The profile may already be slower than braking, e.g. while decelerating
+                let planned = match next <= ticks {
+                    true => self.time_at(tick_distance * next as f64),
+                    false => 0.0,
+                };
+                Some(Duration::from_secs_f64(braked.max(planned)))
+            })
+            .collect()
+    }
+
+    /// Speed in units/s `position` units into the block
+    fn speed_at(&self, position: f64) -> f64 {
+        let accelerated = (self.entry_speed.powi(2) + 2.0 * self.acceleration * position).sqrt();
+        let decelerated = (self.exit_speed.powi(2)
+            + 2.0 * self.acceleration * (self.distance - position).max(0.0))
+        .sqrt();
+        accelerated.min(decelerated).min(self.peak_speed())
+    }
+
+    /// Seconds taken to travel `position` units into the block
+    fn time_at(&self, position: f64) -> f64 {
+        let acceleration = self.acceleration;
+        let peak = self.peak_speed();
+        let accelerate_distance =
+            ((peak.powi(2) - self.entry_speed.powi(2)) / (2.0 * acceleration)).max(0.0);
+        let decelerate_distance =
+            ((peak.powi(2) - self.exit_speed.powi(2)) / (2.0 * acceleration)).max(0.0);
+        let cruise_distance = (self.distance - accelerate_distance - decelerate_distance).max(0.0);
+        let accelerate_time = (peak - self.entry_speed) / acceleration;
+        let cruise_time = cruise_distance / peak;
+
+        if position <= accelerate_distance {
+            let speed = (self.entry_speed.powi(2) + 2.0 * acceleration * position).sqrt();
+            (speed - self.entry_speed) / acceleration
+        } else if position <= accelerate_distance + cruise_distance {
+            accelerate_time + (position - accelerate_distance) / peak
+        } else {
+            let decelerated = position - accelerate_distance - cruise_distance;
+            let speed = (peak.powi(2) - 2.0 * acceleration * decelerated)
+                .max(0.0)
+                .sqrt();
+            accelerate_time + cruise_time + (peak - speed) / acceleration
+        }
+    }
+}
+
+/// Queue of segments with lookahead. Every time a segment is added the junction speeds of the
+/// whole queue are recalculated (backward and forward pass) so consecutive segments flow into
+/// each other without stopping, while the last queued segment always ends at standstill.
+pub struct Planner {
+    blocks: VecDeque<Block>,
+    steps_per_unit: Vec<f64>,
+    junction_deviation: f64,
+    position: Vec<i32>,
+    previous_unit_vector: Option<Vec<f64>>,
+    previous_nominal_speed: f64,
+}
+
+impl Planner {
+    /// Default junction deviation in units, the same default grbl uses for mm
+    pub const DEFAULT_JUNCTION_DEVIATION: f64 = 0.01;
+
+    pub fn new(steps_per_unit: Vec<f64>) -> Self {
+        let axes = steps_per_unit.len();
+        Self {
+            blocks: VecDeque::new(),
+            steps_per_unit,
+            junction_deviation: Self::DEFAULT_JUNCTION_DEVIATION,
+            position: vec![0; axes],
+            previous_unit_vector: None,
+            previous_nominal_speed: 0.0,
+        }
+    }
+
+    /// Sets how far (in units) the path may deviate from a sharp corner, larger values allow
+    /// faster cornering
+    pub fn set_junction_deviation(&mut self, junction_deviation: f64) -> Result<(), &'static str> {
+        if junction_deviation < 0.0 {
+            return Err("Junction deviation can not be negative");
+        }
+        self.junction_deviation = junction_deviation;
+        Ok(())
+    }
+
+    /// Sets the position in steps the next segment starts from, only allowed while the queue is
+    /// empty as the queued segments are relative to it
+    pub fn set_position(&mut self, position: &[i32]) -> Result<(), &'static str> {
+        if !self.blocks.is_empty() {
+            return Err("Position can only be set while the planner queue is empty");
+        }
+        if position.len() != self.position.len() {
+            return Err("Position does not match the amount of planner axes");
+        }
+        self.position = position.to_vec();
+        self.previous_unit_vector = None;
+        Ok(())
+    }
+
+    /// Position in steps at the end of the last queued segment
+    pub fn position(&self) -> &[i32] {
+        &self.position
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Queues a straight move to `target` (in units) at up to `speed` units/s
+    pub fn push(
+        &mut self,
+        target: &[f64],
+        speed: f64,
+        acceleration: f64,
+    ) -> Result<(), &'static str> {
+        if target.len() != self.steps_per_unit.len() {
+            return Err("Target does not match the amount of planner axes");
+        }
+        if speed <= 0.0 || acceleration <= 0.0 {
+            return Err("Speed and acceleration must be greater than zero");
+        }
+
+        let target_steps: Vec<i32> = target
+            .iter()
+            .zip(self.steps_per_unit.iter())
+            .map(|(target, steps_per_unit)| (target * steps_per_unit).round() as i32)
+            .collect();
+        let steps: Vec<i32> = target_steps
+            .iter()
+            .zip(self.position.iter())
+            .map(|(target, position)| target - position)
+            .collect();
+        let deltas: Vec<f64> = steps
+            .iter()
+            .zip(self.steps_per_unit.iter())
+            .map(|(steps, steps_per_unit)| *steps as f64 / steps_per_unit)
+            .collect();
+        let distance = deltas.iter().map(|delta| delta * delta).sum::<f64>().sqrt();
+
+        if steps.iter().all(|steps| *steps == 0) {
+            return Ok(());
+        }
+
+        let unit_vector: Vec<f64> = deltas.iter().map(|delta| delta / distance).collect();
+        let max_entry_speed = match &self.previous_unit_vector {
+            Some(previous) if !self.blocks.is_empty() => self
+                .junction_speed(previous, &unit_vector, acceleration)
+                .min(speed)
+                .min(self.previous_nominal_speed),
+            _ => 0.0,
+        };
+
+        self.blocks.push_back(Block {
+            steps,
+            distance,
+            nominal_speed: speed,
+            acceleration,
+            entry_speed: 0.0,
+            exit_speed: 0.0,
+            max_entry_speed,
+        });
+        self.position = target_steps;
+        self.previous_unit_vector = Some(unit_vector);
+        self.previous_nominal_speed = speed;
+        self.recalculate();
+        Ok(())
+    }
+
+    /// Takes the next segment to execute. Its speeds are final, the segment after it will start
+    /// at the exit speed of this one.
+    pub fn pop(&mut self) -> Option<Block> {
+        let block = self.blocks.pop_front();
+        if self.blocks.is_empty() {
+            self.previous_unit_vector = None;
+        }
+        block
+    }
+
+    /// Highest speed at which the corner between two segments can be taken while staying within
+    /// the junction deviation
+    fn junction_speed(&self, previous: &[f64], next: &[f64], acceleration: f64) -> f64 {
+        let cos_theta: f64 = -previous
+            .iter()
+            .zip(next.iter())
+            .map(|(previous, next)| previous * next)
+            .sum::<f64>();
+
+        if cos_theta > 0.999999 {
+            // Full reversal
+            return 0.0;
+        }
+        if cos_theta < -0.999999 {
+            // Straight continuation
+            return f64::MAX;
+        }
+
+        let sin_theta_d2 = (0.5 * (1.0 - cos_theta)).sqrt();
+        (acceleration * self.junction_deviation * sin_theta_d2 / (1.0 - sin_theta_d2)).sqrt()
+    }
+
+    fn recalculate(&mut self) {
+        let count = self.blocks.len();
+
+        // Backward pass: every block must be able to slow down to the entry of the one after it,
+        // the last block ends at standstill. The first block keeps its entry speed as the block
+        // before it may already be running.
+        let mut next_entry = 0.0;
+        for i in (1..count).rev() {
+            let block = &mut self.blocks[i];
+            let reachable =
+                (next_entry * next_entry + 2.0 * block.acceleration * block.distance).sqrt();
+            block.entry_speed = block.max_entry_speed.min(reachable);
+            next_entry = block.entry_speed;
+        }
+
+        // Forward pass: limit entry speeds to what can be reached from the block before
+        for i in 0..count {
+            let block = &self.blocks[i];
+            let reachable =
+                (block.entry_speed.powi(2) + 2.0 * block.acceleration * block.distance).sqrt();
+            let exit = match self.blocks.get(i + 1) {
+                Some(next) => next.entry_speed.min(reachable),
+                None => 0.0,
+            };
+
+            self.blocks[i].exit_speed = exit;
+            if let Some(next) = self.blocks.get_mut(i + 1) {
+                next.entry_speed = exit;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speeds(planner: &mut Planner) -> Vec<(f64, f64)> {
+        let mut speeds = Vec::new();
+        while let Some(block) = planner.pop() {
+            speeds.push((block.entry_speed, block.exit_speed));
+        }
+        speeds
+    }
+
+    #[test]
+    fn single_block_starts_and_stops() {
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[100.0], 50.0, 100.0).unwrap();
+
+        assert_eq!(speeds(&mut planner), vec![(0.0, 0.0)]);
+    }
+
+    #[test]
+    fn straight_segments_do_not_stop() {
+        let mut planner = Planner::new(vec![10.0, 10.0]);
+        planner.push(&[10.0, 0.0], 20.0, 100.0).unwrap();
+        planner.push(&[20.0, 0.0], 20.0, 100.0).unwrap();
+        planner.push(&[30.0, 0.0], 20.0, 100.0).unwrap();
+
+        let speeds = speeds(&mut planner);
+        assert_eq!(speeds[0].0, 0.0);
+        assert_eq!(speeds[0].1, 20.0);
+        assert_eq!(speeds[1], (20.0, 20.0));
+        assert_eq!(speeds[2], (20.0, 0.0));
+    }
+
+    #[test]
+    fn reversal_stops_at_junction() {
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[100.0], 50.0, 100.0).unwrap();
+        planner.push(&[0.0], 50.0, 100.0).unwrap();
+
+        assert_eq!(speeds(&mut planner), vec![(0.0, 0.0), (0.0, 0.0)]);
+    }
+
+    #[test]
+    fn corner_slows_down() {
+        let mut planner = Planner::new(vec![1.0, 1.0]);
+        planner.push(&[100.0, 0.0], 50.0, 100.0).unwrap();
+        planner.push(&[100.0, 100.0], 50.0, 100.0).unwrap();
+
+        let speeds = speeds(&mut planner);
+        assert!(speeds[0].1 > 0.0 && speeds[0].1 < 50.0);
+        assert_eq!(speeds[0].1, speeds[1].0);
+    }
+
+    #[test]
+    fn short_segments_limited_by_acceleration() {
+        let mut planner = Planner::new(vec![1.0]);
+        for target in 1..=4 {
+            planner.push(&[target as f64], 1000.0, 50.0).unwrap();
+        }
+
+        // Each block can change speed by at most sqrt(2 * a * d) = 10
+        let speeds = speeds(&mut planner);
+        assert_eq!(speeds[0], (0.0, 10.0));
+        assert!((speeds[1].1 - 200.0_f64.sqrt()).abs() < 1e-9);
+        assert_eq!(speeds[3], (10.0, 0.0));
+    }
+
+    #[test]
+    fn zero_length_segment_ignored() {
+        let mut planner = Planner::new(vec![1.0, 1.0]);
+        planner.push(&[0.0, 0.0], 10.0, 10.0).unwrap();
+        assert!(planner.is_empty());
+    }
+
+    #[test]
+    fn tick_times_follow_trapezoid() {
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[100.0], 10.0, 10.0).unwrap();
+        let block = planner.pop().unwrap();
+        let times: Vec<f64> = block.tick_times().map(|time| time.as_secs_f64()).collect();
+
+        // 1 s to accelerate over 5 units, 9 s cruising over 90 units and 1 s to stop
+        assert_eq!(times.len(), 100);
+        assert!((times[4] - 1.0).abs() < 1e-9);
+        assert!((times[94] - 10.0).abs() < 1e-9);
+        assert!((times[99] - 11.0).abs() < 1e-9);
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn stop_tick_times_decelerate() {
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[100.0], 10.0, 10.0).unwrap();
+        let block = planner.pop().unwrap();
+        let times: Vec<f64> = block
+            .stop_tick_times(50)
+            .iter()
+            .map(|time| time.as_secs_f64())
+            .collect();
+
+        // Tick 50 is reached cruising at 10 units/s after 5.5 s, stopping takes 5 units and 1 s
+        assert_eq!(times.len(), 5);
+        assert!((times[4] - 6.5).abs() < 1e-9);
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(block.stop_tick_times(0).is_empty());
+        assert!(block.stop_tick_times(100).is_empty());
+    }
+
+    #[test]
+    fn stop_tick_times_run_past_block() {
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[100.0], 10.0, 10.0).unwrap();
+        planner.push(&[200.0], 10.0, 10.0).unwrap();
+        let block = planner.pop().unwrap();
+
+        // The block ends cruising into the next one, braking takes 5 more ticks
+        assert_eq!(block.exit_speed, 10.0);
+        assert_eq!(block.stop_tick_times(98).len(), 5);
+    }
+
+    #[test]
+    fn set_position_needs_empty_queue() {
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[10.0], 10.0, 10.0).unwrap();
+        assert!(planner.set_position(&[0]).is_err());
+        planner.pop();
+        assert!(planner.set_position(&[0]).is_ok());
+    }
+}
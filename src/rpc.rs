@@ -0,0 +1,703 @@
+//! JSON-RPC 2.0 control API over a Unix socket. The `stepperd` daemon owns the UART and GPIO chip
+//! and serves the axes with `Service`, applications on the same machine share the motors through
+//! `client::Client`. Requests and responses are JSON objects, one per line.
+pub mod client;
+
+use crate::cancel::{CancelToken, EmergencyStop};
+use crate::handle::{self, AxisHandle, MoveHandle, MoveReport};
+use crate::motion_controller::HomingConfig;
+use crate::stepper::{
+    ConfigurableDriver, DiagnosticDriver, Direction, DriverStatus, StepGenerator,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+pub const DEFAULT_SOCKET: &str = "/run/stepperd.sock";
+/// Interval of the driver status reads of the axes served by `stepperd`, see
+/// `MotionController::set_status_interval`
+pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_millis(200);
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The axis or its driver refused the request, the message tells why
+pub const AXIS_ERROR: i64 = -32000;
+/// The client could not reach the daemon or the daemon closed the connection
+pub const CONNECTION_ERROR: i64 = -32001;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// Requests without an id are notifications and get no response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0".to_owned(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn axis(message: &str) -> Self {
+        Self::new(AXIS_ERROR, message)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Result of the `status` method
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AxisStatus {
+    pub name: String,
+    pub position: i32,
+    pub enabled: bool,
+    pub moving: bool,
+    pub max_speed: f64,
+    pub acceleration: f64,
+    /// Last status read by the axis, None before the first read, see `DEFAULT_STATUS_INTERVAL`
+    pub driver: Option<DriverStatus>,
+    /// Following error fault of the encoder, see `clear_faults`
+    pub fault: bool,
+    /// Why the last jog failed, cleared by the next jog
+    pub jog_error: Option<String>,
+}
+
+/// Settings changed by the `configure` method, settings left out keep their value
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_speed: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acceleration: Option<f64>,
+    /// RMS run current in mA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub microsteps: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AxisParams {
+    axis: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MoveParams {
+    axis: String,
+    steps: i32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PositionParams {
+    axis: String,
+    position: i32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JogParams {
+    axis: String,
+    /// Steps/s, the sign gives the direction
+    speed: f64,
+}
+
+/// Homing sequence of the `home` method, the defaults match the `stepper home` command
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HomeParams {
+    axis: String,
+    #[serde(default = "HomeParams::direction")]
+    direction: Direction,
+    #[serde(default = "HomeParams::fast_speed")]
+    fast_speed: f64,
+    #[serde(default = "HomeParams::slow_speed")]
+    slow_speed: f64,
+    #[serde(default = "HomeParams::back_off")]
+    back_off: i32,
+    #[serde(default = "HomeParams::max_travel")]
+    max_travel: i32,
+    #[serde(default)]
+    home_position: i32,
+}
+
+impl HomeParams {
+    fn direction() -> Direction {
+        Direction::CCW
+    }
+
+    fn fast_speed() -> f64 {
+        400.0
+    }
+
+    fn slow_speed() -> f64 {
+        50.0
+    }
+
+    fn back_off() -> i32 {
+        50
+    }
+
+    fn max_travel() -> i32 {
+        100_000
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StopParams {
+    /// Every axis when left out
+    #[serde(default)]
+    axis: Option<String>,
+    #[serde(default)]
+    abort: bool,
+}
+
+#[derive(Deserialize)]
+struct ConfigureParams {
+    axis: String,
+    #[serde(flatten)]
+    settings: Settings,
+}
+
+fn params<P: DeserializeOwned>(params: Value) -> Result<P, RpcError> {
+    // Methods without parameters are also called with an empty object
+    let params = if params.is_null() {
+        Value::Object(Default::default())
+    } else {
+        params
+    };
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn to_value<R: Serialize>(result: R) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|err| RpcError::axis(&err.to_string()))
+}
+
+/// Method for an action on one axis of the HTTP and MQTT APIs, None for actions they do not
+/// offer. `move` with a `position` is an absolute move.
+pub fn axis_method<'a>(action: &'a str, params: &Map<String, Value>) -> Option<&'a str> {
+    match action {
+        "move" if params.contains_key("position") => Some("move_to"),
+        "move" | "jog" | "home" | "stop" | "configure" | "enable" | "disable" | "set_position"
+        | "clear_faults" => Some(action),
+        _ => None,
+    }
+}
+
+/// Serves the methods of the control API on a set of axes. Requests of different clients run
+/// concurrently, the requests for one axis run in the order they arrive, see `AxisHandle`.
+///
+/// Methods:
+/// - `axes` -> names of the axes
+/// - `status {axis}` -> `AxisStatus`
+/// - `move {axis, steps}` and `move_to {axis, position}` -> `MoveReport` once the move ended
+/// - `jog {axis, speed}` starts a velocity move in steps/s and returns right away, a failure
+///   shows up in the `jog_error` of the status
+/// - `home {axis, direction?, fast_speed?, slow_speed?, back_off?, max_travel?, home_position?}`
+///   -> position once the home switch was found, `stop` fails it
+/// - `stop {axis?, abort?}` stops the queued and running moves, of every axis without `axis`
+/// - `set_position {axis, position}`, `enable {axis}`, `disable {axis}`
+/// - `configure {axis, max_speed?, acceleration?, current?, microsteps?}`
+/// - `clear_faults {axis}` clears the driver and following error faults
+/// - `emergency_stop` -> where each axis stopped, `reset_emergency_stop`
+pub struct Service<T> {
+    axes: Vec<AxisHandle<T>>,
+    emergency_stop: EmergencyStop,
+    moves: Arc<MoveTokens>,
+    /// Failures of the last jog of each axis, as jogs end after their request returned
+    jog_errors: Arc<Mutex<HashMap<String, &'static str>>>,
+}
+
+/// Tokens of the moves queued or running on each axis, so `stop` can reach them
+#[derive(Default)]
+struct MoveTokens {
+    next_id: AtomicU64,
+    tokens: Mutex<HashMap<String, Vec<(u64, CancelToken)>>>,
+}
+
+impl MoveTokens {
+    fn add(&self, axis: &str, token: CancelToken) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.entry(axis.to_owned()).or_default().push((id, token));
+        id
+    }
+
+    fn remove(&self, axis: &str, id: u64) {
+        if let Some(tokens) = self.tokens.lock().unwrap().get_mut(axis) {
+            tokens.retain(|(move_id, _)| *move_id != id);
+        }
+    }
+
+    /// Stops the moves of `axis`, of every axis when None
+    fn stop(&self, axis: Option<&str>, abort: bool) {
+        let tokens = self.tokens.lock().unwrap();
+        let tokens = tokens
+            .iter()
+            .filter(|(name, _)| axis.is_none_or(|axis| axis == name.as_str()))
+            .flat_map(|(_, tokens)| tokens);
+        for (_, token) in tokens {
+            match abort {
+                true => token.abort(),
+                false => token.stop(),
+            }
+        }
+    }
+}
+
+impl<T> Service<T>
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    /// The axes must have been given `emergency_stop` before they were spawned
+    pub fn new(axes: Vec<AxisHandle<T>>, emergency_stop: EmergencyStop) -> Self {
+        Self {
+            axes,
+            emergency_stop,
+            moves: Arc::default(),
+            jog_errors: Arc::default(),
+        }
+    }
+
+    pub fn axes(&self) -> &[AxisHandle<T>] {
+        &self.axes
+    }
+
+    pub fn is_emergency_stopped(&self) -> bool {
+        self.emergency_stop.is_triggered()
+    }
+
+    /// Accepts clients on `listener` until it fails
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(self.clone().serve_client(stream));
+        }
+    }
+
+    async fn serve_client(self: Arc<Self>, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        let (responses, mut outgoing) = mpsc::unbounded_channel::<Response>();
+
+        // Responses are written as their requests finish, a long move does not hold back a stop
+        let write = tokio::spawn(async move {
+            while let Some(response) = outgoing.recv().await {
+                let Ok(mut line) = serde_json::to_string(&response) else {
+                    continue;
+                };
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let service = self.clone();
+            let responses = responses.clone();
+            tokio::spawn(async move {
+                if let Some(response) = service.handle_line(&line).await {
+                    let _ = responses.send(response);
+                }
+            });
+        }
+        drop(responses);
+        let _ = write.await;
+    }
+
+    /// Response to one line of a client, None for notifications
+    pub async fn handle_line(&self, line: &str) -> Option<Response> {
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(err) => {
+                let error = RpcError::new(PARSE_ERROR, err.to_string());
+                return Some(Response::new(Value::Null, Err(error)));
+            }
+        };
+        let id = value.get("id").cloned().unwrap_or(Value::Null);
+        match serde_json::from_value::<Request>(value) {
+            Ok(request) if request.jsonrpc == "2.0" => self.handle(request).await,
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request");
+                Some(Response::new(id, Err(error)))
+            }
+        }
+    }
+
+    pub async fn handle(&self, request: Request) -> Option<Response> {
+        let result = self.call(&request.method, request.params).await;
+        request.id.map(|id| Response::new(id, result))
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "axes" => to_value(self.axes.iter().map(|a| a.name()).collect::<Vec<_>>()),
+            "status" => {
+                let params: AxisParams = self::params(params)?;
+                to_value(self.status(self.axis(&params.axis)?))
+            }
+            "move" => {
+                let params: MoveParams = self::params(params)?;
+                let axis = self.axis(&params.axis)?;
+                let handle = axis.start_move(params.steps).map_err(RpcError::axis)?;
+                to_value(self.finish_move(axis, handle).await?)
+            }
+            "move_to" => {
+                let params: PositionParams = self::params(params)?;
+                let axis = self.axis(&params.axis)?;
+                let handle = axis
+                    .start_move_to(params.position)
+                    .map_err(RpcError::axis)?;
+                to_value(self.finish_move(axis, handle).await?)
+            }
+            "stop" => {
+                let params: StopParams = self::params(params)?;
+                if let Some(axis) = &params.axis {
+                    self.axis(axis)?;
+                }
+                self.moves.stop(params.axis.as_deref(), params.abort);
+                Ok(Value::Null)
+            }
+            "jog" => {
+                let params: JogParams = self::params(params)?;
+                let axis = self.axis(&params.axis)?;
+                let handle = axis.start_velocity(params.speed).map_err(RpcError::axis)?;
+                let name = axis.name().to_owned();
+                let id = self.moves.add(&name, handle.token().clone());
+                let moves = self.moves.clone();
+                let jog_errors = self.jog_errors.clone();
+                jog_errors.lock().unwrap().remove(&name);
+                tokio::spawn(async move {
+                    let report = handle.finished().await;
+                    moves.remove(&name, id);
+                    if let Err(err) = report {
+                        jog_errors.lock().unwrap().insert(name, err);
+                    }
+                });
+                Ok(Value::Null)
+            }
+            "home" => {
+                let params: HomeParams = self::params(params)?;
+                let config = HomingConfig {
+                    direction: params.direction,
+                    fast_speed: params.fast_speed,
+                    slow_speed: params.slow_speed,
+                    back_off: params.back_off,
+                    max_travel: params.max_travel,
+                    home_position: params.home_position,
+                };
+                let axis = self.axis(&params.axis)?;
+                let handle = axis.start_home(config).map_err(RpcError::axis)?;
+                to_value(self.finish_move(axis, handle).await?.position)
+            }
+            "set_position" => {
+                let params: PositionParams = self::params(params)?;
+                let position = params.position;
+                self.axis(&params.axis)?
+                    .with(move |controller| controller.set_position(position))
+                    .await
+                    .and_then(|result| result)
+                    .map_err(RpcError::axis)?;
+                Ok(Value::Null)
+            }
+            "enable" | "disable" => {
+                let params: AxisParams = self::params(params)?;
+                let axis = self.axis(&params.axis)?;
+                match method {
+                    "enable" => axis.enable().await,
+                    _ => axis.disable().await,
+                }
+                .map_err(RpcError::axis)?;
+                Ok(Value::Null)
+            }
+            "configure" => {
+                let params: ConfigureParams = self::params(params)?;
+                let settings = params.settings;
+                self.axis(&params.axis)?
+                    .with(move |controller| {
+                        if let Some(max_speed) = settings.max_speed {
+                            controller.set_max_speed(max_speed)?;
+                        }
+                        if let Some(acceleration) = settings.acceleration {
+                            controller.set_acceleration(acceleration)?;
+                        }
+                        if let Some(current) = settings.current {
+                            controller.stepper_mut().set_motor_current(current)?;
+                        }
+                        if let Some(microsteps) = settings.microsteps {
+                            controller.stepper_mut().set_microsteps(microsteps)?;
+                        }
+                        Ok(())
+                    })
+                    .await
+                    .and_then(|result| result)
+                    .map_err(RpcError::axis)?;
+                Ok(Value::Null)
+            }
+            "clear_faults" => {
+                let params: AxisParams = self::params(params)?;
+                self.axis(&params.axis)?
+                    .with(|controller| {
+                        controller.stepper_mut().clear_faults()?;
+                        if controller.has_fault() {
+                            controller.clear_fault()?;
+                        }
+                        Ok(())
+                    })
+                    .await
+                    .and_then(|result| result)
+                    .map_err(RpcError::axis)?;
+                Ok(Value::Null)
+            }
+            "emergency_stop" => {
+                let positions: BTreeMap<String, Option<i32>> =
+                    handle::emergency_stop(&self.emergency_stop, &self.axes)
+                        .await
+                        .into_iter()
+                        .map(|(name, position)| (name, position.ok()))
+                        .collect();
+                to_value(positions)
+            }
+            "reset_emergency_stop" => {
+                self.emergency_stop.reset();
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method '{}'", method),
+            )),
+        }
+    }
+
+    fn axis(&self, name: &str) -> Result<&AxisHandle<T>, RpcError> {
+        self.axes
+            .iter()
+            .find(|axis| axis.name() == name)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Unknown axis '{}'", name)))
+    }
+
+    /// Status from the monitor, so it does not wait for a running move
+    fn status(&self, axis: &AxisHandle<T>) -> AxisStatus {
+        let monitor = axis.monitor();
+        AxisStatus {
+            name: axis.name().to_owned(),
+            position: monitor.position(),
+            enabled: monitor.is_enabled(),
+            moving: monitor.is_moving(),
+            max_speed: monitor.max_speed(),
+            acceleration: monitor.acceleration(),
+            driver: monitor.driver_status().map(|(status, _)| status),
+            fault: monitor.has_fault(),
+            jog_error: self
+                .jog_errors
+                .lock()
+                .unwrap()
+                .get(axis.name())
+                .map(|err| err.to_string()),
+        }
+    }
+
+    /// Waits for a move while `stop` can reach it
+    async fn finish_move(
+        &self,
+        axis: &AxisHandle<T>,
+        handle: MoveHandle<i32>,
+    ) -> Result<MoveReport<i32>, RpcError> {
+        let id = self.moves.add(axis.name(), handle.token().clone());
+        let report = handle.finished().await;
+        self.moves.remove(axis.name(), id);
+        report.map_err(RpcError::axis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client::Client;
+    use super::*;
+    use crate::cancel::MoveOutcome;
+    use crate::handle::{test_controller, test_stepper};
+    use crate::motion_controller::{LimitMode, SoftLimits};
+    use crate::stepper::MockStepper;
+
+    fn get_mock_axis(name: &str, emergency_stop: &EmergencyStop) -> AxisHandle<MockStepper> {
+        let mut stepper = test_stepper();
+        stepper
+            .expect_status()
+            .returning(|| Ok(DriverStatus::default()));
+        stepper.expect_set_motor_current().returning(|_| Ok(()));
+        let mut controller = test_controller(name, stepper);
+        controller.set_emergency_stop(emergency_stop);
+        controller.set_status_interval(Some(Duration::ZERO));
+        AxisHandle::spawn(controller).unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_calls() {
+        let path = std::env::temp_dir().join(format!("stepperd-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let emergency_stop = EmergencyStop::new();
+        let axes = vec![
+            get_mock_axis("x", &emergency_stop),
+            get_mock_axis("y", &emergency_stop),
+        ];
+        let service = Arc::new(Service::new(axes, emergency_stop));
+        tokio::spawn(service.serve(UnixListener::bind(&path).unwrap()));
+
+        let mut client = Client::connect(&path).await.unwrap();
+        assert_eq!(client.axes().await.unwrap(), vec!["x", "y"]);
+
+        let report = client.move_steps("x", 20).await.unwrap();
+        assert_eq!(report.outcome, MoveOutcome::Completed);
+        assert_eq!(report.position, 20);
+        assert_eq!(client.move_to("x", 5).await.unwrap().position, 5);
+        assert!(client.status("x").await.unwrap().driver.is_some());
+
+        let settings = Settings {
+            max_speed: Some(5000.0),
+            current: Some(600),
+            ..Default::default()
+        };
+        client.configure("y", &settings).await.unwrap();
+        let status = client.status("y").await.unwrap();
+        assert_eq!(status.position, 0);
+        assert_eq!(status.max_speed, 5000.0);
+
+        let err = client.status("z").await.unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+        let err = client.call::<Value>("spin", Value::Null).await.unwrap_err();
+        assert_eq!(err.code, METHOD_NOT_FOUND);
+
+        client.jog("y", 50000.0).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = client.status("y").await.unwrap();
+        assert!(status.moving && status.enabled);
+        client.stop(Some("y"), false).await.unwrap();
+        assert!(client.status("y").await.unwrap().position > 0);
+        client.set_position("y", 0).await.unwrap();
+
+        let positions = client.emergency_stop().await.unwrap();
+        assert_eq!(positions.get("x"), Some(&Some(5)));
+        let report = client.move_steps("y", 10).await.unwrap();
+        assert_eq!(report.outcome, MoveOutcome::Aborted);
+        client.reset_emergency_stop().await.unwrap();
+        assert_eq!(
+            client.move_steps("y", 10).await.unwrap().outcome,
+            MoveOutcome::Completed
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn invalid_requests() {
+        let service = Service::new(
+            vec![get_mock_axis("x", &EmergencyStop::new())],
+            EmergencyStop::new(),
+        );
+        let response = service.handle_line("{not json").await.unwrap();
+        assert_eq!(response.error.unwrap().code, PARSE_ERROR);
+        let response = service
+            .handle_line(r#"{"jsonrpc": "1.0", "id": 3, "method": "axes"}"#)
+            .await
+            .unwrap();
+        assert_eq!(response.id, Value::from(3));
+        assert_eq!(response.error.unwrap().code, INVALID_REQUEST);
+        let response = service
+            .handle_line(
+                r#"{"jsonrpc": "2.0", "id": 4, "method": "move", "params": {"axis": "x"}}"#,
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.error.unwrap().code, INVALID_PARAMS);
+        assert!(service
+            .handle_line(r#"{"jsonrpc": "2.0", "method": "enable", "params": {"axis": "x"}}"#)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn jog_failure_in_status() {
+        let emergency_stop = EmergencyStop::new();
+        let mut controller = test_controller("x", test_stepper());
+        controller.set_soft_limits(Some(SoftLimits {
+            min: 0,
+            max: 0,
+            mode: LimitMode::Reject,
+        }));
+        controller.set_emergency_stop(&emergency_stop);
+        let service = Service::new(vec![AxisHandle::spawn(controller).unwrap()], emergency_stop);
+
+        let jog = serde_json::json!({"axis": "x", "speed": 100.0});
+        service.call("jog", jog).await.unwrap();
+        let error = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(error) = service.status(&service.axes[0]).jog_error {
+                    return error;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(error, "Axis is at its soft limit");
+    }
+}
@@ -0,0 +1,167 @@
+//! Client of the `stepperd` control API. A client sends one request at a time, e.g. stopping a
+//! move another task is waiting for needs a second client.
+use super::{AxisStatus, Request, Response, RpcError, Settings, CONNECTION_ERROR};
+use crate::handle::MoveReport;
+use crate::stepper::Direction;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+fn connection_error(message: impl Into<String>) -> RpcError {
+    RpcError::new(CONNECTION_ERROR, message)
+}
+
+impl Client {
+    /// Connects to the daemon listening on `path`, usually `rpc::DEFAULT_SOCKET`
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, RpcError> {
+        let stream = UnixStream::connect(path.as_ref()).await.map_err(|err| {
+            connection_error(format!(
+                "Could not connect to {}: {}",
+                path.as_ref().display(),
+                err
+            ))
+        })?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    /// Calls `method` and waits for its result, for methods without their own function
+    pub async fn call<R: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: Value,
+    ) -> Result<R, RpcError> {
+        self.next_id += 1;
+        let id = Value::from(self.next_id);
+        let request = Request {
+            jsonrpc: "2.0".to_owned(),
+            id: Some(id.clone()),
+            method: method.to_owned(),
+            params,
+        };
+        let mut line = serde_json::to_string(&request)
+            .map_err(|err| RpcError::new(super::INVALID_REQUEST, err.to_string()))?;
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|err| connection_error(err.to_string()))?;
+
+        loop {
+            let line = self
+                .lines
+                .next_line()
+                .await
+                .map_err(|err| connection_error(err.to_string()))?
+                .ok_or_else(|| connection_error("Daemon closed the connection"))?;
+            let response: Response = serde_json::from_str(&line)
+                .map_err(|err| connection_error(format!("Invalid response: {}", err)))?;
+            // Answers to calls that were dropped before their response arrived
+            if response.id != id {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(error);
+            }
+            return serde_json::from_value(response.result.unwrap_or(Value::Null))
+                .map_err(|err| connection_error(format!("Invalid result: {}", err)));
+        }
+    }
+
+    pub async fn axes(&mut self) -> Result<Vec<String>, RpcError> {
+        self.call("axes", Value::Null).await
+    }
+
+    pub async fn status(&mut self, axis: &str) -> Result<AxisStatus, RpcError> {
+        self.call("status", json!({ "axis": axis })).await
+    }
+
+    /// Moves the given amount of steps and reports the position once the move ended
+    pub async fn move_steps(
+        &mut self,
+        axis: &str,
+        steps: i32,
+    ) -> Result<MoveReport<i32>, RpcError> {
+        self.call("move", json!({ "axis": axis, "steps": steps }))
+            .await
+    }
+
+    pub async fn move_to(
+        &mut self,
+        axis: &str,
+        position: i32,
+    ) -> Result<MoveReport<i32>, RpcError> {
+        self.call("move_to", json!({ "axis": axis, "position": position }))
+            .await
+    }
+
+    /// Starts a velocity move in steps/s that runs until it is stopped, returns right away
+    pub async fn jog(&mut self, axis: &str, speed: f64) -> Result<(), RpcError> {
+        self.call("jog", json!({ "axis": axis, "speed": speed }))
+            .await
+    }
+
+    /// Runs the homing sequence with its default speeds and distances towards `direction`,
+    /// `call` with the `home` method sets the others
+    pub async fn home(&mut self, axis: &str, direction: Direction) -> Result<i32, RpcError> {
+        self.call("home", json!({ "axis": axis, "direction": direction }))
+            .await
+    }
+
+    /// Stops the moves of `axis`, or of every axis when None. `abort` ends them right away and
+    /// disables the motors instead of decelerating.
+    pub async fn stop(&mut self, axis: Option<&str>, abort: bool) -> Result<(), RpcError> {
+        self.call("stop", json!({ "axis": axis, "abort": abort }))
+            .await
+    }
+
+    pub async fn set_position(&mut self, axis: &str, position: i32) -> Result<(), RpcError> {
+        self.call(
+            "set_position",
+            json!({ "axis": axis, "position": position }),
+        )
+        .await
+    }
+
+    pub async fn enable(&mut self, axis: &str) -> Result<(), RpcError> {
+        self.call("enable", json!({ "axis": axis })).await
+    }
+
+    pub async fn disable(&mut self, axis: &str) -> Result<(), RpcError> {
+        self.call("disable", json!({ "axis": axis })).await
+    }
+
+    pub async fn configure(&mut self, axis: &str, settings: &Settings) -> Result<(), RpcError> {
+        let mut params = serde_json::to_value(settings)
+            .map_err(|err| RpcError::new(super::INVALID_PARAMS, err.to_string()))?;
+        params["axis"] = Value::from(axis);
+        self.call("configure", params).await
+    }
+
+    pub async fn clear_faults(&mut self, axis: &str) -> Result<(), RpcError> {
+        self.call("clear_faults", json!({ "axis": axis })).await
+    }
+
+    /// Aborts every move and reports where each axis stopped, None when it could not tell
+    pub async fn emergency_stop(&mut self) -> Result<BTreeMap<String, Option<i32>>, RpcError> {
+        self.call("emergency_stop", Value::Null).await
+    }
+
+    pub async fn reset_emergency_stop(&mut self) -> Result<(), RpcError> {
+        self.call("reset_emergency_stop", Value::Null).await
+    }
+}
@@ -0,0 +1,704 @@
+use crate::cancel::{CancelToken, MoveOutcome, StopMode};
+use crate::interpolation::LineSteps;
+use crate::planner::Block;
+use crate::stepper::Direction;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Something that can emit step pulses for one or more axes, e.g. the STEP lines of the drivers.
+/// It is moved into the step thread so it must be `Send`.
+pub trait PulseOutput: Send {
+    /// Emits a single step pulse on `axis` moving in `direction`
+    fn pulse(&mut self, axis: usize, direction: Direction) -> Result<(), &'static str>;
+}
+
+impl<F> PulseOutput for F
+where
+    F: FnMut(usize, Direction) -> Result<(), &'static str> + Send,
+{
+    fn pulse(&mut self, axis: usize, direction: Direction) -> Result<(), &'static str> {
+        self(axis, direction)
+    }
+}
+
+/// A step pulse due `at` after the start of the batch it is part of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepEvent {
+    pub at: Duration,
+    pub axis: usize,
+    pub direction: Direction,
+}
+
+impl StepEvent {
+    /// Step events of every axis in a planner block, timed following the speed profile of the block
+    pub fn from_block(block: &Block) -> Vec<StepEvent> {
+        Self::from_ticks(LineSteps::new(&block.steps).zip(block.tick_times()))
+    }
+
+    /// Events of the ticks following `tick` when the block is asked to stop right after it, see
+    /// `Block::stop_tick_times`
+    pub fn stop_from_block(block: &Block, tick: u32) -> Vec<StepEvent> {
+        let remaining = LineSteps::new(&block.steps).skip(tick as usize);
+        Self::from_ticks(remaining.zip(block.stop_tick_times(tick)))
+    }
+
+    fn from_ticks<I>(ticks: I) -> Vec<StepEvent>
+    where
+        I: Iterator<Item = (Vec<i8>, Duration)>,
+    {
+        let mut events = Vec::new();
+
+        for (steps, at) in ticks {
+            for (axis, step) in steps.into_iter().enumerate() {
+                let direction = match step {
+                    1 => Direction::CW,
+                    -1 => Direction::CCW,
+                    _ => continue,
+                };
+                events.push(StepEvent {
+                    at,
+                    axis,
+                    direction,
+                });
+            }
+        }
+        events
+    }
+}
+
+/// Lets a running batch be stopped through a token
+pub struct Cancellation {
+    pub token: CancelToken,
+    /// Events replacing the rest of the batch when it is asked to stop after the given amount of
+    /// ticks, a tick being all events due at the same time
+    pub stop_events: Box<dyn FnOnce(u32) -> Vec<StepEvent> + Send>,
+}
+
+impl Cancellation {
+    /// Cancellation of the events of `block`, axis N of the block being `axes[N]` of the pulse
+    /// output
+    pub fn for_block(token: CancelToken, block: Block, axes: Vec<usize>) -> Self {
+        Self {
+            token,
+            stop_events: Box::new(move |tick| {
+                StepEvent::stop_from_block(&block, tick)
+                    .into_iter()
+                    .map(|event| StepEvent {
+                        axis: axes[event.axis],
+                        ..event
+                    })
+                    .collect()
+            }),
+        }
+    }
+
+    /// Cancellation of a single axis moving through `block` on `axis` of the pulse output. Unlike
+    /// `for_block` braking may run on past the end of the block.
+    pub fn for_axis(token: CancelToken, block: Block, axis: usize, direction: Direction) -> Self {
+        Self {
+            token,
+            stop_events: Box::new(move |tick| {
+                block
+                    .stop_tick_times(tick)
+                    .into_iter()
+                    .map(|at| StepEvent {
+                        at,
+                        axis,
+                        direction,
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// What a batch did, `steps` holds the net steps emitted on every axis of the pulse output. When
+/// the pulse output failed the batch ended there, `error` holds why and `steps` the steps emitted
+/// before.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult {
+    pub steps: Vec<i32>,
+    pub outcome: MoveOutcome,
+    pub error: Option<&'static str>,
+}
+
+impl BatchResult {
+    /// Net steps emitted on `axis`
+    pub fn steps(&self, axis: usize) -> i32 {
+        self.steps.get(axis).copied().unwrap_or(0)
+    }
+}
+
+/// How the step thread should be scheduled. Everything but the spin threshold needs the right
+/// privileges (root or CAP_SYS_NICE / CAP_IPC_LOCK) and is off by default.
+#[derive(Debug, Clone)]
+pub struct RealtimeConfig {
+    /// SCHED_FIFO priority (1-99), None keeps the normal scheduler
+    pub priority: Option<i32>,
+    /// CPU core the thread is pinned to
+    pub cpu: Option<usize>,
+    /// Locks all memory of the process with mlockall so page faults can not delay a pulse
+    pub lock_memory: bool,
+    /// The last part of every wait is busy-waited instead of slept as sleeping on Linux
+    /// overshoots by 50 µs or more
+    pub spin_threshold: Duration,
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        Self {
+            priority: None,
+            cpu: None,
+            lock_memory: false,
+            spin_threshold: Duration::from_micros(100),
+        }
+    }
+}
+
+/// Histogram with fixed bucket bounds in microseconds, the last bucket catches everything above
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    max: Duration,
+    total: Duration,
+    samples: u64,
+}
+
+impl Histogram {
+    /// Upper bounds (exclusive) of the buckets in µs
+    pub const BOUNDS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; Self::BOUNDS.len() + 1],
+            max: Duration::ZERO,
+            total: Duration::ZERO,
+            samples: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let micros = value.as_micros() as u64;
+        let bucket = Self::BOUNDS
+            .iter()
+            .position(|bound| micros < *bound)
+            .unwrap_or(Self::BOUNDS.len());
+
+        self.counts[bucket] += 1;
+        self.max = self.max.max(value);
+        self.total += value;
+        self.samples += 1;
+    }
+
+    /// Count per bucket, the last entry counts everything of 1000 µs and above
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Sum of the recorded values
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.samples {
+            0 => Duration::ZERO,
+            samples => Duration::from_nanos((self.total.as_nanos() / samples as u128) as u64),
+        }
+    }
+
+    /// Upper bound of the bucket holding the given percentile (0-100), None when it falls in the
+    /// open ended last bucket or nothing was recorded
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.samples == 0 {
+            return None;
+        }
+
+        let wanted = (self.samples as f64 * percentile / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return Self::BOUNDS
+                    .get(bucket)
+                    .map(|bound| Duration::from_micros(*bound));
+            }
+        }
+        None
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.max = self.max.max(other.max);
+        self.total += other.total;
+        self.samples += other.samples;
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Timing statistics of the pulses emitted by a step thread
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimingStats {
+    /// How late every pulse was compared to its scheduled time
+    pub latency: Histogram,
+    /// Difference between the scheduled and the actual time between consecutive pulses
+    pub jitter: Histogram,
+    pub pulses: u64,
+    pub errors: u64,
+}
+
+enum Job {
+    Run {
+        events: Vec<StepEvent>,
+        cancellation: Option<Cancellation>,
+        done: oneshot::Sender<BatchResult>,
+    },
+    Shutdown,
+}
+
+/// Dedicated thread generating step pulses from precomputed timestamps. Waits are slept until
+/// shortly before a pulse is due and busy-waited for the rest, optionally running with SCHED_FIFO
+/// pinned to a CPU so pulses are not delayed by the rest of the system or the tokio runtime.
+pub struct StepThread {
+    jobs: mpsc::Sender<Job>,
+    stats: Arc<Mutex<TimingStats>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StepThread {
+    /// Starts the thread, failing when the requested realtime settings could not be applied
+    pub fn spawn<O>(mut output: O, config: RealtimeConfig) -> Result<Self, &'static str>
+    where
+        O: PulseOutput + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (ready, started) = mpsc::channel();
+        let stats = Arc::new(Mutex::new(TimingStats::default()));
+        let thread_stats = stats.clone();
+
+        let handle = std::thread::Builder::new()
+            .name("step-generator".to_owned())
+            .spawn(move || {
+                let setup = apply_realtime(&config);
+                let failed = setup.is_err();
+                let _ = ready.send(setup);
+                if failed {
+                    return;
+                }
+
+                while let Ok(Job::Run {
+                    events,
+                    cancellation,
+                    done,
+                }) = receiver.recv()
+                {
+                    let result =
+                        run_events(&mut output, events, cancellation, &config, &thread_stats);
+                    let _ = done.send(result);
+                }
+            })
+            .map_err(|_| "Could not start the step thread")?;
+
+        started
+            .recv()
+            .map_err(|_| "Step thread stopped while starting")??;
+
+        Ok(Self {
+            jobs,
+            stats,
+            handle: Some(handle),
+        })
+    }
+
+    /// Queues a batch of events, the returned receiver resolves once the last pulse was emitted or
+    /// the batch was stopped through `cancellation`
+    pub fn submit(
+        &self,
+        events: Vec<StepEvent>,
+        cancellation: Option<Cancellation>,
+    ) -> Result<oneshot::Receiver<BatchResult>, &'static str> {
+        let (done, finished) = oneshot::channel();
+        self.jobs
+            .send(Job::Run {
+                events,
+                cancellation,
+                done,
+            })
+            .map_err(|_| "Step thread is not running")?;
+        Ok(finished)
+    }
+
+    /// Runs a batch of events and waits for it without blocking the async runtime
+    pub async fn run(&self, events: Vec<StepEvent>) -> Result<(), &'static str> {
+        match self.run_cancellable(events, None).await?.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as `run` while `cancellation` can stop the batch early. A failed pulse output is
+    /// returned in the `error` of the result so the steps emitted before are not lost.
+    pub async fn run_cancellable(
+        &self,
+        events: Vec<StepEvent>,
+        cancellation: Option<Cancellation>,
+    ) -> Result<BatchResult, &'static str> {
+        self.submit(events, cancellation)?
+            .await
+            .map_err(|_| "Step thread stopped while running")
+    }
+
+    /// Runs a batch of events blocking the calling thread, must not be used from async code
+    pub fn run_blocking(&self, events: Vec<StepEvent>) -> Result<(), &'static str> {
+        let result = self
+            .submit(events, None)?
+            .blocking_recv()
+            .map_err(|_| "Step thread stopped while running")?;
+        match result.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    pub fn stats(&self) -> TimingStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = TimingStats::default();
+    }
+}
+
+impl Drop for StepThread {
+    fn drop(&mut self) {
+        let _ = self.jobs.send(Job::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Waits until `deadline`, sleeping while more than `spin_threshold` remains and busy-waiting the
+/// rest
+pub fn wait_until(deadline: Instant, spin_threshold: Duration) {
+    let now = Instant::now();
+    if deadline > now + spin_threshold {
+        std::thread::sleep(deadline - now - spin_threshold);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+/// Resolution of the tokio timer, its sleeps end on the next millisecond tick
+const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+
+/// `wait_until` for async callers. Yields to the runtime with a tokio sleep while the deadline is
+/// further away than `spin_threshold` and the timer resolution, then waits the rest on the
+/// calling thread.
+pub async fn wait_until_async(deadline: Instant, spin_threshold: Duration) {
+    let wake = deadline.checked_sub(spin_threshold + TIMER_RESOLUTION);
+    if let Some(wake) = wake.filter(|wake| *wake > Instant::now()) {
+        tokio::time::sleep_until(wake.into()).await;
+    }
+    wait_until(deadline, spin_threshold);
+}
+
+/// Busy-waits for short delays such as step pulse widths where sleeping would overshoot
+pub fn busy_wait(duration: Duration) {
+    wait_until(Instant::now() + duration, duration);
+}
+
+fn run_events<O: PulseOutput>(
+    output: &mut O,
+    mut events: Vec<StepEvent>,
+    cancellation: Option<Cancellation>,
+    config: &RealtimeConfig,
+    stats: &Mutex<TimingStats>,
+) -> BatchResult {
+    let mut latency = Histogram::new();
+    let mut jitter = Histogram::new();
+    let mut pulses = 0;
+    let mut error = None;
+    let mut previous: Option<(Duration, Instant)> = None;
+    let mut steps: Vec<i32> = Vec::new();
+    let mut outcome = MoveOutcome::Completed;
+    let mut ticks = 0;
+    let mut index = 0;
+    let (token, mut stop_events) = match cancellation {
+        Some(cancellation) => (Some(cancellation.token), Some(cancellation.stop_events)),
+        None => (None, None),
+    };
+    let start = Instant::now();
+
+    while let Some(event) = events.get(index).copied() {
+        // Requests are only handled between ticks so the axes of a tick stay in sync
+        let new_tick = previous.is_none_or(|(previous_at, _)| previous_at != event.at);
+        if new_tick {
+            match token.as_ref().and_then(|token| token.requested()) {
+                Some(StopMode::Abort) => {
+                    outcome = MoveOutcome::Aborted;
+                    break;
+                }
+                Some(StopMode::Stop) => {
+                    if let Some(stop_events) = stop_events.take() {
+                        outcome = MoveOutcome::Stopped;
+                        events = stop_events(ticks);
+                        index = 0;
+                        continue;
+                    }
+                }
+                None => {}
+            }
+            ticks += 1;
+        }
+
+        let deadline = start + event.at;
+        wait_until(deadline, config.spin_threshold);
+        let now = Instant::now();
+
+        if let Err(err) = output.pulse(event.axis, event.direction) {
+            error = Some(err);
+            break;
+        }
+
+        if steps.len() <= event.axis {
+            steps.resize(event.axis + 1, 0);
+        }
+        steps[event.axis] += match event.direction {
+            Direction::CW => 1,
+            Direction::CCW => -1,
+        };
+
+        latency.record(now - deadline);
+        if let Some((previous_at, previous_time)) = previous {
+            let scheduled = event.at - previous_at;
+            let actual = now - previous_time;
+            jitter.record(match actual > scheduled {
+                true => actual - scheduled,
+                false => scheduled - actual,
+            });
+        }
+        previous = Some((event.at, now));
+        pulses += 1;
+        index += 1;
+    }
+
+    let mut stats = stats.lock().unwrap();
+    stats.latency.merge(&latency);
+    stats.jitter.merge(&jitter);
+    stats.pulses += pulses;
+    if error.is_some() {
+        stats.errors += 1;
+    }
+    BatchResult {
+        steps,
+        outcome,
+        error,
+    }
+}
+
+fn apply_realtime(config: &RealtimeConfig) -> Result<(), &'static str> {
+    if config.lock_memory {
+        let locked = unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) };
+        if locked != 0 {
+            return Err(
+                "Could not lock memory (mlockall), check CAP_IPC_LOCK or the memlock limit",
+            );
+        }
+    }
+
+    if let Some(cpu) = config.cpu {
+        let pinned = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if pinned != 0 {
+            return Err("Could not pin the step thread to the requested CPU");
+        }
+    }
+
+    if let Some(priority) = config.priority {
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        let scheduled = unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) };
+        if scheduled != 0 {
+            return Err("Could not switch the step thread to SCHED_FIFO, check CAP_SYS_NICE");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::Planner;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new();
+        histogram.record(Duration::from_nanos(500));
+        histogram.record(Duration::from_micros(7));
+        histogram.record(Duration::from_micros(7));
+        histogram.record(Duration::from_millis(3));
+
+        assert_eq!(histogram.counts(), &[1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.samples(), 4);
+        assert_eq!(histogram.max(), Duration::from_millis(3));
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(10)));
+        assert_eq!(histogram.percentile(100.0), None);
+        assert_eq!(histogram.mean(), Duration::from_nanos(753625));
+
+        // Counts past u32::MAX must not wrap in the division
+        histogram.samples = 1 << 32;
+        histogram.total = Duration::from_secs(1 << 32);
+        assert_eq!(histogram.mean(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn events_from_block() {
+        let mut planner = Planner::new(vec![1.0, 1.0]);
+        planner.push(&[4.0, -2.0], 100.0, 1000.0).unwrap();
+        let events = StepEvent::from_block(&planner.pop().unwrap());
+
+        let x: Vec<&StepEvent> = events.iter().filter(|event| event.axis == 0).collect();
+        let y: Vec<&StepEvent> = events.iter().filter(|event| event.axis == 1).collect();
+        assert_eq!(x.len(), 4);
+        assert_eq!(y.len(), 2);
+        assert!(y.iter().all(|event| event.direction == Direction::CCW));
+        assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    }
+
+    #[test]
+    fn runs_events_in_order() {
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        let output_emitted = emitted.clone();
+        let thread = StepThread::spawn(
+            move |axis: usize, _direction: Direction| {
+                output_emitted.lock().unwrap().push(axis);
+                Ok(())
+            },
+            RealtimeConfig::default(),
+        )
+        .unwrap();
+
+        let events = (0..20)
+            .map(|i| StepEvent {
+                at: Duration::from_micros(200 * i),
+                axis: i as usize % 2,
+                direction: Direction::CW,
+            })
+            .collect();
+        thread.run_blocking(events).unwrap();
+
+        assert_eq!(emitted.lock().unwrap().len(), 20);
+        let stats = thread.stats();
+        assert_eq!(stats.pulses, 20);
+        assert_eq!(stats.latency.samples(), 20);
+        assert_eq!(stats.jitter.samples(), 19);
+    }
+
+    #[test]
+    fn output_error_stops_batch() {
+        let thread = StepThread::spawn(
+            |axis: usize, _direction: Direction| match axis {
+                0 => Ok(()),
+                _ => Err("Axis not connected"),
+            },
+            RealtimeConfig::default(),
+        )
+        .unwrap();
+
+        let events = vec![
+            StepEvent {
+                at: Duration::ZERO,
+                axis: 0,
+                direction: Direction::CW,
+            },
+            StepEvent {
+                at: Duration::ZERO,
+                axis: 1,
+                direction: Direction::CW,
+            },
+        ];
+        assert_eq!(
+            thread.run_blocking(events.clone()),
+            Err("Axis not connected")
+        );
+        assert_eq!(thread.stats().errors, 1);
+
+        // The step made before the failure is still reported
+        let result = thread
+            .submit(events, None)
+            .unwrap()
+            .blocking_recv()
+            .unwrap();
+        assert_eq!(result.steps, vec![1]);
+        assert_eq!(result.error, Some("Axis not connected"));
+    }
+
+    fn stopping_batch(mode: StopMode) -> BatchResult {
+        let token = CancelToken::new();
+        let output_token = token.clone();
+        let mut pulses = 0;
+        let thread = StepThread::spawn(
+            move |_axis: usize, _direction: Direction| {
+                pulses += 1;
+                if pulses == 10 {
+                    match mode {
+                        StopMode::Stop => output_token.stop(),
+                        StopMode::Abort => output_token.abort(),
+                    }
+                }
+                Ok(())
+            },
+            RealtimeConfig::default(),
+        )
+        .unwrap();
+
+        let mut planner = Planner::new(vec![1.0]);
+        planner.push(&[-1000.0], 1000.0, 100000.0).unwrap();
+        let block = planner.pop().unwrap();
+        let cancellation = Cancellation::for_block(token, block.clone(), vec![0]);
+        thread
+            .submit(StepEvent::from_block(&block), Some(cancellation))
+            .unwrap()
+            .blocking_recv()
+            .unwrap()
+    }
+
+    #[test]
+    fn stop_decelerates_batch() {
+        // Cruising at 1000 steps/s takes 5 steps to stop
+        let result = stopping_batch(StopMode::Stop);
+        assert_eq!(result.outcome, MoveOutcome::Stopped);
+        assert_eq!(result.steps(0), -15);
+    }
+
+    #[test]
+    fn abort_ends_batch() {
+        let result = stopping_batch(StopMode::Abort);
+        assert_eq!(result.outcome, MoveOutcome::Aborted);
+        assert_eq!(result.steps, vec![-10]);
+    }
+}
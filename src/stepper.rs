@@ -1,24 +1,159 @@
-use crate::connection::Connection;
-use mockall::automock;
+use mockall::mock;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 /// Direction of the stepper CW = Clockwise / CCW = Counter clockwise
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     CW,
     CCW,
 }
 
-#[automock]
-pub trait Stepper {
-    fn new(pins: (u8, u8, u8), connection: Connection) -> Self;
-    //fn move_to_position(&mut self, position: i32);
-    //fn move_steps(&mut self, steps: i32);
-    //fn set_steps_to_move(&mut self, steps: i32);
+/// Minimal interface every driver implements: making steps in a direction and switching the
+/// motor outputs. Drivers are created through their own builders.
+pub trait StepGenerator {
+    /// Amount of steps to make with `step`, the sign gives the direction
+    fn set_steps_to_move(&mut self, steps: i32);
+    /// Makes one of the remaining steps, returns Err() when no more steps remain
     fn step(&mut self) -> Result<(), &'static str>;
-    fn set_direction(&mut self, direction: Direction);
+    /// Sets the direction of the following steps
+    fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str>;
+
+    /// Turns the motor outputs on or off. Drivers without an enable line return an error.
+    fn set_enabled(&mut self, _enabled: bool) -> Result<(), &'static str> {
+        Err("Driver does not support enabling or disabling the motor")
+    }
+}
+
+/// Settings of drivers that can be configured at runtime, settings a driver does not have
+/// return an error
+pub trait ConfigurableDriver {
+    /// Sets the RMS run current in mA for drivers with a configurable current
+    fn set_motor_current(&mut self, _current: u16) -> Result<(), &'static str> {
+        Err("Driver does not support setting the motor current")
+    }
+
+    /// Sets the amount of microsteps per full step for drivers with a configurable resolution
+    fn set_microsteps(&mut self, _microsteps: u16) -> Result<(), &'static str> {
+        Err("Driver does not support setting the microstep resolution")
+    }
+
+    /// Sets how long the motor has to stand still before the driver lowers the current to the
+    /// hold current on its own
+    fn set_power_down_delay(&mut self, _delay: Duration) -> Result<(), &'static str> {
+        Err("Driver does not support reducing the current at standstill")
+    }
+}
+
+/// Drivers that report their state and detect faults such as shorts or overheating
+pub trait DiagnosticDriver {
+    fn status(&mut self) -> Result<DriverStatus, &'static str>;
+    /// Clears the latched error flags, the driver turns its outputs back on once the cause is gone
+    fn clear_faults(&mut self) -> Result<(), &'static str>;
+}
+
+/// State reported by a driver, flags a driver can not detect stay false
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DriverStatus {
+    pub standstill: bool,
+    pub stalled: bool,
+    pub overtemperature_warning: bool,
+    pub overtemperature: bool,
+    pub short_to_ground: bool,
+    pub short_to_supply: bool,
+    /// Often shows at standstill or low speeds too, so it is only a warning
+    pub open_load: bool,
+    /// The chopper runs in stealthChop instead of spreadCycle
+    pub stealth: bool,
+    /// Current scale 0-31 the chopper runs at (CS_ACTUAL), lower than the run current at
+    /// standstill or while CoolStep lowers it
+    pub current_scale: u8,
+    /// Highest of the 120, 143, 150 and 157°C thresholds the driver is above, 0 below all of them
+    /// or for drivers without these flags
+    pub temperature_threshold: u16,
+}
+
+impl DriverStatus {
+    /// Descriptions of the active faults, the driver disables its outputs on any of them
+    pub fn faults(&self) -> Vec<&'static str> {
+        let mut faults = Vec::new();
+        if self.overtemperature {
+            faults.push("Driver overheated");
+        }
+        if self.short_to_ground {
+            faults.push("Short to ground");
+        }
+        if self.short_to_supply {
+            faults.push("Short to supply");
+        }
+        faults
+    }
+
+    /// Descriptions of the conditions that do not stop the driver but deserve a look
+    pub fn warnings(&self) -> Vec<&'static str> {
+        let mut warnings = Vec::new();
+        if self.overtemperature_warning {
+            warnings.push("Driver temperature above the warning threshold");
+        }
+        if self.open_load {
+            warnings.push("Open load");
+        }
+        warnings
+    }
+
+    pub fn has_fault(&self) -> bool {
+        self.overtemperature || self.short_to_ground || self.short_to_supply
+    }
+}
+
+mock! {
+    /// Driver implementing all driver traits, for testing controllers without hardware
+    pub Stepper {}
+
+    impl StepGenerator for Stepper {
+        fn set_steps_to_move(&mut self, steps: i32);
+        fn step(&mut self) -> Result<(), &'static str>;
+        fn set_direction(&mut self, direction: Direction) -> Result<(), &'static str>;
+        fn set_enabled(&mut self, enabled: bool) -> Result<(), &'static str>;
+    }
+
+    impl ConfigurableDriver for Stepper {
+        fn set_motor_current(&mut self, current: u16) -> Result<(), &'static str>;
+        fn set_microsteps(&mut self, microsteps: u16) -> Result<(), &'static str>;
+        fn set_power_down_delay(&mut self, delay: Duration) -> Result<(), &'static str>;
+    }
+
+    impl DiagnosticDriver for Stepper {
+        fn status(&mut self) -> Result<DriverStatus, &'static str>;
+        fn clear_faults(&mut self) -> Result<(), &'static str>;
+    }
 }
 
-// Trait used to activating a stepper ready for movements
-pub trait Activatable {
-    fn activate();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_faults() {
+        let status = DriverStatus {
+            overtemperature_warning: true,
+            open_load: true,
+            ..Default::default()
+        };
+        assert!(!status.has_fault());
+        assert!(status.faults().is_empty());
+        assert_eq!(status.warnings().len(), 2);
+
+        let status = DriverStatus {
+            short_to_ground: true,
+            overtemperature: true,
+            ..status
+        };
+        assert!(status.has_fault());
+        assert_eq!(
+            status.faults(),
+            vec!["Driver overheated", "Short to ground"]
+        );
+    }
 }
@@ -0,0 +1,236 @@
+use crate::cancel::CancelToken;
+use crate::gpio::open_chip;
+use gpio_cdev::{Chip, EventRequestFlags, LineEventHandle, LineRequestFlags};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Level of the input line while the switch is triggered
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ActiveLevel {
+    High,
+    /// E.g. normally closed switches pulling the line to ground, which also trigger when a wire
+    /// breaks
+    Low,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SwitchConfig {
+    pub pin: u32,
+    pub active_level: ActiveLevel,
+    /// How long the line has to stay released before the switch counts as released again
+    pub debounce: Duration,
+}
+
+impl SwitchConfig {
+    pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(5);
+
+    pub fn new(pin: u32, active_level: ActiveLevel) -> Self {
+        Self {
+            pin,
+            active_level,
+            debounce: Self::DEFAULT_DEBOUNCE,
+        }
+    }
+}
+
+/// Limit or home switch on a GPIO input. A thread waits for the edge events of the line and
+/// aborts the moves heading into the switch as soon as it triggers, releasing is debounced.
+pub struct Switch {
+    token: CancelToken,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Switch {
+    /// How often the watcher thread checks whether the switch was dropped
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Switch without a GPIO line whose state is set with `set_triggered`, e.g. for switches
+    /// read over a bus or for simulations
+    pub fn manual() -> Self {
+        Self {
+            token: CancelToken::new(),
+            running: Arc::new(AtomicBool::new(false)),
+            handle: None,
+        }
+    }
+
+    /// Sets the state of a manual switch, a switch on a GPIO line overrides it on its next event
+    pub fn set_triggered(&self, triggered: bool) {
+        match triggered {
+            true => self.token.abort(),
+            false => self.token.reset(),
+        }
+    }
+
+    pub fn open(gpio_chip: &str, config: SwitchConfig) -> Result<Self, &'static str> {
+        Self::request(&mut open_chip(gpio_chip)?, config)
+    }
+
+    pub fn request(chip: &mut Chip, config: SwitchConfig) -> Result<Self, &'static str> {
+        let flags = match config.active_level {
+            ActiveLevel::High => LineRequestFlags::INPUT,
+            ActiveLevel::Low => LineRequestFlags::INPUT | LineRequestFlags::ACTIVE_LOW,
+        };
+        let mut events = chip
+            .get_line(config.pin)
+            .map_err(|_| "GPIO line does not exist on the chip")?
+            .events(flags, EventRequestFlags::BOTH_EDGES, "stepper-switch")
+            .map_err(|_| "GPIO line could not be requested as input, is it already in use?")?;
+
+        let token = CancelToken::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut debouncer = Debouncer::new(config.debounce);
+        if read_active(&events)? {
+            debouncer.edge(true, Instant::now());
+            token.abort();
+        }
+
+        let thread_token = token.clone();
+        let thread_running = running.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("switch-{}", config.pin))
+            .spawn(move || {
+                while thread_running.load(Ordering::SeqCst) {
+                    let timeout = debouncer.timeout(Instant::now(), Self::POLL_INTERVAL);
+                    if wait_for_event(&events, timeout) {
+                        let _ = events.get_event();
+                        let active = read_active(&events).unwrap_or(true);
+                        debouncer.edge(active, Instant::now());
+                    }
+                    debouncer.update(Instant::now());
+
+                    match debouncer.is_triggered() {
+                        true => thread_token.abort(),
+                        false => thread_token.reset(),
+                    }
+                }
+            })
+            .map_err(|_| "Could not start the switch thread")?;
+
+        Ok(Self {
+            token,
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.requested().is_some()
+    }
+
+    /// Token aborting moves while the switch is triggered, see
+    /// `MotionController::set_limit_switch`
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for Switch {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Values read from a line requested with ACTIVE_LOW are already inverted by the kernel
+fn read_active(events: &LineEventHandle) -> Result<bool, &'static str> {
+    events
+        .get_value()
+        .map(|value| value == 1)
+        .map_err(|_| "Could not read GPIO line value")
+}
+
+/// Waits until an edge event is pending on the line or `timeout` passed
+fn wait_for_event(events: &LineEventHandle, timeout: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: events.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    unsafe { libc::poll(&mut fd, 1, timeout) > 0 }
+}
+
+/// Triggers on the first active edge and releases once the line stayed inactive for the
+/// debounce time, so a bouncing contact can not let a move continue into the switch
+#[derive(Debug)]
+struct Debouncer {
+    debounce: Duration,
+    triggered: bool,
+    release_at: Option<Instant>,
+}
+
+impl Debouncer {
+    fn new(debounce: Duration) -> Self {
+        Self {
+            debounce,
+            triggered: false,
+            release_at: None,
+        }
+    }
+
+    fn edge(&mut self, active: bool, now: Instant) {
+        if active {
+            self.triggered = true;
+            self.release_at = None;
+        } else if self.triggered {
+            self.release_at = Some(now + self.debounce);
+        }
+    }
+
+    fn update(&mut self, now: Instant) {
+        if self.release_at.is_some_and(|at| now >= at) {
+            self.triggered = false;
+            self.release_at = None;
+        }
+    }
+
+    /// How long to wait for the next event before the state has to be updated
+    fn timeout(&self, now: Instant, max: Duration) -> Duration {
+        match self.release_at {
+            Some(at) => at.saturating_duration_since(now).min(max),
+            None => max,
+        }
+    }
+
+    fn is_triggered(&self) -> bool {
+        self.triggered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounce_release() {
+        let start = Instant::now();
+        let debounce = Duration::from_millis(5);
+        let mut debouncer = Debouncer::new(debounce);
+
+        debouncer.edge(true, start);
+        assert!(debouncer.is_triggered());
+
+        // Bouncing contact
+        debouncer.edge(false, start + Duration::from_millis(1));
+        debouncer.update(start + Duration::from_millis(2));
+        assert!(debouncer.is_triggered());
+        debouncer.edge(true, start + Duration::from_millis(3));
+        debouncer.update(start + Duration::from_millis(10));
+        assert!(debouncer.is_triggered());
+
+        debouncer.edge(false, start + Duration::from_millis(10));
+        assert_eq!(
+            debouncer.timeout(start + Duration::from_millis(12), Duration::from_secs(1)),
+            Duration::from_millis(3)
+        );
+        debouncer.update(start + Duration::from_millis(15));
+        assert!(!debouncer.is_triggered());
+    }
+}
@@ -0,0 +1,148 @@
+//! Position, velocity and driver status of the axes of a `rpc::Service`, sampled for the feeds
+//! of the `http` and `mqtt` features
+use crate::rpc::Service;
+use crate::stepper::{ConfigurableDriver, DiagnosticDriver, DriverStatus, StepGenerator};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Age of a driver status after which a frame marks it as stale, a few status intervals of
+/// `stepperd`
+pub const DRIVER_STALE_AFTER: Duration = Duration::from_secs(1);
+
+/// Snapshot of the axes sent by the WebSocket and MQTT feeds
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub emergency_stop: bool,
+    pub axes: Vec<AxisTelemetry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AxisTelemetry {
+    pub name: String,
+    pub position: i32,
+    /// Steps/s over the last interval
+    pub velocity: f64,
+    pub moving: bool,
+    /// Last status read by the axis, see `MotionController::set_status_interval`
+    pub driver: Option<DriverStatus>,
+    /// `driver` was read longer than `DRIVER_STALE_AFTER` ago, e.g. as a long block of a move
+    /// holds back the next read
+    pub driver_stale: bool,
+    pub faults: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Samples the axes of `service` every `interval`. The sampler stops once every receiver is
+/// dropped.
+pub fn spawn_telemetry<T>(
+    service: Arc<Service<T>>,
+    interval: Duration,
+) -> watch::Receiver<Telemetry>
+where
+    T: StepGenerator + ConfigurableDriver + DiagnosticDriver + Send + 'static,
+{
+    let (frames, receiver) = watch::channel(Telemetry::default());
+
+    tokio::spawn(async move {
+        let axes = service.axes();
+        let mut last: Vec<(i32, Instant)> = axes
+            .iter()
+            .map(|axis| (axis.monitor().position(), Instant::now()))
+            .collect();
+        let mut ticks = tokio::time::interval(interval);
+
+        loop {
+            ticks.tick().await;
+            let mut frame = Telemetry {
+                emergency_stop: service.is_emergency_stopped(),
+                axes: Vec::with_capacity(axes.len()),
+            };
+
+            for (index, axis) in axes.iter().enumerate() {
+                let monitor = axis.monitor();
+                let position = monitor.position();
+                let now = Instant::now();
+                let (last_position, last_time) = last[index];
+                let elapsed = now.duration_since(last_time).as_secs_f64();
+                let velocity = match elapsed > 0.0 {
+                    true => (position - last_position) as f64 / elapsed,
+                    false => 0.0,
+                };
+                last[index] = (position, now);
+
+                let driver = monitor.driver_status();
+                let status = driver.map(|(status, _)| status);
+                frame.axes.push(AxisTelemetry {
+                    name: axis.name().to_owned(),
+                    position,
+                    velocity,
+                    moving: monitor.is_moving(),
+                    driver: status,
+                    driver_stale: driver.is_some_and(|(_, read_at)| {
+                        now.duration_since(read_at) > DRIVER_STALE_AFTER
+                    }),
+                    faults: status
+                        .map(|status| status.faults().into_iter().map(String::from).collect())
+                        .unwrap_or_default(),
+                    warnings: status
+                        .map(|status| status.warnings().into_iter().map(String::from).collect())
+                        .unwrap_or_default(),
+                });
+            }
+
+            if frames.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::EmergencyStop;
+    use crate::handle::{test_controller, test_stepper, AxisHandle};
+    use crate::stepper::MockStepper;
+
+    fn get_mock_service() -> Arc<Service<MockStepper>> {
+        let mut stepper = test_stepper();
+        stepper.expect_status().returning(|| {
+            Ok(DriverStatus {
+                overtemperature_warning: true,
+                ..Default::default()
+            })
+        });
+        let mut controller = test_controller("x", stepper);
+        controller.set_status_interval(Some(Duration::ZERO));
+        let axis = AxisHandle::spawn(controller).unwrap();
+        Arc::new(Service::new(vec![axis], EmergencyStop::new()))
+    }
+
+    #[tokio::test]
+    async fn telemetry_frames() {
+        let service = get_mock_service();
+        let mut telemetry = spawn_telemetry(service.clone(), Duration::from_millis(5));
+        service
+            .call("move", serde_json::json!({"axis": "x", "steps": 40}))
+            .await
+            .unwrap();
+
+        let frame = telemetry
+            .wait_for(|frame| {
+                frame
+                    .axes
+                    .first()
+                    .is_some_and(|axis| axis.position == 40 && axis.driver.is_some())
+            })
+            .await
+            .unwrap()
+            .clone();
+        assert!(!frame.emergency_stop);
+        assert!(!frame.axes[0].moving);
+        assert_eq!(frame.axes[0].warnings.len(), 1);
+        assert!(!frame.axes[0].driver_stale);
+    }
+}